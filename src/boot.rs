@@ -0,0 +1,215 @@
+use crate::procstate::MachineState;
+use crate::config;
+use crate::mips;
+use crate::elf;
+use crate::kseg01_to_paddr;
+use log::info;
+
+/*
+Direct boot of a Linux kernel without U-Boot.
+
+The kernel is an ELF file (vmlinux) or an uncompressed legacy uImage.
+Arguments are passed in the way of the ath79 firmware (arch/mips/ath79/prom.c):
+  a0 : argc
+  a1 : argv (argv[0] is a dummy program name, the others are the words of the command line)
+  a2 : envp ("initrd_start=0x..." and "initrd_size=0x..." when an initrd is given)
+  a3 : 0
+The argument strings are placed at the top of DRAM and the initrd is placed just below them.
+*/
+
+const UIMAGE_MAGIC       : u32   = 0x27051956;
+const UIMAGE_HEADER_SIZE : usize = 64;
+const UIMAGE_COMP_NONE   : u8    = 0;
+
+// size of the area for argv, envp and their strings at the top of DRAM
+const FW_ARGS_AREA_SIZE : u32 = 0x10000;
+
+fn read32(data: &[u8], off: usize) -> u32 {
+    u32::from_be_bytes([data[off], data[off+1], data[off+2], data[off+3]])
+}
+
+fn copy_to_dram(ms: &mut MachineState, paddr: u32, data: &[u8]) -> Result<(), String> {
+    let start = paddr.wrapping_sub(config::RAM_AREA_ADDR) as usize;
+    match ms.mem.mem0.get_mut(start .. start + data.len()) {
+        Some(dst) => { dst.copy_from_slice(data); Ok(()) }
+        None      => Err(format!("0x{:x} bytes at 0x{:>08x} do not fit in DRAM", data.len(), paddr)),
+    }
+}
+
+// loads a legacy uImage. Returns the entry point and the end of the image in physical address
+fn load_uimage(ms: &mut MachineState, data: &[u8]) -> Result<(u32, u32), String> {
+    if data.len() < UIMAGE_HEADER_SIZE {
+        return Err("truncated uImage header".to_string());
+    }
+    let size  = read32(data, 12) as usize;
+    let load  = read32(data, 16);
+    let entry = read32(data, 20);
+    if data[31] != UIMAGE_COMP_NONE {
+        return Err(format!("compressed uImage (compression type {}) is not supported", data[31]));
+    }
+    let body = data.get(UIMAGE_HEADER_SIZE .. UIMAGE_HEADER_SIZE + size).ok_or("truncated uImage".to_string())?;
+    let paddr = kseg01_to_paddr!(load);
+    copy_to_dram(ms, paddr, body)?;
+    info!("uImage: load 0x{:>08x}, entry 0x{:>08x}, size 0x{:x}", load, entry, size);
+    Ok((entry, paddr + size as u32))
+}
+
+// loads a kernel image (ELF or uImage). Returns the entry point and the end of the kernel in physical address
+pub fn load_kernel(ms: &mut MachineState, data: &[u8]) -> Result<(u32, u32), String> {
+    if elf::is_elf(data) {
+        elf::load(ms, data)
+    }else if data.len() >= 4 && read32(data, 0) == UIMAGE_MAGIC {
+        load_uimage(ms, data)
+    }else{
+        Err("unknown kernel image format (neither ELF nor uImage)".to_string())
+    }
+}
+
+/*
+Loads a kernel and an initrd, places the command line and prepares the registers for the kernel.
+Returns the entry point.
+*/
+pub fn boot_kernel(ms: &mut MachineState, kernel: &[u8], initrd: Option<&[u8]>, cmdline: &str) -> Result<u32, String> {
+    let (entry, kernel_end) = load_kernel(ms, kernel)?;
+
+    let args_base = config::RAM_AREA_ADDR + config::DRAM_SIZE as u32 - FW_ARGS_AREA_SIZE;
+    let mut envs : Vec<String> = Vec::new();
+
+    if let Some(rd) = initrd {
+        let rd_start = match args_base.checked_sub(rd.len() as u32) {
+            Some(a) if (a & !0xfff) >= kernel_end => a & !0xfff,
+            _ => { return Err(format!("initrd (0x{:x} bytes) does not fit in DRAM", rd.len())); }
+        };
+        copy_to_dram(ms, rd_start, rd)?;
+        info!("initrd: 0x{:>08x} - 0x{:>08x}", rd_start, rd_start + rd.len() as u32);
+        envs.push(format!("initrd_start=0x{:x}", rd_start | mips::KSEG0));
+        envs.push(format!("initrd_size=0x{:x}", rd.len()));
+    }
+
+    let mut argv : Vec<String> = vec!["vmlinux".to_string()];
+    argv.extend(cmdline.split_whitespace().map(|s| s.to_string()));
+
+    // pointer arrays (terminated by NULL) followed by the strings
+    let ptr_size = 4 * (argv.len() + 1 + envs.len() + 1) as u32;
+    let argv_addr = args_base;
+    let envp_addr = args_base + 4 * (argv.len() as u32 + 1);
+    let mut str_addr = args_base + ptr_size;
+    let mut ptrs : Vec<u8> = Vec::new();
+    let mut strs : Vec<u8> = Vec::new();
+
+    for list in [&argv, &envs] {
+        for s in list.iter() {
+            ptrs.extend_from_slice(&(str_addr | mips::KSEG0).to_be_bytes());
+            strs.extend_from_slice(s.as_bytes());
+            strs.push(0);
+            str_addr += s.len() as u32 + 1;
+        }
+        ptrs.extend_from_slice(&0u32.to_be_bytes());
+    }
+    if ptrs.len() + strs.len() > FW_ARGS_AREA_SIZE as usize {
+        return Err("kernel command line is too long".to_string());
+    }
+    copy_to_dram(ms, args_base, &ptrs)?;
+    copy_to_dram(ms, args_base + ptr_size, &strs)?;
+
+    info!("kernel command line: \"{}\"", cmdline);
+    elf::set_entry(ms, entry, &[argv.len() as u32, argv_addr | mips::KSEG0, envp_addr | mips::KSEG0, 0]);
+    Ok(entry)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dev_spiflash::SPI_FLASH_PARAM_S25FL164K;
+    use crate::exrmips::generate_machine_state;
+
+    // legacy uImage with the body loaded at load and started at entry
+    fn uimage(load: u32, entry: u32, body: &[u8], comp: u8) -> Vec<u8> {
+        let mut v = vec![0u8; UIMAGE_HEADER_SIZE];
+        v[0..4].copy_from_slice(&UIMAGE_MAGIC.to_be_bytes());
+        v[12..16].copy_from_slice(&(body.len() as u32).to_be_bytes());
+        v[16..20].copy_from_slice(&load.to_be_bytes());
+        v[20..24].copy_from_slice(&entry.to_be_bytes());
+        v[31] = comp;
+        v.extend_from_slice(body);
+        v
+    }
+
+    fn word(ms: &MachineState, vaddr: u32) -> u32 {
+        read32(&ms.mem.mem0, kseg01_to_paddr!(vaddr) as usize)
+    }
+
+    fn string(ms: &MachineState, vaddr: u32) -> String {
+        let start = kseg01_to_paddr!(vaddr) as usize;
+        let len = ms.mem.mem0[start..].iter().position(|b| *b == 0).unwrap();
+        String::from_utf8(ms.mem.mem0[start .. start + len].to_vec()).unwrap()
+    }
+
+    // strings pointed by a NULL-terminated pointer array
+    fn strings(ms: &MachineState, vaddr: u32) -> Vec<String> {
+        (0..).map(|i| word(ms, vaddr + 4*i)).take_while(|p| *p != 0).map(|p| string(ms, p)).collect()
+    }
+
+    #[test]
+    fn arguments() {
+        let mut ms = generate_machine_state(&SPI_FLASH_PARAM_S25FL164K, &[]);
+        let kernel = uimage(0x80001000, 0x80001400, &[0x11; 0x800], UIMAGE_COMP_NONE);
+        let entry = boot_kernel(&mut ms, &kernel, None, " console=ttyS0,115200  root=/dev/mtdblock2 ").unwrap();
+        assert_eq!(entry, 0x80001400);
+        assert_eq!(ms.reg.pc, 0x80001400);
+        assert!(ms.mem.mem0[0x1000..0x1800].iter().all(|b| *b == 0x11));
+
+        let args_base = config::RAM_AREA_ADDR + config::DRAM_SIZE as u32 - FW_ARGS_AREA_SIZE;
+        assert_eq!(&ms.reg.r[4..8], &[3, args_base | mips::KSEG0, (args_base + 16) | mips::KSEG0, 0]);
+        assert_eq!(strings(&ms, ms.reg.r[5]), vec!["vmlinux", "console=ttyS0,115200", "root=/dev/mtdblock2"]);
+        assert!(strings(&ms, ms.reg.r[6]).is_empty());
+        // the strings follow the pointer arrays
+        assert_eq!(word(&ms, ms.reg.r[5]), (args_base + 5*4) | mips::KSEG0);
+    }
+
+    #[test]
+    fn initrd() {
+        let mut ms = generate_machine_state(&SPI_FLASH_PARAM_S25FL164K, &[]);
+        let kernel = uimage(0x80001000, 0x80001000, &[0; 0x100], UIMAGE_COMP_NONE);
+        let rd = vec![0x5a; 0x1234];
+        boot_kernel(&mut ms, &kernel, Some(&rd), "rdinit=/init").unwrap();
+
+        // the initrd is placed at a page boundary below the argument area
+        let args_base = config::RAM_AREA_ADDR + config::DRAM_SIZE as u32 - FW_ARGS_AREA_SIZE;
+        let rd_start = (args_base - 0x1234) & !0xfff;
+        assert_eq!(rd_start, 0x3fee000);
+        assert_eq!(&ms.mem.mem0[rd_start as usize .. rd_start as usize + rd.len()], &rd[..]);
+
+        assert_eq!(ms.reg.r[4], 2);
+        assert_eq!(strings(&ms, ms.reg.r[5]), vec!["vmlinux", "rdinit=/init"]);
+        assert_eq!(strings(&ms, ms.reg.r[6]), vec!["initrd_start=0x83fee000", "initrd_size=0x1234"]);
+    }
+
+    #[test]
+    fn initrd_over_the_kernel() {
+        // the kernel ends at 0x3f01000
+        let kernel = uimage(0x83f00000, 0x83f00000, &[0; 0x1000], UIMAGE_COMP_NONE);
+        let mut ms = generate_machine_state(&SPI_FLASH_PARAM_S25FL164K, &[]);
+        assert!(boot_kernel(&mut ms, &kernel, Some(&vec![0; 0xef000]), "").is_ok());
+
+        let mut ms = generate_machine_state(&SPI_FLASH_PARAM_S25FL164K, &[]);
+        assert_eq!(boot_kernel(&mut ms, &kernel, Some(&vec![0; 0xef001]), ""),
+                   Err("initrd (0xef001 bytes) does not fit in DRAM".to_string()));
+        assert_eq!(boot_kernel(&mut ms, &kernel, Some(&vec![0; config::DRAM_SIZE]), ""),
+                   Err(format!("initrd (0x{:x} bytes) does not fit in DRAM", config::DRAM_SIZE)));
+    }
+
+    #[test]
+    fn uimage_errors() {
+        let mut ms = generate_machine_state(&SPI_FLASH_PARAM_S25FL164K, &[]);
+        let kernel = uimage(0x80001000, 0x80001000, &[0; 0x100], 1); // gzip
+        assert_eq!(load_kernel(&mut ms, &kernel), Err("compressed uImage (compression type 1) is not supported".to_string()));
+
+        let kernel = uimage(0x80001000, 0x80001000, &[0; 0x100], UIMAGE_COMP_NONE);
+        assert_eq!(load_kernel(&mut ms, &kernel[..kernel.len() - 1]), Err("truncated uImage".to_string()));
+        assert_eq!(load_kernel(&mut ms, &kernel[..UIMAGE_HEADER_SIZE - 1]), Err("truncated uImage header".to_string()));
+        assert_eq!(load_kernel(&mut ms, &kernel), Ok((0x80001000, 0x1100)));
+
+        assert_eq!(load_kernel(&mut ms, b"\x1f\x8b\x08\x00"), Err("unknown kernel image format (neither ELF nor uImage)".to_string()));
+    }
+}
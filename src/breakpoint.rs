@@ -0,0 +1,434 @@
+use crate::procstate::Reg;
+use crate::mips;
+use crate::symbols::SymbolTable;
+
+/*
+Breakpoints and watchpoints of the emulator.
+
+A breakpoint stops execution before the instruction at its address is executed.
+It may have a condition on a register value and a hit count;
+execution stops when the condition holds and the address has been hit that many times.
+
+A watchpoint stops execution after a load or store accessing its address range.
+The range is given as a virtual or a physical address.
+Watchpoints are checked in mem::load_memory/store_memory only while at least one of them is enabled.
+
+Specification strings (numbers in hexadecimal except the hit count N in decimal):
+  breakpoint : ADDR[,REG==VAL][,hits=N]      (==, !=, <, <=, >, >= are available, unsigned comparison)
+  watchpoint : ADDR[+LEN][,r|w|rw][,phys]    (LEN: 4 and mode: w by default)
+ADDR of a breakpoint may be a symbol with an offset, SYMBOL[+OFF]. That of a watchpoint may be a symbol.
+*/
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum CmpOp { Eq, Ne, Lt, Le, Gt, Ge }
+
+#[derive(Clone, Copy)]
+pub struct Condition {
+    pub reg : usize, // 0-31: general purpose registers, 32: hi, 33: lo
+    pub op  : CmpOp,
+    pub val : u32,
+}
+
+pub struct Breakpoint {
+    pub id      : u32,
+    pub addr    : u32,
+    pub cond    : Option<Condition>,
+    pub count   : u64, // stops at the count-th hit and later
+    pub hits    : u64,
+    pub enabled : bool,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum WatchKind { Read, Write, Access }
+
+pub struct Watchpoint {
+    pub id      : u32,
+    pub start   : u32,
+    pub len     : u32,
+    pub phys    : bool,
+    pub kind    : WatchKind,
+    pub hits    : u64,
+    pub enabled : bool,
+}
+
+pub struct WatchHit {
+    pub id    : u32,
+    pub vaddr : u32,
+    pub paddr : u32,
+    pub width : u32,
+    pub write : bool,
+    pub data  : u32,  // stored data (for a store)
+}
+
+pub struct BreakpointSet {
+    pub breakpoints : Vec<Breakpoint>,
+    pub watchpoints : Vec<Watchpoint>,
+    pub bp_active   : bool,  // at least one breakpoint is enabled
+    pub wp_active   : bool,  // at least one watchpoint is enabled
+    pub watch_hit   : Option<WatchHit>,
+    next_id : u32,
+}
+
+impl Condition {
+    fn holds(&self, reg: &Reg) -> bool {
+        let v = match self.reg {
+            32 => reg.hi,
+            33 => reg.lo,
+            n  => reg.r[n],
+        };
+        match self.op {
+            CmpOp::Eq => v == self.val,
+            CmpOp::Ne => v != self.val,
+            CmpOp::Lt => v <  self.val,
+            CmpOp::Le => v <= self.val,
+            CmpOp::Gt => v >  self.val,
+            CmpOp::Ge => v >= self.val,
+        }
+    }
+
+    fn reg_name(&self) -> &'static str {
+        match self.reg {
+            32 => "hi",
+            33 => "lo",
+            n  => mips::REGSTR[n],
+        }
+    }
+}
+
+impl std::fmt::Display for Breakpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "breakpoint {}: 0x{:>08x}", self.id, self.addr)?;
+        if let Some(c) = self.cond {
+            let op = match c.op { CmpOp::Eq => "==", CmpOp::Ne => "!=", CmpOp::Lt => "<", CmpOp::Le => "<=", CmpOp::Gt => ">", CmpOp::Ge => ">=" };
+            write!(f, " if {}{}0x{:x}", c.reg_name(), op, c.val)?;
+        }
+        if self.count > 1 {
+            write!(f, " hits={}", self.count)?;
+        }
+        write!(f, " (hit {} times){}", self.hits, if self.enabled { "" }else{ " [disabled]" })
+    }
+}
+
+impl std::fmt::Display for Watchpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let kind = match self.kind { WatchKind::Read => "r", WatchKind::Write => "w", WatchKind::Access => "rw" };
+        write!(f, "watchpoint {}: {} 0x{:>08x}-0x{:>08x} {} (hit {} times){}",
+            self.id, if self.phys { "paddr" }else{ "vaddr" }, self.start, self.start.wrapping_add(self.len).wrapping_sub(1),
+            kind, self.hits, if self.enabled { "" }else{ " [disabled]" })
+    }
+}
+
+fn parse_hex(s: &str) -> Result<u32, String> {
+    let t = s.trim();
+    let t = t.trim_start_matches("0x").trim_start_matches("0X");
+    u32::from_str_radix(t, 16).map_err(|_| format!("\"{}\" is not a hexadecimal number", s))
+}
+
+// a symbol is preferred to a hexadecimal number without "0x" (e.g., "add")
+fn parse_addr(s: &str, syms: &SymbolTable) -> Result<u32, String> {
+    let t = s.trim();
+    if t.starts_with("0x") || t.starts_with("0X") {
+        return parse_hex(t);
+    }
+    match syms.resolve(t) {
+        Some(addr) => Ok(addr),
+        None       => parse_hex(t).map_err(|_| format!("\"{}\" is neither a symbol nor a hexadecimal number", s)),
+    }
+}
+
+fn parse_reg(s: &str) -> Result<usize, String> {
+    let name = s.trim().trim_start_matches('$');
+    match name {
+        "hi" => return Ok(32),
+        "lo" => return Ok(33),
+        "s8" => return Ok(30),
+        _    => {}
+    }
+    if let Some(n) = name.strip_prefix('r').and_then(|n| n.parse::<usize>().ok()) {
+        if n < 32 { return Ok(n); }
+    }
+    mips::REGSTR.iter().position(|r| r[1..] == *name).ok_or(format!("unknown register \"{}\"", s))
+}
+
+fn parse_condition(s: &str) -> Result<Condition, String> {
+    // two-character operators are tried first
+    for (opstr, op) in [("==", CmpOp::Eq), ("!=", CmpOp::Ne), ("<=", CmpOp::Le), (">=", CmpOp::Ge), ("<", CmpOp::Lt), (">", CmpOp::Gt)] {
+        if let Some((reg, val)) = s.split_once(opstr) {
+            return Ok(Condition{ reg: parse_reg(reg)?, op, val: parse_hex(val)? });
+        }
+    }
+    Err(format!("\"{}\" is not a condition", s))
+}
+
+impl BreakpointSet {
+    pub fn new() -> Self {
+        Self {
+            breakpoints : Vec::new(),
+            watchpoints : Vec::new(),
+            bp_active   : false,
+            wp_active   : false,
+            watch_hit   : None,
+            next_id     : 1,
+        }
+    }
+
+    fn update(&mut self) {
+        self.bp_active = self.breakpoints.iter().any(|b| b.enabled);
+        self.wp_active = self.watchpoints.iter().any(|w| w.enabled);
+    }
+
+    pub fn add_breakpoint(&mut self, addr: u32, cond: Option<Condition>, count: u64) -> u32 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.breakpoints.push(Breakpoint{ id, addr, cond, count: count.max(1), hits: 0, enabled: true });
+        self.update();
+        id
+    }
+
+    pub fn add_watchpoint(&mut self, start: u32, len: u32, phys: bool, kind: WatchKind) -> u32 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.watchpoints.push(Watchpoint{ id, start, len: len.max(1), phys, kind, hits: 0, enabled: true });
+        self.update();
+        id
+    }
+
+    // adds a breakpoint given as "ADDR[,REG==VAL][,hits=N]"
+    pub fn add_breakpoint_spec(&mut self, spec: &str, syms: &SymbolTable) -> Result<u32, String> {
+        let mut items = spec.split(',');
+        let addr = parse_addr(items.next().unwrap_or(""), syms)?;
+        let mut cond  = None;
+        let mut count = 1;
+        for item in items {
+            if let Some(n) = item.trim().strip_prefix("hits=") {
+                count = n.trim().parse::<u64>().map_err(|_| format!("\"{}\" is not a decimal number", n))?;
+            }else{
+                cond = Some(parse_condition(item)?);
+            }
+        }
+        Ok(self.add_breakpoint(addr, cond, count))
+    }
+
+    // adds a watchpoint given as "ADDR[+LEN][,r|w|rw][,phys]"
+    pub fn add_watchpoint_spec(&mut self, spec: &str, syms: &SymbolTable) -> Result<u32, String> {
+        let mut items = spec.split(',');
+        let range = items.next().unwrap_or("");
+        let (start, len) = match range.split_once('+') {
+            Some((a, l)) => (parse_addr(a, syms)?, parse_hex(l)?),
+            None         => (parse_addr(range, syms)?, 4),
+        };
+        let mut kind = WatchKind::Write;
+        let mut phys = false;
+        for item in items {
+            match item.trim() {
+                "r"         => { kind = WatchKind::Read;   }
+                "w"         => { kind = WatchKind::Write;  }
+                "rw" | "a"  => { kind = WatchKind::Access; }
+                "phys" | "p"=> { phys = true; }
+                _           => { return Err(format!("unknown watchpoint option \"{}\"", item)); }
+            }
+        }
+        Ok(self.add_watchpoint(start, len, phys, kind))
+    }
+
+    pub fn remove(&mut self, id: u32) -> bool {
+        let n = self.breakpoints.len() + self.watchpoints.len();
+        self.breakpoints.retain(|b| b.id != id);
+        self.watchpoints.retain(|w| w.id != id);
+        self.update();
+        n != self.breakpoints.len() + self.watchpoints.len()
+    }
+
+    pub fn clear(&mut self) {
+        self.breakpoints.clear();
+        self.watchpoints.clear();
+        self.update();
+    }
+
+    pub fn set_enabled(&mut self, id: u32, enabled: bool) -> bool {
+        let mut found = false;
+        for b in self.breakpoints.iter_mut().filter(|b| b.id == id) { b.enabled = enabled; found = true; }
+        for w in self.watchpoints.iter_mut().filter(|w| w.id == id) { w.enabled = enabled; found = true; }
+        self.update();
+        found
+    }
+
+    // returns the id of the breakpoint which stops execution at pc
+    #[inline]
+    pub fn check_pc(&mut self, reg: &Reg, pc: u32) -> Option<u32> {
+        if !self.bp_active {
+            return None;
+        }
+        let mut stop = None;
+        for b in self.breakpoints.iter_mut() {
+            // The ISA bit is not compared so that MIPS16e code can be stopped with an even address
+            if b.enabled && (b.addr & !1) == (pc & !1) && b.cond.is_none_or(|c| c.holds(reg)) {
+                b.hits += 1;
+                if b.hits >= b.count && stop.is_none() {
+                    stop = Some(b.id);
+                }
+            }
+        }
+        stop
+    }
+
+    // records the first watchpoint hit by a memory access
+    #[inline]
+    pub fn check_access(&mut self, vaddr: u32, paddr: u32, width: u32, write: bool, data: u32) {
+        if !self.wp_active {
+            return;
+        }
+        for w in self.watchpoints.iter_mut() {
+            let kind_match = match w.kind {
+                WatchKind::Read   => !write,
+                WatchKind::Write  =>  write,
+                WatchKind::Access =>  true,
+            };
+            let addr = if w.phys { paddr }else{ vaddr };
+            let overlap = addr.wrapping_sub(w.start) < w.len || w.start.wrapping_sub(addr) < width;
+            if w.enabled && kind_match && overlap {
+                w.hits += 1;
+                if self.watch_hit.is_none() {
+                    self.watch_hit = Some(WatchHit{ id: w.id, vaddr, paddr, width, write, data });
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn symbols() -> SymbolTable {
+        let mut syms = SymbolTable::new();
+        syms.load(b"80001000 T start_kernel\n80002000 t add\n").unwrap();
+        syms
+    }
+
+    #[test]
+    fn registers() {
+        assert_eq!(parse_reg("$sp"), Ok(29));
+        assert_eq!(parse_reg(" a0 "), Ok(4));
+        assert_eq!(parse_reg("zero"), Ok(0));
+        assert_eq!(parse_reg("r31"), Ok(31));
+        assert_eq!(parse_reg("s8"), Ok(30));
+        assert_eq!(parse_reg("fp"), Ok(30));
+        assert_eq!(parse_reg("hi"), Ok(32));
+        assert_eq!(parse_reg("$lo"), Ok(33));
+        assert!(parse_reg("r32").is_err());
+        assert!(parse_reg("x1").is_err());
+    }
+
+    #[test]
+    fn conditions() {
+        for (s, op) in [("v0==1", CmpOp::Eq), ("v0!=1", CmpOp::Ne), ("v0<1", CmpOp::Lt),
+                        ("v0<=1", CmpOp::Le), ("v0>1", CmpOp::Gt), ("v0>=1", CmpOp::Ge)] {
+            let c = parse_condition(s).unwrap();
+            assert!(c.reg == 2 && c.op == op && c.val == 1, "{}", s);
+        }
+        let c = parse_condition(" $a1 == 0xffffffff ").unwrap();
+        assert!(c.reg == 5 && c.op == CmpOp::Eq && c.val == 0xffffffff);
+        assert!(parse_condition("v0=1").is_err());
+        assert!(parse_condition("v0==z").is_err());
+
+        // unsigned comparison
+        let mut reg = Reg::new();
+        reg.r[2] = 0x80000000;
+        assert!(parse_condition("v0>7fffffff").unwrap().holds(&reg));
+        reg.hi = 3;
+        assert!(parse_condition("hi<=3").unwrap().holds(&reg));
+        assert!(!parse_condition("hi!=3").unwrap().holds(&reg));
+    }
+
+    #[test]
+    fn breakpoint_specs() {
+        let syms = symbols();
+        let mut set = BreakpointSet::new();
+
+        let id = set.add_breakpoint_spec("80000400", &syms).unwrap();
+        let b = set.breakpoints.iter().find(|b| b.id == id).unwrap();
+        assert!(b.addr == 0x80000400 && b.cond.is_none() && b.count == 1);
+
+        let id = set.add_breakpoint_spec("start_kernel+0x10,a0==2,hits=10", &syms).unwrap();
+        let b = set.breakpoints.iter().find(|b| b.id == id).unwrap();
+        assert!(b.addr == 0x80001010 && b.cond.is_some_and(|c| c.reg == 4 && c.val == 2) && b.count == 10);
+        assert_eq!(b.to_string(), format!("breakpoint {}: 0x80001010 if $a0==0x2 hits=10 (hit 0 times)", id));
+
+        // a symbol is preferred to a hexadecimal number
+        let id = set.add_breakpoint_spec("add", &syms).unwrap();
+        assert_eq!(set.breakpoints.iter().find(|b| b.id == id).unwrap().addr, 0x80002000);
+        let id = set.add_breakpoint_spec("0xadd", &syms).unwrap();
+        assert_eq!(set.breakpoints.iter().find(|b| b.id == id).unwrap().addr, 0xadd);
+
+        assert!(set.add_breakpoint_spec("no_such_symbol", &syms).is_err());
+        assert!(set.add_breakpoint_spec("80000400,hits=x", &syms).is_err());
+        assert!(set.add_breakpoint_spec("80000400,hits=0x10", &syms).is_err());
+        assert!(set.add_breakpoint_spec("80000400,hits=a", &syms).is_err());
+        assert!(set.add_breakpoint_spec("80000400,v0", &syms).is_err());
+        assert_eq!(set.breakpoints.len(), 4);
+    }
+
+    #[test]
+    fn watchpoint_specs() {
+        let syms = symbols();
+        let mut set = BreakpointSet::new();
+
+        let id = set.add_watchpoint_spec("80010000", &syms).unwrap();
+        let w = set.watchpoints.iter().find(|w| w.id == id).unwrap();
+        assert!(w.start == 0x80010000 && w.len == 4 && !w.phys && w.kind == WatchKind::Write);
+
+        let id = set.add_watchpoint_spec("1000+10,rw,phys", &syms).unwrap();
+        let w = set.watchpoints.iter().find(|w| w.id == id).unwrap();
+        assert!(w.start == 0x1000 && w.len == 0x10 && w.phys && w.kind == WatchKind::Access);
+        assert_eq!(w.to_string(), format!("watchpoint {}: paddr 0x00001000-0x0000100f rw (hit 0 times)", id));
+
+        let id = set.add_watchpoint_spec("add, r", &syms).unwrap();
+        let w = set.watchpoints.iter().find(|w| w.id == id).unwrap();
+        assert!(w.start == 0x80002000 && w.kind == WatchKind::Read);
+
+        assert!(set.add_watchpoint_spec("1000,x", &syms).is_err());
+        assert!(set.add_watchpoint_spec("1000+z", &syms).is_err());
+    }
+
+    #[test]
+    fn breakpoint_hits() {
+        let mut set = BreakpointSet::new();
+        let mut reg = Reg::new();
+        assert_eq!(set.check_pc(&reg, 0x80000400), None);
+
+        let id = set.add_breakpoint(0x80000400, Some(Condition{ reg: 2, op: CmpOp::Eq, val: 1 }), 2);
+        assert_eq!(set.check_pc(&reg, 0x80000400), None);
+        reg.r[2] = 1;
+        assert_eq!(set.check_pc(&reg, 0x80000400), None);
+        assert_eq!(set.check_pc(&reg, 0x80000401), Some(id)); // MIPS16e
+        assert_eq!(set.breakpoints[0].hits, 2);
+
+        assert!(set.set_enabled(id, false));
+        assert!(!set.bp_active);
+        assert_eq!(set.check_pc(&reg, 0x80000400), None);
+        assert!(set.remove(id));
+        assert!(!set.remove(id));
+        assert!(!set.set_enabled(id, true));
+    }
+
+    #[test]
+    fn watchpoint_hits() {
+        let mut set = BreakpointSet::new();
+        let id = set.add_watchpoint(0x80010004, 4, false, WatchKind::Write);
+
+        set.check_access(0x80010004, 0x10004, 4, false, 0);
+        set.check_access(0x80010000, 0x10000, 4, true, 0);
+        set.check_access(0x80010008, 0x10008, 1, true, 0);
+        assert!(set.watch_hit.is_none());
+
+        // a store partially overlapping the range
+        set.check_access(0x80010002, 0x10002, 4, true, 0x1234);
+        let hit = set.watch_hit.take().unwrap();
+        assert!(hit.id == id && hit.vaddr == 0x80010002 && hit.write && hit.data == 0x1234);
+
+        let id = set.add_watchpoint(0x10007, 1, true, WatchKind::Read);
+        set.check_access(0x80010004, 0x10004, 4, false, 0);
+        assert_eq!(set.watch_hit.take().map(|h| h.id), Some(id));
+    }
+}
@@ -6,6 +6,9 @@ pub const CPU_FREQ_COUNT_RESOLUTION    : u32 = 2;  /* CCRes value of RDHWR 3 */
 pub const SYSTEM_TIMER_INTERVAL_IN_USEC: u32 = 1000;
 
 pub const NUM_TLB_ENTRY: u32 = 32;
+
+// GPR sets including the normal one (SRSCtl.HSS+1) in the vectored and EIC interrupt modes
+pub const NUM_SHADOW_SETS: usize = 4;
 pub const TLB_CACHE_BITS : usize = 10;
 pub const TLB_CACHE_SIZE : usize = 1<<TLB_CACHE_BITS;
 
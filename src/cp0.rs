@@ -1,5 +1,5 @@
 use crate::procstate::MachineState;
-use crate::{config, cp0def};
+use crate::{config, cp0def, exception, perf, watch};
 use crate::mips;
 use crate::c0_val;
 use log::{error,info};
@@ -39,7 +39,11 @@ pub fn store(ms : &mut MachineState, (reg,sel) : (u32,u32), val : u32){
 
     match rs {
         cp0def::C0_STATUS   => { c0_val!(ms.reg,rs) = store_masked_val!(val, cp0def::C0_STATUS_SETTING  ); }
-        cp0def::C0_CAUSE    => { c0_val!(ms.reg,rs) = store_masked_val!(val, cp0def::C0_CAUSE_SETTING   ); }
+        cp0def::C0_CAUSE    => {
+            // PCI follows the performance counters
+            let pci = c0_val!(ms.reg,rs) & (1<<cp0def::C0_CAUSE_BIT_PCI);
+            c0_val!(ms.reg,rs) = store_masked_val!(val, cp0def::C0_CAUSE_SETTING   ) | pci;
+        }
 
         cp0def::C0_ENTRYHI  => { c0_val!(ms.reg,rs) = store_masked_val!(val, cp0def::C0_ENTRYHI_SETTING ); }
         cp0def::C0_ENTRYLO0 => { c0_val!(ms.reg,rs) = store_masked_val!(val, cp0def::C0_ENTRYLO0_SETTING); }
@@ -55,7 +59,26 @@ pub fn store(ms : &mut MachineState, (reg,sel) : (u32,u32), val : u32){
         cp0def::C0_CONFIG   => { c0_val!(ms.reg,rs) = store_masked_val!(val, cp0def::C0_CONFIG_SETTING  ); }
         cp0def::C0_CONFIG2  => { c0_val!(ms.reg,rs) = store_masked_val!(val, cp0def::C0_CONFIG2_SETTING ); }
 
-        cp0def::C0_INTCTL   => { c0_val!(ms.reg,rs) = store_masked_val!(val, cp0def::C0_INTCTL_SETTING  ); }
+        cp0def::C0_INTCTL   => {
+            // VS is reserved without vectored interrupts
+            let val = if exception::irq_vectors_implemented(ms) { val }else{ val & !cp0def::C0_INTCTL_VS_MASK };
+            c0_val!(ms.reg,rs) = store_masked_val!(val, cp0def::C0_INTCTL_SETTING  );
+        }
+        cp0def::C0_SRSCTL   => {
+            // ESS and PSS are limited to the existing shadow sets
+            let hss = (c0_val!(ms.reg,rs) >> cp0def::C0_SRSCTL_BIT_HSS) & 0xf;
+            let ess = ((val >> cp0def::C0_SRSCTL_BIT_ESS) & 0xf).min(hss);
+            let pss = ((val >> cp0def::C0_SRSCTL_BIT_PSS) & 0xf).min(hss);
+            c0_val!(ms.reg,rs) &= !cp0def::C0_SRSCTL_SETTING.mask_w;
+            c0_val!(ms.reg,rs) |= (ess << cp0def::C0_SRSCTL_BIT_ESS) | (pss << cp0def::C0_SRSCTL_BIT_PSS);
+        }
+        cp0def::C0_SRSMAP   => { c0_val!(ms.reg,rs) = store_masked_val!(val, cp0def::C0_SRSMAP_SETTING  ); }
+
+        cp0def::C0_WATCHLO0 | cp0def::C0_WATCHLO1 | cp0def::C0_WATCHLO2 | cp0def::C0_WATCHLO3 => { watch::store_watchlo(ms, rs, val); }
+        cp0def::C0_WATCHHI0 | cp0def::C0_WATCHHI1 | cp0def::C0_WATCHHI2 | cp0def::C0_WATCHHI3 => { watch::store_watchhi(ms, rs, val); }
+
+        cp0def::C0_PERFCTL0 | cp0def::C0_PERFCTL1 | cp0def::C0_PERFCTL2 | cp0def::C0_PERFCTL3 => { perf::store_ctl(ms, rs, val); }
+        cp0def::C0_PERFCNT0 | cp0def::C0_PERFCNT1 | cp0def::C0_PERFCNT2 | cp0def::C0_PERFCNT3 => { perf::store_cnt(ms, rs, val); }
 
         cp0def::C0_COUNT    => { store_counter(ms, val); }
         cp0def::C0_COMPARE  => {
@@ -85,7 +108,7 @@ pub fn load(ms : &mut MachineState, (reg,sel) : (u32,u32) ) -> u32 {
 
     match rs {
         cp0def::C0_STATUS    =>{ return load_masked_val!(c0_val!(ms.reg,rs), cp0def::C0_STATUS_SETTING   ); }
-        cp0def::C0_CAUSE     =>{ return load_masked_val!(c0_val!(ms.reg,rs), cp0def::C0_CAUSE_SETTING    ); }
+        cp0def::C0_CAUSE     =>{ return load_masked_val!(exception::load_cause(ms), cp0def::C0_CAUSE_SETTING); }
 
         cp0def::C0_ENTRYHI   =>{ return load_masked_val!(c0_val!(ms.reg,rs), cp0def::C0_ENTRYHI_SETTING  ); }
         cp0def::C0_ENTRYLO0  =>{ return load_masked_val!(c0_val!(ms.reg,rs), cp0def::C0_ENTRYLO0_SETTING ); }
@@ -104,6 +127,14 @@ pub fn load(ms : &mut MachineState, (reg,sel) : (u32,u32) ) -> u32 {
         cp0def::C0_PRID      =>{ return load_masked_val!(c0_val!(ms.reg,rs), cp0def::C0_PRID_SETTING     ); }
 
         cp0def::C0_INTCTL    =>{ return load_masked_val!(c0_val!(ms.reg,rs), cp0def::C0_INTCTL_SETTING ); }
+        cp0def::C0_SRSCTL    =>{ return load_masked_val!(c0_val!(ms.reg,rs), cp0def::C0_SRSCTL_SETTING ); }
+        cp0def::C0_SRSMAP    =>{ return load_masked_val!(c0_val!(ms.reg,rs), cp0def::C0_SRSMAP_SETTING ); }
+
+        cp0def::C0_WATCHLO0 | cp0def::C0_WATCHLO1 | cp0def::C0_WATCHLO2 | cp0def::C0_WATCHLO3 =>{ return c0_val!(ms.reg,rs); }
+        cp0def::C0_WATCHHI0 | cp0def::C0_WATCHHI1 | cp0def::C0_WATCHHI2 | cp0def::C0_WATCHHI3 =>{ return watch::load_watchhi(ms, rs); }
+
+        cp0def::C0_PERFCTL0 | cp0def::C0_PERFCTL1 | cp0def::C0_PERFCTL2 | cp0def::C0_PERFCTL3 =>{ return perf::load_ctl(ms, rs); }
+        cp0def::C0_PERFCNT0 | cp0def::C0_PERFCNT1 | cp0def::C0_PERFCNT2 | cp0def::C0_PERFCNT3 =>{ return c0_val!(ms.reg,rs); }
 
         cp0def::C0_COUNT     =>{ return load_counter(ms); }
         cp0def::C0_COMPARE | cp0def::C0_EPC | cp0def::C0_CONTEXT | cp0def::C0_BADVADDR  =>{ return c0_val!(ms.reg,rs); }
@@ -26,13 +26,15 @@ const C0_CONFIG1_INIT_VAL : u32 =
     (0<<6) /*existence of CP2*/ | 
     (0<<5) /*MDMX ASE is not implemented*/ | 
     (1<<4) /*#performance counter*/ | 
-    (0<<3) /*#watchpoint registers*/ | 
+    (1<<3) /*watchpoint registers are implemented*/ | 
     (0<<2) /*MIPS16e is not available*/ | 
     (0<<1) /*EJTAG is not available*/ | 
     (0<<0) /*floating point unit is not available*/;
 
+pub const C0_CONFIG1_FP : u32 = 1<<0; /* set when the FPU is enabled (fpu::present) */
+
 pub const C0_CONFIG1_SETTING : C0RegSetting = C0RegSetting {
-    mask_r   : 0,
+    mask_r   : C0_CONFIG1_FP,
     mask_w   : 0,
     init_val : C0_CONFIG1_INIT_VAL,
     const_val: C0_CONFIG1_INIT_VAL,
@@ -48,11 +50,14 @@ pub const C0_CONFIG2_SETTING : C0RegSetting = C0RegSetting {
 };
 
 // Definitions for C0_CONFIG3
+pub const C0_CONFIG3_VINT : u32 = 1<<5; /* set in the vectored interrupt mode (exception::set_irq_mode) */
+pub const C0_CONFIG3_VEIC : u32 = 1<<6; /* set in the EIC interrupt mode */
+
 pub const C0_CONFIG3_SETTING : C0RegSetting = C0RegSetting {
-    mask_r   : 0,
+    mask_r   : C0_CONFIG3_VINT | C0_CONFIG3_VEIC,
     mask_w   : 0,
-    init_val : 1<<13, /*USERLOCAL is implemented*/
-    const_val: 1<<13, /*USERLOCAL is implemented*/
+    init_val : (1<<13 /*USERLOCAL is implemented*/) | (1<<11 /*DSP ASE r2*/) | (1<<10 /*DSP ASE*/),
+    const_val: (1<<13 /*USERLOCAL is implemented*/) | (1<<11 /*DSP ASE r2*/) | (1<<10 /*DSP ASE*/),
 };
 
 pub const C0_EBASE_SETTING : C0RegSetting = C0RegSetting {
@@ -75,11 +80,35 @@ pub const C0_PRID_SETTING : C0RegSetting = C0RegSetting {
 
 // Definitions for C0_INTCTL
 pub const C0_INTCTL_TIMER_INT_IPNUM : u32 = 7;   /* IP num for Timer int.*/
+pub const C0_INTCTL_PCI_INT_IPNUM   : u32 = 3;   /* IP num for Performance Counter int. (not used by the devices of the SoC) */
+pub const C0_INTCTL_BIT_VS  : u32 = 5;   /* Vector Spacing (in units of 32 bytes) */
+pub const C0_INTCTL_VS_MASK : u32 = 0x1f<<C0_INTCTL_BIT_VS;
 pub const C0_INTCTL_SETTING : C0RegSetting = C0RegSetting {
-    mask_r   : 0xf<<5, /* spacing between vectored interrupts */
-    mask_w   : 0xf<<5, /* spacing between vectored interrupts */
-    init_val : (C0_INTCTL_TIMER_INT_IPNUM<<29) | (5<<26 /**/) | (4<<23 /**/),
-    const_val: (C0_INTCTL_TIMER_INT_IPNUM<<29) | (5<<26 /**/) | (4<<23 /**/),
+    mask_r   : C0_INTCTL_VS_MASK, /* spacing between vectored interrupts */
+    mask_w   : C0_INTCTL_VS_MASK, /* spacing between vectored interrupts */
+    init_val : (C0_INTCTL_TIMER_INT_IPNUM<<29) | (C0_INTCTL_PCI_INT_IPNUM<<26) | (4<<23 /**/),
+    const_val: (C0_INTCTL_TIMER_INT_IPNUM<<29) | (C0_INTCTL_PCI_INT_IPNUM<<26) | (4<<23 /**/),
+};
+
+// Definitions for C0_SRSCTL
+pub const C0_SRSCTL_BIT_HSS   : u32 = 26;  /* Highest Shadow Set */
+pub const C0_SRSCTL_BIT_EICSS : u32 = 18;  /* shadow set given by the EIC with an interrupt */
+pub const C0_SRSCTL_BIT_ESS   : u32 = 12;  /* Exception Shadow Set */
+pub const C0_SRSCTL_BIT_PSS   : u32 =  6;  /* Previous Shadow Set */
+pub const C0_SRSCTL_BIT_CSS   : u32 =  0;  /* Current Shadow Set */
+pub const C0_SRSCTL_SETTING : C0RegSetting = C0RegSetting {
+    mask_r   : (0xf<<C0_SRSCTL_BIT_HSS) | (0xf<<C0_SRSCTL_BIT_EICSS) | (0xf<<C0_SRSCTL_BIT_ESS) | (0xf<<C0_SRSCTL_BIT_PSS) | (0xf<<C0_SRSCTL_BIT_CSS),
+    mask_w   : (0xf<<C0_SRSCTL_BIT_ESS) | (0xf<<C0_SRSCTL_BIT_PSS),
+    init_val : 0,
+    const_val: 0,
+};
+
+// Definitions for C0_SRSMAP (shadow sets of the interrupt vectors 0..7, 4 bits each)
+pub const C0_SRSMAP_SETTING : C0RegSetting = C0RegSetting {
+    mask_r   : 0xffffffff,
+    mask_w   : 0xffffffff,
+    init_val : 0,
+    const_val: 0,
 };
 
 // Definitions for C0_ENTRYHI
@@ -172,6 +201,8 @@ pub const C0_STATUS_BIT_SM   : u32 = 3;  /* Supervisor Mode */
 pub const C0_STATUS_BIT_UM   : u32 = 4;  /* User Mode */
 pub const C0_STATUS_BIT_IM   : u32 = 8;  /* Interrupt Mask */
 pub const C0_STATUS_IM_MASK  : u32 = 0xff<<C0_STATUS_BIT_IM;
+pub const C0_STATUS_BIT_IPL  : u32 = 10; /* Interrupt Priority Level (IM7..IM2 in the EIC interrupt mode) */
+pub const C0_STATUS_IPL_MASK : u32 = 0x3f<<C0_STATUS_BIT_IPL;
 pub const C0_STATUS_BIT_CEE  : u32 = 17; /* CorExtend Enable. Enable/disable CorExtend User Defined Instructions */
 pub const C0_STATUS_BIT_NMI  : u32 = 19; /* Indicates that the entry through the reset exception vector was due to an NMI */
 pub const C0_STATUS_BIT_SR   : u32 = 20; /* Soft Reset */
@@ -236,18 +267,21 @@ pub const C0_CAUSE_BIT_IV     : u32 = 23;    /* Indicates whether an interrupt e
 pub const C0_CAUSE_BIT_WP     : u32 = 22;    /* a watch exception was deferred because StatusEXL or StatusERL was a one at the time the watch exception was detected */
 pub const C0_CAUSE_BIT_FDCI   : u32 = 21;    /* Fast Debug Channel Interrupt */
 pub const C0_CAUSE_BIT_IP     : u32 =  8;    /* pending interrupt or request for software interrupt */
+pub const C0_CAUSE_BIT_RIPL   : u32 = 10;    /* Requested Interrupt Priority Level (IP7..IP2 in the EIC interrupt mode) */
 pub const C0_CAUSE_BIT_EXCCODE: u32 =  2;    /* pending interrupt or request for software interrupt */
 
 pub const C0_CAUSE_CE_MASK      : u32 =    3 << C0_CAUSE_BIT_CE;
 pub const C0_CAUSE_IP_MASK      : u32 = 0xff << C0_CAUSE_BIT_IP;
+pub const C0_CAUSE_RIPL_MASK    : u32 = 0x3f << C0_CAUSE_BIT_RIPL;
 pub const C0_CAUSE_EXCCODE_MASK : u32 = 0x1f << C0_CAUSE_BIT_EXCCODE;
 
 const C0_CAUSE_MASK_R: u32 = C0_CAUSE_CE_MASK | C0_CAUSE_IP_MASK | C0_CAUSE_EXCCODE_MASK | 
                             (1<<C0_CAUSE_BIT_BD) | 
                             (1<<C0_CAUSE_BIT_TI) | 
                             (1<<C0_CAUSE_BIT_DC) | 
+                            (1<<C0_CAUSE_BIT_PCI) | 
                             (1<<C0_CAUSE_BIT_IV) | 
-                            (1<<C0_CAUSE_BIT_WP); /* PCI, FDCI are ignored */
+                            (1<<C0_CAUSE_BIT_WP); /* FDCI is ignored */
 const C0_CAUSE_MASK_W: u32 = (1<<C0_CAUSE_BIT_DC) | (1<<C0_CAUSE_BIT_IV) | (1<<C0_CAUSE_BIT_WP) | (3<<C0_CAUSE_BIT_IP);
 
 pub const C0_CAUSE_SETTING : C0RegSetting = C0RegSetting {
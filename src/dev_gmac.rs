@@ -0,0 +1,711 @@
+use crate::config;
+use crate::mem::MemRegion;
+use crate::snapshot::{Reader, Writer};
+use log::debug;
+
+use std::collections::BTreeMap;
+
+/*
+Ethernet MACs (AG71xx GMAC0 and GMAC1) of AR9342.
+
+Frames are transferred by DMA with descriptor rings in DRAM.
+A descriptor consists of 4 words: buffer address, control, next descriptor address and padding.
+  control[31]   : empty (owned by the driver for TX, available for the MAC for RX)
+  control[24]   : more (the frame continues in the next descriptor, TX only)
+  control[13:0] : length
+Transmission starts when TX_CTRL.TXE is set and continues until an empty descriptor is found.
+Received frames (with FCS) are stored in the RX ring by poll() called periodically from the main loop.
+
+Each MAC has an MDIO bus. GMAC0 has PHYs at addresses 0-4,
+and GMAC1 has the built-in switch (AR7240 compatible register access) and its PHYs.
+
+Frames are exchanged with the host through a NetBackend.
+*/
+
+pub const GMAC0_BASE_ADDRESS : u32 = 0x19000000;
+pub const GMAC1_BASE_ADDRESS : u32 = 0x1a000000;
+pub const GMAC_ADDR_SIZE     : u32 = 0x200;
+pub const GMAC_ADDR_MASK     : u32 = GMAC_ADDR_SIZE - 1;
+pub const GMAC_NUM           : usize = 2;
+
+// interrupt number (IP of C0_CAUSE) of each MAC
+pub const GMAC_IRQ_IPNUM : [u32; GMAC_NUM] = [4, 5];
+
+/*
+ * GMAC registers
+ */
+pub const GMAC_REG_MAC_CFG1   : u32 = 0x000;
+pub const GMAC_REG_MAC_CFG2   : u32 = 0x004;
+pub const GMAC_REG_MAC_MFL    : u32 = 0x010;
+pub const GMAC_REG_MII_CFG    : u32 = 0x020;
+pub const GMAC_REG_MII_CMD    : u32 = 0x024;
+pub const GMAC_REG_MII_ADDR   : u32 = 0x028;
+pub const GMAC_REG_MII_CTRL   : u32 = 0x02c;
+pub const GMAC_REG_MII_STATUS : u32 = 0x030;
+pub const GMAC_REG_MII_IND    : u32 = 0x034;
+pub const GMAC_REG_MAC_ADDR1  : u32 = 0x040;
+pub const GMAC_REG_MAC_ADDR2  : u32 = 0x044;
+pub const GMAC_REG_TX_CTRL    : u32 = 0x180;
+pub const GMAC_REG_TX_DESC    : u32 = 0x184;
+pub const GMAC_REG_TX_STATUS  : u32 = 0x188;
+pub const GMAC_REG_RX_CTRL    : u32 = 0x18c;
+pub const GMAC_REG_RX_DESC    : u32 = 0x190;
+pub const GMAC_REG_RX_STATUS  : u32 = 0x194;
+pub const GMAC_REG_INT_ENABLE : u32 = 0x198;
+pub const GMAC_REG_INT_STATUS : u32 = 0x19c;
+
+pub const MAC_CFG1_BIT_LOOPBACK : u32 = 8;
+pub const MII_CMD_BIT_READ      : u32 = 0;
+pub const TX_CTRL_BIT_TXE       : u32 = 0;
+pub const RX_CTRL_BIT_RXE       : u32 = 0;
+
+pub const TX_STATUS_PS : u32 = 1<<0; // packet sent
+pub const TX_STATUS_UR : u32 = 1<<1; // underrun
+pub const TX_STATUS_BE : u32 = 1<<3; // bus error
+pub const RX_STATUS_PR : u32 = 1<<0; // packet received
+pub const RX_STATUS_OF : u32 = 1<<2; // overflow
+pub const RX_STATUS_BE : u32 = 1<<3; // bus error
+pub const STATUS_PKTCNT_SHIFT : u32 = 16;
+
+// INT_STATUS/INT_ENABLE: TX_STATUS bits in [3:0] and RX_STATUS bits in [7:4]
+pub const INT_RX_SHIFT : u32 = 4;
+
+pub const DESC_EMPTY    : u32 = 1<<31;
+pub const DESC_MORE     : u32 = 1<<24;
+pub const DESC_PKTLEN_M : u32 = 0x3fff;
+pub const DESC_SIZE     : u32 = 16;
+
+const ETH_FRAME_MIN : usize = 60;   // without FCS
+const ETH_FRAME_MAX : usize = 9600;
+const ETH_FCS_LEN   : usize = 4;
+
+// The number of descriptors handled at once (to stop at a broken ring)
+const MAX_DESC_PER_CALL : usize = 256;
+
+/*
+ * PHY registers
+ */
+const MII_BMCR       : usize = 0;
+const MII_BMSR       : usize = 1;
+const MII_PHYSID1    : usize = 2;
+const MII_PHYSID2    : usize = 3;
+const MII_ADVERTISE  : usize = 4;
+const MII_LPA        : usize = 5;
+const MII_EXPANSION  : usize = 6;
+
+const BMCR_RESET     : u16 = 1<<15;
+const BMCR_ANRESTART : u16 = 1<<9;
+
+const PHY_NUM        : usize = 5;
+
+/*
+ * Built-in switch registers
+ */
+const SW_REG_MASK_CTRL   : u32 = 0x00;
+const SW_REG_MDIO_CTRL   : u32 = 0x98;
+const SW_REG_PORT_BASE   : u32 = 0x100;
+
+const SW_MASK_CTRL_SOFT_RESET : u32 = 1<<31;
+const SW_MASK_CTRL_VERSION    : u32 = 0x02<<8 | 0x01; // AR934x built-in switch
+const SW_MDIO_CTRL_BUSY       : u32 = 1<<31;
+const SW_MDIO_CTRL_CMD_READ   : u32 = 1<<27;
+// link up, 1000Mbps full duplex, MAC enabled
+const SW_PORT_STATUS_LINK_1000FD : u32 = (1<<8) | (1<<6) | (1<<3) | (1<<2) | 2;
+
+// MDIO addresses used to access the switch registers
+const SW_MDIO_ADDR_PAGE  : u32 = 0x1f;
+const SW_MDIO_REG_PAGE   : u32 = 0x10;
+const SW_MDIO_ADDR_BASE  : u32 = 0x10;
+
+/*
+Host side of an Ethernet interface.
+send() is called for a transmitted frame (without FCS) and
+recv() returns a frame to be received by the MAC, if any.
+*/
+pub trait NetBackend {
+    fn send(&mut self, frame: &[u8]);
+    fn recv(&mut self) -> Option<Vec<u8>>;
+}
+
+// An interface connected to nothing
+pub struct NullNetBackend { }
+
+impl NetBackend for NullNetBackend {
+    fn send(&mut self, _frame: &[u8]) { }
+    fn recv(&mut self) -> Option<Vec<u8>> { None }
+}
+
+pub struct Phy {
+    regs : [u16; 32],
+}
+
+impl Phy {
+    pub fn new() -> Self {
+        let mut regs = [0u16; 32];
+        regs[MII_BMCR]      = 0x1000; // auto-negotiation enabled
+        regs[MII_BMSR]      = 0x786d; // 10/100 capable, link up, auto-negotiation complete
+        regs[MII_PHYSID1]   = 0x004d; // Atheros
+        regs[MII_PHYSID2]   = 0xd041;
+        regs[MII_ADVERTISE] = 0x01e1;
+        regs[MII_LPA]       = 0xc5e1; // link partner: 10/100 full/half duplex
+        regs[MII_EXPANSION] = 0x0001;
+        Self { regs }
+    }
+
+    fn read(&self, reg: u32) -> u16 {
+        self.regs[(reg & 0x1f) as usize]
+    }
+
+    fn write(&mut self, reg: u32, data: u16) {
+        match reg as usize & 0x1f {
+            MII_BMCR => {
+                if 0 != (data & BMCR_RESET) {
+                    *self = Phy::new();
+                }else{
+                    self.regs[MII_BMCR] = data & !BMCR_ANRESTART;
+                }
+            }
+            MII_BMSR | MII_PHYSID1 | MII_PHYSID2 | MII_LPA | MII_EXPANSION => { } // read only
+            r => { self.regs[r] = data; }
+        }
+    }
+}
+
+pub struct EthSwitch {
+    regs    : BTreeMap<u32, u32>,
+    page    : u32,
+    hi_data : u32, // upper half of a register latched until the lower half is written
+}
+
+impl EthSwitch {
+    pub fn new() -> Self {
+        Self { regs: BTreeMap::new(), page: 0, hi_data: 0 }
+    }
+
+    fn read_reg(&self, addr: u32) -> u32 {
+        let val = self.regs.get(&addr).copied().unwrap_or(0);
+        match addr {
+            SW_REG_MASK_CTRL => (val & !(SW_MASK_CTRL_SOFT_RESET | 0xffff)) | SW_MASK_CTRL_VERSION,
+            a if a >= SW_REG_PORT_BASE && (a & 0xff) == 0 => val | SW_PORT_STATUS_LINK_1000FD,
+            _ => val,
+        }
+    }
+
+    fn write_reg(&mut self, phys: &mut [Phy; PHY_NUM], addr: u32, data: u32) {
+        match addr {
+            SW_REG_MASK_CTRL => { self.regs.insert(addr, data & !SW_MASK_CTRL_SOFT_RESET); }
+            SW_REG_MDIO_CTRL if 0 != (data & SW_MDIO_CTRL_BUSY) => {
+                // access to a PHY of the switch
+                let phy = ((data >> 21) & 0x1f) as usize;
+                let reg = (data >> 16) & 0x1f;
+                let mut val = data & 0xffff;
+                if let Some(p) = phys.get_mut(phy) {
+                    if 0 != (data & SW_MDIO_CTRL_CMD_READ) {
+                        val = p.read(reg) as u32;
+                    }else{
+                        p.write(reg, val as u16);
+                    }
+                }
+                self.regs.insert(addr, (data & !SW_MDIO_CTRL_BUSY & 0xffff0000) | val);
+            }
+            _ => { self.regs.insert(addr, data); }
+        }
+    }
+}
+
+pub struct MdioBus {
+    phys   : [Phy; PHY_NUM],
+    switch : Option<EthSwitch>,
+}
+
+impl MdioBus {
+    fn read(&mut self, phy: u32, reg: u32) -> u16 {
+        if let Some(sw) = self.switch.as_ref() {
+            if phy == SW_MDIO_ADDR_PAGE && reg == SW_MDIO_REG_PAGE {
+                return sw.page as u16;
+            }
+            if (SW_MDIO_ADDR_BASE..SW_MDIO_ADDR_BASE+8).contains(&phy) {
+                let val = sw.read_reg(switch_reg_addr(sw.page, phy, reg));
+                return if 0 != (reg & 1) { (val >> 16) as u16 }else{ val as u16 };
+            }
+        }
+        match self.phys.get(phy as usize) {
+            Some(p) => p.read(reg),
+            None    => 0xffff,
+        }
+    }
+
+    fn write(&mut self, phy: u32, reg: u32, data: u16) {
+        if let Some(sw) = self.switch.as_mut() {
+            if phy == SW_MDIO_ADDR_PAGE && reg == SW_MDIO_REG_PAGE {
+                sw.page = data as u32 & 0x1ff;
+                return;
+            }
+            if (SW_MDIO_ADDR_BASE..SW_MDIO_ADDR_BASE+8).contains(&phy) {
+                if 0 != (reg & 1) {
+                    sw.hi_data = data as u32;
+                }else{
+                    let addr = switch_reg_addr(sw.page, phy, reg);
+                    let val = (sw.hi_data << 16) | data as u32;
+                    sw.write_reg(&mut self.phys, addr, val);
+                }
+                return;
+            }
+        }
+        if let Some(p) = self.phys.get_mut(phy as usize) {
+            p.write(reg, data);
+        }
+    }
+}
+
+// address of a switch register accessed with the page, the MDIO address and the register number
+fn switch_reg_addr(page: u32, phy: u32, reg: u32) -> u32 {
+    ((page << 7) | ((phy & 7) << 4) | ((reg & 0x1e) >> 1)) << 2
+}
+
+pub struct IoGMAC {
+    regs     : [u32; (GMAC_ADDR_SIZE/4) as usize],
+    tx_count : u32,  // packet count of TX_STATUS
+    rx_count : u32,  // packet count of RX_STATUS
+    mdio     : MdioBus,
+    pub irq  : bool, // interrupt request
+    pub backend : Box<dyn NetBackend>,
+}
+
+impl IoGMAC {
+    pub fn new(index: usize) -> Self {
+        let mut regs = [0u32; (GMAC_ADDR_SIZE/4) as usize];
+        regs[(GMAC_REG_MAC_MFL/4) as usize] = 0x600;
+        Self {
+            regs,
+            tx_count: 0,
+            rx_count: 0,
+            mdio: MdioBus {
+                phys: [Phy::new(), Phy::new(), Phy::new(), Phy::new(), Phy::new()],
+                switch: if index == 1 { Some(EthSwitch::new()) }else{ None },
+            },
+            irq: false,
+            backend: Box::new(NullNetBackend{}),
+        }
+    }
+
+    fn reg(&self, offset: u32) -> u32 { self.regs[((offset & GMAC_ADDR_MASK) >> 2) as usize] }
+    fn reg_mut(&mut self, offset: u32) -> &mut u32 { &mut self.regs[((offset & GMAC_ADDR_MASK) >> 2) as usize] }
+
+    fn tx_status(&self) -> u32 {
+        (self.tx_count << STATUS_PKTCNT_SHIFT) | self.reg(GMAC_REG_TX_STATUS) | if self.tx_count > 0 { TX_STATUS_PS }else{ 0 }
+    }
+
+    fn rx_status(&self) -> u32 {
+        (self.rx_count << STATUS_PKTCNT_SHIFT) | self.reg(GMAC_REG_RX_STATUS) | if self.rx_count > 0 { RX_STATUS_PR }else{ 0 }
+    }
+
+    fn int_status(&self) -> u32 {
+        (self.tx_status() & 0xf) | ((self.rx_status() & 0xf) << INT_RX_SHIFT)
+    }
+
+    fn update_irq(&mut self) {
+        self.irq = 0 != (self.int_status() & self.reg(GMAC_REG_INT_ENABLE));
+    }
+}
+
+fn dram_index(paddr: u32) -> usize {
+    (paddr.wrapping_sub(config::RAM_AREA_ADDR) & config::DRAM_ADDR_MASK) as usize
+}
+
+fn read_dram_word(mem: &MemRegion, paddr: u32) -> u32 {
+    let a = dram_index(paddr & !3);
+    u32::from_be_bytes([mem.mem0[a], mem.mem0[a+1], mem.mem0[a+2], mem.mem0[a+3]])
+}
+
+fn write_dram_word(mem: &mut MemRegion, paddr: u32, data: u32) {
+    let a = dram_index(paddr & !3);
+    mem.mem0[a..a+4].copy_from_slice(&data.to_be_bytes());
+}
+
+// Ethernet FCS (CRC-32)
+fn eth_fcs(data: &[u8]) -> u32 {
+    let mut crc : u32 = 0xffffffff;
+    for d in data {
+        crc ^= *d as u32;
+        for _ in 0..8 {
+            crc = if 0 != (crc & 1) { (crc >> 1) ^ 0xedb88320 }else{ crc >> 1 };
+        }
+    }
+    !crc
+}
+
+pub fn read_reg(gmac: &mut IoGMAC, addr: u32) -> u32 {
+    let offset = addr & GMAC_ADDR_MASK;
+    match offset {
+        GMAC_REG_MII_IND    => 0, // never busy
+        GMAC_REG_TX_STATUS  => gmac.tx_status(),
+        GMAC_REG_RX_STATUS  => gmac.rx_status(),
+        GMAC_REG_INT_STATUS => gmac.int_status(),
+        _                   => gmac.reg(offset),
+    }
+}
+
+pub fn write_reg(gmac: &mut IoGMAC, mem: &mut MemRegion, addr: u32, data: u32) {
+    let offset = addr & GMAC_ADDR_MASK;
+    match offset {
+        GMAC_REG_MII_CMD => {
+            *gmac.reg_mut(offset) = data;
+            if 0 != (data & (1<<MII_CMD_BIT_READ)) {
+                let a = gmac.reg(GMAC_REG_MII_ADDR);
+                let val = gmac.mdio.read((a >> 8) & 0x1f, a & 0x1f);
+                *gmac.reg_mut(GMAC_REG_MII_STATUS) = val as u32;
+            }
+        }
+        GMAC_REG_MII_CTRL => {
+            *gmac.reg_mut(offset) = data;
+            let a = gmac.reg(GMAC_REG_MII_ADDR);
+            gmac.mdio.write((a >> 8) & 0x1f, a & 0x1f, data as u16);
+        }
+        GMAC_REG_MII_STATUS | GMAC_REG_MII_IND | GMAC_REG_INT_STATUS => { } // read only
+        GMAC_REG_TX_STATUS => {
+            if 0 != (data & TX_STATUS_PS) {
+                gmac.tx_count = gmac.tx_count.saturating_sub(1);
+            }
+            *gmac.reg_mut(offset) &= !(data & (TX_STATUS_UR | TX_STATUS_BE));
+        }
+        GMAC_REG_RX_STATUS => {
+            if 0 != (data & RX_STATUS_PR) {
+                gmac.rx_count = gmac.rx_count.saturating_sub(1);
+            }
+            *gmac.reg_mut(offset) &= !(data & (RX_STATUS_OF | RX_STATUS_BE));
+        }
+        GMAC_REG_TX_CTRL => {
+            *gmac.reg_mut(offset) = data;
+            if 0 != (data & (1<<TX_CTRL_BIT_TXE)) {
+                transmit(gmac, mem);
+            }
+        }
+        GMAC_REG_RX_CTRL => {
+            *gmac.reg_mut(offset) = data;
+            if 0 != (data & (1<<RX_CTRL_BIT_RXE)) {
+                poll(gmac, mem);
+            }
+        }
+        _ => { *gmac.reg_mut(offset) = data; }
+    }
+    gmac.update_irq();
+}
+
+// sends frames in the TX ring until an empty descriptor is found
+fn transmit(gmac: &mut IoGMAC, mem: &mut MemRegion) {
+    let mut frame : Vec<u8> = Vec::new();
+    let mut frame_descs : Vec<u32> = Vec::new();
+
+    for _ in 0..MAX_DESC_PER_CALL {
+        let desc = gmac.reg(GMAC_REG_TX_DESC);
+        let ctrl = read_dram_word(mem, desc.wrapping_add(4));
+        if 0 != (ctrl & DESC_EMPTY) {
+            // no more frames to be sent
+            *gmac.reg_mut(GMAC_REG_TX_STATUS) |= TX_STATUS_UR;
+            *gmac.reg_mut(GMAC_REG_TX_CTRL) &= !(1<<TX_CTRL_BIT_TXE);
+            break;
+        }
+        let buf = read_dram_word(mem, desc);
+        let len = (ctrl & DESC_PKTLEN_M) as usize;
+        let start = dram_index(buf);
+        match mem.mem0.get(start .. start + len) {
+            Some(data) if frame.len() + len <= ETH_FRAME_MAX => { frame.extend_from_slice(data); }
+            _ => {
+                *gmac.reg_mut(GMAC_REG_TX_STATUS) |= TX_STATUS_BE;
+                *gmac.reg_mut(GMAC_REG_TX_CTRL) &= !(1<<TX_CTRL_BIT_TXE);
+                break;
+            }
+        }
+        frame_descs.push(desc);
+        *gmac.reg_mut(GMAC_REG_TX_DESC) = read_dram_word(mem, desc.wrapping_add(8));
+
+        if 0 == (ctrl & DESC_MORE) {
+            debug!("GMAC: TX {} bytes\r", frame.len());
+            if 0 != (gmac.reg(GMAC_REG_MAC_CFG1) & (1<<MAC_CFG1_BIT_LOOPBACK)) {
+                receive(gmac, mem, &frame);
+            }else{
+                gmac.backend.send(&frame);
+            }
+            for d in frame_descs.iter() {
+                let c = read_dram_word(mem, d.wrapping_add(4));
+                write_dram_word(mem, d.wrapping_add(4), c | DESC_EMPTY);
+            }
+            gmac.tx_count = (gmac.tx_count + 1).min(0xff);
+            frame.clear();
+            frame_descs.clear();
+        }
+    }
+}
+
+// stores a frame into the RX ring. Returns false if no descriptor is available
+fn receive(gmac: &mut IoGMAC, mem: &mut MemRegion, frame: &[u8]) -> bool {
+    let desc = gmac.reg(GMAC_REG_RX_DESC);
+    let ctrl = read_dram_word(mem, desc.wrapping_add(4));
+    if 0 == (ctrl & DESC_EMPTY) {
+        *gmac.reg_mut(GMAC_REG_RX_STATUS) |= RX_STATUS_OF;
+        return false;
+    }
+
+    // short frames are padded and FCS is appended as a real MAC does
+    let mut data = frame[.. frame.len().min(ETH_FRAME_MAX)].to_vec();
+    if data.len() < ETH_FRAME_MIN {
+        data.resize(ETH_FRAME_MIN, 0);
+    }
+    let fcs = eth_fcs(&data);
+    data.extend_from_slice(&fcs.to_le_bytes());
+
+    let start = dram_index(read_dram_word(mem, desc));
+    match mem.mem0.get_mut(start .. start + data.len()) {
+        Some(dst) => { dst.copy_from_slice(&data); }
+        None => {
+            *gmac.reg_mut(GMAC_REG_RX_STATUS) |= RX_STATUS_BE;
+            return false;
+        }
+    }
+    write_dram_word(mem, desc.wrapping_add(4), data.len() as u32 & DESC_PKTLEN_M);
+    *gmac.reg_mut(GMAC_REG_RX_DESC) = read_dram_word(mem, desc.wrapping_add(8));
+    gmac.rx_count = (gmac.rx_count + 1).min(0xff);
+    debug!("GMAC: RX {} bytes\r", frame.len());
+    true
+}
+
+/*
+Receives frames from the backend while RX is enabled and the RX ring has available descriptors.
+This function is called periodically from the main loop.
+*/
+pub fn poll(gmac: &mut IoGMAC, mem: &mut MemRegion) {
+    for _ in 0..MAX_DESC_PER_CALL {
+        if 0 == (gmac.reg(GMAC_REG_RX_CTRL) & (1<<RX_CTRL_BIT_RXE)) {
+            break;
+        }
+        // Frames are kept in the backend until the driver provides a buffer
+        let desc = gmac.reg(GMAC_REG_RX_DESC);
+        if 0 == (read_dram_word(mem, desc.wrapping_add(4)) & DESC_EMPTY) {
+            break;
+        }
+        match gmac.backend.recv() {
+            Some(frame) => { receive(gmac, mem, &frame); }
+            None        => { break; }
+        }
+    }
+    gmac.update_irq();
+}
+
+pub fn save_state(gmac: &IoGMAC, w: &mut Writer) {
+    w.u32(gmac.regs.len() as u32);
+    for r in gmac.regs.iter() { w.u32(*r); }
+    w.u32(gmac.tx_count);
+    w.u32(gmac.rx_count);
+    for p in gmac.mdio.phys.iter() {
+        for r in p.regs.iter() { w.u32(*r as u32); }
+    }
+    match gmac.mdio.switch.as_ref() {
+        Some(sw) => {
+            w.bool(true);
+            w.u32(sw.page);
+            w.u32(sw.hi_data);
+            w.u32(sw.regs.len() as u32);
+            for (a, v) in sw.regs.iter() { w.u32(*a); w.u32(*v); }
+        }
+        None => { w.bool(false); }
+    }
+}
+
+pub fn load_state(gmac: &mut IoGMAC, r: &mut Reader) -> Result<(), String> {
+    let n = r.u32()? as usize;
+    if n != gmac.regs.len() {
+        return Err(format!("number of GMAC registers differs ({} in the snapshot)", n));
+    }
+    for i in 0..n { gmac.regs[i] = r.u32()?; }
+    gmac.tx_count = r.u32()?;
+    gmac.rx_count = r.u32()?;
+    for p in gmac.mdio.phys.iter_mut() {
+        for i in 0..p.regs.len() { p.regs[i] = r.u32()? as u16; }
+    }
+    if r.bool()? {
+        let mut sw = EthSwitch::new();
+        sw.page    = r.u32()?;
+        sw.hi_data = r.u32()?;
+        for _ in 0..r.u32()? {
+            let (a, v) = (r.u32()?, r.u32()?);
+            sw.regs.insert(a, v);
+        }
+        gmac.mdio.switch = Some(sw);
+    }else{
+        gmac.mdio.switch = None;
+    }
+    gmac.update_irq();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+    use std::rc::Rc;
+
+    // backend keeping the sent frames and providing the frames to be received
+    #[derive(Default)]
+    struct Frames {
+        sent     : Vec<Vec<u8>>,
+        received : VecDeque<Vec<u8>>,
+    }
+
+    struct Backend(Rc<RefCell<Frames>>);
+
+    impl NetBackend for Backend {
+        fn send(&mut self, frame: &[u8]) { self.0.borrow_mut().sent.push(frame.to_vec()); }
+        fn recv(&mut self) -> Option<Vec<u8>> { self.0.borrow_mut().received.pop_front() }
+    }
+
+    fn setup() -> (IoGMAC, MemRegion, Rc<RefCell<Frames>>) {
+        let mut gmac = IoGMAC::new(0);
+        let frames = Rc::new(RefCell::new(Frames::default()));
+        gmac.backend = Box::new(Backend(Rc::clone(&frames)));
+        (gmac, MemRegion::new(), frames)
+    }
+
+    fn set_desc(mem: &mut MemRegion, desc: u32, buf: u32, ctrl: u32, next: u32) {
+        write_dram_word(mem, desc, buf);
+        write_dram_word(mem, desc + 4, ctrl);
+        write_dram_word(mem, desc + 8, next);
+    }
+
+    fn dram(mem: &MemRegion, paddr: u32, len: usize) -> &[u8] {
+        &mem.mem0[paddr as usize .. paddr as usize + len]
+    }
+
+    #[test]
+    fn fcs() {
+        assert_eq!(eth_fcs(b"123456789"), 0xcbf43926);
+    }
+
+    #[test]
+    fn transmit_frames() {
+        let (mut gmac, mut mem, frames) = setup();
+        // a frame in two descriptors and a frame in one descriptor
+        set_desc(&mut mem, 0x100, 0x1000, DESC_MORE | 10, 0x110);
+        set_desc(&mut mem, 0x110, 0x2000, 6, 0x120);
+        set_desc(&mut mem, 0x120, 0x3000, 4, 0x130);
+        set_desc(&mut mem, 0x130, 0, DESC_EMPTY, 0x100);
+        mem.mem0[0x1000..0x100a].copy_from_slice(b"0123456789");
+        mem.mem0[0x2000..0x2006].copy_from_slice(b"abcdef");
+        mem.mem0[0x3000..0x3004].copy_from_slice(b"wxyz");
+
+        write_reg(&mut gmac, &mut mem, GMAC_REG_INT_ENABLE, TX_STATUS_PS);
+        write_reg(&mut gmac, &mut mem, GMAC_REG_TX_DESC, 0x100);
+        write_reg(&mut gmac, &mut mem, GMAC_REG_TX_CTRL, 1<<TX_CTRL_BIT_TXE);
+        assert_eq!(frames.borrow().sent, vec![b"0123456789abcdef".to_vec(), b"wxyz".to_vec()]);
+
+        // the descriptors are handed back to the driver and TX stops at the empty descriptor
+        assert_eq!(read_dram_word(&mem, 0x104), DESC_EMPTY | DESC_MORE | 10);
+        assert_eq!(read_dram_word(&mem, 0x114), DESC_EMPTY | 6);
+        assert_eq!(read_dram_word(&mem, 0x124), DESC_EMPTY | 4);
+        assert_eq!(read_reg(&mut gmac, GMAC_REG_TX_DESC), 0x130);
+        assert_eq!(read_reg(&mut gmac, GMAC_REG_TX_CTRL), 0);
+        assert_eq!(read_reg(&mut gmac, GMAC_REG_TX_STATUS), (2 << STATUS_PKTCNT_SHIFT) | TX_STATUS_UR | TX_STATUS_PS);
+        assert!(gmac.irq);
+
+        // each write of PS acknowledges a packet
+        write_reg(&mut gmac, &mut mem, GMAC_REG_TX_STATUS, TX_STATUS_PS | TX_STATUS_UR);
+        assert_eq!(read_reg(&mut gmac, GMAC_REG_TX_STATUS), (1 << STATUS_PKTCNT_SHIFT) | TX_STATUS_PS);
+        assert!(gmac.irq);
+        write_reg(&mut gmac, &mut mem, GMAC_REG_TX_STATUS, TX_STATUS_PS);
+        assert_eq!(read_reg(&mut gmac, GMAC_REG_TX_STATUS), 0);
+        assert!(!gmac.irq);
+    }
+
+    #[test]
+    fn receive_frames() {
+        let (mut gmac, mut mem, frames) = setup();
+        set_desc(&mut mem, 0x200, 0x4000, DESC_EMPTY, 0x210);
+        set_desc(&mut mem, 0x210, 0x5000, 0, 0x200);
+        frames.borrow_mut().received.extend([b"short frame".to_vec(), b"next".to_vec()]);
+
+        write_reg(&mut gmac, &mut mem, GMAC_REG_INT_ENABLE, RX_STATUS_PR << INT_RX_SHIFT);
+        write_reg(&mut gmac, &mut mem, GMAC_REG_RX_DESC, 0x200);
+        write_reg(&mut gmac, &mut mem, GMAC_REG_RX_CTRL, 1<<RX_CTRL_BIT_RXE);
+
+        // padded to the minimum length and followed by FCS
+        let mut expected = b"short frame".to_vec();
+        expected.resize(ETH_FRAME_MIN, 0);
+        let fcs = eth_fcs(&expected);
+        expected.extend_from_slice(&fcs.to_le_bytes());
+        assert_eq!(dram(&mem, 0x4000, ETH_FRAME_MIN + ETH_FCS_LEN), &expected[..]);
+        assert_eq!(read_dram_word(&mem, 0x204), (ETH_FRAME_MIN + ETH_FCS_LEN) as u32);
+        assert_eq!(read_reg(&mut gmac, GMAC_REG_RX_DESC), 0x210);
+        assert_eq!(read_reg(&mut gmac, GMAC_REG_RX_STATUS), (1 << STATUS_PKTCNT_SHIFT) | RX_STATUS_PR);
+        assert!(gmac.irq);
+
+        // the next frame waits in the backend until a descriptor is available
+        assert_eq!(frames.borrow().received.len(), 1);
+        poll(&mut gmac, &mut mem);
+        assert_eq!(frames.borrow().received.len(), 1);
+        set_desc(&mut mem, 0x210, 0x5000, DESC_EMPTY, 0x200);
+        poll(&mut gmac, &mut mem);
+        assert!(frames.borrow().received.is_empty());
+        assert_eq!(dram(&mem, 0x5000, 4), b"next");
+        assert_eq!(read_reg(&mut gmac, GMAC_REG_RX_STATUS) >> STATUS_PKTCNT_SHIFT, 2);
+    }
+
+    #[test]
+    fn loopback() {
+        let (mut gmac, mut mem, frames) = setup();
+        set_desc(&mut mem, 0x100, 0x1000, 64, 0x110);
+        set_desc(&mut mem, 0x110, 0, DESC_EMPTY, 0x100);
+        set_desc(&mut mem, 0x200, 0x4000, DESC_EMPTY, 0x210);
+        set_desc(&mut mem, 0x210, 0x5000, 0, 0x200);
+        for i in 0..64 { mem.mem0[0x1000 + i] = i as u8; }
+
+        write_reg(&mut gmac, &mut mem, GMAC_REG_MAC_CFG1, 1<<MAC_CFG1_BIT_LOOPBACK);
+        write_reg(&mut gmac, &mut mem, GMAC_REG_RX_DESC, 0x200);
+        write_reg(&mut gmac, &mut mem, GMAC_REG_TX_DESC, 0x100);
+        write_reg(&mut gmac, &mut mem, GMAC_REG_TX_CTRL, 1<<TX_CTRL_BIT_TXE);
+
+        // the frame is received by the MAC itself instead of the backend
+        assert!(frames.borrow().sent.is_empty());
+        let data = dram(&mem, 0x1000, 64).to_vec();
+        assert_eq!(dram(&mem, 0x4000, 64), &data[..]);
+        assert_eq!(dram(&mem, 0x4040, 4), &eth_fcs(&data).to_le_bytes());
+        assert_eq!(read_dram_word(&mem, 0x204), 68);
+        assert_eq!(read_reg(&mut gmac, GMAC_REG_RX_STATUS), (1 << STATUS_PKTCNT_SHIFT) | RX_STATUS_PR);
+
+        // overflow when no descriptor is empty
+        set_desc(&mut mem, 0x110, 0x1000, 64, 0x100);
+        set_desc(&mut mem, 0x100, 0, DESC_EMPTY, 0x110);
+        write_reg(&mut gmac, &mut mem, GMAC_REG_TX_CTRL, 1<<TX_CTRL_BIT_TXE);
+        assert_eq!(read_reg(&mut gmac, GMAC_REG_RX_DESC), 0x210);
+        assert_eq!(read_reg(&mut gmac, GMAC_REG_RX_STATUS), (1 << STATUS_PKTCNT_SHIFT) | RX_STATUS_OF | RX_STATUS_PR);
+        write_reg(&mut gmac, &mut mem, GMAC_REG_RX_STATUS, RX_STATUS_OF);
+        assert_eq!(read_reg(&mut gmac, GMAC_REG_RX_STATUS), (1 << STATUS_PKTCNT_SHIFT) | RX_STATUS_PR);
+    }
+
+    #[test]
+    fn bus_errors() {
+        let (mut gmac, mut mem, frames) = setup();
+        let end = config::DRAM_SIZE as u32;
+        // TX buffer beyond the end of DRAM
+        set_desc(&mut mem, 0x100, end - 4, 16, 0x110);
+        set_desc(&mut mem, 0x110, 0, DESC_EMPTY, 0x100);
+        write_reg(&mut gmac, &mut mem, GMAC_REG_TX_DESC, 0x100);
+        write_reg(&mut gmac, &mut mem, GMAC_REG_TX_CTRL, 1<<TX_CTRL_BIT_TXE);
+        assert!(frames.borrow().sent.is_empty());
+        assert_eq!(read_dram_word(&mem, 0x104), 16);
+        assert_eq!(read_reg(&mut gmac, GMAC_REG_TX_DESC), 0x100);
+        assert_eq!(read_reg(&mut gmac, GMAC_REG_TX_CTRL), 0);
+        assert_eq!(read_reg(&mut gmac, GMAC_REG_TX_STATUS), TX_STATUS_BE);
+        write_reg(&mut gmac, &mut mem, GMAC_REG_TX_STATUS, TX_STATUS_BE);
+        assert_eq!(read_reg(&mut gmac, GMAC_REG_TX_STATUS), 0);
+
+        // RX buffer beyond the end of DRAM
+        set_desc(&mut mem, 0x200, end - 8, DESC_EMPTY, 0x210);
+        frames.borrow_mut().received.push_back(vec![0; 16]);
+        write_reg(&mut gmac, &mut mem, GMAC_REG_RX_DESC, 0x200);
+        write_reg(&mut gmac, &mut mem, GMAC_REG_RX_CTRL, 1<<RX_CTRL_BIT_RXE);
+        assert_eq!(read_dram_word(&mem, 0x204), DESC_EMPTY);
+        assert_eq!(read_reg(&mut gmac, GMAC_REG_RX_DESC), 0x200);
+        assert_eq!(read_reg(&mut gmac, GMAC_REG_RX_STATUS), RX_STATUS_BE);
+    }
+}
@@ -1,5 +1,9 @@
 use crate::procstate::MachineState;
 use crate::dev_uart;
+use crate::mips;
+use crate::cp0def;
+use crate::c0_val;
+use crate::mem;
 
 
 pub const APB_BASE_REG                     :u32 = 0x18000000;
@@ -13,9 +17,11 @@ pub const RTC_BASE_REG                     :u32 = APB_BASE_REG + 0x00107000;
 pub const PLL_SRIF_BASE_REG                :u32 = APB_BASE_REG + 0x00116000;
 pub const PCIE_RC0_CTRL_BASE_REG           :u32 = APB_BASE_REG + 0x000F0000;
 pub const PCIE_RC1_CTRL_BASE_REG           :u32 = APB_BASE_REG + 0x00280000;
+pub const TEST_BASE_REG                    :u32 = APB_BASE_REG + 0x00FF0000; /* emulator only (unused in AR934x) */
 
 pub const RST_MISC_INTERRUPT_STATUS_REG    :u32 = RST_BASE_REG + 0x10;
 pub const RST_MISC_INTERRUPT_MASK_REG      :u32 = RST_BASE_REG + 0x14;
+pub const RST_MISC_INT_BIT_PERF            :u32 = 5; /* overflow of the performance counters of the CPU */
 pub const RST_GLOBALINTERRUPT_STATUS_REG   :u32 = RST_BASE_REG + 0x18;
 pub const RST_RESET_REG                    :u32 = RST_BASE_REG + 0x1C;
 
@@ -23,6 +29,21 @@ pub const RST_BOOTSTRAP_REG                :u32 = RST_BASE_REG + 0xB0;
 pub const RST_REVISION_ID_REG              :u32 = RST_BASE_REG + 0x90;
 pub const RST_REVISION_ID_MAJOR_AR9342_VAL :u32 = 0x1120;
 
+/*
+Test device for bare-metal and kernel tests (not in the real hardware)
+  EXIT : writing a value stops the emulator with the value as the exit status
+  PUTC : writing a value prints its lower 8 bits as a character on the console (UART0)
+  PUTS : writing the (virtual) address of a NUL-terminated string prints it on the console
+  ID   : read only, TEST_ID_VAL
+*/
+pub const TEST_EXIT_REG                    :u32 = TEST_BASE_REG;
+pub const TEST_PUTC_REG                    :u32 = TEST_BASE_REG + 0x4;
+pub const TEST_PUTS_REG                    :u32 = TEST_BASE_REG + 0x8;
+pub const TEST_ID_REG                      :u32 = TEST_BASE_REG + 0xC;
+pub const TEST_REG_SIZE                    :u32 = 0x10;
+pub const TEST_ID_VAL                      :u32 = 0x45584954; /* "EXIT" */
+const TEST_PUTS_MAX_LEN                    :u32 = 4096;
+
 pub const PLL_CPU_DDR_CLK_CTRL_REG         :u32 = PLL_BASE_REG + 0x08;
 
 pub const PLL_SRIF_CPU_DPLL_BASE_REG       :u32 = PLL_SRIF_BASE_REG + 0x1C0;
@@ -33,6 +54,8 @@ pub const PLL_SRIF_CPU_DPLL2_REG           :u32 = PLL_SRIF_CPU_DPLL_BASE_REG + 0
 pub struct IoMisc{
     pub int_mask      : u32,
     pub reset_request : bool,
+    pub exit_request  : Option<i32>, /* exit status written to the test device */
+    pub perf_irq_ip   : bool,        /* the performance counter interrupt is routed to IntCtl.IPPCI instead of here */
 }
 
 impl IoMisc {
@@ -40,6 +63,8 @@ impl IoMisc {
         Self { 
             int_mask: 0, 
             reset_request: false,
+            exit_request: None,
+            perf_irq_ip: false,
         }
     }
 }
@@ -60,8 +85,96 @@ impl IoGPIO {
 
 pub fn read_misc_int_status_reg(ms : &MachineState) -> u32{
 
-    let uart_int: bool = 
-    0 != ms.uart.int_enable && ms.uart.int_ident != dev_uart::UART_REG_INTID_NO_INT;
+    let mut status = 0;
+    for (uart, bit) in ms.uart.iter().zip(dev_uart::UART_MISC_INT_BIT) {
+        let uart_int: bool = 
+        0 != uart.int_enable && uart.int_ident != dev_uart::UART_REG_INTID_NO_INT;
+
+        if uart_int { status |= 1<<bit; }
+    }
+    if !ms.misc.perf_irq_ip && 0 != (c0_val!(ms.reg, cp0def::C0_CAUSE) & (1<<cp0def::C0_CAUSE_BIT_PCI)) {
+        status |= 1<<RST_MISC_INT_BIT_PERF;
+    }
+    status
+}
+
+fn test_putc(ms : &mut MachineState, c : u8){
+    if c == b'\n' {
+        let _ = ms.uart[0].rw.write('\r');
+    }
+    let _ = ms.uart[0].rw.write(c as char);
+}
+
+pub fn write_test_reg(ms : &mut MachineState, addr : u32, data : u32){
+    match addr {
+        TEST_EXIT_REG => { ms.misc.exit_request = Some(data as i32); }
+        TEST_PUTC_REG => { test_putc(ms, data as u8); }
+        TEST_PUTS_REG => {
+            for i in 0..TEST_PUTS_MAX_LEN {
+                match mem::debug_read_byte(ms, data.wrapping_add(i)) {
+                    Ok(0) | Err(_) => { break; }
+                    Ok(c)          => { test_putc(ms, c); }
+                }
+            }
+        }
+        _ => { }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dev_spiflash::SPI_FLASH_PARAM_S25FL164K;
+    use crate::exrmips::generate_machine_state;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    // UART backend keeping the console output
+    struct Capture(Rc<RefCell<Vec<u8>>>);
+
+    impl dev_uart::UartReadWrite for Capture {
+        fn read(&mut self)   -> Result<u8,()> { Err(()) }
+        fn write(&mut self, data : char)  -> Result<(),()> { self.0.borrow_mut().push(data as u8); Ok(()) }
+    }
+
+    // kseg1 address of a register
+    fn kseg1(reg: u32) -> u32 { reg | 0xa0000000 }
+
+    #[test]
+    fn test_device() {
+        let mut ms = generate_machine_state(&SPI_FLASH_PARAM_S25FL164K, &[]);
+        let out = Rc::new(RefCell::new(Vec::new()));
+        ms.uart[0].rw = Box::new(Capture(Rc::clone(&out)));
 
-    return if uart_int { 1<<3 }else{ 0 };
+        assert_eq!(mem::load_word(&mut ms, kseg1(TEST_ID_REG)), Ok(TEST_ID_VAL));
+        assert_eq!(mem::load_byte(&mut ms, kseg1(TEST_ID_REG)), Ok(b'E' as u32));
+        assert_eq!(mem::load_word(&mut ms, kseg1(TEST_EXIT_REG)), Ok(0));
+
+        // ID is read only
+        mem::store_word(&mut ms, kseg1(TEST_ID_REG), 0).unwrap();
+        assert_eq!(mem::load_word(&mut ms, kseg1(TEST_ID_REG)), Ok(TEST_ID_VAL));
+
+        mem::store_word(&mut ms, kseg1(TEST_PUTC_REG), b'o' as u32).unwrap();
+        mem::store_word(&mut ms, kseg1(TEST_PUTC_REG), 0x100 | b'k' as u32).unwrap();
+        mem::store_word(&mut ms, kseg1(TEST_PUTC_REG), b'\n' as u32).unwrap();
+        ms.mem.mem0[0x200..0x208].copy_from_slice(b"pass\n\0xx");
+        mem::store_word(&mut ms, kseg1(TEST_PUTS_REG), 0x80000200).unwrap();
+        assert_eq!(out.borrow().as_slice(), b"ok\r\npass\r\n");
+        assert_eq!(ms.misc.exit_request, None);
+
+        mem::store_word(&mut ms, kseg1(TEST_EXIT_REG), 0xffffffff).unwrap();
+        assert_eq!(ms.misc.exit_request, Some(-1));
+        mem::store_word(&mut ms, kseg1(TEST_EXIT_REG), 3).unwrap();
+        assert_eq!(ms.misc.exit_request, Some(3));
+    }
+
+    #[test]
+    fn perf_irq_in_misc_status() {
+        let mut ms = generate_machine_state(&SPI_FLASH_PARAM_S25FL164K, &[]);
+        assert_eq!(read_misc_int_status_reg(&ms), 0);
+        c0_val!(ms.reg, cp0def::C0_CAUSE) |= 1<<cp0def::C0_CAUSE_BIT_PCI;
+        assert_eq!(read_misc_int_status_reg(&ms), 1<<RST_MISC_INT_BIT_PERF);
+        ms.misc.perf_irq_ip = true;
+        assert_eq!(read_misc_int_status_reg(&ms), 0);
+    }
 }
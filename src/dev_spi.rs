@@ -1,4 +1,5 @@
 use log::info;
+use crate::snapshot::{Reader, Writer};
 
 // Base addresses
 pub const SPI0_BASE_ADDRESS:u32 = 0x1f000000;
@@ -43,6 +44,19 @@ pub trait SPIWorker {
     fn select(&mut self)     -> bool;
     fn deselect(&mut self)   -> bool;
     fn write(&mut self, d:u8)-> u8;
+
+    // memory image of the device, if any (e.g., the content of a flash memory)
+    fn image(&self) -> Option<&[u8]> { None }
+    fn image_mut(&mut self) -> Option<&mut [u8]> { None }
+
+    // unit of modification tracking of the memory image
+    fn sector_size(&self) -> u32 { 0 }
+    // indexes of sectors modified since the last call
+    fn take_dirty_sectors(&mut self) -> Vec<u32> { Vec::new() }
+
+    // internal state of the device for snapshots
+    fn save_state(&self, _w: &mut Writer) { }
+    fn load_state(&mut self, _r: &mut Reader) -> Result<(), String> { Ok(()) }
 }
 
 pub struct IoSPI{
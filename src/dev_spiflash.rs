@@ -1,5 +1,6 @@
 
 use crate::dev_spi::SPIWorker;
+use crate::snapshot::{Reader, Writer};
 use log::info;
 
 pub const  FLASH_BUF_SIZE : usize = 64;
@@ -89,6 +90,7 @@ pub struct SPIFlash{
     flash_cnt : u32,
     addr : u32,
     param : &'static SPIFlashParam,
+    dirty : Box<[bool]>,  // sectors erased or programmed since the last check
 }
 
 impl SPIWorker for SPIFlash {
@@ -97,6 +99,16 @@ impl SPIWorker for SPIFlash {
     fn select(&mut self)     -> bool{ select( self); return true; }
     fn deselect(&mut self)   -> bool{ deselect(self); return true; }
     fn write(&mut self, d:u8)-> u8  { return write(self, d as u32);    }
+    fn image(&self) -> Option<&[u8]> { Some(&self.mem) }
+    fn image_mut(&mut self) -> Option<&mut [u8]> { Some(&mut self.mem) }
+    fn sector_size(&self) -> u32 { self.param.sector_size }
+    fn take_dirty_sectors(&mut self) -> Vec<u32> {
+        let sectors = self.dirty.iter().enumerate().filter(|(_, d)| **d).map(|(i, _)| i as u32).collect();
+        self.dirty.fill(false);
+        sectors
+    }
+    fn save_state(&self, w: &mut Writer) { save_state(self, w); }
+    fn load_state(&mut self, r: &mut Reader) -> Result<(), String> { load_state(self, r) }
 }
 
 /*
@@ -223,9 +235,52 @@ pub fn generate_flash(param: &'static SPIFlashParam, data: Box<[u8]>) -> SPIFlas
         flash_cnt: 0, 
         addr: 0, 
         param: param,
+        dirty: vec![false; (param.capacity / param.sector_size) as usize].into_boxed_slice(),
     };
 }
 
+fn mark_dirty(flash: &mut SPIFlash, addr: u32, len: u32){
+    let first = (addr as usize % flash.mem.len()) / flash.param.sector_size as usize;
+    let num   = len.div_ceil(flash.param.sector_size) as usize;
+    for i in 0..num {
+        let n = (first + i) % flash.dirty.len();
+        flash.dirty[n] = true;
+    }
+}
+
+fn save_state(flash: &SPIFlash, w: &mut Writer){
+    w.bool(flash.selected);
+    w.bool(flash.error);
+    for v in flash.sr.iter() { w.u8(*v); }
+    w.bytes(&flash.flash_buf);
+    w.u32(flash.flash_cnt);
+    w.u32(flash.addr);
+    w.bytes(&flash.mem);
+}
+
+fn load_state(flash: &mut SPIFlash, r: &mut Reader) -> Result<(), String> {
+    flash.selected = r.bool()?;
+    flash.error    = r.bool()?;
+    for i in 0..flash.sr.len() { flash.sr[i] = r.u8()?; }
+    r.bytes_into(&mut flash.flash_buf, "flash buffer")?;
+    flash.flash_cnt = r.u32()?;
+    flash.addr      = r.u32()?;
+
+    let mem = r.bytes()?;
+    if mem.len() != flash.mem.len() {
+        return Err(format!("flash capacity differs (0x{:x} bytes in the snapshot)", mem.len()));
+    }
+    // sectors which differ from the current contents are recorded as modified
+    let sector_size = flash.param.sector_size as usize;
+    for (n, (cur, new)) in flash.mem.chunks_mut(sector_size).zip(mem.chunks(sector_size)).enumerate() {
+        if cur != new {
+            cur.copy_from_slice(new);
+            flash.dirty[n] = true;
+        }
+    }
+    Ok(())
+}
+
 fn remove(flash: &mut SPIFlash){ 
 //    flash.mem = ...
     flash.selected = false;
@@ -364,6 +419,7 @@ fn write(flash: &mut SPIFlash, val : u32) -> u8 {
                         for i in 0..flash.param.block_size {
                             flash.mem[ (flash.addr + i) as usize % flash.mem.len() ] = 0xff;
                         }
+                        mark_dirty(flash, flash.addr, flash.param.block_size);
                     }
                 }
                 _ => {  }
@@ -381,6 +437,7 @@ fn write(flash: &mut SPIFlash, val : u32) -> u8 {
                         for i in 0..flash.param.sector_size {
                             flash.mem[ (flash.addr + i) as usize % flash.mem.len() ] = 0xff;
                         }
+                        mark_dirty(flash, flash.addr, flash.param.sector_size);
                     }
                 }
                 _ => {  }
@@ -396,6 +453,7 @@ fn write(flash: &mut SPIFlash, val : u32) -> u8 {
                     result = 0xff;
                     if 0!=(flash.sr[0] & (1<<SPI_FLASH_SR1_BIT_WE)) {
                         flash.mem[ flash.addr as usize % flash.mem.len() ] = val as u8;
+                        mark_dirty(flash, flash.addr, 1);
                     }
                     flash.addr+=1;
                 }
@@ -414,6 +472,7 @@ fn write(flash: &mut SPIFlash, val : u32) -> u8 {
                         for i in 0..flash.param.block_size {
                             flash.mem[ (flash.addr + i) as usize % flash.mem.len() ] = 0xff;
                         }
+                        mark_dirty(flash, flash.addr, flash.param.block_size);
                     }
                 }
                 _ => {  }
@@ -432,6 +491,7 @@ fn write(flash: &mut SPIFlash, val : u32) -> u8 {
                         for i in 0..flash.param.sector_size {
                             flash.mem[ (flash.addr + i) as usize % flash.mem.len() ] = 0xff;
                         }
+                        mark_dirty(flash, flash.addr, flash.param.sector_size);
                     }
                 }
                 _ => {  }
@@ -448,6 +508,7 @@ fn write(flash: &mut SPIFlash, val : u32) -> u8 {
                     result = 0xff;
                     if 0!=(flash.sr[0] & (1<<SPI_FLASH_SR1_BIT_WE)) {
                         flash.mem[ flash.addr as usize % flash.mem.len() ] = val as u8;
+                        mark_dirty(flash, flash.addr, 1);
                     }
                     flash.addr+=1;
                 }
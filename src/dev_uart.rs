@@ -2,9 +2,11 @@ use log::debug;
 use crate::wasm_utils;
 use std::io::{stdout, Write};
 
+use std::rc::Rc;
 use std::sync::mpsc::Receiver;
 
 pub struct IoUART{
+    pub rw           : Box<dyn UartReadWrite>, // backend connected to the serial port
     pub buffered     : bool,
     pub int_enable   : u8,
     pub int_ident    : u8,
@@ -17,8 +19,9 @@ pub struct IoUART{
 }
 
 impl IoUART {
-    pub fn new() -> Self {
+    pub fn new(rw: Box<dyn UartReadWrite>) -> Self {
         Self { 
+            rw,
             buffered: false, 
             int_enable: 0, 
             int_ident: 0, 
@@ -38,7 +41,7 @@ pub trait UartReadWrite {
 }
 
 pub struct NativeUARTConsole {
-    pub receiver: Receiver<u8>
+    pub receiver: Rc<Receiver<u8>>
 }
 
 impl UartReadWrite for NativeUARTConsole {
@@ -54,6 +57,14 @@ impl UartReadWrite for NativeUARTConsole {
     }
 }
 
+// backend of an unconnected serial port
+pub struct NullUART { }
+
+impl UartReadWrite for NullUART {
+    fn read(&mut self)   -> Result<u8,()> { Err(()) }
+    fn write(&mut self, _data : char)  -> Result<(),()> { Ok(()) }
+}
+
 pub struct WasmUARTConsole { }
 
 impl UartReadWrite for WasmUARTConsole {
@@ -71,6 +82,12 @@ impl UartReadWrite for WasmUARTConsole {
 pub const IOADDR_UART0_BASE  : u32 = 0x18020000;
 pub const IOADDR_UART1_BASE  : u32 = 0x18500000;
 
+pub const UART_NUM           : usize = 2;
+pub const IOADDR_UART_BASE   : [u32; UART_NUM] = [IOADDR_UART0_BASE, IOADDR_UART1_BASE];
+
+// bits of the misc. interrupt status register
+pub const UART_MISC_INT_BIT  : [u32; UART_NUM] = [3, 6];
+
 pub const IOADDR_UART_ADDR_SHIFT : u32 = 2;
 
 pub const IOADDR_UART_SIZE   : u32 = 0x8<<IOADDR_UART_ADDR_SHIFT;
@@ -105,11 +122,16 @@ const ASCII_DEL : u8 = 0x7f;
 const ASCII_BS  : u8 = 0x08;
 
 
+// index of the UART at the physical address
+pub fn uart_index(paddr: u32) -> Option<usize> {
+    IOADDR_UART_BASE.iter().position(|base| paddr >= *base && paddr < *base + IOADDR_UART_SIZE)
+}
+
 pub fn request_send_break(uart: &mut IoUART){
     uart.break_request = true;
 }
 
-pub fn read_reg(uart: &mut IoUART, uart_rw : &mut Box<dyn UartReadWrite>, addr : u32) -> u8 {
+pub fn read_reg(uart: &mut IoUART, addr : u32) -> u8 {
 
     match addr&IOADDR_UART_MASK {
         UART_REG_RXBUF => 
@@ -162,7 +184,7 @@ pub fn read_reg(uart: &mut IoUART, uart_rw : &mut Box<dyn UartReadWrite>, addr :
                     uart.buf      = 3;
                     uart.break_request = false; // Clear the request
                 }else{
-                    match uart_rw.read() {
+                    match uart.rw.read() {
                         Ok(d) => { uart.buffered = true; uart.buf = d; }
                         _         => { uart.buffered = false; }
                     }
@@ -198,7 +220,7 @@ pub fn read_reg(uart: &mut IoUART, uart_rw : &mut Box<dyn UartReadWrite>, addr :
     return 0;
 }
 
-pub fn write_reg(uart : &mut IoUART, uart_rw : &mut Box<dyn UartReadWrite>, addr : u32, data : u8){
+pub fn write_reg(uart : &mut IoUART, addr : u32, data : u8){
 
     match addr&IOADDR_UART_MASK {
         
@@ -208,10 +230,10 @@ pub fn write_reg(uart : &mut IoUART, uart_rw : &mut Box<dyn UartReadWrite>, addr
                 uart.divisor[0] = data;
             }else{
                 if data == b'\n' {
-                    uart_rw.write('\r').unwrap();
+                    uart.rw.write('\r').unwrap();
                 }
 
-                uart_rw.write(data as char).unwrap();
+                uart.rw.write(data as char).unwrap();
 
                 if 0!=(uart.int_enable & (1<<UART_REG_INTEN_BIT_TX_DATA_EMPTY) ){
                     if uart.int_ident == UART_REG_INTID_NO_INT {
@@ -226,7 +248,7 @@ pub fn write_reg(uart : &mut IoUART, uart_rw : &mut Box<dyn UartReadWrite>, addr
                 uart.divisor[1] = data;
             }else{
                 uart.int_enable = data;
-                read_reg(uart, uart_rw, UART_REG_LINESTAT); // to update internal state
+                read_reg(uart, UART_REG_LINESTAT); // to update internal state
             }
         }
         UART_REG_INTID => { () /* read only */ } // interrupt ident. register
@@ -0,0 +1,712 @@
+use crate::mips;
+use crate::exec_mips32::*;
+use crate::exec_mips16::*;
+
+/*
+Disassembler for the instructions executed by exec_mips32::exec and exec_mips16::exec.
+
+Instructions are given in the same form as the ones passed to the executors.
+A MIPS16e instruction with an EXTEND prefix (or a JAL/JALX) holds the first halfword in inst[31:16].
+Branch and jump targets are calculated from the address of the instruction.
+Instructions not handled by the emulator are shown as ".word" (or ".half").
+*/
+
+macro_rules! r  { ( $n:expr ) => ( mips::REGSTR[($n) as usize] ) }
+
+// register of a 3-bit MIPS16e register field
+macro_rules! r16 { ( $n:expr ) => ( mips::REGSTR[ (if ($n)<2 { ($n)+16 }else{ $n }) as usize ] ) }
+
+fn simm(v : u32) -> String {
+    let v = v as i32;
+    if v < 0 { format!("-0x{:x}", (v as i64).unsigned_abs()) }else{ format!("0x{:x}", v) }
+}
+
+fn sext(v : u32, bits: u32) -> u32 {
+    (((v << (32-bits)) as i32) >> (32-bits)) as u32
+}
+
+fn op(mnemonic: &str, operands: String) -> String {
+    if operands.is_empty() { mnemonic.to_string() }else{ format!("{:<7} {}", mnemonic, operands) }
+}
+
+// disassembles an instruction at pc (pc[0]=1 for MIPS16e)
+pub fn disassemble(pc: u32, inst: u32) -> String {
+    if 0 != (pc & 1) { disasm_mips16(pc, inst) }else{ disasm_mips32(pc, inst) }
+}
+
+// returns true if the halfword is the first half of a 32-bit MIPS16e instruction (EXTEND or JAL/JALX)
+pub fn mips16_is_32bit(half: u32) -> bool {
+    let op5 = (half >> 11) & 0x1f;
+    op5 == MIPS16E_OP_EXTEND || op5 == MIPS16E_OP_JAL
+}
+
+pub fn disasm_mips32(pc: u32, inst: u32) -> String {
+    let opcode = (inst >> 26) & 0x3f;
+    let rs     = (inst >> 21) & 0x1f;
+    let rt     = (inst >> 16) & 0x1f;
+    let rd     = (inst >> 11) & 0x1f;
+    let shamt  = (inst >>  6) & 0x1f;
+    let funct  =  inst        & 0x3f;
+    let imm    =  inst        & 0xffff;
+    let simm16 = sext(imm, 16);
+
+    let btarget = pc.wrapping_add(simm16 << 2).wrapping_add(4);
+    let jtarget = (pc.wrapping_add(4) & 0xf0000000) | ((inst & 0x03ffffff) << 2);
+
+    let unknown = format!(".word   0x{:>08x}", inst);
+
+    let rd_rs_rt = || format!("{}, {}, {}", r!(rd), r!(rs), r!(rt));
+    let rs_rt    = || format!("{}, {}", r!(rs), r!(rt));
+    let rt_rs_s  = || format!("{}, {}, {}", r!(rt), r!(rs), simm(simm16));
+    let rt_rs_u  = || format!("{}, {}, 0x{:x}", r!(rt), r!(rs), imm);
+    let mem      = || format!("{}, {}({})", r!(rt), simm(simm16), r!(rs));
+    // ac of DSP ASE is shown only if it is not ac0
+    let ac_rs_rt = || if (rd & 3) == 0 { rs_rt() }else{ format!("$ac{}, {}", rd & 3, rs_rt()) };
+    let fmem     = || format!("$f{}, {}({})", rt, simm(simm16), r!(rs));
+
+    match opcode {
+        MIPS32_OP_SPECIAL => {
+            match funct {
+                0x00 => {
+                    match inst {
+                        0x00000000 => "nop".to_string(),
+                        0x00000040 => "ssnop".to_string(),
+                        0x000000c0 => "ehb".to_string(),
+                        _          => op("sll", format!("{}, {}, {}", r!(rd), r!(rt), shamt)),
+                    }
+                }
+                0x01 => op(if 0 != (rt & 1) { "movt" }else{ "movf" }, format!("{}, {}, {}", r!(rd), r!(rs), fcc(rt >> 2))),
+                0x02 => op(if rs == 1 { "rotr" }else{ "srl" }, format!("{}, {}, {}", r!(rd), r!(rt), shamt)),
+                0x03 => op("sra",  format!("{}, {}, {}", r!(rd), r!(rt), shamt)),
+                0x04 => op("sllv", format!("{}, {}, {}", r!(rd), r!(rt), r!(rs))),
+                0x06 => op(if shamt == 1 { "rotrv" }else{ "srlv" }, format!("{}, {}, {}", r!(rd), r!(rt), r!(rs))),
+                0x07 => op("srav", format!("{}, {}, {}", r!(rd), r!(rt), r!(rs))),
+                0x08 => op(if 0 != (inst & (1<<10)) { "jr.hb" }else{ "jr" }, r!(rs).to_string()),
+                0x09 => {
+                    let mnemonic = if 0 != (inst & (1<<10)) { "jalr.hb" }else{ "jalr" };
+                    if rd == 31 { op(mnemonic, r!(rs).to_string()) }else{ op(mnemonic, format!("{}, {}", r!(rd), r!(rs))) }
+                }
+                0x0a => op("movz", rd_rs_rt()),
+                0x0b => op("movn", rd_rs_rt()),
+                0x0c => "syscall".to_string(),
+                0x0d => "break".to_string(),
+                0x0f => "sync".to_string(),
+                0x10 => op("mfhi",  with_ac(r!(rd).to_string(), rs & 3)),
+                0x11 => op("mthi",  with_ac(r!(rs).to_string(), rd & 3)),
+                0x12 => op("mflo",  with_ac(r!(rd).to_string(), rs & 3)),
+                0x13 => op("mtlo",  with_ac(r!(rs).to_string(), rd & 3)),
+                0x18 => op("mult",  ac_rs_rt()),
+                0x19 => op("multu", ac_rs_rt()),
+                0x1a => op("div",   rs_rt()),
+                0x1b => op("divu",  rs_rt()),
+                0x20 => op("add",   rd_rs_rt()),
+                0x21 => if rt == 0 { op("move", format!("{}, {}", r!(rd), r!(rs))) }else{ op("addu", rd_rs_rt()) },
+                0x22 => op("sub",   rd_rs_rt()),
+                0x23 => if rs == 0 { op("negu", format!("{}, {}", r!(rd), r!(rt))) }else{ op("subu", rd_rs_rt()) },
+                0x24 => op("and",   rd_rs_rt()),
+                0x25 => if rt == 0 { op("move", format!("{}, {}", r!(rd), r!(rs))) }else{ op("or", rd_rs_rt()) },
+                0x26 => op("xor",   rd_rs_rt()),
+                0x27 => if rt == 0 { op("not", format!("{}, {}", r!(rd), r!(rs))) }else{ op("nor", rd_rs_rt()) },
+                0x2a => op("slt",   rd_rs_rt()),
+                0x2b => op("sltu",  rd_rs_rt()),
+                0x30 => op("tge",   rs_rt()),
+                0x31 => op("tgeu",  rs_rt()),
+                0x32 => op("tlt",   rs_rt()),
+                0x33 => op("tltu",  rs_rt()),
+                0x34 => op("teq",   rs_rt()),
+                0x36 => op("tne",   rs_rt()),
+                _    => unknown,
+            }
+        }
+        MIPS32_OP_REGIMM => {
+            let rs_target = || format!("{}, 0x{:>08x}", r!(rs), btarget);
+            let rs_imm    = || format!("{}, {}", r!(rs), simm(simm16));
+            match rt {
+                0x00 => op("bltz",   rs_target()),
+                0x01 => op("bgez",   rs_target()),
+                0x02 => op("bltzl",  rs_target()),
+                0x03 => op("bgezl",  rs_target()),
+                0x08 => op("tgei",   rs_imm()),
+                0x09 => op("tgeiu",  rs_imm()),
+                0x0a => op("tlti",   rs_imm()),
+                0x0b => op("tltiu",  rs_imm()),
+                0x0c => op("teqi",   rs_imm()),
+                0x0e => op("tnei",   rs_imm()),
+                0x10 => op("bltzal", rs_target()),
+                0x11 => if rs == 0 { op("bal", format!("0x{:>08x}", btarget)) }else{ op("bgezal", rs_target()) },
+                0x1c => op("bposge32", format!("0x{:>08x}", btarget)),
+                _    => unknown,
+            }
+        }
+        MIPS32_OP_J    => op("j",    format!("0x{:>08x}", jtarget)),
+        MIPS32_OP_JAL  => op("jal",  format!("0x{:>08x}", jtarget)),
+        MIPS32_OP_JALX => op("jalx", format!("0x{:>08x}", jtarget)),
+        MIPS32_OP_BEQ  => {
+            if rs == 0 && rt == 0 { op("b",    format!("0x{:>08x}", btarget)) }
+            else if rt == 0       { op("beqz", format!("{}, 0x{:>08x}", r!(rs), btarget)) }
+            else                  { op("beq",  format!("{}, {}, 0x{:>08x}", r!(rs), r!(rt), btarget)) }
+        }
+        MIPS32_OP_BNE  => {
+            if rt == 0 { op("bnez", format!("{}, 0x{:>08x}", r!(rs), btarget)) }
+            else       { op("bne",  format!("{}, {}, 0x{:>08x}", r!(rs), r!(rt), btarget)) }
+        }
+        MIPS32_OP_BEQL  => op("beql",  format!("{}, {}, 0x{:>08x}", r!(rs), r!(rt), btarget)),
+        MIPS32_OP_BNEL  => op("bnel",  format!("{}, {}, 0x{:>08x}", r!(rs), r!(rt), btarget)),
+        MIPS32_OP_BLEZ  => op("blez",  format!("{}, 0x{:>08x}", r!(rs), btarget)),
+        MIPS32_OP_BGTZ  => op("bgtz",  format!("{}, 0x{:>08x}", r!(rs), btarget)),
+        MIPS32_OP_BLEZL => op("blezl", format!("{}, 0x{:>08x}", r!(rs), btarget)),
+        MIPS32_OP_BGTZL => op("bgtzl", format!("{}, 0x{:>08x}", r!(rs), btarget)),
+        MIPS32_OP_ADDI  => op("addi",  rt_rs_s()),
+        MIPS32_OP_ADDIU => {
+            if rs == 0 { op("li", format!("{}, {}", r!(rt), simm(simm16))) }else{ op("addiu", rt_rs_s()) }
+        }
+        MIPS32_OP_SLTI  => op("slti",  rt_rs_s()),
+        MIPS32_OP_SLTIU => op("sltiu", rt_rs_s()),
+        MIPS32_OP_ANDI  => op("andi",  rt_rs_u()),
+        MIPS32_OP_ORI   => {
+            if rs == 0 { op("li", format!("{}, 0x{:x}", r!(rt), imm)) }else{ op("ori", rt_rs_u()) }
+        }
+        MIPS32_OP_XORI  => op("xori",  rt_rs_u()),
+        MIPS32_OP_LUI   => op("lui",   format!("{}, 0x{:x}", r!(rt), imm)),
+        MIPS32_OP_LB    => op("lb",  mem()),
+        MIPS32_OP_LH    => op("lh",  mem()),
+        MIPS32_OP_LWL   => op("lwl", mem()),
+        MIPS32_OP_LW    => op("lw",  mem()),
+        MIPS32_OP_LBU   => op("lbu", mem()),
+        MIPS32_OP_LHU   => op("lhu", mem()),
+        MIPS32_OP_LWR   => op("lwr", mem()),
+        MIPS32_OP_SB    => op("sb",  mem()),
+        MIPS32_OP_SH    => op("sh",  mem()),
+        MIPS32_OP_SWL   => op("swl", mem()),
+        MIPS32_OP_SW    => op("sw",  mem()),
+        MIPS32_OP_SWR   => op("swr", mem()),
+        MIPS32_OP_LL    => op("ll",  mem()),
+        MIPS32_OP_SC    => op("sc",  mem()),
+        MIPS32_OP_LWC1  => op("lwc1", fmem()),
+        MIPS32_OP_LDC1  => op("ldc1", fmem()),
+        MIPS32_OP_SWC1  => op("swc1", fmem()),
+        MIPS32_OP_SDC1  => op("sdc1", fmem()),
+        MIPS32_OP_CACHE => op("cache", format!("0x{:x}, {}({})", rt, simm(simm16), r!(rs))),
+        MIPS32_OP_PREF  => op("pref",  format!("0x{:x}, {}({})", rt, simm(simm16), r!(rs))),
+        MIPS32_OP_SPECIAL2 => {
+            match funct {
+                0x00 => op("madd",  ac_rs_rt()),
+                0x01 => op("maddu", ac_rs_rt()),
+                0x02 => op("mul",   rd_rs_rt()),
+                0x04 => op("msub",  ac_rs_rt()),
+                0x05 => op("msubu", ac_rs_rt()),
+                0x20 => op("clz",   format!("{}, {}", r!(rd), r!(rs))),
+                0x21 => op("clo",   format!("{}, {}", r!(rd), r!(rs))),
+                _    => unknown,
+            }
+        }
+        MIPS32_OP_SPECIAL3 => {
+            match funct {
+                0x00 => op("ext", format!("{}, {}, {}, {}", r!(rt), r!(rs), shamt, rd+1)),
+                0x04 => op("ins", format!("{}, {}, {}, {}", r!(rt), r!(rs), shamt, (rd+1).wrapping_sub(shamt))),
+                0x20 => {
+                    match shamt {
+                        0x02 => op("wsbh", format!("{}, {}", r!(rd), r!(rt))),
+                        0x10 => op("seb",  format!("{}, {}", r!(rd), r!(rt))),
+                        0x18 => op("seh",  format!("{}, {}", r!(rd), r!(rt))),
+                        _    => unknown,
+                    }
+                }
+                0x3b => op("rdhwr", format!("{}, ${}", r!(rt), rd)),
+                _    => disasm_dsp(inst).unwrap_or(unknown),
+            }
+        }
+        MIPS32_OP_COP0 => {
+            match rs {
+                0x00 => op("mfc0", format!("{}, ${}, {}", r!(rt), rd, inst & 0x7)),
+                0x04 => op("mtc0", format!("{}, ${}, {}", r!(rt), rd, inst & 0x7)),
+                0x0a => op("rdpgpr", format!("{}, {}", r!(rd), r!(rt))),
+                0x0e => op("wrpgpr", format!("{}, {}", r!(rd), r!(rt))),
+                0x0b => {
+                    match imm {
+                        0x6000 => if rt == 0 { "di".to_string() }else{ op("di", r!(rt).to_string()) },
+                        0x6020 => if rt == 0 { "ei".to_string() }else{ op("ei", r!(rt).to_string()) },
+                        _      => unknown,
+                    }
+                }
+                0x10 => {
+                    match inst {
+                        0x42000001 => "tlbr".to_string(),
+                        0x42000002 => "tlbwi".to_string(),
+                        0x42000006 => "tlbwr".to_string(),
+                        0x42000008 => "tlbp".to_string(),
+                        0x42000018 => "eret".to_string(),
+                        0x42000020 => "wait".to_string(),
+                        _          => unknown,
+                    }
+                }
+                _ => unknown,
+            }
+        }
+        MIPS32_OP_COP1  => disasm_cop1(btarget, inst).unwrap_or(unknown),
+        MIPS32_OP_COP1X => disasm_cop1x(inst).unwrap_or(unknown),
+        _ => unknown,
+    }
+}
+
+fn with_ac(operands: String, ac: u32) -> String {
+    if ac == 0 { operands }else{ format!("{}, $ac{}", operands, ac) }
+}
+
+// mnemonics of the DSP ASE instructions in SPECIAL3 by (funct, inst[10:6])
+const DSP_MNEMONICS : &[(u32, &[(u32, &str)])] = &[
+    (0x0a, &[(0x00, "lwx"), (0x04, "lhx"), (0x06, "lbux")]),
+    (0x0c, &[(0x00, "insv")]),
+    (0x10, &[
+        (0x00, "addu.qb"), (0x01, "subu.qb"), (0x04, "addu_s.qb"), (0x05, "subu_s.qb"),
+        (0x06, "muleu_s.ph.qbl"), (0x07, "muleu_s.ph.qbr"), (0x08, "addu.ph"), (0x09, "subu.ph"),
+        (0x0a, "addq.ph"), (0x0b, "subq.ph"), (0x0c, "addu_s.ph"), (0x0d, "subu_s.ph"),
+        (0x0e, "addq_s.ph"), (0x0f, "subq_s.ph"), (0x10, "addsc"), (0x11, "addwc"),
+        (0x12, "modsub"), (0x14, "raddu.w.qb"), (0x16, "addq_s.w"), (0x17, "subq_s.w"),
+        (0x1c, "muleq_s.w.phl"), (0x1d, "muleq_s.w.phr"), (0x1e, "mulq_s.ph"), (0x1f, "mulq_rs.ph"),
+    ]),
+    (0x11, &[
+        (0x00, "cmpu.eq.qb"), (0x01, "cmpu.lt.qb"), (0x02, "cmpu.le.qb"), (0x03, "pick.qb"),
+        (0x04, "cmpgu.eq.qb"), (0x05, "cmpgu.lt.qb"), (0x06, "cmpgu.le.qb"),
+        (0x08, "cmp.eq.ph"), (0x09, "cmp.lt.ph"), (0x0a, "cmp.le.ph"), (0x0b, "pick.ph"),
+        (0x0c, "precrq.qb.ph"), (0x0d, "precr.qb.ph"), (0x0e, "packrl.ph"), (0x0f, "precrqu_s.qb.ph"),
+        (0x14, "precrq.ph.w"), (0x15, "precrq_rs.ph.w"),
+        (0x18, "cmpgdu.eq.qb"), (0x19, "cmpgdu.lt.qb"), (0x1a, "cmpgdu.le.qb"),
+        (0x1e, "precr_sra.ph.w"), (0x1f, "precr_sra_r.ph.w"),
+    ]),
+    (0x12, &[
+        (0x01, "absq_s.qb"), (0x02, "repl.qb"), (0x03, "replv.qb"),
+        (0x04, "precequ.ph.qbl"), (0x05, "precequ.ph.qbr"), (0x06, "precequ.ph.qbla"), (0x07, "precequ.ph.qbra"),
+        (0x09, "absq_s.ph"), (0x0a, "repl.ph"), (0x0b, "replv.ph"), (0x0c, "preceq.w.phl"), (0x0d, "preceq.w.phr"),
+        (0x11, "absq_s.w"), (0x1b, "bitrev"),
+        (0x1c, "preceu.ph.qbl"), (0x1d, "preceu.ph.qbr"), (0x1e, "preceu.ph.qbla"), (0x1f, "preceu.ph.qbra"),
+    ]),
+    (0x13, &[
+        (0x00, "shll.qb"), (0x01, "shrl.qb"), (0x02, "shllv.qb"), (0x03, "shrlv.qb"),
+        (0x04, "shra.qb"), (0x05, "shra_r.qb"), (0x06, "shrav.qb"), (0x07, "shrav_r.qb"),
+        (0x08, "shll.ph"), (0x09, "shra.ph"), (0x0a, "shllv.ph"), (0x0b, "shrav.ph"),
+        (0x0c, "shll_s.ph"), (0x0d, "shra_r.ph"), (0x0e, "shllv_s.ph"), (0x0f, "shrav_r.ph"),
+        (0x14, "shll_s.w"), (0x15, "shra_r.w"), (0x16, "shllv_s.w"), (0x17, "shrav_r.w"),
+        (0x19, "shrl.ph"), (0x1b, "shrlv.ph"),
+    ]),
+    (0x18, &[
+        (0x00, "adduh.qb"), (0x01, "subuh.qb"), (0x02, "adduh_r.qb"), (0x03, "subuh_r.qb"),
+        (0x08, "addqh.ph"), (0x09, "subqh.ph"), (0x0a, "addqh_r.ph"), (0x0b, "subqh_r.ph"),
+        (0x0c, "mul.ph"), (0x0e, "mul_s.ph"),
+        (0x10, "addqh.w"), (0x11, "subqh.w"), (0x12, "addqh_r.w"), (0x13, "subqh_r.w"),
+        (0x16, "mulq_s.w"), (0x17, "mulq_rs.w"),
+    ]),
+    (0x30, &[
+        (0x00, "dpa.w.ph"), (0x01, "dps.w.ph"), (0x02, "mulsa.w.ph"), (0x03, "dpau.h.qbl"),
+        (0x04, "dpaq_s.w.ph"), (0x05, "dpsq_s.w.ph"), (0x06, "mulsaq_s.w.ph"), (0x07, "dpau.h.qbr"),
+        (0x08, "dpax.w.ph"), (0x09, "dpsx.w.ph"), (0x0b, "dpsu.h.qbl"),
+        (0x0c, "dpaq_sa.l.w"), (0x0d, "dpsq_sa.l.w"), (0x0f, "dpsu.h.qbr"),
+        (0x10, "maq_sa.w.phl"), (0x12, "maq_sa.w.phr"), (0x14, "maq_s.w.phl"), (0x16, "maq_s.w.phr"),
+        (0x18, "dpaqx_s.w.ph"), (0x19, "dpsqx_s.w.ph"), (0x1a, "dpaqx_sa.w.ph"), (0x1b, "dpsqx_sa.w.ph"),
+    ]),
+    (0x31, &[(0x00, "append"), (0x01, "prepend"), (0x10, "balign")]),
+    (0x38, &[
+        (0x00, "extr.w"), (0x01, "extrv.w"), (0x02, "extp"), (0x03, "extpv"),
+        (0x04, "extr_r.w"), (0x05, "extrv_r.w"), (0x06, "extr_rs.w"), (0x07, "extrv_rs.w"),
+        (0x0a, "extpdp"), (0x0b, "extpdpv"), (0x0e, "extr_s.h"), (0x0f, "extrv_s.h"),
+        (0x12, "rddsp"), (0x13, "wrdsp"), (0x1a, "shilo"), (0x1b, "shilov"), (0x1f, "mthlip"),
+    ]),
+];
+
+// DSP ASE instructions in SPECIAL3
+fn disasm_dsp(inst: u32) -> Option<String> {
+    let rs    = (inst >> 21) & 0x1f;
+    let rt    = (inst >> 16) & 0x1f;
+    let rd    = (inst >> 11) & 0x1f;
+    let sub   = (inst >>  6) & 0x1f;
+    let funct =  inst        & 0x3f;
+    let ac    = rd & 3;
+
+    let (_, ops) = DSP_MNEMONICS.iter().find(|(f, _)| *f == funct)?;
+    let (_, mnemonic) = ops.iter().find(|(o, _)| *o == sub)?;
+
+    let operands = match funct {
+        0x0a => format!("{}, {}({})", r!(rd), r!(rt), r!(rs)),
+        0x0c => format!("{}, {}", r!(rt), r!(rs)),
+        0x10 if sub == 0x14 => format!("{}, {}", r!(rd), r!(rs)),
+        0x11 if sub <= 0x02 || (0x08..=0x0a).contains(&sub) => format!("{}, {}", r!(rs), r!(rt)),
+        0x11 if sub >= 0x1e => format!("{}, {}, {}", r!(rt), r!(rs), rd),
+        0x12 if sub == 0x02 => format!("{}, 0x{:x}", r!(rd), (inst >> 16) & 0xff),
+        0x12 if sub == 0x0a => format!("{}, {}", r!(rd), simm(sext((inst >> 16) & 0x3ff, 10))),
+        0x12 => format!("{}, {}", r!(rd), r!(rt)),
+        0x13 if 0 != (sub & 2) => format!("{}, {}, {}", r!(rd), r!(rt), r!(rs)),
+        0x13 => format!("{}, {}, {}", r!(rd), r!(rt), rs),
+        0x30 => format!("$ac{}, {}, {}", ac, r!(rs), r!(rt)),
+        0x31 if sub == 0x10 => format!("{}, {}, {}", r!(rt), r!(rs), ac),
+        0x31 => format!("{}, {}, {}", r!(rt), r!(rs), rd),
+        0x38 => {
+            match sub {
+                0x12 => format!("{}, 0x{:x}", r!(rd), (inst >> 16) & 0x3ff),
+                0x13 => format!("{}, 0x{:x}", r!(rs), (inst >> 11) & 0x3ff),
+                0x1a => format!("$ac{}, {}", ac, ((inst >> 20) << 26) as i32 >> 26),
+                0x1b => format!("$ac{}, {}", ac, r!(rs)),
+                0x1f => format!("{}, $ac{}", r!(rs), ac),
+                _ if 0 != (sub & 1) => format!("{}, $ac{}, {}", r!(rt), ac, r!(rs)),
+                _ => format!("{}, $ac{}, {}", r!(rt), ac, rs),
+            }
+        }
+        _ => format!("{}, {}, {}", r!(rd), r!(rs), r!(rt)),
+    };
+    Some(op(mnemonic, operands))
+}
+
+// FP condition code operand ("$fccN")
+fn fcc(cc: u32) -> String {
+    format!("$fcc{}", cc)
+}
+
+const FP_COND_NAMES : [&str; 16] = [
+    "f", "un", "eq", "ueq", "olt", "ult", "ole", "ule",
+    "sf", "ngle", "seq", "ngl", "lt", "nge", "le", "ngt",
+];
+
+fn disasm_cop1(btarget: u32, inst: u32) -> Option<String> {
+    let fmt   = (inst >> 21) & 0x1f;
+    let rt    = (inst >> 16) & 0x1f;
+    let fs    = (inst >> 11) & 0x1f;
+    let fd    = (inst >>  6) & 0x1f;
+    let funct =  inst        & 0x3f;
+    let ft    = rt;
+
+    let suffix = match fmt {
+        0x00 => { return Some(op("mfc1",  format!("{}, $f{}", r!(rt), fs))); }
+        0x02 => { return Some(op("cfc1",  format!("{}, ${}",  r!(rt), fs))); }
+        0x03 => { return Some(op("mfhc1", format!("{}, $f{}", r!(rt), fs))); }
+        0x04 => { return Some(op("mtc1",  format!("{}, $f{}", r!(rt), fs))); }
+        0x06 => { return Some(op("ctc1",  format!("{}, ${}",  r!(rt), fs))); }
+        0x07 => { return Some(op("mthc1", format!("{}, $f{}", r!(rt), fs))); }
+        0x08 => {
+            let mnemonic = ["bc1f", "bc1t", "bc1fl", "bc1tl"][(rt & 3) as usize];
+            let cc = rt >> 2;
+            return Some(if cc == 0 { op(mnemonic, format!("0x{:>08x}", btarget)) }else{ op(mnemonic, format!("{}, 0x{:>08x}", fcc(cc), btarget)) });
+        }
+        0x10 => "s",
+        0x11 => "d",
+        0x14 => "w",
+        0x15 => "l",
+        _    => { return None; }
+    };
+
+    // the W and L formats are only converted to S or D
+    if (fmt == 0x14 || fmt == 0x15) && funct != 0x20 && funct != 0x21 {
+        return None;
+    }
+
+    let fd_fs_ft = || format!("$f{}, $f{}, $f{}", fd, fs, ft);
+    let fd_fs    = || format!("$f{}, $f{}", fd, fs);
+    let name = |base: &str| format!("{}.{}", base, suffix);
+
+    Some(match funct {
+        0x00 => op(&name("add"), fd_fs_ft()),
+        0x01 => op(&name("sub"), fd_fs_ft()),
+        0x02 => op(&name("mul"), fd_fs_ft()),
+        0x03 => op(&name("div"), fd_fs_ft()),
+        0x04 => op(&name("sqrt"), fd_fs()),
+        0x05 => op(&name("abs"),  fd_fs()),
+        0x06 => op(&name("mov"),  fd_fs()),
+        0x07 => op(&name("neg"),  fd_fs()),
+        0x08..=0x0f => {
+            let base = ["round", "trunc", "ceil", "floor"][(funct & 3) as usize];
+            let to   = if 0 != (funct & 4) { "w" }else{ "l" };
+            op(&format!("{}.{}.{}", base, to, suffix), fd_fs())
+        }
+        0x11 => op(&name(if 0 != (ft & 1) { "movt" }else{ "movf" }), format!("$f{}, $f{}, {}", fd, fs, fcc(ft >> 2))),
+        0x12 => op(&name("movz"), format!("$f{}, $f{}, {}", fd, fs, r!(rt))),
+        0x13 => op(&name("movn"), format!("$f{}, $f{}, {}", fd, fs, r!(rt))),
+        0x15 => op(&name("recip"), fd_fs()),
+        0x16 => op(&name("rsqrt"), fd_fs()),
+        0x20 if fmt != 0x10 => op(&format!("cvt.s.{}", suffix), fd_fs()),
+        0x21 if fmt != 0x11 => op(&format!("cvt.d.{}", suffix), fd_fs()),
+        0x24 => op(&format!("cvt.w.{}", suffix), fd_fs()),
+        0x25 => op(&format!("cvt.l.{}", suffix), fd_fs()),
+        0x30..=0x3f => {
+            let mnemonic = format!("c.{}.{}", FP_COND_NAMES[(funct & 0xf) as usize], suffix);
+            let cc = fd >> 2;
+            if cc == 0 { op(&mnemonic, format!("$f{}, $f{}", fs, ft)) }else{ op(&mnemonic, format!("{}, $f{}, $f{}", fcc(cc), fs, ft)) }
+        }
+        _ => { return None; }
+    })
+}
+
+fn disasm_cop1x(inst: u32) -> Option<String> {
+    let base  = (inst >> 21) & 0x1f;
+    let index = (inst >> 16) & 0x1f;
+    let fs    = (inst >> 11) & 0x1f;
+    let fd    = (inst >>  6) & 0x1f;
+    let funct =  inst        & 0x3f;
+
+    let xmem = |f: u32| format!("$f{}, {}({})", f, r!(index), r!(base));
+
+    Some(match funct {
+        0x00 => op("lwxc1", xmem(fd)),
+        0x01 => op("ldxc1", xmem(fd)),
+        0x05 => op("luxc1", xmem(fd)),
+        0x08 => op("swxc1", xmem(fs)),
+        0x09 => op("sdxc1", xmem(fs)),
+        0x0d => op("suxc1", xmem(fs)),
+        0x0f => op("prefx", format!("0x{:x}, {}({})", fs, r!(index), r!(base))),
+        0x20 | 0x21 | 0x28 | 0x29 | 0x30 | 0x31 | 0x38 | 0x39 => {
+            let base_name = ["madd", "msub", "nmadd", "nmsub"][((funct >> 3) & 3) as usize];
+            let suffix    = if 0 != (funct & 1) { "d" }else{ "s" };
+            // fr is in the rs field
+            op(&format!("{}.{}", base_name, suffix), format!("$f{}, $f{}, $f{}, $f{}", fd, base, fs, index))
+        }
+        _ => { return None; }
+    })
+}
+
+pub fn disasm_mips16(pc: u32, inst32: u32) -> String {
+    let pc = pc & !1;
+
+    let  op5  : u32 = (inst32>>11) & 0x1f;
+    let  rx   : u32 = (inst32>> 8) & 0x07;
+    let  ry   : u32 = (inst32>> 5) & 0x07;
+    let  rz   : u32 = (inst32>> 2) & 0x07;
+    let  sa3  : u32 = (inst32>> 2) & 0x07;
+    let funct5: u32 =  inst32      & 0x1f;
+    let  imm8 : u32 =  inst32      & 0xff;
+
+    let imm16 = ((inst32>>5)&(0x1f<<11)) | ((inst32>>16)&(0x3f<<5)) | funct5;
+    let imm15 = ((inst32>>5)&(0x0f<<11)) | ((inst32>>16)&(0x7f<<4)) | (inst32&0xf);
+
+    let ext = 0 != (inst32 & 0xffff0000);
+    let unknown = if ext { format!(".word   0x{:>08x}", inst32) }else{ format!(".half   0x{:>04x}", inst32) };
+
+    if (inst32>>27) == MIPS16E_OP_JAL {
+        let mut jumpaddr = inst32 & 0xffff;
+        jumpaddr|= ((inst32>>21) & 0x1f)<<16;
+        jumpaddr|= ((inst32>>16) & 0x1f)<<21;
+        let target = (pc.wrapping_add(4) & 0xf0000000) | (jumpaddr<<2);
+        return if 0 != (inst32 & (1<<26)) {
+            op("jalx", format!("0x{:>08x}", target))
+        }else{
+            op("jal",  format!("0x{:>08x}", target))
+        };
+    }
+
+    // branch target and offset of the memory operand
+    let btarget = |imm_nonext: u32| -> u32 {
+        if ext { pc.wrapping_add(sext(imm16, 16) << 1).wrapping_add(4) }else{ pc.wrapping_add(imm_nonext << 1).wrapping_add(2) }
+    };
+    let offset = |off_nonext: u32| -> String {
+        if ext { simm(sext(imm16, 16)) }else{ format!("0x{:x}", off_nonext) }
+    };
+    let mem = |mnemonic: &str, off_nonext: u32| -> String {
+        op(mnemonic, format!("{}, {}({})", r16!(ry), offset(off_nonext), r16!(rx)))
+    };
+
+    match op5 {
+        MIPS16E_OP_ADDIUSP => op("addiu", format!("{}, $sp, {}", r16!(rx), offset(imm8<<2))),
+        MIPS16E_OP_ADDIUPC => op("addiu", format!("{}, $pc, {}", r16!(rx), offset(imm8<<2))),
+        MIPS16E_OP_B       => op("b", format!("0x{:>08x}", btarget(sext(inst32 & 0x7ff, 11)))),
+        MIPS16E_OP_BEQZ    => op("beqz", format!("{}, 0x{:>08x}", r16!(rx), btarget(sext(imm8, 8)))),
+        MIPS16E_OP_BNEZ    => op("bnez", format!("{}, 0x{:>08x}", r16!(rx), btarget(sext(imm8, 8)))),
+        MIPS16E_OP_SHIFT   => {
+            let sa = if ext { (inst32>>22) & 0x1f }else if sa3 == 0 { 8 }else{ sa3 };
+            let mnemonic = match funct5 & 3 {
+                0 => "sll",
+                2 => "srl",
+                3 => "sra",
+                _ => { return unknown; }
+            };
+            op(mnemonic, format!("{}, {}, {}", r16!(rx), r16!(ry), sa))
+        }
+        MIPS16E_OP_RRIA => {
+            if 0 != (inst32 & (1<<4)) {
+                unknown
+            }else{
+                let imm = if ext { sext(imm15, 15) }else{ sext(inst32 & 0xf, 4) };
+                op("addiu", format!("{}, {}, {}", r16!(ry), r16!(rx), simm(imm)))
+            }
+        }
+        MIPS16E_OP_ADDIU8 => {
+            let imm = if ext { sext(imm16, 16) }else{ sext(imm8, 8) };
+            op("addiu", format!("{}, {}", r16!(rx), simm(imm)))
+        }
+        MIPS16E_OP_SLTI  => op("slti",  format!("{}, {}", r16!(rx), offset(imm8))),
+        MIPS16E_OP_SLTIU => op("sltiu", format!("{}, {}", r16!(rx), offset(imm8))),
+        MIPS16E_OP_LI    => op("li",    format!("{}, 0x{:x}", r16!(rx), if ext { imm16 }else{ imm8 })),
+        MIPS16E_OP_CMPI  => op("cmpi",  format!("{}, 0x{:x}", r16!(rx), if ext { imm16 }else{ imm8 })),
+        MIPS16E_OP_I8 => {
+            match rx {
+                0 => op("bteqz", format!("0x{:>08x}", btarget(sext(imm8, 8)))),
+                1 => op("btnez", format!("0x{:>08x}", btarget(sext(imm8, 8)))),
+                2 => op("sw",    format!("$ra, {}($sp)", offset(imm8<<2))),
+                3 => {
+                    let imm = if ext { sext(imm16, 16) }else{ sext(imm8, 8) << 3 };
+                    op("addiu", format!("$sp, {}", simm(imm)))
+                }
+                4 => {
+                    let mnemonic = if 0 != (inst32 & (1<<7)) { "save" }else{ "restore" };
+                    let mut regs : Vec<String> = Vec::new();
+                    let framesize;
+                    if ext {
+                        let aregs  = (inst32>>16) & 0xf;
+                        let xsregs = (inst32>>24) & 0x7;
+                        framesize  = ((inst32 & 0xf) | ((inst32>>16) & 0xf0)) << 3;
+                        regs.push(format!("aregs=0x{:x}", aregs));
+                        if 0 != (inst32 & (1<<6)) { regs.push("$ra".to_string()); }
+                        if 0 != (inst32 & (1<<5)) { regs.push("$s0".to_string()); }
+                        if 0 != (inst32 & (1<<4)) { regs.push("$s1".to_string()); }
+                        for i in 0..xsregs {
+                            regs.push(if i == 6 { "$fp".to_string() }else{ r!(18+i).to_string() });
+                        }
+                    }else{
+                        framesize = if 0 == (inst32 & 0xf) { 128 }else{ (inst32 & 0xf) << 3 };
+                        if 0 != (inst32 & (1<<6)) { regs.push("$ra".to_string()); }
+                        if 0 != (inst32 & (1<<5)) { regs.push("$s0".to_string()); }
+                        if 0 != (inst32 & (1<<4)) { regs.push("$s1".to_string()); }
+                    }
+                    regs.push(format!("{}", framesize));
+                    op(mnemonic, regs.join(", "))
+                }
+                5 if !ext => op("move", format!("{}, {}", r!(ry | (funct5 & 0x18)), r16!(funct5 & 0x7))),
+                7 if !ext => op("move", format!("{}, {}", r16!(ry), r!(funct5))),
+                _ => unknown,
+            }
+        }
+        MIPS16E_OP_LB    => mem("lb",  funct5),
+        MIPS16E_OP_LH    => mem("lh",  funct5<<1),
+        MIPS16E_OP_LW    => mem("lw",  funct5<<2),
+        MIPS16E_OP_LBU   => mem("lbu", funct5),
+        MIPS16E_OP_LHU   => mem("lhu", funct5<<1),
+        MIPS16E_OP_SB    => mem("sb",  funct5),
+        MIPS16E_OP_SH    => mem("sh",  funct5<<1),
+        MIPS16E_OP_SW    => mem("sw",  funct5<<2),
+        MIPS16E_OP_LWSP  => op("lw", format!("{}, {}($sp)", r16!(rx), offset(imm8<<2))),
+        MIPS16E_OP_SWSP  => op("sw", format!("{}, {}($sp)", r16!(rx), offset(imm8<<2))),
+        MIPS16E_OP_LWPC  => op("lw", format!("{}, {}($pc)", r16!(rx), offset(imm8<<2))),
+        MIPS16E_OP_RRR => {
+            match inst32 & 3 {
+                1 => op("addu", format!("{}, {}, {}", r16!(rz), r16!(rx), r16!(ry))),
+                3 => op("subu", format!("{}, {}, {}", r16!(rz), r16!(rx), r16!(ry))),
+                _ => unknown,
+            }
+        }
+        MIPS16E_OP_RR => {
+            let rx_ry = || format!("{}, {}", r16!(rx), r16!(ry));
+            let ry_rx = || format!("{}, {}", r16!(ry), r16!(rx));
+            match funct5 {
+                MIPS16E_RRFUNCT_JR => {
+                    match ry {
+                        0 => op("jr",    r16!(rx).to_string()),
+                        1 => op("jr",    "$ra".to_string()),
+                        2 => op("jalr",  r16!(rx).to_string()),
+                        4 => op("jrc",   r16!(rx).to_string()),
+                        5 => op("jrc",   "$ra".to_string()),
+                        6 => op("jalrc", r16!(rx).to_string()),
+                        _ => unknown,
+                    }
+                }
+                MIPS16E_RRFUNCT_SDBBP => "sdbbp".to_string(),
+                MIPS16E_RRFUNCT_BREAK => "break".to_string(),
+                MIPS16E_RRFUNCT_SLT   => op("slt",   rx_ry()),
+                MIPS16E_RRFUNCT_SLTU  => op("sltu",  rx_ry()),
+                MIPS16E_RRFUNCT_SLLV  => op("sllv",  ry_rx()),
+                MIPS16E_RRFUNCT_SRLV  => op("srlv",  ry_rx()),
+                MIPS16E_RRFUNCT_SRAV  => op("srav",  ry_rx()),
+                MIPS16E_RRFUNCT_CMP   => op("cmp",   rx_ry()),
+                MIPS16E_RRFUNCT_NEG   => op("neg",   rx_ry()),
+                MIPS16E_RRFUNCT_AND   => op("and",   rx_ry()),
+                MIPS16E_RRFUNCT_OR    => op("or",    rx_ry()),
+                MIPS16E_RRFUNCT_XOR   => op("xor",   rx_ry()),
+                MIPS16E_RRFUNCT_NOT   => op("not",   rx_ry()),
+                MIPS16E_RRFUNCT_MFHI  => op("mfhi",  r16!(rx).to_string()),
+                MIPS16E_RRFUNCT_MFLO  => op("mflo",  r16!(rx).to_string()),
+                MIPS16E_RRFUNCT_MULT  => op("mult",  rx_ry()),
+                MIPS16E_RRFUNCT_MULTU => op("multu", rx_ry()),
+                MIPS16E_RRFUNCT_DIV   => op("div",   rx_ry()),
+                MIPS16E_RRFUNCT_DIVU  => op("divu",  rx_ry()),
+                MIPS16E_RRFUNCT_CNVT  => {
+                    match ry {
+                        0 => op("zeb", r16!(rx).to_string()),
+                        1 => op("zeh", r16!(rx).to_string()),
+                        4 => op("seb", r16!(rx).to_string()),
+                        5 => op("seh", r16!(rx).to_string()),
+                        _ => unknown,
+                    }
+                }
+                _ => unknown,
+            }
+        }
+        _ => unknown,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // encodings given by an assembler (GNU as or LLVM) for MIPS32R2 with the DSP ASE revision 2
+    const MIPS32 : &[(u32, &str)] = &[
+        (0x27bdffe0, "addiu   $sp, $sp, -0x20"),
+        (0x8fbf001c, "lw      $ra, 0x1c($sp)"),
+        (0xad24fffc, "sw      $a0, -0x4($t1)"),
+        (0x3c08b8ff, "lui     $t0, 0xb8ff"),
+        (0x35081234, "ori     $t0, $t0, 0x1234"),
+        (0x00851021, "addu    $v0, $a0, $a1"),
+        (0x00000000, "nop"),
+        (0x000940c0, "sll     $t0, $t1, 3"),
+        (0x03e00008, "jr      $ra"),
+        (0x0320f809, "jalr    $t9"),
+        (0x40086000, "mfc0    $t0, $12, 0"),
+        (0x40897801, "mtc0    $t1, $15, 1"),
+        (0x42000018, "eret"),
+        (0x0000000c, "syscall"),
+        (0x7d283900, "ext     $t0, $t1, 4, 8"),
+        (0x7d285904, "ins     $t0, $t1, 4, 8"),
+        (0x7c031420, "seb     $v0, $v1"),
+        (0x7c0310a0, "wsbh    $v0, $v1"),
+        (0x70850000, "madd    $a0, $a1"),
+        (0xbc940000, "cache   0x14, 0x0($a0)"),
+        (0xc0880000, "ll      $t0, 0x0($a0)"),
+        (0xe0880000, "sc      $t0, 0x0($a0)"),
+        (0x0064100b, "movn    $v0, $v1, $a0"),
+        (0x70621020, "clz     $v0, $v1"),
+        (0x42000006, "tlbwr"),
+        (0x42000020, "wait"),
+        (0x46241000, "add.d   $f0, $f2, $f4"),
+        (0x46801060, "cvt.s.w $f1, $f2"),
+        (0x46020a34, "c.olt.s $fcc2, $f1, $f2"),
+        (0xc7a30008, "lwc1    $f3, 0x8($sp)"),
+        (0x4c231020, "madd.s  $f0, $f1, $f2, $f3"),
+        (0x7c850930, "dpaq_s.w.ph $ac1, $a0, $a1"),
+        (0x7c851910, "addu_s.qb $v1, $a0, $a1"),
+        (0x00401012, "mflo    $v0, $ac2"),
+        (0x7c0a8a0a, ".word   0x7c0a8a0a"),
+    ];
+
+    #[test]
+    fn mips32_instructions() {
+        for (inst, text) in MIPS32 {
+            assert_eq!(disasm_mips32(0x80001000, *inst), *text, "inst {:08x}", inst);
+        }
+    }
+
+    #[test]
+    fn mips32_branch_targets() {
+        assert_eq!(disassemble(0x80001000, 0x1085000f), "beq     $a0, $a1, 0x80001040");
+        assert_eq!(disassemble(0x80001000, 0x0411fff0), "bal     0x80000fc4");
+        assert_eq!(disassemble(0x80001000, 0x0c000400), "jal     0x80001000");
+        assert_eq!(disassemble(0x9fc00000, 0x0c000400), "jal     0x90001000");
+        assert_eq!(disassemble(0x80001000, 0x45010003), "bc1t    0x80001010");
+    }
+
+    #[test]
+    fn mips16e_instructions() {
+        // pc[0] = 1 selects MIPS16e
+        assert_eq!(disassemble(0x80001001, 0xe820), "jr      $ra");
+        assert_eq!(disassemble(0x80001001, 0x6a05), "li      $v0, 0x5");
+        assert_eq!(disassemble(0x80001001, 0x63fc), "addiu   $sp, -0x20");
+        assert_eq!(disassemble(0x80001001, 0x2a05), "bnez    $v0, 0x8000100c");
+        // EXTEND and JAL hold the first halfword in inst[31:16]
+        assert_eq!(disassemble(0x80001001, 0xf2226a14), "li      $v0, 0x1234");
+        assert_eq!(disassemble(0x80001001, 0x18000400), "jal     0x80001000");
+        assert!(mips16_is_32bit(0xf222) && mips16_is_32bit(0x1800) && !mips16_is_32bit(0x6a05));
+    }
+}
@@ -0,0 +1,770 @@
+use crate::procstate::{MachineState, Reg};
+use crate::exception;
+use crate::cp0def;
+use crate::mem;
+use crate::disasm;
+use crate::mips;
+use crate::c0_val;
+use crate::sign_ext16;
+use crate::update_pc_next32;
+use crate::update_pc_next32_with_delayed_imm;
+use log::{error,info};
+
+/*
+DSP ASE revision 2 of the 74K core.
+
+The instructions are in SPECIAL3 (and bposge32 in REGIMM), and they raise the DSP State Disabled
+exception unless Status.MX is set. The DSP ASE adds the accumulators ac1..ac3 next to HI/LO (ac0),
+which are also accessed by mfhi/mthi/mflo/mtlo and the multiply(-add) instructions with an ac field.
+
+DSPControl:
+  [5:0]   pos    : bit position for extp, extpdp, insv and bposge32
+  [12:7]  scount : size for insv
+  [13]    c      : carry of addsc for addwc
+  [14]    EFI    : set when extp or extpdp fails
+  [23:16] ouflag : overflow or saturation (bits 16..19: ac0..ac3, 20: add/sub, 21: multiply, 22: shift, 23: extract)
+  [27:24] ccond  : results of comparisons for pick
+SIMD lanes are numbered from the least significant one (e.g., byte 0 is rt[7:0]).
+*/
+
+macro_rules! unknown_instruction{
+    ( $inst:expr, $msg:expr ) =>
+    {
+        error!("Unknown MIPS32 instruction (inst={:>08x}, {})", $inst, $msg);
+        return false;
+    }
+}
+
+// DSPControl fields
+const DSP_POS_MASK    : u32 = 0x3f;
+const DSP_BIT_SCOUNT  : u32 = 7;
+const DSP_SCOUNT_MASK : u32 = 0x3f << DSP_BIT_SCOUNT;
+const DSP_BIT_C       : u32 = 13;
+const DSP_BIT_EFI     : u32 = 14;
+const DSP_BIT_OUFLAG  : u32 = 16;
+const DSP_OUFLAG_MASK : u32 = 0xff << DSP_BIT_OUFLAG;
+const DSP_BIT_CCOND   : u32 = 24;
+const DSP_CCOND_MASK  : u32 = 0xf << DSP_BIT_CCOND;
+
+// fields selected by the mask of rddsp and wrdsp
+const DSP_FIELDS : [u32; 6] = [DSP_POS_MASK, DSP_SCOUNT_MASK, 1<<DSP_BIT_C, DSP_OUFLAG_MASK, DSP_CCOND_MASK, 1<<DSP_BIT_EFI];
+
+// ouflag bits other than the accumulators
+const OU_ADD   : u32 = 20;
+const OU_MUL   : u32 = 21;
+const OU_SHIFT : u32 = 22;
+const OU_EXTR  : u32 = 23;
+
+// accumulator ac (0: HI/LO)
+pub fn acc(reg: &Reg, ac: usize) -> i64 {
+    (((hi(reg, ac) as u64) << 32) | lo(reg, ac) as u64) as i64
+}
+
+pub fn set_acc(reg: &mut Reg, ac: usize, v: i64) {
+    set_hi(reg, ac, (v >> 32) as u32);
+    set_lo(reg, ac, v as u32);
+}
+
+pub fn hi(reg: &Reg, ac: usize) -> u32 {
+    if ac == 0 { reg.hi }else{ reg.ac_hi[ac-1] }
+}
+
+pub fn lo(reg: &Reg, ac: usize) -> u32 {
+    if ac == 0 { reg.lo }else{ reg.ac_lo[ac-1] }
+}
+
+pub fn set_hi(reg: &mut Reg, ac: usize, v: u32) {
+    if ac == 0 { reg.hi = v; }else{ reg.ac_hi[ac-1] = v; }
+}
+
+pub fn set_lo(reg: &mut Reg, ac: usize, v: u32) {
+    if ac == 0 { reg.lo = v; }else{ reg.ac_lo[ac-1] = v; }
+}
+
+// raises the DSP State Disabled exception unless Status.MX is set. Returns true if enabled.
+fn check_enabled(ms: &mut MachineState) -> bool {
+    if 0 == (c0_val!(ms.reg, cp0def::C0_STATUS) & (1<<cp0def::C0_STATUS_BIT_MX)) {
+        exception::prepare_exception(ms, cp0def::EXCEPT_CODE_DSP, 0);
+        return false;
+    }
+    true
+}
+
+// checks the ac field of the base instructions (ac0 is always available). Returns true if available.
+pub fn check_acc(ms: &mut MachineState, ac: usize) -> bool {
+    ac == 0 || check_enabled(ms)
+}
+
+// unsigned byte, signed byte, unsigned halfword and signed halfword of the lane i
+fn ub(v: u32, i: u32) -> i64 { ((v >> (8*i)) & 0xff) as i64 }
+fn sb(v: u32, i: u32) -> i64 { ((v >> (8*i)) as u8 as i8) as i64 }
+fn uh(v: u32, i: u32) -> i64 { ((v >> (16*i)) & 0xffff) as i64 }
+fn sh(v: u32, i: u32) -> i64 { ((v >> (16*i)) as u16 as i16) as i64 }
+fn sw(v: u32) -> i64 { v as i32 as i64 }
+
+// packs the results of the lanes
+fn qb(mut f: impl FnMut(u32) -> i64) -> u32 {
+    (0..4).fold(0, |r, i| r | (((f(i) as u32) & 0xff) << (8*i)))
+}
+fn ph(mut f: impl FnMut(u32) -> i64) -> u32 {
+    (0..2).fold(0, |r, i| r | (((f(i) as u32) & 0xffff) << (16*i)))
+}
+
+// saturates v to a signed or unsigned value of bits, and sets the ouflag bit on saturation
+fn sat_s(v: i64, bits: u32, ou: &mut u32, flag: u32) -> i64 {
+    let max = (1i64 << (bits-1)) - 1;
+    let min = -(1i64 << (bits-1));
+    if v > max      { *ou |= 1<<flag; max }
+    else if v < min { *ou |= 1<<flag; min }
+    else            { v }
+}
+fn sat_u(v: i64, bits: u32, ou: &mut u32, flag: u32) -> i64 {
+    let max = (1i64 << bits) - 1;
+    if v > max    { *ou |= 1<<flag; max }
+    else if v < 0 { *ou |= 1<<flag; 0 }
+    else          { v }
+}
+
+// sets the ouflag bit if v overflows a signed or unsigned value of bits (the result wraps around)
+fn wrap_s(v: i64, bits: u32, ou: &mut u32, flag: u32) -> i64 {
+    if v != sat_s(v, bits, &mut 0, 0) { *ou |= 1<<flag; }
+    v
+}
+fn wrap_u(v: i64, bits: u32, ou: &mut u32, flag: u32) -> i64 {
+    if v != sat_u(v, bits, &mut 0, 0) { *ou |= 1<<flag; }
+    v
+}
+
+// Q15 x Q15 -> Q31 (0x8000 x 0x8000 saturates)
+fn mul_q15(a: i64, b: i64, ou: &mut u32, flag: u32) -> i64 {
+    if a == -0x8000 && b == -0x8000 {
+        *ou |= 1<<flag;
+        0x7fffffff
+    }else{
+        (a * b) << 1
+    }
+}
+
+// Q31 x Q31 -> Q63 (0x80000000 x 0x80000000 saturates)
+fn mul_q31(a: i64, b: i64, ou: &mut u32, flag: u32) -> i64 {
+    if a == i32::MIN as i64 && b == i32::MIN as i64 {
+        *ou |= 1<<flag;
+        i64::MAX
+    }else{
+        (a * b) << 1
+    }
+}
+
+// arithmetic right shift with rounding (adds 1 at the bit below the result)
+fn shr_round(v: i128, s: u32) -> i128 {
+    if s == 0 { v }else{ ((v >> (s-1)) + 1) >> 1 }
+}
+
+// mask of the ccond bits for the results of the lanes
+fn cond_mask(lanes: u32, f: impl Fn(u32) -> bool) -> u32 {
+    (0..lanes).fold(0, |m, i| m | ((f(i) as u32) << i))
+}
+
+fn set_ccond(reg: &mut Reg, lanes: u32, m: u32) {
+    let mask = ((1 << lanes) - 1) << DSP_BIT_CCOND;
+    reg.dspcontrol = (reg.dspcontrol & !mask) | (m << DSP_BIT_CCOND);
+}
+
+fn ccond(reg: &Reg, i: u32) -> bool {
+    0 != (reg.dspcontrol & (1 << (DSP_BIT_CCOND + i)))
+}
+
+// SPECIAL3 funct 0x10 : add, subtract and multiply of bytes, halfwords and words
+fn exec_addu_qb(reg: &mut Reg, inst: u32) -> bool {
+    let rs = ((inst>>21) & 0x1f) as usize;
+    let rt = ((inst>>16) & 0x1f) as usize;
+    let rd = ((inst>>11) & 0x1f) as usize;
+    let (x, y) = (reg.r[rs], reg.r[rt]);
+    let mut ou = 0;
+
+    reg.r[rd] = match (inst>>6) & 0x1f {
+        0x00 => qb(|i| wrap_u(ub(x,i) + ub(y,i), 8, &mut ou, OU_ADD)),      // addu.qb
+        0x01 => qb(|i| wrap_u(ub(x,i) - ub(y,i), 8, &mut ou, OU_ADD)),      // subu.qb
+        0x04 => qb(|i| sat_u(ub(x,i) + ub(y,i), 8, &mut ou, OU_ADD)),       // addu_s.qb
+        0x05 => qb(|i| sat_u(ub(x,i) - ub(y,i), 8, &mut ou, OU_ADD)),       // subu_s.qb
+        0x06 => ph(|i| sat_u(ub(x,i+2) * uh(y,i), 16, &mut ou, OU_MUL)),    // muleu_s.ph.qbl
+        0x07 => ph(|i| sat_u(ub(x,i) * uh(y,i), 16, &mut ou, OU_MUL)),      // muleu_s.ph.qbr
+        0x08 => ph(|i| wrap_u(uh(x,i) + uh(y,i), 16, &mut ou, OU_ADD)),     // addu.ph
+        0x09 => ph(|i| wrap_u(uh(x,i) - uh(y,i), 16, &mut ou, OU_ADD)),     // subu.ph
+        0x0a => ph(|i| wrap_s(sh(x,i) + sh(y,i), 16, &mut ou, OU_ADD)),     // addq.ph
+        0x0b => ph(|i| wrap_s(sh(x,i) - sh(y,i), 16, &mut ou, OU_ADD)),     // subq.ph
+        0x0c => ph(|i| sat_u(uh(x,i) + uh(y,i), 16, &mut ou, OU_ADD)),      // addu_s.ph
+        0x0d => ph(|i| sat_u(uh(x,i) - uh(y,i), 16, &mut ou, OU_ADD)),      // subu_s.ph
+        0x0e => ph(|i| sat_s(sh(x,i) + sh(y,i), 16, &mut ou, OU_ADD)),      // addq_s.ph
+        0x0f => ph(|i| sat_s(sh(x,i) - sh(y,i), 16, &mut ou, OU_ADD)),      // subq_s.ph
+        0x10 => {                                                           // addsc
+            let (v, carry) = x.overflowing_add(y);
+            reg.dspcontrol = (reg.dspcontrol & !(1<<DSP_BIT_C)) | ((carry as u32) << DSP_BIT_C);
+            v
+        }
+        0x11 => {                                                           // addwc
+            let c = ((reg.dspcontrol >> DSP_BIT_C) & 1) as i64;
+            wrap_s(sw(x) + sw(y) + c, 32, &mut ou, OU_ADD) as u32
+        }
+        0x12 => if x == 0 { (y >> 8) & 0xffff }else{ x.wrapping_sub(y & 0xff) }, // modsub
+        0x14 => (0..4).map(|i| ub(x,i) as u32).sum(),                           // raddu.w.qb
+        0x16 => sat_s(sw(x) + sw(y), 32, &mut ou, OU_ADD) as u32,               // addq_s.w
+        0x17 => sat_s(sw(x) - sw(y), 32, &mut ou, OU_ADD) as u32,               // subq_s.w
+        0x1c => mul_q15(sh(x,1), sh(y,1), &mut ou, OU_MUL) as u32,              // muleq_s.w.phl
+        0x1d => mul_q15(sh(x,0), sh(y,0), &mut ou, OU_MUL) as u32,              // muleq_s.w.phr
+        0x1e => ph(|i| mul_q15(sh(x,i), sh(y,i), &mut ou, OU_MUL) >> 16),       // mulq_s.ph
+        0x1f => ph(|i| {                                                        // mulq_rs.ph
+            if sh(x,i) == -0x8000 && sh(y,i) == -0x8000 {
+                ou |= 1<<OU_MUL;
+                0x7fff
+            }else{
+                (((sh(x,i) * sh(y,i)) << 1) + 0x8000) >> 16
+            }
+        }),
+        _ => { unknown_instruction!(inst, "op=0x1f, funct=0x10"); }
+    };
+    reg.dspcontrol |= ou;
+    true
+}
+
+// SPECIAL3 funct 0x11 : compare, pick, pack and precision reduction
+fn exec_cmpu_eq_qb(reg: &mut Reg, inst: u32) -> bool {
+    let rs = ((inst>>21) & 0x1f) as usize;
+    let rt = ((inst>>16) & 0x1f) as usize;
+    let rd = ((inst>>11) & 0x1f) as usize;
+    let sa = (inst>>11) & 0x1f;
+    let (x, y) = (reg.r[rs], reg.r[rt]);
+    let mut ou = 0;
+
+    // Q15 to an unsigned byte with saturation
+    let sat_qb = |h: i64, ou: &mut u32| -> i64 {
+        if h < 0           { *ou |= 1<<OU_SHIFT; 0 }
+        else if h > 0x7f80 { *ou |= 1<<OU_SHIFT; 0xff }
+        else               { h >> 7 }
+    };
+    // rounds a Q31 to Q15 with saturation
+    let round_ph = |v: u32, ou: &mut u32| -> u32 {
+        if sw(v) > 0x7fff7fff { *ou |= 1<<OU_SHIFT; 0x7fff }else{ ((sw(v) + 0x8000) >> 16) as u32 & 0xffff }
+    };
+
+    match (inst>>6) & 0x1f {
+        0x00 => { let m = cond_mask(4, |i| ub(x,i) == ub(y,i)); set_ccond(reg, 4, m); }          // cmpu.eq.qb
+        0x01 => { let m = cond_mask(4, |i| ub(x,i) <  ub(y,i)); set_ccond(reg, 4, m); }          // cmpu.lt.qb
+        0x02 => { let m = cond_mask(4, |i| ub(x,i) <= ub(y,i)); set_ccond(reg, 4, m); }          // cmpu.le.qb
+        0x03 => { reg.r[rd] = qb(|i| if ccond(reg, i) { ub(x,i) }else{ ub(y,i) }); }             // pick.qb
+        0x04 => { reg.r[rd] = cond_mask(4, |i| ub(x,i) == ub(y,i)); }                             // cmpgu.eq.qb
+        0x05 => { reg.r[rd] = cond_mask(4, |i| ub(x,i) <  ub(y,i)); }                             // cmpgu.lt.qb
+        0x06 => { reg.r[rd] = cond_mask(4, |i| ub(x,i) <= ub(y,i)); }                             // cmpgu.le.qb
+        0x08 => { let m = cond_mask(2, |i| sh(x,i) == sh(y,i)); set_ccond(reg, 2, m); }          // cmp.eq.ph
+        0x09 => { let m = cond_mask(2, |i| sh(x,i) <  sh(y,i)); set_ccond(reg, 2, m); }          // cmp.lt.ph
+        0x0a => { let m = cond_mask(2, |i| sh(x,i) <= sh(y,i)); set_ccond(reg, 2, m); }          // cmp.le.ph
+        0x0b => { reg.r[rd] = ph(|i| if ccond(reg, i) { uh(x,i) }else{ uh(y,i) }); }             // pick.ph
+        0x0c => { reg.r[rd] = qb(|i| if i < 2 { ub(y, 2*i+1) }else{ ub(x, 2*i-3) }); }           // precrq.qb.ph
+        0x0d => { reg.r[rd] = qb(|i| if i < 2 { ub(y, 2*i) }else{ ub(x, 2*i-4) }); }             // precr.qb.ph
+        0x0e => { reg.r[rd] = (x << 16) | (y >> 16); }                                            // packrl.ph
+        0x0f => { reg.r[rd] = qb(|i| if i < 2 { sat_qb(sh(y,i), &mut ou) }else{ sat_qb(sh(x,i-2), &mut ou) }); } // precrqu_s.qb.ph
+        0x14 => { reg.r[rd] = (x & 0xffff0000) | (y >> 16); }                                     // precrq.ph.w
+        0x15 => { reg.r[rd] = (round_ph(x, &mut ou) << 16) | round_ph(y, &mut ou); }              // precrq_rs.ph.w
+        0x18 => { let m = cond_mask(4, |i| ub(x,i) == ub(y,i)); set_ccond(reg, 4, m); reg.r[rd] = m; } // cmpgdu.eq.qb
+        0x19 => { let m = cond_mask(4, |i| ub(x,i) <  ub(y,i)); set_ccond(reg, 4, m); reg.r[rd] = m; } // cmpgdu.lt.qb
+        0x1a => { let m = cond_mask(4, |i| ub(x,i) <= ub(y,i)); set_ccond(reg, 4, m); reg.r[rd] = m; } // cmpgdu.le.qb
+        0x1e => { reg.r[rt] = ((((sw(y) >> sa) as u32) & 0xffff) << 16) | (((sw(x) >> sa) as u32) & 0xffff); } // precr_sra.ph.w
+        0x1f => {                                                                                 // precr_sra_r.ph.w
+            let r = |v: u32| (shr_round(sw(v) as i128, sa) as u32) & 0xffff;
+            reg.r[rt] = (r(y) << 16) | r(x);
+        }
+        _ => { unknown_instruction!(inst, "op=0x1f, funct=0x11"); }
+    }
+    reg.dspcontrol |= ou;
+    true
+}
+
+// SPECIAL3 funct 0x12 : absolute values, replication and precision expansion
+fn exec_absq_s_ph(reg: &mut Reg, inst: u32) -> bool {
+    let rt = ((inst>>16) & 0x1f) as usize;
+    let rd = ((inst>>11) & 0x1f) as usize;
+    let y  = reg.r[rt];
+    let mut ou = 0;
+
+    let abs = |v: i64, bits: u32, ou: &mut u32| sat_s(v.abs(), bits, ou, OU_ADD);
+
+    reg.r[rd] = match (inst>>6) & 0x1f {
+        0x01 => qb(|i| abs(sb(y,i), 8, &mut ou)),                           // absq_s.qb
+        0x02 => ((inst>>16) & 0xff) * 0x01010101,                           // repl.qb
+        0x03 => (y & 0xff) * 0x01010101,                                    // replv.qb
+        0x04 => ph(|i| ub(y, i+2) << 7),                                    // precequ.ph.qbl
+        0x05 => ph(|i| ub(y, i) << 7),                                      // precequ.ph.qbr
+        0x06 => ph(|i| ub(y, 2*i+1) << 7),                                  // precequ.ph.qbla
+        0x07 => ph(|i| ub(y, 2*i) << 7),                                    // precequ.ph.qbra
+        0x09 => ph(|i| abs(sh(y,i), 16, &mut ou)),                          // absq_s.ph
+        0x0a => (((((inst>>16) & 0x3ff) << 22) as i32 >> 22) as u32 & 0xffff) * 0x00010001, // repl.ph
+        0x0b => (y & 0xffff) * 0x00010001,                                  // replv.ph
+        0x0c => y & 0xffff0000,                                             // preceq.w.phl
+        0x0d => y << 16,                                                    // preceq.w.phr
+        0x11 => abs(sw(y), 32, &mut ou) as u32,                             // absq_s.w
+        0x1b => (y as u16).reverse_bits() as u32,                           // bitrev
+        0x1c => ph(|i| ub(y, i+2)),                                         // preceu.ph.qbl
+        0x1d => ph(|i| ub(y, i)),                                           // preceu.ph.qbr
+        0x1e => ph(|i| ub(y, 2*i+1)),                                       // preceu.ph.qbla
+        0x1f => ph(|i| ub(y, 2*i)),                                         // preceu.ph.qbra
+        _ => { unknown_instruction!(inst, "op=0x1f, funct=0x12"); }
+    };
+    reg.dspcontrol |= ou;
+    true
+}
+
+// SPECIAL3 funct 0x13 : shifts of bytes, halfwords and words
+fn exec_shll_qb(reg: &mut Reg, inst: u32) -> bool {
+    let rs = ((inst>>21) & 0x1f) as usize;
+    let rt = ((inst>>16) & 0x1f) as usize;
+    let rd = ((inst>>11) & 0x1f) as usize;
+    let op = (inst>>6) & 0x1f;
+    let y  = reg.r[rt];
+    let mut ou = 0;
+
+    // the shift amount is in the rs field, or in rs for the "v" variants
+    let s = if 0 != (op & 0x02) { reg.r[rs] }else{ rs as u32 };
+    let (s3, s4, s5) = (s & 7, s & 0xf, s & 0x1f);
+
+    reg.r[rd] = match op {
+        0x00 | 0x02 => qb(|i| wrap_u(ub(y,i) << s3, 8, &mut ou, OU_SHIFT)),     // shll.qb, shllv.qb
+        0x01 | 0x03 => qb(|i| ub(y,i) >> s3),                                   // shrl.qb, shrlv.qb
+        0x04 | 0x06 => qb(|i| sb(y,i) >> s3),                                   // shra.qb, shrav.qb
+        0x05 | 0x07 => qb(|i| shr_round(sb(y,i) as i128, s3) as i64),          // shra_r.qb, shrav_r.qb
+        0x08 | 0x0a => ph(|i| wrap_s(sh(y,i) << s4, 16, &mut ou, OU_SHIFT)),    // shll.ph, shllv.ph
+        0x09 | 0x0b => ph(|i| sh(y,i) >> s4),                                   // shra.ph, shrav.ph
+        0x0c | 0x0e => ph(|i| sat_s(sh(y,i) << s4, 16, &mut ou, OU_SHIFT)),     // shll_s.ph, shllv_s.ph
+        0x0d | 0x0f => ph(|i| shr_round(sh(y,i) as i128, s4) as i64),          // shra_r.ph, shrav_r.ph
+        0x14 | 0x16 => sat_s(sw(y) << s5, 32, &mut ou, OU_SHIFT) as u32,        // shll_s.w, shllv_s.w
+        0x15 | 0x17 => shr_round(sw(y) as i128, s5) as u32,                     // shra_r.w, shrav_r.w
+        0x19 | 0x1b => ph(|i| uh(y,i) >> s4),                                   // shrl.ph, shrlv.ph
+        _ => { unknown_instruction!(inst, "op=0x1f, funct=0x13"); }
+    };
+    reg.dspcontrol |= ou;
+    true
+}
+
+// SPECIAL3 funct 0x18 : halving add and subtract, and multiply (DSP ASE r2)
+fn exec_adduh_qb(reg: &mut Reg, inst: u32) -> bool {
+    let rs = ((inst>>21) & 0x1f) as usize;
+    let rt = ((inst>>16) & 0x1f) as usize;
+    let rd = ((inst>>11) & 0x1f) as usize;
+    let (x, y) = (reg.r[rs], reg.r[rt]);
+    let mut ou = 0;
+
+    reg.r[rd] = match (inst>>6) & 0x1f {
+        0x00 => qb(|i| (ub(x,i) + ub(y,i)) >> 1),                           // adduh.qb
+        0x01 => qb(|i| (ub(x,i) - ub(y,i)) >> 1),                           // subuh.qb
+        0x02 => qb(|i| (ub(x,i) + ub(y,i) + 1) >> 1),                       // adduh_r.qb
+        0x03 => qb(|i| (ub(x,i) - ub(y,i) + 1) >> 1),                       // subuh_r.qb
+        0x08 => ph(|i| (sh(x,i) + sh(y,i)) >> 1),                           // addqh.ph
+        0x09 => ph(|i| (sh(x,i) - sh(y,i)) >> 1),                           // subqh.ph
+        0x0a => ph(|i| (sh(x,i) + sh(y,i) + 1) >> 1),                       // addqh_r.ph
+        0x0b => ph(|i| (sh(x,i) - sh(y,i) + 1) >> 1),                       // subqh_r.ph
+        0x0c => ph(|i| wrap_s(sh(x,i) * sh(y,i), 16, &mut ou, OU_MUL)),     // mul.ph
+        0x0e => ph(|i| sat_s(sh(x,i) * sh(y,i), 16, &mut ou, OU_MUL)),      // mul_s.ph
+        0x10 => ((sw(x) + sw(y)) >> 1) as u32,                              // addqh.w
+        0x11 => ((sw(x) - sw(y)) >> 1) as u32,                              // subqh.w
+        0x12 => ((sw(x) + sw(y) + 1) >> 1) as u32,                          // addqh_r.w
+        0x13 => ((sw(x) - sw(y) + 1) >> 1) as u32,                          // subqh_r.w
+        0x16 => (mul_q31(sw(x), sw(y), &mut ou, OU_MUL) >> 32) as u32,      // mulq_s.w
+        0x17 => {                                                           // mulq_rs.w
+            if x == 0x80000000 && y == 0x80000000 {
+                ou |= 1<<OU_MUL;
+                0x7fffffff
+            }else{
+                ((((sw(x) * sw(y)) << 1) + 0x80000000) >> 32) as u32
+            }
+        }
+        _ => { unknown_instruction!(inst, "op=0x1f, funct=0x18"); }
+    };
+    reg.dspcontrol |= ou;
+    true
+}
+
+// SPECIAL3 funct 0x30 : dot products and multiply-accumulate to ac
+fn exec_dpa_w_ph(reg: &mut Reg, inst: u32) -> bool {
+    let rs = ((inst>>21) & 0x1f) as usize;
+    let rt = ((inst>>16) & 0x1f) as usize;
+    let ac = ((inst>>11) & 3) as usize;
+    let (x, y) = (reg.r[rs], reg.r[rt]);
+    let a  = acc(reg, ac);
+    let fl = DSP_BIT_OUFLAG + ac as u32;
+    let mut ou = 0;
+
+    // saturates the result to Q31 (maq_sa, dpaqx_sa and dpsqx_sa)
+    let sat32 = |v: i64, ou: &mut u32| sat_s(v, 32, ou, fl);
+    // adds to the accumulator with saturation of 64 bits (dpaq_sa.l.w and dpsq_sa.l.w)
+    let sat64 = |v: i64, p: i64, ou: &mut u32| {
+        v.checked_add(p).unwrap_or_else(|| { *ou |= 1<<fl; if p > 0 { i64::MAX }else{ i64::MIN } })
+    };
+
+    let v = match (inst>>6) & 0x1f {
+        0x00 => a.wrapping_add(sh(x,1)*sh(y,1) + sh(x,0)*sh(y,0)),          // dpa.w.ph
+        0x01 => a.wrapping_sub(sh(x,1)*sh(y,1) + sh(x,0)*sh(y,0)),          // dps.w.ph
+        0x02 => a.wrapping_add(sh(x,1)*sh(y,1) - sh(x,0)*sh(y,0)),          // mulsa.w.ph
+        0x03 => a.wrapping_add(ub(x,3)*ub(y,3) + ub(x,2)*ub(y,2)),          // dpau.h.qbl
+        0x04 => a.wrapping_add(mul_q15(sh(x,1), sh(y,1), &mut ou, fl) + mul_q15(sh(x,0), sh(y,0), &mut ou, fl)), // dpaq_s.w.ph
+        0x05 => a.wrapping_sub(mul_q15(sh(x,1), sh(y,1), &mut ou, fl) + mul_q15(sh(x,0), sh(y,0), &mut ou, fl)), // dpsq_s.w.ph
+        0x06 => a.wrapping_add(mul_q15(sh(x,1), sh(y,1), &mut ou, fl) - mul_q15(sh(x,0), sh(y,0), &mut ou, fl)), // mulsaq_s.w.ph
+        0x07 => a.wrapping_add(ub(x,1)*ub(y,1) + ub(x,0)*ub(y,0)),          // dpau.h.qbr
+        0x08 => a.wrapping_add(sh(x,1)*sh(y,0) + sh(x,0)*sh(y,1)),          // dpax.w.ph
+        0x09 => a.wrapping_sub(sh(x,1)*sh(y,0) + sh(x,0)*sh(y,1)),          // dpsx.w.ph
+        0x0b => a.wrapping_sub(ub(x,3)*ub(y,3) + ub(x,2)*ub(y,2)),          // dpsu.h.qbl
+        0x0c => { let p = mul_q31(sw(x), sw(y), &mut ou, fl); sat64(a, p, &mut ou) }     // dpaq_sa.l.w
+        0x0d => {                                                                         // dpsq_sa.l.w
+            let p = mul_q31(sw(x), sw(y), &mut ou, fl);
+            a.checked_sub(p).unwrap_or_else(|| { ou |= 1<<fl; if p > 0 { i64::MIN }else{ i64::MAX } })
+        }
+        0x0f => a.wrapping_sub(ub(x,1)*ub(y,1) + ub(x,0)*ub(y,0)),          // dpsu.h.qbr
+        0x10 => { let p = mul_q15(sh(x,1), sh(y,1), &mut ou, fl); sat32(a.saturating_add(p), &mut ou) } // maq_sa.w.phl
+        0x12 => { let p = mul_q15(sh(x,0), sh(y,0), &mut ou, fl); sat32(a.saturating_add(p), &mut ou) } // maq_sa.w.phr
+        0x14 => a.wrapping_add(mul_q15(sh(x,1), sh(y,1), &mut ou, fl)),     // maq_s.w.phl
+        0x16 => a.wrapping_add(mul_q15(sh(x,0), sh(y,0), &mut ou, fl)),     // maq_s.w.phr
+        0x18 => a.wrapping_add(mul_q15(sh(x,1), sh(y,0), &mut ou, fl) + mul_q15(sh(x,0), sh(y,1), &mut ou, fl)), // dpaqx_s.w.ph
+        0x19 => a.wrapping_sub(mul_q15(sh(x,1), sh(y,0), &mut ou, fl) + mul_q15(sh(x,0), sh(y,1), &mut ou, fl)), // dpsqx_s.w.ph
+        0x1a => {                                                                                             // dpaqx_sa.w.ph
+            let p = mul_q15(sh(x,1), sh(y,0), &mut ou, fl) + mul_q15(sh(x,0), sh(y,1), &mut ou, fl);
+            sat32(a.saturating_add(p), &mut ou)
+        }
+        0x1b => {                                                                                             // dpsqx_sa.w.ph
+            let p = mul_q15(sh(x,1), sh(y,0), &mut ou, fl) + mul_q15(sh(x,0), sh(y,1), &mut ou, fl);
+            sat32(a.saturating_sub(p), &mut ou)
+        }
+        _ => { unknown_instruction!(inst, "op=0x1f, funct=0x30"); }
+    };
+    set_acc(reg, ac, v);
+    reg.dspcontrol |= ou;
+    true
+}
+
+// SPECIAL3 funct 0x31 : append, prepend and balign (DSP ASE r2)
+fn exec_append(reg: &mut Reg, inst: u32) -> bool {
+    let rs = ((inst>>21) & 0x1f) as usize;
+    let rt = ((inst>>16) & 0x1f) as usize;
+    let sa = (inst>>11) & 0x1f;
+    let (x, y) = (reg.r[rs], reg.r[rt]);
+
+    reg.r[rt] = match (inst>>6) & 0x1f {
+        0x00 if sa == 0 => y,
+        0x00 => (y << sa) | (x & ((1 << sa) - 1)),              // append
+        0x01 if sa == 0 => y,
+        0x01 => (x << (32 - sa)) | (y >> sa),                   // prepend
+        0x10 => {                                               // balign
+            let bp = sa & 3;
+            if bp == 0 { y }else{ (y << (8*bp)) | (x >> (32 - 8*bp)) }
+        }
+        _ => { unknown_instruction!(inst, "op=0x1f, funct=0x31"); }
+    };
+    true
+}
+
+// SPECIAL3 funct 0x38 : extraction from ac, accumulator shifts and DSPControl access
+fn exec_extr_w(reg: &mut Reg, inst: u32) -> bool {
+    let rs = ((inst>>21) & 0x1f) as usize;
+    let rt = ((inst>>16) & 0x1f) as usize;
+    let rd = ((inst>>11) & 0x1f) as usize;
+    let ac = ((inst>>11) & 3) as usize;
+    let op = (inst>>6) & 0x1f;
+    let a  = acc(reg, ac);
+    let mut ou = 0;
+
+    // the shift amount or size is in the rs field, or in rs for the "v" variants
+    let s = if 0 != (op & 1) { reg.r[rs] & 0x1f }else{ rs as u32 };
+    let fits32 = |v: i128| v == v as i32 as i128;
+
+    match op {
+        0x00 | 0x01 => {                                                    // extr.w, extrv.w
+            let v = (a >> s) as i128;
+            if !fits32(v) { ou |= 1<<OU_EXTR; }
+            reg.r[rt] = v as u32;
+        }
+        0x04..=0x07 => {                                                    // extr_r.w, extrv_r.w, extr_rs.w, extrv_rs.w
+            let t = (a >> s) as i128;
+            let v = shr_round(a as i128, s);
+            let overflow = !fits32(t) || !fits32(v);
+            if overflow { ou |= 1<<OU_EXTR; }
+            reg.r[rt] = if overflow && op >= 0x06 {
+                if v < 0 { 0x80000000 }else{ 0x7fffffff }
+            }else{
+                v as u32
+            };
+        }
+        0x0e | 0x0f => {                                                    // extr_s.h, extrv_s.h
+            reg.r[rt] = sat_s(a >> s, 16, &mut ou, OU_EXTR) as u32;
+        }
+        0x02 | 0x03 | 0x0a | 0x0b => {                                      // extp, extpv, extpdp, extpdpv
+            let pos = reg.dspcontrol & DSP_POS_MASK;
+            if pos >= s {
+                reg.r[rt] = (((a as u64) >> (pos - s)) & ((2u64 << s) - 1)) as u32;
+                reg.dspcontrol &= !(1<<DSP_BIT_EFI);
+                if op >= 0x0a {
+                    reg.dspcontrol = (reg.dspcontrol & !DSP_POS_MASK) | (pos.wrapping_sub(s + 1) & DSP_POS_MASK);
+                }
+            }else{
+                reg.dspcontrol |= 1<<DSP_BIT_EFI;
+            }
+        }
+        0x12 => {                                                           // rddsp
+            let mask = field_mask((inst>>16) & 0x3ff);
+            reg.r[rd] = reg.dspcontrol & mask;
+        }
+        0x13 => {                                                           // wrdsp
+            let mask = field_mask((inst>>11) & 0x3ff);
+            reg.dspcontrol = (reg.dspcontrol & !mask) | (reg.r[rs] & mask);
+        }
+        0x1a | 0x1b => {                                                    // shilo, shilov
+            let shift = if op == 0x1b { reg.r[rs] } else { inst >> 20 };
+            let shift = ((shift << 26) as i32) >> 26;
+            let v = if shift >= 0 { (a as u64) >> shift }else{ (a as u64) << -shift };
+            set_acc(reg, ac, v as i64);
+        }
+        0x1f => {                                                           // mthlip
+            let (l, v) = (lo(reg, ac), reg.r[rs]);
+            set_hi(reg, ac, l);
+            set_lo(reg, ac, v);
+            let pos = reg.dspcontrol & DSP_POS_MASK;
+            reg.dspcontrol = (reg.dspcontrol & !DSP_POS_MASK) | ((pos + 32) & DSP_POS_MASK);
+        }
+        _ => { unknown_instruction!(inst, "op=0x1f, funct=0x38"); }
+    }
+    reg.dspcontrol |= ou;
+    true
+}
+
+// DSPControl bits selected by the mask of rddsp and wrdsp
+fn field_mask(mask: u32) -> u32 {
+    DSP_FIELDS.iter().enumerate().filter(|(i, _)| 0 != (mask & (1<<i))).fold(0, |m, (_, f)| m | f)
+}
+
+// insv : inserts rs[scount-1:0] into rt at pos
+fn exec_insv(reg: &mut Reg, inst: u32) -> bool {
+    let rs = ((inst>>21) & 0x1f) as usize;
+    let rt = ((inst>>16) & 0x1f) as usize;
+    if 0 != (inst & (0x3ff<<6)) {
+        unknown_instruction!(inst, "op=0x1f, funct=0x0c");
+    }
+    let pos  = reg.dspcontrol & DSP_POS_MASK;
+    let size = (reg.dspcontrol & DSP_SCOUNT_MASK) >> DSP_BIT_SCOUNT;
+    let mask = ((((1u64 << size) - 1) << pos) & 0xffffffff) as u32;
+    reg.r[rt] = (reg.r[rt] & !mask) | ((((reg.r[rs] as u64) << pos) as u32) & mask);
+    true
+}
+
+// lwx, lhx and lbux : indexed loads
+fn exec_lx(ms: &mut MachineState, inst: u32) -> bool {
+    let base  = ((inst>>21) & 0x1f) as usize;
+    let index = ((inst>>16) & 0x1f) as usize;
+    let rd    = ((inst>>11) & 0x1f) as usize;
+    let addr  = ms.reg.r[base].wrapping_add(ms.reg.r[index]);
+
+    let res = match (inst>>6) & 0x1f {
+        0x00 => mem::load_word(ms, addr),                                               // lwx
+        0x04 => mem::load_halfword(ms, addr).map(|v| sign_ext16!(v)),                   // lhx
+        0x06 => mem::load_byte(ms, addr),                                               // lbux
+        _ => { unknown_instruction!(inst, "op=0x1f, funct=0x0a"); }
+    };
+    match res {
+        Ok(v)      => { ms.reg.r[rd] = v; update_pc_next32!(ms); }
+        Err(ecode) => { exception::prepare_exception(ms, ecode, addr); }
+    }
+    true
+}
+
+// SPECIAL3 instructions of DSP ASE (funct 0x0a, 0x0c, 0x10..0x13, 0x18, 0x30, 0x31 and 0x38)
+pub fn exec(ms: &mut MachineState, inst: u32) -> bool {
+    if ms.emu.debug { info!("{}", disasm::disasm_mips32(ms.reg.pc, inst)); }
+    if !check_enabled(ms) {
+        return true;
+    }
+
+    let ok = match inst & 0x3f {
+        0x0a => { return exec_lx(ms, inst); }
+        0x0c => exec_insv(&mut ms.reg, inst),
+        0x10 => exec_addu_qb(&mut ms.reg, inst),
+        0x11 => exec_cmpu_eq_qb(&mut ms.reg, inst),
+        0x12 => exec_absq_s_ph(&mut ms.reg, inst),
+        0x13 => exec_shll_qb(&mut ms.reg, inst),
+        0x18 => exec_adduh_qb(&mut ms.reg, inst),
+        0x30 => exec_dpa_w_ph(&mut ms.reg, inst),
+        0x31 => exec_append(&mut ms.reg, inst),
+        0x38 => exec_extr_w(&mut ms.reg, inst),
+        _ => { unknown_instruction!(inst, "op=0x1f, DSP ASE"); }
+    };
+    if ok {
+        update_pc_next32!(ms);
+    }
+    ok
+}
+
+// bposge32 (REGIMM rt=0x1c) : branches if DSPControl.pos >= 32
+pub fn exec_bposge32(ms: &mut MachineState, inst: u32) -> bool {
+    if ms.emu.debug { info!("{}", disasm::disasm_mips32(ms.reg.pc, inst)); }
+    if !check_enabled(ms) {
+        return true;
+    }
+    if (ms.reg.dspcontrol & DSP_POS_MASK) >= 32 {
+        update_pc_next32_with_delayed_imm!(ms, ms.reg.pc + (sign_ext16!(inst & 0xffff) << 2) + 4 );
+    }else{
+        update_pc_next32!(ms);
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // executes an instruction of the functions taking only the registers (as exec does)
+    fn run(reg: &mut Reg, inst: u32) {
+        let ok = match inst & 0x3f {
+            0x0c => exec_insv(reg, inst),
+            0x10 => exec_addu_qb(reg, inst),
+            0x11 => exec_cmpu_eq_qb(reg, inst),
+            0x12 => exec_absq_s_ph(reg, inst),
+            0x13 => exec_shll_qb(reg, inst),
+            0x18 => exec_adduh_qb(reg, inst),
+            0x30 => exec_dpa_w_ph(reg, inst),
+            0x31 => exec_append(reg, inst),
+            0x38 => exec_extr_w(reg, inst),
+            _    => false,
+        };
+        assert!(ok, "inst {:08x}", inst);
+    }
+
+    // rd = $3, rs = $4, rt = $5 ; returns $3 and ouflag
+    fn op(inst: u32, rs: u32, rt: u32) -> (u32, u32) {
+        let mut reg = Reg::new();
+        reg.r[4] = rs;
+        reg.r[5] = rt;
+        run(&mut reg, inst);
+        (reg.r[3], (reg.dspcontrol & DSP_OUFLAG_MASK) >> DSP_BIT_OUFLAG)
+    }
+
+    const FLAG_AC1 : u32 = 1<<1;
+    const FLAG_ADD : u32 = 1<<(OU_ADD - DSP_BIT_OUFLAG);
+    const FLAG_MUL : u32 = 1<<(OU_MUL - DSP_BIT_OUFLAG);
+    const FLAG_SFT : u32 = 1<<(OU_SHIFT - DSP_BIT_OUFLAG);
+    const FLAG_EXT : u32 = 1<<(OU_EXTR - DSP_BIT_OUFLAG);
+
+    #[test]
+    fn add_saturates_or_wraps_and_sets_ouflag() {
+        assert_eq!(op(0x7c851910, 0x80ff7f01, 0x80017f01), (0xfffffe02, FLAG_ADD)); // addu_s.qb
+        assert_eq!(op(0x7c851810, 0x80ff7f01, 0x80017f01), (0x0000fe02, FLAG_ADD)); // addu.qb
+        assert_eq!(op(0x7c851810, 0x01020304, 0x01010101), (0x02030405, 0));        // addu.qb
+        assert_eq!(op(0x7c851b90, 0x7fff8000, 0x00018000), (0x7fff8000, FLAG_ADD)); // addq_s.ph
+        assert_eq!(op(0x7c851bd0, 0x00050003, 0x00020001), (0x00030002, 0));        // subq_s.ph
+        assert_eq!(op(0x7c041a52, 0x8000fffe, 0), (0x7fff0002, FLAG_ADD));          // absq_s.ph
+        assert_eq!(op(0x7c851898, 0xff010203, 0x01010204), (0x80010204, 0));        // adduh_r.qb
+        assert_eq!(op(0x7c801d10, 0xffffffff, 0), (0x3fc, 0));                      // raddu.w.qb
+    }
+
+    #[test]
+    fn carry_of_addsc_is_used_by_addwc() {
+        let mut reg = Reg::new();
+        reg.r[4] = 0xffffffff;
+        reg.r[5] = 1;
+        run(&mut reg, 0x7c851c10);                                                 // addsc
+        assert_eq!(reg.r[3], 0);
+        assert_ne!(0, reg.dspcontrol & (1<<DSP_BIT_C));
+        reg.r[4] = 1;
+        reg.r[5] = 2;
+        run(&mut reg, 0x7c851c50);                                                 // addwc
+        assert_eq!(reg.r[3], 4);
+        assert_eq!(0, reg.dspcontrol & DSP_OUFLAG_MASK);
+    }
+
+    #[test]
+    fn fractional_multiplications_saturate() {
+        assert_eq!(op(0x7c851f10, 0x80000000, 0x80000000), (0x7fffffff, FLAG_MUL)); // muleq_s.w.phl
+        assert_eq!(op(0x7c851f10, 0x40000000, 0x40000000), (0x20000000, 0));        // muleq_s.w.phl
+        assert_eq!(op(0x7c8519d0, 0x000000ff, 0x00000200), (0x0000ffff, FLAG_MUL)); // muleu_s.ph.qbr
+        assert_eq!(op(0x7c851dd8, 0x80000000, 0x80000000), (0x7fffffff, FLAG_MUL)); // mulq_rs.w
+    }
+
+    #[test]
+    fn dot_products_accumulate_with_saturation() {
+        let mut reg = Reg::new();
+        reg.r[4] = 0x80004000;
+        reg.r[5] = 0x80004000;
+        run(&mut reg, 0x7c850930);                                                 // dpaq_s.w.ph $ac1
+        assert_eq!(acc(&reg, 1), 0x9fffffff);
+        assert_eq!((reg.dspcontrol & DSP_OUFLAG_MASK) >> DSP_BIT_OUFLAG, FLAG_AC1);
+
+        let mut reg = Reg::new();
+        set_acc(&mut reg, 1, 0x7fffffffffffff00);
+        reg.r[4] = 0x10000;
+        reg.r[5] = 1;
+        run(&mut reg, 0x7c850b30);                                                 // dpaq_sa.l.w $ac1
+        assert_eq!(acc(&reg, 1), i64::MAX);
+        assert_eq!((reg.dspcontrol & DSP_OUFLAG_MASK) >> DSP_BIT_OUFLAG, FLAG_AC1);
+        assert_eq!(acc(&reg, 0), 0);
+    }
+
+    #[test]
+    fn extraction_from_accumulators() {
+        let mut reg = Reg::new();
+        set_acc(&mut reg, 1, 0x128);
+        run(&mut reg, 0x7c830838);                                                 // extr.w $3, $ac1, 4
+        assert_eq!(reg.r[3], 0x12);
+        run(&mut reg, 0x7c830938);                                                 // extr_r.w
+        assert_eq!(reg.r[3], 0x13);
+        assert_eq!(0, reg.dspcontrol & DSP_OUFLAG_MASK);
+
+        set_acc(&mut reg, 1, 0x7fffffff00000000);
+        run(&mut reg, 0x7c8309b8);                                                 // extr_rs.w
+        assert_eq!(reg.r[3], 0x7fffffff);
+        assert_eq!((reg.dspcontrol & DSP_OUFLAG_MASK) >> DSP_BIT_OUFLAG, FLAG_EXT);
+
+        let mut reg = Reg::new();
+        set_acc(&mut reg, 1, 0xab00);
+        reg.dspcontrol = 15;
+        run(&mut reg, 0x7ce308b8);                                                 // extp $3, $ac1, 7
+        assert_eq!(reg.r[3], 0xab);
+        assert_eq!(0, reg.dspcontrol & (1<<DSP_BIT_EFI));
+        assert_eq!(reg.dspcontrol & DSP_POS_MASK, 15);
+    }
+
+    #[test]
+    fn comparisons_set_ccond_for_pick() {
+        let mut reg = Reg::new();
+        reg.r[4] = 0x01020304;
+        reg.r[5] = 0x01ff03ff;
+        run(&mut reg, 0x7c850011);                                                 // cmpu.eq.qb
+        assert_eq!(reg.dspcontrol & DSP_CCOND_MASK, 0b1010 << DSP_BIT_CCOND);
+        run(&mut reg, 0x7c851951);                                                 // cmpgu.lt.qb
+        assert_eq!(reg.r[3], 0b0101);
+        reg.r[4] = 0x11223344;
+        reg.r[5] = 0x55667788;
+        run(&mut reg, 0x7c8518d1);                                                 // pick.qb
+        assert_eq!(reg.r[3], 0x11663388);
+    }
+
+    #[test]
+    fn shifts_saturate_and_round() {
+        assert_eq!(op(0x7c841b13, 0x10000100, 0), (0x7fff1000, FLAG_SFT));          // shll_s.ph
+        assert_eq!(op(0x7c841d53, 0xffffffe8, 0), (0xffffffff, 0));                 // shra_r.w
+        assert_eq!(op(0x7c851d51, 0x7fffffff, 0x12345678), (0x7fff1234, FLAG_SFT)); // precrq_rs.ph.w
+    }
+
+    #[test]
+    fn dspcontrol_fields() {
+        let mut reg = Reg::new();
+        reg.r[4] = 0xffffffff;
+        run(&mut reg, 0x7c800cf8);                                                 // wrdsp $4, 1 (pos)
+        assert_eq!(reg.dspcontrol, DSP_POS_MASK);
+        run(&mut reg, 0x7c3f1cb8);                                                 // rddsp $3, 0x3f
+        assert_eq!(reg.r[3], DSP_POS_MASK);
+
+        reg.dspcontrol = 8 | (4 << DSP_BIT_SCOUNT);
+        reg.r[3] = 0xffffffff;
+        reg.r[4] = 0;
+        run(&mut reg, 0x7c83000c);                                                 // insv $3, $4
+        assert_eq!(reg.r[3], 0xfffff0ff);
+
+        reg.r[3] = 0x11223344;
+        reg.r[4] = 0xaabbccdd;
+        run(&mut reg, 0x7c834031);                                                 // append $3, $4, 8
+        assert_eq!(reg.r[3], 0x223344dd);
+    }
+}
@@ -0,0 +1,319 @@
+use crate::procstate::MachineState;
+use crate::config;
+use crate::cp0def;
+use crate::mips;
+use crate::kseg01_to_paddr;
+use crate::c0_val;
+use log::info;
+
+/*
+Loader of ELF32 big-endian MIPS executables (bare-metal programs and vmlinux).
+
+PT_LOAD segments are copied into DRAM. The load address of a segment is
+the physical address of its kseg0/kseg1 virtual address, or p_paddr for a segment
+linked to a mapped segment. The area between p_filesz and p_memsz is cleared.
+*/
+
+const ELF_MAGIC   : [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const ELFCLASS32  : u8  = 1;
+const ELFDATA2MSB : u8  = 2;
+const ET_EXEC     : u16 = 2;
+const EM_MIPS     : u16 = 8;
+const PT_LOAD     : u32 = 1;
+const SHT_SYMTAB  : u32 = 2;
+const STT_NOTYPE  : u8  = 0;
+const STT_FUNC    : u8  = 2;
+
+pub struct ElfSegment {
+    pub offset : u32,
+    pub vaddr  : u32,
+    pub paddr  : u32,
+    pub filesz : u32,
+    pub memsz  : u32,
+}
+
+pub struct ElfFile<'a> {
+    data : &'a [u8],
+    pub entry     : u32,
+    pub phoff     : u32,
+    pub phentsize : u32,
+    pub phnum     : u32,
+    pub shoff     : u32,
+    pub shentsize : u32,
+    pub shnum     : u32,
+    pub shstrndx  : u32,
+}
+
+fn read16(data: &[u8], off: usize) -> Result<u16, String> {
+    data.get(off..off+2).map(|b| u16::from_be_bytes([b[0], b[1]])).ok_or("truncated ELF file".to_string())
+}
+
+fn read32(data: &[u8], off: usize) -> Result<u32, String> {
+    data.get(off..off+4).map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]])).ok_or("truncated ELF file".to_string())
+}
+
+// returns true if the data begins with the ELF magic number
+pub fn is_elf(data: &[u8]) -> bool {
+    data.len() >= 4 && data[0..4] == ELF_MAGIC
+}
+
+impl<'a> ElfFile<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Self, String> {
+        if !is_elf(data) {
+            return Err("not an ELF file".to_string());
+        }
+        if data.len() < 52 {
+            return Err("truncated ELF header".to_string());
+        }
+        if data[4] != ELFCLASS32 || data[5] != ELFDATA2MSB {
+            return Err("not a 32-bit big-endian ELF file".to_string());
+        }
+        if read16(data, 16)? != ET_EXEC {
+            return Err("not an executable ELF file".to_string());
+        }
+        if read16(data, 18)? != EM_MIPS {
+            return Err("not a MIPS ELF file".to_string());
+        }
+        Ok(Self {
+            data,
+            entry     : read32(data, 24)?,
+            phoff     : read32(data, 28)?,
+            shoff     : read32(data, 32)?,
+            phentsize : read16(data, 42)? as u32,
+            phnum     : read16(data, 44)? as u32,
+            shentsize : read16(data, 46)? as u32,
+            shnum     : read16(data, 48)? as u32,
+            shstrndx  : read16(data, 50)? as u32,
+        })
+    }
+
+    // PT_LOAD segments
+    pub fn segments(&self) -> Result<Vec<ElfSegment>, String> {
+        let mut segs = Vec::new();
+        for i in 0..self.phnum {
+            let ph = self.phoff as usize + (i * self.phentsize) as usize;
+            if read32(self.data, ph)? != PT_LOAD {
+                continue;
+            }
+            segs.push(ElfSegment {
+                offset : read32(self.data, ph +  4)?,
+                vaddr  : read32(self.data, ph +  8)?,
+                paddr  : read32(self.data, ph + 12)?,
+                filesz : read32(self.data, ph + 16)?,
+                memsz  : read32(self.data, ph + 20)?,
+            });
+        }
+        Ok(segs)
+    }
+
+    // function symbols (and labels without a type) in the symbol table as (address, size, name)
+    pub fn symbols(&self) -> Result<Vec<(u32, u32, String)>, String> {
+        let mut syms = Vec::new();
+        for i in 0..self.shnum {
+            let sh = self.shoff as usize + (i * self.shentsize) as usize;
+            if read32(self.data, sh + 4)? != SHT_SYMTAB {
+                continue;
+            }
+            let offset  = read32(self.data, sh + 16)? as usize;
+            let size    = read32(self.data, sh + 20)? as usize;
+            let strtab  = self.shoff as usize + (read32(self.data, sh + 24)? * self.shentsize) as usize;
+            let str_off = read32(self.data, strtab + 16)? as usize;
+
+            for s in (offset..offset+size).step_by(16).skip(1) {
+                let name  = read32(self.data, s)? as usize;
+                let value = read32(self.data, s + 4)?;
+                let ssize = read32(self.data, s + 8)?;
+                let info  = *self.data.get(s + 12).ok_or("truncated ELF file".to_string())?;
+                let shndx = read16(self.data, s + 14)?;
+                if (info & 0xf != STT_FUNC && info & 0xf != STT_NOTYPE) || shndx == 0 || shndx >= 0xff00 || name == 0 {
+                    continue;
+                }
+                let bytes = self.data.get(str_off + name..).ok_or("truncated ELF file".to_string())?;
+                let len = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+                syms.push((value, ssize, String::from_utf8_lossy(&bytes[..len]).into_owned()));
+            }
+        }
+        Ok(syms)
+    }
+}
+
+// DRAM offset where a segment is placed
+fn load_addr(seg: &ElfSegment) -> u32 {
+    if (mips::KSEG0..mips::KSEG2).contains(&seg.vaddr) {
+        kseg01_to_paddr!(seg.vaddr)
+    }else{
+        kseg01_to_paddr!(seg.paddr)
+    }
+}
+
+/*
+Loads PT_LOAD segments of an ELF file into DRAM.
+Returns the entry point and the highest physical address used by the segments.
+*/
+pub fn load(ms: &mut MachineState, data: &[u8]) -> Result<(u32, u32), String> {
+    let elf  = ElfFile::parse(data)?;
+    let segs = elf.segments()?;
+    if segs.is_empty() {
+        return Err("no loadable segment".to_string());
+    }
+
+    let mut end : u32 = 0;
+    for seg in segs.iter() {
+        let paddr = load_addr(seg);
+        if seg.filesz > seg.memsz {
+            return Err(format!("segment at 0x{:>08x} has p_filesz larger than p_memsz", seg.vaddr));
+        }
+        if (paddr as u64) + (seg.memsz as u64) > (config::RAM_AREA_ADDR as u64) + (config::DRAM_SIZE as u64) {
+            return Err(format!("segment at 0x{:>08x} (0x{:x} bytes) does not fit in DRAM", seg.vaddr, seg.memsz));
+        }
+        let src = data.get(seg.offset as usize .. seg.offset as usize + seg.filesz as usize).ok_or("truncated ELF segment".to_string())?;
+
+        let start = (paddr - config::RAM_AREA_ADDR) as usize;
+        ms.mem.mem0[start .. start + src.len()].copy_from_slice(src);
+        ms.mem.mem0[start + src.len() .. start + seg.memsz as usize].fill(0);
+
+        info!("ELF segment: vaddr 0x{:>08x}, paddr 0x{:>08x}, filesz 0x{:x}, memsz 0x{:x}", seg.vaddr, paddr, seg.filesz, seg.memsz);
+        end = end.max(paddr + seg.memsz);
+    }
+    Ok((elf.entry, end))
+}
+
+/*
+Prepares the processor to start from the entry point of a loaded program.
+The error level set at reset is cleared so that the program runs as after a boot loader.
+*/
+pub fn set_entry(ms: &mut MachineState, entry: u32, args: &[u32]) {
+    for (i, a) in args.iter().take(4).enumerate() {
+        ms.reg.r[4 + i] = *a;
+    }
+    ms.reg.pc = entry;
+    c0_val!(ms.reg, cp0def::C0_STATUS) &= !(1 << cp0def::C0_STATUS_BIT_ERL);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dev_spiflash::SPI_FLASH_PARAM_S25FL164K;
+    use crate::exrmips::generate_machine_state;
+
+    fn put16(v: &mut [u8], off: usize, d: u16) { v[off..off+2].copy_from_slice(&d.to_be_bytes()); }
+    fn put32(v: &mut [u8], off: usize, d: u32) { v[off..off+4].copy_from_slice(&d.to_be_bytes()); }
+
+    /*
+    ELF file with a PT_LOAD segment of the code (and memsz bytes in memory) at vaddr,
+    and a symbol table of the symbols (address, size, type, name)
+    */
+    fn build(vaddr: u32, paddr: u32, code: &[u8], memsz: u32, syms: &[(u32, u32, u8, &str)]) -> Vec<u8> {
+        let mut v = vec![0u8; 52 + 32];
+        v[0..4].copy_from_slice(&ELF_MAGIC);
+        v[4] = ELFCLASS32;
+        v[5] = ELFDATA2MSB;
+        put16(&mut v, 16, ET_EXEC);
+        put16(&mut v, 18, EM_MIPS);
+        put32(&mut v, 24, vaddr);
+        put32(&mut v, 28, 52);
+        put16(&mut v, 42, 32);
+        put16(&mut v, 44, 1);
+
+        // program header
+        let offset = v.len() as u32;
+        put32(&mut v, 52, PT_LOAD);
+        put32(&mut v, 56, offset);
+        put32(&mut v, 60, vaddr);
+        put32(&mut v, 64, paddr);
+        put32(&mut v, 68, code.len() as u32);
+        put32(&mut v, 72, memsz);
+        v.extend_from_slice(code);
+
+        // symbol table (with the null symbol) and string table
+        let mut strtab = vec![0u8];
+        let symtab_off = v.len();
+        v.extend_from_slice(&[0; 16]);
+        for (addr, size, kind, name) in syms {
+            let mut s = [0u8; 16];
+            put32(&mut s, 0, strtab.len() as u32);
+            put32(&mut s, 4, *addr);
+            put32(&mut s, 8, *size);
+            s[12] = 0x10 | kind; // global
+            put16(&mut s, 14, 1);
+            v.extend_from_slice(&s);
+            strtab.extend_from_slice(name.as_bytes());
+            strtab.push(0);
+        }
+        let symtab_size = v.len() - symtab_off;
+        let strtab_off = v.len();
+        v.extend_from_slice(&strtab);
+
+        // section headers: null, .symtab and .strtab
+        let shoff = v.len();
+        v.resize(shoff + 40*3, 0);
+        put32(&mut v, shoff + 40 + 4,  SHT_SYMTAB);
+        put32(&mut v, shoff + 40 + 16, symtab_off as u32);
+        put32(&mut v, shoff + 40 + 20, symtab_size as u32);
+        put32(&mut v, shoff + 40 + 24, 2);
+        put32(&mut v, shoff + 80 + 4,  3); // SHT_STRTAB
+        put32(&mut v, shoff + 80 + 16, strtab_off as u32);
+        put32(&mut v, shoff + 80 + 20, strtab.len() as u32);
+        put32(&mut v, 32, shoff as u32);
+        put16(&mut v, 46, 40);
+        put16(&mut v, 48, 3);
+        v
+    }
+
+    #[test]
+    fn headers() {
+        let data = build(0x80001000, 0x00001000, &[1, 2, 3, 4], 8, &[]);
+        assert!(is_elf(&data));
+        let elf = ElfFile::parse(&data).unwrap();
+        assert_eq!(elf.entry, 0x80001000);
+        let segs = elf.segments().unwrap();
+        assert_eq!(segs.len(), 1);
+        assert!(segs[0].vaddr == 0x80001000 && segs[0].filesz == 4 && segs[0].memsz == 8);
+
+        assert_eq!(ElfFile::parse(b"\x7fELG").err(), Some("not an ELF file".to_string()));
+        assert_eq!(ElfFile::parse(&data[..40]).err(), Some("truncated ELF header".to_string()));
+        let mut d = data.clone();
+        d[5] = 1; // little-endian
+        assert_eq!(ElfFile::parse(&d).err(), Some("not a 32-bit big-endian ELF file".to_string()));
+        let mut d = data.clone();
+        put16(&mut d, 16, 3); // ET_DYN
+        assert_eq!(ElfFile::parse(&d).err(), Some("not an executable ELF file".to_string()));
+        let mut d = data.clone();
+        put16(&mut d, 18, 3); // EM_386
+        assert_eq!(ElfFile::parse(&d).err(), Some("not a MIPS ELF file".to_string()));
+    }
+
+    #[test]
+    fn symbols() {
+        let data = build(0x80001000, 0x00001000, &[0; 8], 8, &[
+            (0x80001000, 8, STT_FUNC, "main"), (0x80001004, 0, STT_NOTYPE, "loop"), (0x80002000, 4, 1, "variable"),
+        ]);
+        let syms = ElfFile::parse(&data).unwrap().symbols().unwrap();
+        assert_eq!(syms, vec![(0x80001000, 8, "main".to_string()), (0x80001004, 0, "loop".to_string())]);
+    }
+
+    #[test]
+    fn loading() {
+        let mut ms = generate_machine_state(&SPI_FLASH_PARAM_S25FL164K, &[]);
+        ms.mem.mem0[0x1000..0x1010].fill(0xaa);
+
+        // the area between filesz and memsz is cleared
+        let data = build(0x80001000, 0x00001000, &[1, 2, 3, 4], 12, &[]);
+        assert_eq!(load(&mut ms, &data), Ok((0x80001000, 0x100c)));
+        assert_eq!(&ms.mem.mem0[0x1000..0x1010], &[1, 2, 3, 4, 0, 0, 0, 0, 0, 0, 0, 0, 0xaa, 0xaa, 0xaa, 0xaa]);
+
+        // a mapped segment is placed at p_paddr
+        let data = build(0xc0000000, 0x80002000, &[5, 6], 2, &[]);
+        assert_eq!(load(&mut ms, &data), Ok((0xc0000000, 0x2002)));
+        assert_eq!(&ms.mem.mem0[0x2000..0x2002], &[5, 6]);
+
+        let data = build(0x80001000, 0x00001000, &[1, 2, 3, 4], 2, &[]);
+        assert!(load(&mut ms, &data).unwrap_err().contains("p_filesz larger than p_memsz"));
+        let data = build(0x80000000 + config::DRAM_SIZE as u32 - 2, 0, &[1, 2, 3, 4], 4, &[]);
+        assert!(load(&mut ms, &data).unwrap_err().contains("does not fit in DRAM"));
+
+        set_entry(&mut ms, 0x80001000, &[1, 2]);
+        assert_eq!((ms.reg.pc, ms.reg.r[4], ms.reg.r[5]), (0x80001000, 1, 2));
+        assert_eq!(c0_val!(ms.reg, cp0def::C0_STATUS) & (1 << cp0def::C0_STATUS_BIT_ERL), 0);
+    }
+}
@@ -1,12 +1,17 @@
 use crate::procstate::MachineState;
+use crate::syscall_trace;
+use crate::perf;
+use crate::config;
 use crate::cp0def;
 use crate::mips;
 use crate::mode_is_in_exception;
+use crate::mode_is_exception;
 use crate::c0_val;
 use crate::except_vect_all_other;
 use crate::except_vect_cache_err;
 use crate::except_vect_int;
 use crate::except_vect_tlb_refill;
+use log::info;
 
 
 /*
@@ -30,6 +35,18 @@ pub fn prepare_exception(ms: &mut MachineState, ecode : u32, option : u32){
 
     let prev_mode_is_exl:bool = mode_is_in_exception!(c0_val!(ms.reg,cp0def::C0_STATUS));
 
+    if ms.emu.debug {
+        let epc = if ms.reg.delay_en { ms.reg.pc_prev_jump }else{ ms.reg.pc };
+        info!("Exception (code {}, option 0x{:x}) at 0x{:>08x}{}\r", ecode, option, epc, ms.symbols.annotate(epc));
+    }
+    if ecode == cp0def::EXCEPT_CODE_SYSCALL && ms.syscall_trace.is_some() {
+        syscall_trace::on_syscall(ms);
+    }
+    if ms.reg.perf_en != 0 {
+        let refill = ecode == cp0def::EXCEPT_CODE_TLB_REFILL_LOAD || ecode == cp0def::EXCEPT_CODE_TLB_REFILL_STORE;
+        perf::count(ms, (1<<perf::EV_EXCEPTIONS) | if refill { 1<<perf::EV_TLB_REFILL }else{ 0 });
+    }
+
     c0_val!(ms.reg,cp0def::C0_CAUSE) &= !cp0def::C0_CAUSE_EXCCODE_MASK;
     c0_val!(ms.reg,cp0def::C0_CAUSE) |= (ecode << cp0def::C0_CAUSE_BIT_EXCCODE) & cp0def::C0_CAUSE_EXCCODE_MASK;
     c0_val!(ms.reg,cp0def::C0_CAUSE) &= !cp0def::C0_CAUSE_CE_MASK;
@@ -56,6 +73,11 @@ pub fn prepare_exception(ms: &mut MachineState, ecode : u32, option : u32){
 
     ms.reg.delay_en = false;
 
+    if !prev_mode_is_exl && ecode != cp0def::EXCEPT_CODE_CACHE_ERROR {
+        let ess = srsctl_field(ms, cp0def::C0_SRSCTL_BIT_ESS);
+        enter_shadow_set(ms, ess);
+    }
+
     match ecode {
         cp0def::EXCEPT_CODE_CACHE_ERROR =>
         {
@@ -109,18 +131,196 @@ pub fn prepare_exception(ms: &mut MachineState, ecode : u32, option : u32){
             ms.reg.pc = except_vect_all_other!( c0_val!(ms.reg,cp0def::C0_EBASE), c0_val!(ms.reg,cp0def::C0_STATUS) & (1<<cp0def::C0_STATUS_BIT_BEV) );
         }
     }
+
+    if let Some(t) = ms.trace.as_mut() {
+        t.exception(ecode, c0_val!(ms.reg,cp0def::C0_CAUSE), c0_val!(ms.reg,cp0def::C0_EPC), c0_val!(ms.reg,cp0def::C0_BADVADDR), ms.reg.pc);
+    }
+}
+
+/*
+Interrupt modes of MIPS32R2.
+
+Compat  : interrupts masked by Status.IM use the single vector selected by Cause.IV.
+Vectored: with Config3.VInt, Cause.IV=1, IntCtl.VS!=0 and Status.BEV=0, the pending interrupt with the highest IP number n
+          is dispatched to EBase+0x200+n*spacing with the shadow register set given by SRSMap for n.
+External: with Config3.VEIC under the same conditions, the interrupt lines are sent to the external interrupt controller (EIC),
+          and its request (Cause.RIPL) is taken when it is above Status.IPL.
+
+The EIC is a simple built-in one: the line n (IP n in the other modes) is requested with the priority level n+1,
+the vector number n and the shadow register set of SRSMap for n.
+Software interrupts (IP1 and IP0) are sent to the EIC in the same way.
+*/
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum IrqMode {
+    Compat,
+    Vectored,
+    External,
+}
+
+// highest RIPL requested by the built-in EIC
+const EIC_MAX_RIPL : u32 = 8;
+
+// Selects the interrupt controller of the processor. Shadow register sets are available in the vectored and EIC modes.
+pub fn set_irq_mode(ms: &mut MachineState, mode: IrqMode) {
+    let (config3, hss) = match mode {
+        IrqMode::Compat   => (0, 0),
+        IrqMode::Vectored => (cp0def::C0_CONFIG3_VINT, config::NUM_SHADOW_SETS as u32 - 1),
+        IrqMode::External => (cp0def::C0_CONFIG3_VINT | cp0def::C0_CONFIG3_VEIC, config::NUM_SHADOW_SETS as u32 - 1),
+    };
+    c0_val!(ms.reg, cp0def::C0_CONFIG3) &= !(cp0def::C0_CONFIG3_VINT | cp0def::C0_CONFIG3_VEIC);
+    c0_val!(ms.reg, cp0def::C0_CONFIG3) |= config3;
+    c0_val!(ms.reg, cp0def::C0_SRSCTL)  &= !(0xf<<cp0def::C0_SRSCTL_BIT_HSS);
+    c0_val!(ms.reg, cp0def::C0_SRSCTL)  |= hss<<cp0def::C0_SRSCTL_BIT_HSS;
+}
+
+// true if the vectored interrupts can be enabled by IntCtl.VS
+pub fn irq_vectors_implemented(ms: &MachineState) -> bool {
+    0 != (c0_val!(ms.reg, cp0def::C0_CONFIG3) & (cp0def::C0_CONFIG3_VINT | cp0def::C0_CONFIG3_VEIC))
+}
+
+// interrupt mode currently enabled by the guest
+pub fn irq_mode(ms: &MachineState) -> IrqMode {
+    let config3 = c0_val!(ms.reg, cp0def::C0_CONFIG3);
+    if  0 == (c0_val!(ms.reg, cp0def::C0_CAUSE)  & (1<<cp0def::C0_CAUSE_BIT_IV))  ||
+        0 != (c0_val!(ms.reg, cp0def::C0_STATUS) & (1<<cp0def::C0_STATUS_BIT_BEV)) ||
+        0 == (c0_val!(ms.reg, cp0def::C0_INTCTL) & cp0def::C0_INTCTL_VS_MASK) {
+        IrqMode::Compat
+    }else if 0 != (config3 & cp0def::C0_CONFIG3_VEIC) {
+        IrqMode::External
+    }else if 0 != (config3 & cp0def::C0_CONFIG3_VINT) {
+        IrqMode::Vectored
+    }else{
+        IrqMode::Compat
+    }
+}
+
+// (RIPL, vector number) requested by the built-in EIC, RIPL is 0 without a request
+fn eic_request(ms: &MachineState) -> (u32, u32) {
+    let lines = (c0_val!(ms.reg, cp0def::C0_CAUSE) & cp0def::C0_CAUSE_IP_MASK) >> cp0def::C0_CAUSE_BIT_IP;
+    if lines == 0 {
+        return (0, 0);
+    }
+    let n = 31 - lines.leading_zeros();
+    (n + 1, n)
+}
+
+// value of C0_CAUSE seen by the guest (IP7..IP2 are replaced with RIPL in the EIC mode)
+pub fn load_cause(ms: &MachineState) -> u32 {
+    let cause = c0_val!(ms.reg, cp0def::C0_CAUSE);
+    if irq_mode(ms) == IrqMode::External {
+        (cause & !cp0def::C0_CAUSE_RIPL_MASK) | (eic_request(ms).0 << cp0def::C0_CAUSE_BIT_RIPL)
+    }else{
+        cause
+    }
+}
+
+// true if no interrupt can be taken whatever is requested
+pub fn irq_all_masked(ms: &MachineState) -> bool {
+    let status = c0_val!(ms.reg, cp0def::C0_STATUS);
+    match irq_mode(ms) {
+        IrqMode::External => ((status & cp0def::C0_STATUS_IPL_MASK) >> cp0def::C0_STATUS_BIT_IPL) >= EIC_MAX_RIPL,
+        _                 => 0 == (status & cp0def::C0_STATUS_IM_MASK),
+    }
+}
+
+// Takes a requested interrupt if interrupts are enabled
+pub fn check_interrupt(ms: &mut MachineState) {
+    let status = c0_val!(ms.reg, cp0def::C0_STATUS);
+    if 0 == (status & (1<<cp0def::C0_STATUS_BIT_IE)) || mode_is_exception!(status) {
+        return;
+    }
+    match irq_mode(ms) {
+        IrqMode::External => {
+            let (ripl, vect) = eic_request(ms);
+            if ripl > (status & cp0def::C0_STATUS_IPL_MASK) >> cp0def::C0_STATUS_BIT_IPL {
+                prepare_interrupt(ms, vect);
+            }
+        }
+        _ => {
+            let pending = (c0_val!(ms.reg, cp0def::C0_CAUSE) & status & cp0def::C0_CAUSE_IP_MASK) >> cp0def::C0_CAUSE_BIT_IP;
+            if pending != 0 {
+                prepare_interrupt(ms, 31 - pending.leading_zeros());
+            }
+        }
+    }
+}
+
+/*
+Shadow register sets.
+
+The GPRs of the current set (SRSCtl.CSS) are in ms.reg.r and the other sets are kept in ms.reg.srs.
+Sets are switched on exceptions and eret only when SRSCtl.HSS is not 0 and Status.BEV is 0.
+*/
+fn srsctl_field(ms: &MachineState, bit: u32) -> usize {
+    ((c0_val!(ms.reg, cp0def::C0_SRSCTL) >> bit) & 0xf) as usize
 }
 
-pub fn prepare_interrupt(ms: &mut MachineState, icode : u32){
+fn shadow_sets_enabled(ms: &MachineState) -> bool {
+    0 != srsctl_field(ms, cp0def::C0_SRSCTL_BIT_HSS) && 0 == (c0_val!(ms.reg, cp0def::C0_STATUS) & (1<<cp0def::C0_STATUS_BIT_BEV))
+}
+
+fn switch_gprs(ms: &mut MachineState, from: usize, to: usize) {
+    if from != to {
+        ms.reg.srs[from] = ms.reg.r;
+        ms.reg.r = ms.reg.srs[to];
+    }
+}
+
+// on an exception: PSS <- CSS, CSS <- css
+fn enter_shadow_set(ms: &mut MachineState, css: usize) {
+    if !shadow_sets_enabled(ms) {
+        return;
+    }
+    let prev = srsctl_field(ms, cp0def::C0_SRSCTL_BIT_CSS);
+    switch_gprs(ms, prev, css);
+    c0_val!(ms.reg, cp0def::C0_SRSCTL) &= !((0xf<<cp0def::C0_SRSCTL_BIT_PSS) | (0xf<<cp0def::C0_SRSCTL_BIT_CSS));
+    c0_val!(ms.reg, cp0def::C0_SRSCTL) |= ((prev as u32)<<cp0def::C0_SRSCTL_BIT_PSS) | ((css as u32)<<cp0def::C0_SRSCTL_BIT_CSS);
+}
+
+// on eret from an exception: CSS <- PSS
+pub fn leave_shadow_set(ms: &mut MachineState) {
+    if !shadow_sets_enabled(ms) {
+        return;
+    }
+    let (css, pss) = (srsctl_field(ms, cp0def::C0_SRSCTL_BIT_CSS), srsctl_field(ms, cp0def::C0_SRSCTL_BIT_PSS));
+    switch_gprs(ms, css, pss);
+    c0_val!(ms.reg, cp0def::C0_SRSCTL) &= !(0xf<<cp0def::C0_SRSCTL_BIT_CSS);
+    c0_val!(ms.reg, cp0def::C0_SRSCTL) |= (pss as u32)<<cp0def::C0_SRSCTL_BIT_CSS;
+}
+
+// GPR n of the previous shadow set (rdpgpr)
+pub fn read_prev_gpr(ms: &MachineState, n: usize) -> u32 {
+    let pss = srsctl_field(ms, cp0def::C0_SRSCTL_BIT_PSS);
+    if pss == srsctl_field(ms, cp0def::C0_SRSCTL_BIT_CSS) { ms.reg.r[n] }else{ ms.reg.srs[pss][n] }
+}
+
+// wrpgpr
+pub fn write_prev_gpr(ms: &mut MachineState, n: usize, val: u32) {
+    if n == 0 {
+        return;
+    }
+    let pss = srsctl_field(ms, cp0def::C0_SRSCTL_BIT_PSS);
+    if pss == srsctl_field(ms, cp0def::C0_SRSCTL_BIT_CSS) { ms.reg.r[n] = val; }else{ ms.reg.srs[pss][n] = val; }
+}
+
+/*
+Preparation for entering an interrupt.
+
+vect is the vector number (the IP number of the interrupt, or the vector given by the EIC),
+which selects the vector and the shadow register set in the vectored and EIC modes.
+*/
+pub fn prepare_interrupt(ms: &mut MachineState, vect : u32){
 
     let prev_mode_is_exl:bool = mode_is_in_exception!(c0_val!(ms.reg,cp0def::C0_STATUS));
+    let mode = irq_mode(ms);
+
+    if ms.reg.perf_en != 0 {
+        perf::count(ms, 1<<perf::EV_EXCEPTIONS);
+    }
 
     c0_val!(ms.reg,cp0def::C0_CAUSE) &= !cp0def::C0_CAUSE_EXCCODE_MASK;
     c0_val!(ms.reg,cp0def::C0_CAUSE) |= cp0def::EXCEPT_CODE_INTERRUPT << cp0def::C0_CAUSE_BIT_EXCCODE;
 
-    c0_val!(ms.reg,cp0def::C0_CAUSE) &= !cp0def::C0_CAUSE_IP_MASK;
-    c0_val!(ms.reg,cp0def::C0_CAUSE) |= (icode << cp0def::C0_CAUSE_BIT_IP) & cp0def::C0_CAUSE_IP_MASK;
-
     if ! prev_mode_is_exl {
         if ms.reg.delay_en {
             c0_val!(ms.reg,cp0def::C0_EPC)    =  ms.reg.pc_prev_jump;
@@ -133,6 +333,171 @@ pub fn prepare_interrupt(ms: &mut MachineState, icode : u32){
 
     ms.reg.delay_en = false;
 
+    if ! prev_mode_is_exl {
+        let srsmap = (c0_val!(ms.reg,cp0def::C0_SRSMAP) >> ((vect & 7) * 4)) & 0xf;
+        let css = match mode {
+            IrqMode::Compat   => srsctl_field(ms, cp0def::C0_SRSCTL_BIT_ESS),
+            IrqMode::Vectored => srsmap as usize,
+            IrqMode::External => {
+                c0_val!(ms.reg,cp0def::C0_SRSCTL) &= !(0xf<<cp0def::C0_SRSCTL_BIT_EICSS);
+                c0_val!(ms.reg,cp0def::C0_SRSCTL) |= srsmap<<cp0def::C0_SRSCTL_BIT_EICSS;
+                srsmap as usize
+            }
+        };
+        enter_shadow_set(ms, css);
+    }
+
     c0_val!(ms.reg,cp0def::C0_STATUS) |= 1<<cp0def::C0_STATUS_BIT_EXL; // exception level
-    ms.reg.pc = except_vect_int!( c0_val!(ms.reg,cp0def::C0_EBASE), c0_val!(ms.reg,cp0def::C0_STATUS) & (1<<cp0def::C0_STATUS_BIT_BEV), c0_val!(ms.reg,cp0def::C0_CAUSE) & (1<<cp0def::C0_CAUSE_BIT_IV) );
-}
\ No newline at end of file
+    ms.reg.pc = if mode == IrqMode::Compat {
+        except_vect_int!( c0_val!(ms.reg,cp0def::C0_EBASE), c0_val!(ms.reg,cp0def::C0_STATUS) & (1<<cp0def::C0_STATUS_BIT_BEV), c0_val!(ms.reg,cp0def::C0_CAUSE) & (1<<cp0def::C0_CAUSE_BIT_IV) )
+    }else{
+        let spacing = ((c0_val!(ms.reg,cp0def::C0_INTCTL) & cp0def::C0_INTCTL_VS_MASK) >> cp0def::C0_INTCTL_BIT_VS) << 5;
+        except_vect_int!( c0_val!(ms.reg,cp0def::C0_EBASE), 0, 1 ) + vect * spacing
+    };
+
+    if let Some(t) = ms.trace.as_mut() {
+        let pending = (c0_val!(ms.reg,cp0def::C0_CAUSE) & cp0def::C0_CAUSE_IP_MASK) >> cp0def::C0_CAUSE_BIT_IP;
+        t.interrupt(pending, c0_val!(ms.reg,cp0def::C0_CAUSE), c0_val!(ms.reg,cp0def::C0_EPC), ms.reg.pc);
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dev_spiflash::SPI_FLASH_PARAM_S25FL164K;
+    use crate::exrmips::generate_machine_state;
+
+    const IE : u32 = 1<<cp0def::C0_STATUS_BIT_IE;
+    const IV : u32 = 1<<cp0def::C0_CAUSE_BIT_IV;
+
+    fn ip(n: u32) -> u32 { 1<<(cp0def::C0_CAUSE_BIT_IP + n) }
+
+    // interrupts enabled at pc with the exception vectors at 0x80010000 and IntCtl.VS
+    fn setup(mode: IrqMode, vs: u32) -> MachineState {
+        let mut ms = generate_machine_state(&SPI_FLASH_PARAM_S25FL164K, &[]);
+        set_irq_mode(&mut ms, mode);
+        c0_val!(ms.reg, cp0def::C0_STATUS) = IE | cp0def::C0_STATUS_IM_MASK;
+        c0_val!(ms.reg, cp0def::C0_CAUSE)  = IV;
+        c0_val!(ms.reg, cp0def::C0_EBASE)  = 0x80010000;
+        c0_val!(ms.reg, cp0def::C0_INTCTL) = vs<<cp0def::C0_INTCTL_BIT_VS;
+        ms.reg.pc = 0x80400000;
+        ms
+    }
+
+    fn css(ms: &MachineState) -> usize { srsctl_field(ms, cp0def::C0_SRSCTL_BIT_CSS) }
+    fn pss(ms: &MachineState) -> usize { srsctl_field(ms, cp0def::C0_SRSCTL_BIT_PSS) }
+
+    #[test]
+    fn compat_vectors() {
+        let mut ms = setup(IrqMode::Vectored, 0);
+        assert_eq!(irq_mode(&ms), IrqMode::Compat);
+        c0_val!(ms.reg, cp0def::C0_CAUSE) |= ip(4) | ip(2);
+        check_interrupt(&mut ms);
+        assert_eq!(ms.reg.pc, 0x80010200);
+        assert_eq!(c0_val!(ms.reg, cp0def::C0_EPC), 0x80400000);
+        assert_eq!(c0_val!(ms.reg, cp0def::C0_CAUSE) & cp0def::C0_CAUSE_EXCCODE_MASK, 0);
+
+        // the general exception vector with Cause.IV cleared
+        let mut ms = setup(IrqMode::Compat, 1);
+        c0_val!(ms.reg, cp0def::C0_CAUSE) = ip(7);
+        check_interrupt(&mut ms);
+        assert_eq!(ms.reg.pc, 0x80010180);
+    }
+
+    #[test]
+    fn vectored_interrupts() {
+        let mut ms = setup(IrqMode::Vectored, 2);
+        assert_eq!(irq_mode(&ms), IrqMode::Vectored);
+        c0_val!(ms.reg, cp0def::C0_CAUSE) |= ip(4) | ip(2);
+
+        // the highest unmasked IP number is taken: EBase + 0x200 + vect * (VS<<5)
+        c0_val!(ms.reg, cp0def::C0_STATUS) &= !(cp0def::C0_STATUS_IM_MASK & !(1<<(cp0def::C0_STATUS_BIT_IM + 2)));
+        check_interrupt(&mut ms);
+        assert_eq!(ms.reg.pc, 0x80010200 + 2*64);
+
+        let mut ms = setup(IrqMode::Vectored, 2);
+        c0_val!(ms.reg, cp0def::C0_CAUSE) |= ip(4) | ip(2);
+        check_interrupt(&mut ms);
+        assert_eq!(ms.reg.pc, 0x80010200 + 4*64);
+        assert!(0 != (c0_val!(ms.reg, cp0def::C0_STATUS) & (1<<cp0def::C0_STATUS_BIT_EXL)));
+
+        // no other interrupt while EXL is set
+        ms.reg.pc = 0x80010300;
+        c0_val!(ms.reg, cp0def::C0_CAUSE) |= ip(7);
+        check_interrupt(&mut ms);
+        assert_eq!(ms.reg.pc, 0x80010300);
+    }
+
+    #[test]
+    fn external_interrupt_controller() {
+        let mut ms = setup(IrqMode::External, 1);
+        assert_eq!(irq_mode(&ms), IrqMode::External);
+        c0_val!(ms.reg, cp0def::C0_SRSMAP) = 3<<(6*4);
+        c0_val!(ms.reg, cp0def::C0_CAUSE) |= ip(6) | ip(2);
+        assert_eq!(eic_request(&ms), (7, 6));
+        assert_eq!((load_cause(&ms) & cp0def::C0_CAUSE_RIPL_MASK) >> cp0def::C0_CAUSE_BIT_RIPL, 7);
+
+        // the request is taken only above Status.IPL
+        c0_val!(ms.reg, cp0def::C0_STATUS) = IE | (7<<cp0def::C0_STATUS_BIT_IPL);
+        check_interrupt(&mut ms);
+        assert_eq!(ms.reg.pc, 0x80400000);
+        assert!(!irq_all_masked(&ms));
+        c0_val!(ms.reg, cp0def::C0_STATUS) = IE | (EIC_MAX_RIPL<<cp0def::C0_STATUS_BIT_IPL);
+        assert!(irq_all_masked(&ms));
+
+        c0_val!(ms.reg, cp0def::C0_STATUS) = IE | (6<<cp0def::C0_STATUS_BIT_IPL);
+        check_interrupt(&mut ms);
+        assert_eq!(ms.reg.pc, 0x80010200 + 6*32);
+        // the shadow set of SRSMap for the vector
+        assert_eq!(srsctl_field(&ms, cp0def::C0_SRSCTL_BIT_EICSS), 3);
+        assert_eq!((css(&ms), pss(&ms)), (3, 0));
+
+        // Cause.IP is shown as RIPL in the EIC mode only
+        assert_eq!(load_cause(&ms) & cp0def::C0_CAUSE_IP_MASK, 7<<cp0def::C0_CAUSE_BIT_RIPL);
+        set_irq_mode(&mut ms, IrqMode::Compat);
+        assert_eq!(load_cause(&ms) & cp0def::C0_CAUSE_IP_MASK, ip(6) | ip(2));
+    }
+
+    #[test]
+    fn shadow_register_sets() {
+        let mut ms = setup(IrqMode::Vectored, 1);
+        c0_val!(ms.reg, cp0def::C0_SRSMAP) = 2<<(5*4);
+        ms.reg.r[29] = 0x80ffff00;
+        ms.reg.srs[2][29] = 0x80001000;
+
+        c0_val!(ms.reg, cp0def::C0_CAUSE) |= ip(5);
+        check_interrupt(&mut ms);
+        assert_eq!(ms.reg.pc, 0x80010200 + 5*32);
+        assert_eq!((css(&ms), pss(&ms)), (2, 0));
+        assert_eq!(ms.reg.r[29], 0x80001000);
+
+        // rdpgpr and wrpgpr access the interrupted set
+        assert_eq!(read_prev_gpr(&ms, 29), 0x80ffff00);
+        write_prev_gpr(&mut ms, 2, 0x1234);
+        write_prev_gpr(&mut ms, 0, 0x1234);
+        assert_eq!(ms.reg.r[2], 0);
+        ms.reg.r[29] = 0x80000ff0;
+
+        // eret returns to the previous set and keeps the handler's registers in its set
+        leave_shadow_set(&mut ms);
+        assert_eq!((css(&ms), pss(&ms)), (0, 0));
+        assert_eq!(ms.reg.r[29], 0x80ffff00);
+        assert_eq!(ms.reg.r[2], 0x1234);
+        assert_eq!(ms.reg.r[0], 0);
+        assert_eq!(ms.reg.srs[2][29], 0x80000ff0);
+        // without a previous set, the current one is accessed
+        assert_eq!(read_prev_gpr(&ms, 2), 0x1234);
+
+        // exceptions use SRSCtl.ESS
+        c0_val!(ms.reg, cp0def::C0_SRSCTL) |= 1<<cp0def::C0_SRSCTL_BIT_ESS;
+        c0_val!(ms.reg, cp0def::C0_STATUS) &= !(1<<cp0def::C0_STATUS_BIT_EXL);
+        prepare_exception(&mut ms, cp0def::EXCEPT_CODE_SYSCALL, 0);
+        assert_eq!((css(&ms), pss(&ms)), (1, 0));
+        leave_shadow_set(&mut ms);
+
+        // no switch with Status.BEV set
+        c0_val!(ms.reg, cp0def::C0_STATUS) = IE | cp0def::C0_STATUS_IM_MASK | (1<<cp0def::C0_STATUS_BIT_BEV);
+        prepare_exception(&mut ms, cp0def::EXCEPT_CODE_SYSCALL, 0);
+        assert_eq!((css(&ms), pss(&ms)), (0, 0));
+        assert_eq!(ms.reg.r[29], 0x80ffff00);
+    }
+}
@@ -8,6 +8,9 @@ use crate::mode_is_user;
 use crate::tlb;
 use crate::cp0;
 use crate::mem;
+use crate::syscall_trace;
+use crate::fpu;
+use crate::dsp;
 
 //use crate::exec_common;
 use crate::sign_ext16;
@@ -309,39 +312,45 @@ pub fn exec(ms: &mut MachineState, inst : u32) -> bool {
                 }
                 0x10 => // mfhi
                 {
-                    if ms.emu.debug { info!("mfhi {}", mips::REGSTR[rd]); }
-                    ms.reg.r[rd] = ms.reg.hi;
+                    if ms.emu.debug { info!("mfhi {}, $ac{}", mips::REGSTR[rd], rs&3); }
+                    if !dsp::check_acc(ms, rs&3) { return true; }
+                    ms.reg.r[rd] = dsp::hi(&ms.reg, rs&3);
                     update_pc_next32!(ms);
                     return true;
                 }
                 0x11 => // mthi
                 {
-                    if ms.emu.debug { info!("mthi {}", mips::REGSTR[rs]); }
-                    ms.reg.hi = ms.reg.r[rs];
+                    if ms.emu.debug { info!("mthi {}, $ac{}", mips::REGSTR[rs], rd&3); }
+                    if !dsp::check_acc(ms, rd&3) { return true; }
+                    let val = ms.reg.r[rs];
+                    dsp::set_hi(&mut ms.reg, rd&3, val);
                     update_pc_next32!(ms);
                     return true;
                 }
                 0x12 => // mflo
                 {
-                    if ms.emu.debug { info!("mflo {}", mips::REGSTR[rd]); }
-                    ms.reg.r[rd] = ms.reg.lo;
+                    if ms.emu.debug { info!("mflo {}, $ac{}", mips::REGSTR[rd], rs&3); }
+                    if !dsp::check_acc(ms, rs&3) { return true; }
+                    ms.reg.r[rd] = dsp::lo(&ms.reg, rs&3);
                     update_pc_next32!(ms);
                     return true;
                 }
                 0x13 => // mtlo
                 {
-                    if ms.emu.debug { info!("mtlo {}", mips::REGSTR[rs]); }
-                    ms.reg.lo = ms.reg.r[rs];
+                    if ms.emu.debug { info!("mtlo {}, $ac{}", mips::REGSTR[rs], rd&3); }
+                    if !dsp::check_acc(ms, rd&3) { return true; }
+                    let val = ms.reg.r[rs];
+                    dsp::set_lo(&mut ms.reg, rd&3, val);
                     update_pc_next32!(ms);
                     return true;
                 }
                 0x18 =>
                 {
-                    if shamt == 0x00 && rd == 0x00 {
-                        if ms.emu.debug { info!("mult {}, {}", mips::REGSTR[rs], mips::REGSTR[rt]); }
+                    if shamt == 0x00 && (rd & !3) == 0x00 { // rd[1:0] is ac of DSP ASE
+                        if ms.emu.debug { info!("mult $ac{}, {}, {}", rd, mips::REGSTR[rs], mips::REGSTR[rt]); }
+                        if !dsp::check_acc(ms, rd) { return true; }
                         let mul_tmp: i64 = ((ms.reg.r[rs] as i32) as i64) * ((ms.reg.r[rt] as i32) as i64);
-                        ms.reg.hi = ((mul_tmp>>16)>>16) as u32;
-                        ms.reg.lo = mul_tmp as u32;
+                        dsp::set_acc(&mut ms.reg, rd, mul_tmp);
                         update_pc_next32!(ms);
                     }else{
                         unknown_instruction!(inst,"op=0x00, funct=0x19");
@@ -350,11 +359,11 @@ pub fn exec(ms: &mut MachineState, inst : u32) -> bool {
                 }
                 0x19 =>
                 {
-                    if shamt == 0x00 && rd == 0x00 {
-                        if ms.emu.debug { info!("multu {}, {}", mips::REGSTR[rs], mips::REGSTR[rt]); }
+                    if shamt == 0x00 && (rd & !3) == 0x00 {
+                        if ms.emu.debug { info!("multu $ac{}, {}, {}", rd, mips::REGSTR[rs], mips::REGSTR[rt]); }
+                        if !dsp::check_acc(ms, rd) { return true; }
                         let mul_tmp: u64 = ((ms.reg.r[rs] as u32) as u64) * ((ms.reg.r[rt] as u32) as u64);
-                        ms.reg.hi = ((mul_tmp>>16)>>16) as u32;
-                        ms.reg.lo = mul_tmp as u32;
+                        dsp::set_acc(&mut ms.reg, rd, mul_tmp as i64);
                         update_pc_next32!(ms);
                     }else{
                         unknown_instruction!(inst,"op=0x00, funct=0x19");
@@ -462,6 +471,10 @@ pub fn exec(ms: &mut MachineState, inst : u32) -> bool {
                     update_pc_next32!(ms);
                     return true;
                 }
+                0x01 => // movf, movt
+                {
+                    return fpu::exec_movci(ms, inst);
+                }
                 _ =>
                 {
                     unknown_instruction!(inst,"op=0x00");
@@ -723,6 +736,10 @@ pub fn exec(ms: &mut MachineState, inst : u32) -> bool {
                         update_pc_next32!(ms);
                     }
                 }
+                0x1c => // bposge32
+                {
+                    return dsp::exec_bposge32(ms, inst);
+                }
                 0x0e => // tnei
                 {
                     if ms.emu.debug { info!("tnei {}, 0x{:>x}", mips::REGSTR[rs], imm); }
@@ -985,37 +1002,33 @@ pub fn exec(ms: &mut MachineState, inst : u32) -> bool {
                 if ms.emu.debug { info!("mul {}, {}, {}", mips::REGSTR[rd], mips::REGSTR[rs], mips::REGSTR[rt]); }
                 ms.reg.r[rd] = ((ms.reg.r[rs] as i32) * (ms.reg.r[rt] as i32)) as u32;
                 update_pc_next32!(ms);
-            }else if imm == 0x00 {
-                if ms.emu.debug { info!("madd {}, {}", mips::REGSTR[rs], mips::REGSTR[rt]); }
-                let hilo : i64 = ((((ms.reg.hi as u64)<<16)<<16) + (ms.reg.lo as u64)) as i64;
+            }else if (imm & !0x1800) == 0x00 { // imm[12:11] is ac of DSP ASE
+                if ms.emu.debug { info!("madd $ac{}, {}, {}", rd&3, mips::REGSTR[rs], mips::REGSTR[rt]); }
+                if !dsp::check_acc(ms, rd&3) { return true; }
+                let hilo : i64 = dsp::acc(&ms.reg, rd&3);
                 let tmul : i64 = ((ms.reg.r[rs] as i32) as i64) * ((ms.reg.r[rt] as i32) as i64);
-                let tmul2: i64 = hilo + tmul;
-                ms.reg.hi = ((tmul2>>16)>>16) as u32;
-                ms.reg.lo = tmul2 as u32;
+                dsp::set_acc(&mut ms.reg, rd&3, hilo.wrapping_add(tmul));
                 update_pc_next32!(ms);
-            }else if imm == 0x01 {
-                if ms.emu.debug { info!("maddu {}, {}", mips::REGSTR[rs], mips::REGSTR[rt]); }
-                let hilo : u64 = (((ms.reg.hi as u64)<<16)<<16) + (ms.reg.lo as u64);
+            }else if (imm & !0x1800) == 0x01 {
+                if ms.emu.debug { info!("maddu $ac{}, {}, {}", rd&3, mips::REGSTR[rs], mips::REGSTR[rt]); }
+                if !dsp::check_acc(ms, rd&3) { return true; }
+                let hilo : u64 = dsp::acc(&ms.reg, rd&3) as u64;
                 let tmul0: u64 =  (ms.reg.r[rs] as u64) * (ms.reg.r[rt] as u64);
-                let tmul : u64 = hilo + tmul0;
-                ms.reg.hi = ((tmul>>16)>>16) as u32;
-                ms.reg.lo = tmul as u32;
+                dsp::set_acc(&mut ms.reg, rd&3, hilo.wrapping_add(tmul0) as i64);
                 update_pc_next32!(ms);
-            }else if imm == 0x04 {
-                if ms.emu.debug { info!("msub {}, {}", mips::REGSTR[rs], mips::REGSTR[rt]); }
-                let hilo : i64 = ((((ms.reg.hi as u64)<<16)<<16) + (ms.reg.lo as u64)) as i64;
+            }else if (imm & !0x1800) == 0x04 {
+                if ms.emu.debug { info!("msub $ac{}, {}, {}", rd&3, mips::REGSTR[rs], mips::REGSTR[rt]); }
+                if !dsp::check_acc(ms, rd&3) { return true; }
+                let hilo : i64 = dsp::acc(&ms.reg, rd&3);
                 let tmul0: i64 = ((ms.reg.r[rs] as i32) as i64) * ((ms.reg.r[rt] as i32) as i64);
-                let tmul : i64 = hilo - tmul0;
-                ms.reg.hi = ((tmul>>16)>>16) as u32;
-                ms.reg.lo = tmul as u32;
+                dsp::set_acc(&mut ms.reg, rd&3, hilo.wrapping_sub(tmul0));
                 update_pc_next32!(ms);
-            }else if imm == 0x05 {
-                if ms.emu.debug { info!("msubu {}, {}", mips::REGSTR[rs], mips::REGSTR[rt]); }
-                let hilo : u64 = (((ms.reg.hi as u64)<<16)<<16) + (ms.reg.lo as u64);
+            }else if (imm & !0x1800) == 0x05 {
+                if ms.emu.debug { info!("msubu $ac{}, {}, {}", rd&3, mips::REGSTR[rs], mips::REGSTR[rt]); }
+                if !dsp::check_acc(ms, rd&3) { return true; }
+                let hilo : u64 = dsp::acc(&ms.reg, rd&3) as u64;
                 let tmul0: u64 =   (ms.reg.r[rs] as u64) * (ms.reg.r[rt] as u64);
-                let tmul : u64 = hilo - tmul0;
-                ms.reg.hi = ((tmul>>16)>>16) as u32;
-                ms.reg.lo = tmul as u32;
+                dsp::set_acc(&mut ms.reg, rd&3, hilo.wrapping_sub(tmul0) as i64);
                 update_pc_next32!(ms);
             }else if funct == 0x21 && shamt == 0x00 {
                 if ms.emu.debug { info!("clo {}, {}", mips::REGSTR[rd], mips::REGSTR[rs]); }
@@ -1047,6 +1060,10 @@ pub fn exec(ms: &mut MachineState, inst : u32) -> bool {
         MIPS32_OP_SPECIAL3 =>
         {
             match funct {
+                0x0a | 0x0c | 0x10 | 0x11 | 0x12 | 0x13 | 0x18 | 0x30 | 0x31 | 0x38 => // DSP ASE
+                {
+                    return dsp::exec(ms, inst);
+                }
                 0x3b =>
                 {
                     if rs != 0 {
@@ -1179,6 +1196,18 @@ pub fn exec(ms: &mut MachineState, inst : u32) -> bool {
                     ms.reg.r[rt] = cp0::load(ms, (rd as u32, imm & ((1<<mips::CP_SEL_BITS)-1)));
                     update_pc_next32!(ms);
                 }
+                0x0a =>
+                {
+                    if ms.emu.debug { info!("rdpgpr {}, {}", mips::REGSTR[rd], mips::REGSTR[rt]); }
+                    ms.reg.r[rd] = exception::read_prev_gpr(ms, rt);
+                    update_pc_next32!(ms);
+                }
+                0x0e =>
+                {
+                    if ms.emu.debug { info!("wrpgpr {}, {}", mips::REGSTR[rd], mips::REGSTR[rt]); }
+                    exception::write_prev_gpr(ms, rd, ms.reg.r[rt]);
+                    update_pc_next32!(ms);
+                }
                 0x0b =>
                 {
                     if imm == 0x6000 { // di
@@ -1219,9 +1248,12 @@ pub fn exec(ms: &mut MachineState, inst : u32) -> bool {
                         }else{
                             ms.reg.pc = c0_val!(ms.reg, cp0def::C0_EPC);
                             c0_val!(ms.reg,cp0def::C0_STATUS) &= !(1<<cp0def::C0_STATUS_BIT_EXL);
+                            exception::leave_shadow_set(ms);
+                            if ms.syscall_trace.is_some() {
+                                syscall_trace::on_eret(ms);
+                            }
                         }
                         ms.reg.ll_sc = false;
-                        // TODO: processing of SRSCTL etc
                     }else if inst == 0x42000020 {
                         if ms.emu.debug { info!("wait"); }
 
@@ -1242,6 +1274,18 @@ pub fn exec(ms: &mut MachineState, inst : u32) -> bool {
                 }
             }
         }
+        MIPS32_OP_COP1 => // COP1
+        {
+            return fpu::exec_cop1(ms, inst);
+        }
+        MIPS32_OP_COP1X => // COP1X
+        {
+            return fpu::exec_cop1x(ms, inst);
+        }
+        MIPS32_OP_LWC1 | MIPS32_OP_LDC1 | MIPS32_OP_SWC1 | MIPS32_OP_SDC1 => // lwc1, ldc1, swc1, sdc1
+        {
+            return fpu::exec_load_store(ms, inst);
+        }
         MIPS32_OP_CACHE => // cache
         {
             if ms.emu.debug { info!("cache 0x{:>x}, 0x{:>x}({})", rt, imm, mips::REGSTR[rs]); }
@@ -0,0 +1,282 @@
+use crate::dev_spi::SPIWorker;
+use log::info;
+
+use std::collections::BTreeSet;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/*
+Persistence of the flash memory modified in the emulator.
+
+Three modes are available:
+  WriteBack : modified sectors are written back to the image file.
+  Output    : the whole image is written to another file, and then modified sectors are updated.
+  Overlay   : modified sectors are saved in a copy-on-write overlay file and the base image is never modified.
+              The overlay is applied to the flash image at startup.
+
+The store is flushed on exit and on demand ("sync" command of the monitor).
+
+Overlay file format (big-endian):
+  "EXRMOVL1", capacity (u32), sector size (u32), number of sectors (u32),
+  then for each sector: sector index (u32) and the sector data.
+*/
+
+const OVERLAY_MAGIC : &[u8; 8] = b"EXRMOVL1";
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum FlashStoreMode { WriteBack, Output, Overlay }
+
+pub struct FlashStore {
+    path : PathBuf,
+    mode : FlashStoreMode,
+    modified    : BTreeSet<u32>, // sectors recorded in the overlay
+    pending     : BTreeSet<u32>, // modified sectors not written yet (kept until a flush succeeds)
+    initialized : bool,          // the output file has been written entirely
+}
+
+fn read_u32(f: &mut File) -> io::Result<u32> {
+    let mut b = [0u8; 4];
+    f.read_exact(&mut b)?;
+    Ok(u32::from_be_bytes(b))
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+impl FlashStore {
+    pub fn new(path: &Path, mode: FlashStoreMode) -> Self {
+        Self { path: path.to_path_buf(), mode, modified: BTreeSet::new(), pending: BTreeSet::new(), initialized: false }
+    }
+
+    /*
+    Opens an overlay file and applies the recorded sectors to the flash image.
+    A new overlay is created on the first flush if the file does not exist.
+    */
+    pub fn open_overlay(path: &Path, flash: &mut dyn SPIWorker) -> io::Result<Self> {
+        let mut store = Self::new(path, FlashStoreMode::Overlay);
+        let sector_size = flash.sector_size() as usize;
+        let image = flash.image_mut().ok_or(invalid("no flash memory image".to_string()))?;
+
+        let mut f = match File::open(path) {
+            Ok(f) => f,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => { return Ok(store); }
+            Err(e) => { return Err(e); }
+        };
+        let mut magic = [0u8; 8];
+        f.read_exact(&mut magic)?;
+        if &magic != OVERLAY_MAGIC {
+            return Err(invalid("not a flash overlay file".to_string()));
+        }
+        let capacity = read_u32(&mut f)? as usize;
+        let size     = read_u32(&mut f)? as usize;
+        let num      = read_u32(&mut f)?;
+        if capacity != image.len() || size != sector_size {
+            return Err(invalid(format!("overlay is for a flash of {} bytes with {}-byte sectors", capacity, size)));
+        }
+        for _ in 0..num {
+            let n = read_u32(&mut f)?;
+            let start = n as usize * sector_size;
+            let dst = image.get_mut(start .. start + sector_size).ok_or(invalid(format!("sector {} is out of the flash", n)))?;
+            f.read_exact(dst)?;
+            store.modified.insert(n);
+        }
+        info!("Flash overlay \"{}\" is applied ({} sectors)", path.display(), num);
+        Ok(store)
+    }
+
+    pub fn mode(&self) -> FlashStoreMode { self.mode }
+    pub fn path(&self) -> &Path { &self.path }
+
+    /*
+    Writes the modifications of the flash memory. Returns the number of sectors written.
+    On an error, the modified sectors are kept and written by the next flush.
+    */
+    pub fn flush(&mut self, flash: &mut dyn SPIWorker) -> io::Result<usize> {
+        let sector_size = flash.sector_size() as usize;
+        self.pending.extend(flash.take_dirty_sectors());
+        let image = match flash.image() {
+            Some(image) => image,
+            None        => { return Ok(0); }
+        };
+        let written = self.write(image, sector_size)?;
+        self.pending.clear();
+        Ok(written)
+    }
+
+    fn write(&mut self, image: &[u8], sector_size: usize) -> io::Result<usize> {
+        let dirty = &self.pending;
+        match self.mode {
+            FlashStoreMode::Overlay => {
+                if dirty.is_empty() {
+                    return Ok(0);
+                }
+                self.modified.extend(dirty.iter());
+
+                // The overlay is rewritten into a temporary file and renamed so that it is never left half-written
+                let tmp = self.path.with_extension("tmp");
+                let mut f = File::create(&tmp)?;
+                f.write_all(OVERLAY_MAGIC)?;
+                f.write_all(&(image.len() as u32).to_be_bytes())?;
+                f.write_all(&(sector_size as u32).to_be_bytes())?;
+                f.write_all(&(self.modified.len() as u32).to_be_bytes())?;
+                for n in self.modified.iter() {
+                    let start = *n as usize * sector_size;
+                    f.write_all(&n.to_be_bytes())?;
+                    f.write_all(&image[start .. start + sector_size])?;
+                }
+                f.sync_all()?;
+                std::fs::rename(&tmp, &self.path)?;
+                Ok(dirty.len())
+            }
+            FlashStoreMode::Output if !self.initialized => {
+                std::fs::write(&self.path, image)?;
+                self.initialized = true;
+                Ok(image.len() / sector_size)
+            }
+            FlashStoreMode::WriteBack | FlashStoreMode::Output => {
+                if dirty.is_empty() {
+                    return Ok(0);
+                }
+                let mut f = OpenOptions::new().write(true).create(true).truncate(false).open(&self.path)?;
+                let mut file_len = f.metadata()?.len() as usize;
+                for n in dirty.iter() {
+                    let start = *n as usize * sector_size;
+                    let end   = start + sector_size;
+                    // The gap beyond the end of a short image file is filled with the flash contents
+                    let from  = start.min(file_len);
+                    f.seek(SeekFrom::Start(from as u64))?;
+                    f.write_all(&image[from .. end])?;
+                    file_len = file_len.max(end);
+                }
+                f.sync_all()?;
+                Ok(dirty.len())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECTOR : usize = 16;
+
+    struct Flash {
+        image : Vec<u8>,
+        dirty : Vec<u32>,
+    }
+
+    impl Flash {
+        fn new(fill: u8) -> Self { Self { image: vec![fill; SECTOR * 4], dirty: Vec::new() } }
+        fn modify(&mut self, n: u32, v: u8) {
+            let start = n as usize * SECTOR;
+            self.image[start .. start + SECTOR].fill(v);
+            self.dirty.push(n);
+        }
+    }
+
+    impl SPIWorker for Flash {
+        fn init(&mut self)     -> bool { true }
+        fn remove(&mut self)   -> bool { true }
+        fn select(&mut self)   -> bool { true }
+        fn deselect(&mut self) -> bool { true }
+        fn write(&mut self, _d: u8) -> u8 { 0 }
+        fn image(&self) -> Option<&[u8]> { Some(&self.image) }
+        fn image_mut(&mut self) -> Option<&mut [u8]> { Some(&mut self.image) }
+        fn sector_size(&self) -> u32 { SECTOR as u32 }
+        fn take_dirty_sectors(&mut self) -> Vec<u32> { std::mem::take(&mut self.dirty) }
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("exrmips-flash-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn overlay_round_trip() {
+        let path = temp_path("overlay");
+        let mut flash = Flash::new(0xff);
+        let mut store = FlashStore::open_overlay(&path, &mut flash).unwrap();
+        assert_eq!(store.flush(&mut flash).unwrap(), 0);
+        assert!(!path.exists());
+
+        flash.modify(1, 0x11);
+        assert_eq!(store.flush(&mut flash).unwrap(), 1);
+        flash.modify(3, 0x33);
+        assert_eq!(store.flush(&mut flash).unwrap(), 1);
+
+        // both sectors are applied to a fresh image, the others are left as they are
+        let mut fresh = Flash::new(0xff);
+        let store = FlashStore::open_overlay(&path, &mut fresh).unwrap();
+        assert!(store.mode() == FlashStoreMode::Overlay);
+        assert_eq!(fresh.image, flash.image);
+        assert_eq!(fresh.image[0], 0xff);
+        assert_eq!(fresh.image[SECTOR], 0x11);
+        assert_eq!(fresh.image[SECTOR * 3], 0x33);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn overlay_of_another_flash() {
+        let path = temp_path("mismatch");
+        let mut flash = Flash::new(0xff);
+        let mut store = FlashStore::open_overlay(&path, &mut flash).unwrap();
+        flash.modify(0, 0);
+        store.flush(&mut flash).unwrap();
+
+        let mut larger = Flash { image: vec![0xff; SECTOR * 8], dirty: Vec::new() };
+        let err = FlashStore::open_overlay(&path, &mut larger).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(err.to_string(), "overlay is for a flash of 64 bytes with 16-byte sectors");
+        assert!(larger.image.iter().all(|b| *b == 0xff));
+
+        std::fs::write(&path, b"not an overlay file").unwrap();
+        let err = FlashStore::open_overlay(&path, &mut flash).err().unwrap();
+        assert_eq!(err.to_string(), "not a flash overlay file");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn write_back_extends_a_short_file() {
+        let path = temp_path("short");
+        std::fs::write(&path, [0xaa; SECTOR + 4]).unwrap();
+        let mut flash = Flash::new(0xff);
+        flash.image[.. SECTOR + 4].fill(0xaa);
+        let mut store = FlashStore::new(&path, FlashStoreMode::WriteBack);
+
+        flash.modify(3, 0x33);
+        assert_eq!(store.flush(&mut flash).unwrap(), 1);
+        // the gap up to the modified sector is filled with the flash contents
+        assert_eq!(std::fs::read(&path).unwrap(), flash.image);
+
+        flash.modify(0, 0x00);
+        assert_eq!(store.flush(&mut flash).unwrap(), 1);
+        assert_eq!(std::fs::read(&path).unwrap(), flash.image);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn failed_flush_is_retried() {
+        let dir = temp_path("dir");
+        let path = dir.join("flash.bin");
+        let _ = std::fs::remove_dir_all(&dir);
+        let mut flash = Flash::new(0xff);
+        let mut store = FlashStore::new(&path, FlashStoreMode::WriteBack);
+
+        flash.modify(2, 0x22);
+        assert!(store.flush(&mut flash).is_err());
+        assert!(flash.dirty.is_empty());
+
+        // the sectors taken by the failed flush are written by the next one
+        std::fs::create_dir(&dir).unwrap();
+        assert_eq!(store.flush(&mut flash).unwrap(), 1);
+        let data = std::fs::read(&path).unwrap();
+        assert_eq!(data.len(), SECTOR * 3);
+        assert_eq!(&data[SECTOR * 2 ..], &[0x22; SECTOR]);
+        assert_eq!(store.flush(&mut flash).unwrap(), 0);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,927 @@
+use crate::procstate::MachineState;
+use crate::exception;
+use crate::cp0def;
+use crate::mem;
+use crate::disasm;
+use crate::mips;
+use crate::exec_mips32::{MIPS32_OP_LDC1, MIPS32_OP_SWC1, MIPS32_OP_SDC1};
+use crate::c0_val;
+use crate::sign_ext16;
+use crate::update_pc_next32;
+use crate::update_pc_next32_with_delayed_imm;
+use log::{error,info};
+
+/*
+Floating point unit (CP1) of MIPS32R2.
+
+The FPU is available when Config1.FP is set (enable_fpu), and its instructions raise
+the Coprocessor Unusable exception unless Status.CU1 is set, so that the kernel can emulate them otherwise.
+
+Registers follow Status.FR:
+  FR=0 : 32 32-bit registers, and a 64-bit value occupies an even-odd pair (the odd one holds the upper half)
+  FR=1 : 32 64-bit registers, and a 32-bit value is in the lower half
+The formats S, D, W and L are supported (not PS). NaNs follow the legacy MIPS encoding,
+where the most significant bit of the fraction is set for signaling NaNs.
+
+The host computes the results rounded to nearest. The exact error of an operation
+(from an error-free transformation or a fused multiply-add) tells the inexact exception
+and the direction to adjust the result for the other rounding modes of FCSR.RM.
+*/
+
+macro_rules! unknown_instruction{
+    ( $inst:expr, $msg:expr ) =>
+    {
+        error!("Unknown MIPS32 instruction (inst={:>08x}, {})", $inst, $msg);
+        return false;
+    }
+}
+
+const FMT_S : u32 = 16;
+const FMT_D : u32 = 17;
+const FMT_W : u32 = 20;
+const FMT_L : u32 = 21;
+
+// FIR : 64-bit registers, L, W, D and S formats
+pub const FIR_VAL : u32 = (1<<22) | (1<<21) | (1<<20) | (1<<17) | (1<<16) | (cp0def::MIPS_PRID_PROCESSOR_ID_74K_CORE<<8);
+
+// control registers for cfc1/ctc1
+const FCR_FIR  : usize = 0;
+const FCR_FCCR : usize = 25;
+const FCR_FEXR : usize = 26;
+const FCR_FENR : usize = 28;
+pub const FCR_FCSR : usize = 31;
+
+// IEEE exceptions in the bit order of the Cause, Enables and Flags fields of FCSR
+const EXC_I : u32 = 1<<0; // inexact
+const EXC_U : u32 = 1<<1; // underflow
+const EXC_O : u32 = 1<<2; // overflow
+const EXC_Z : u32 = 1<<3; // divide by zero
+const EXC_V : u32 = 1<<4; // invalid operation
+const EXC_E : u32 = 1<<5; // unimplemented operation (Cause only)
+
+const FCSR_BIT_FLAGS  : u32 = 2;
+const FCSR_BIT_ENABLE : u32 = 7;
+const FCSR_BIT_CAUSE  : u32 = 12;
+const FCSR_BIT_FCC0   : u32 = 23;
+const FCSR_BIT_FS     : u32 = 24;
+const FCSR_BIT_FCC1   : u32 = 25;
+
+const FCSR_RM_MASK     : u32 = 3;
+const FCSR_FLAGS_MASK  : u32 = 0x1f << FCSR_BIT_FLAGS;
+const FCSR_ENABLE_MASK : u32 = 0x1f << FCSR_BIT_ENABLE;
+const FCSR_CAUSE_MASK  : u32 = 0x3f << FCSR_BIT_CAUSE;
+const FCSR_FCC_MASK    : u32 = (0x7f << FCSR_BIT_FCC1) | (1 << FCSR_BIT_FCC0);
+const FCSR_MASK        : u32 = FCSR_FCC_MASK | (1 << FCSR_BIT_FS) | FCSR_CAUSE_MASK | FCSR_ENABLE_MASK | FCSR_FLAGS_MASK | FCSR_RM_MASK;
+
+// rounding modes (FCSR.RM)
+const RM_NEAREST : u32 = 0;
+const RM_ZERO    : u32 = 1;
+const RM_PLUS    : u32 = 2;
+const RM_MINUS   : u32 = 3;
+
+pub struct Fpu {
+    pub fpr  : [u64; 32],
+    pub fcsr : u32,
+}
+
+impl Fpu {
+    pub fn new() -> Self {
+        Self { fpr: [0; 32], fcsr: 0 }
+    }
+
+    pub fn get32(&self, n: usize) -> u32 {
+        self.fpr[n] as u32
+    }
+
+    // The upper half of a 64-bit register is kept
+    pub fn set32(&mut self, n: usize, v: u32) {
+        self.fpr[n] = (self.fpr[n] & !0xffffffff) | v as u64;
+    }
+
+    pub fn get64(&self, fr: bool, n: usize) -> u64 {
+        if fr { self.fpr[n] }else{ ((self.get32(n | 1) as u64) << 32) | self.get32(n & !1) as u64 }
+    }
+
+    pub fn set64(&mut self, fr: bool, n: usize, v: u64) {
+        if fr {
+            self.fpr[n] = v;
+        }else{
+            self.set32(n & !1, v as u32);
+            self.set32(n | 1, (v >> 32) as u32);
+        }
+    }
+
+    // upper half of a 64-bit value (mfhc1, mthc1)
+    fn get_hi(&self, fr: bool, n: usize) -> u32 {
+        if fr { (self.fpr[n] >> 32) as u32 }else{ self.get32(n | 1) }
+    }
+
+    fn set_hi(&mut self, fr: bool, n: usize, v: u32) {
+        if fr {
+            self.fpr[n] = (self.fpr[n] & 0xffffffff) | ((v as u64) << 32);
+        }else{
+            self.set32(n | 1, v);
+        }
+    }
+
+    fn fcc(&self, cc: u32) -> bool {
+        let bit = if cc == 0 { FCSR_BIT_FCC0 }else{ FCSR_BIT_FCC1 + cc - 1 };
+        0 != (self.fcsr & (1 << bit))
+    }
+
+    fn set_fcc(&mut self, cc: u32, v: bool) {
+        let bit = if cc == 0 { FCSR_BIT_FCC0 }else{ FCSR_BIT_FCC1 + cc - 1 };
+        if v { self.fcsr |= 1 << bit; }else{ self.fcsr &= !(1 << bit); }
+    }
+
+    fn rm(&self) -> u32 {
+        self.fcsr & FCSR_RM_MASK
+    }
+
+    fn enables(&self) -> u32 {
+        (self.fcsr & FCSR_ENABLE_MASK) >> FCSR_BIT_ENABLE
+    }
+
+    // true if the cause bits raise the floating point exception
+    fn trapped(&self) -> bool {
+        let cause = (self.fcsr & FCSR_CAUSE_MASK) >> FCSR_BIT_CAUSE;
+        0 != (cause & (self.enables() | EXC_E))
+    }
+
+    /*
+    Records the exceptions of an operation in the Cause field.
+    Returns false if an enabled exception is raised; the result is not written and the Flags field is not updated then.
+    */
+    fn signal(&mut self, exc: u32) -> bool {
+        self.fcsr = (self.fcsr & !FCSR_CAUSE_MASK) | (exc << FCSR_BIT_CAUSE);
+        if self.trapped() {
+            return false;
+        }
+        self.fcsr |= (exc & 0x1f) << FCSR_BIT_FLAGS;
+        true
+    }
+
+    pub fn read_control(&self, fs: usize) -> u32 {
+        match fs {
+            FCR_FIR  => FIR_VAL,
+            FCR_FCCR => ((self.fcsr >> FCSR_BIT_FCC1) << 1) | ((self.fcsr >> FCSR_BIT_FCC0) & 1),
+            FCR_FEXR => self.fcsr & (FCSR_CAUSE_MASK | FCSR_FLAGS_MASK),
+            FCR_FENR => (self.fcsr & (FCSR_ENABLE_MASK | FCSR_RM_MASK)) | (((self.fcsr >> FCSR_BIT_FS) & 1) << 2),
+            FCR_FCSR => self.fcsr,
+            _        => 0,
+        }
+    }
+
+    pub fn write_control(&mut self, fs: usize, v: u32) {
+        match fs {
+            FCR_FCCR => { self.fcsr = (self.fcsr & !FCSR_FCC_MASK) | ((v >> 1) << FCSR_BIT_FCC1 & FCSR_FCC_MASK) | ((v & 1) << FCSR_BIT_FCC0); }
+            FCR_FEXR => { self.fcsr = (self.fcsr & !(FCSR_CAUSE_MASK | FCSR_FLAGS_MASK)) | (v & (FCSR_CAUSE_MASK | FCSR_FLAGS_MASK)); }
+            FCR_FENR => {
+                self.fcsr = (self.fcsr & !(FCSR_ENABLE_MASK | FCSR_RM_MASK | (1 << FCSR_BIT_FS))) |
+                    (v & (FCSR_ENABLE_MASK | FCSR_RM_MASK)) | (((v >> 2) & 1) << FCSR_BIT_FS);
+            }
+            FCR_FCSR => { self.fcsr = v & FCSR_MASK; }
+            _        => { }
+        }
+    }
+}
+
+// values of the S and D formats
+trait FpVal : Copy + PartialEq + PartialOrd +
+    std::ops::Add<Output=Self> + std::ops::Sub<Output=Self> + std::ops::Mul<Output=Self> + std::ops::Div<Output=Self> + std::ops::Neg<Output=Self> {
+    const QUIET_BIT   : u64;  // set for signaling NaNs in the legacy encoding
+    const DEFAULT_NAN : u64;
+    const ZERO        : Self;
+    const ONE         : Self;
+    const MAX         : Self;
+    const MIN_POSITIVE: Self;
+    const INFINITY    : Self;
+    const SCALE       : Self; // power of two scaling tiny operands so that residuals do not underflow
+    const TINY        : Self; // MIN_POSITIVE * SCALE, below which residuals may underflow
+
+    fn from_bits64(v: u64) -> Self;
+    fn to_bits64(self) -> u64;
+    fn to_f64(self) -> f64;
+    fn from_i64(v: i64) -> Self;
+    fn mul_add(self, a: Self, b: Self) -> Self;
+    fn sqrt(self) -> Self;
+    fn abs(self) -> Self;
+    fn is_nan(self) -> bool;
+    fn is_finite(self) -> bool;
+    fn is_sign_negative(self) -> bool;
+    fn next_up(self) -> Self;
+    fn next_down(self) -> Self;
+}
+
+macro_rules! impl_fpval {
+    ( $t:ty, $bits:ty, $quiet:expr, $nan:expr, $scale:expr ) => {
+        impl FpVal for $t {
+            const QUIET_BIT   : u64  = $quiet;
+            const DEFAULT_NAN : u64  = $nan;
+            const ZERO        : Self = 0.0;
+            const ONE         : Self = 1.0;
+            const MAX         : Self = <$t>::MAX;
+            const MIN_POSITIVE: Self = <$t>::MIN_POSITIVE;
+            const INFINITY    : Self = <$t>::INFINITY;
+            const SCALE       : Self = $scale;
+            const TINY        : Self = <$t>::MIN_POSITIVE * $scale;
+
+            fn from_bits64(v: u64) -> Self { <$t>::from_bits(v as $bits) }
+            fn to_bits64(self) -> u64 { self.to_bits() as u64 }
+            fn to_f64(self) -> f64 { self as f64 }
+            fn from_i64(v: i64) -> Self { v as $t }
+            fn mul_add(self, a: Self, b: Self) -> Self { <$t>::mul_add(self, a, b) }
+            fn sqrt(self) -> Self { <$t>::sqrt(self) }
+            fn abs(self) -> Self { <$t>::abs(self) }
+            fn is_nan(self) -> bool { <$t>::is_nan(self) }
+            fn is_finite(self) -> bool { <$t>::is_finite(self) }
+            fn is_sign_negative(self) -> bool { <$t>::is_sign_negative(self) }
+            fn next_up(self) -> Self { <$t>::next_up(self) }
+            fn next_down(self) -> Self { <$t>::next_down(self) }
+        }
+    }
+}
+
+impl_fpval!(f32, u32, 1<<22, 0x7fbfffff, 4611686018427387904.0 /* 2^62 */);
+impl_fpval!(f64, u64, 1<<51, 0x7ff7ffffffffffff, 340282366920938463463374607431768211456.0 /* 2^128 */);
+
+fn is_snan<F: FpVal>(x: F) -> bool {
+    x.is_nan() && 0 != (x.to_bits64() & F::QUIET_BIT)
+}
+
+fn default_nan<F: FpVal>() -> F {
+    F::from_bits64(F::DEFAULT_NAN)
+}
+
+// result for NaN operands: the default NaN for a signaling NaN, or the first quiet NaN
+fn nan_operand<F: FpVal>(ops: &[F]) -> Option<(F, u32)> {
+    if ops.iter().any(|x| is_snan(*x)) {
+        return Some((default_nan(), EXC_V));
+    }
+    ops.iter().find(|x| x.is_nan()).map(|x| (*x, 0))
+}
+
+// sign of a residual: 1 if the exact value is above the rounded one, -1 if below
+fn sign_of<F: FpVal>(err: F) -> i32 {
+    if err > F::ZERO { 1 }else if err < F::ZERO { -1 }else{ 0 }
+}
+
+/*
+Rounds the result r (rounded to nearest by the host) in the mode rm, where err is the sign of (exact - r),
+and detects overflow, underflow and inexact. finite tells whether all the operands were finite.
+*/
+fn round<F: FpVal>(r: F, err: i32, finite: bool, rm: u32, enables: u32) -> (F, u32) {
+    if r.is_nan() {
+        return (default_nan(), EXC_V);
+    }
+    let mut r = r;
+    let mut exc = 0;
+    if err != 0 {
+        exc |= EXC_I;
+        r = match rm {
+            RM_ZERO  if (err < 0) != r.is_sign_negative() => if r.is_sign_negative() { r.next_up() }else{ r.next_down() },
+            RM_PLUS  if err > 0 => r.next_up(),
+            RM_MINUS if err < 0 => r.next_down(),
+            _ => r,
+        };
+    }
+    if finite && !r.is_finite() {
+        exc |= EXC_O | EXC_I;
+        let neg = r.is_sign_negative();
+        r = match rm {
+            RM_ZERO                 => F::MAX,
+            RM_PLUS  if neg         => F::MAX,
+            RM_MINUS if !neg        => F::MAX,
+            _                       => F::INFINITY,
+        };
+        if neg { r = -r; }
+    }
+    if r.abs() < F::MIN_POSITIVE && (r != F::ZERO || 0 != (exc & EXC_I)) && (0 != (exc & EXC_I) || 0 != (enables & EXC_U)) {
+        exc |= EXC_U;
+    }
+    (r, exc)
+}
+
+fn add<F: FpVal>(a: F, b: F, rm: u32, enables: u32) -> (F, u32) {
+    if let Some(n) = nan_operand(&[a, b]) {
+        return n;
+    }
+    let r = a + b;
+    if !r.is_finite() || !a.is_finite() || !b.is_finite() {
+        return round(r, 0, a.is_finite() && b.is_finite(), rm, enables);
+    }
+    // TwoSum
+    let bb  = r - a;
+    let err = (a - (r - bb)) + (b - bb);
+    if r == F::ZERO && err == F::ZERO && rm == RM_MINUS &&
+        (!(a == F::ZERO && b == F::ZERO) || a.is_sign_negative() || b.is_sign_negative()) {
+        // an exact zero sum is -0 when rounding toward minus infinity
+        return (-F::ZERO, 0);
+    }
+    round(r, sign_of(err), true, rm, enables)
+}
+
+fn mul<F: FpVal>(a: F, b: F, rm: u32, enables: u32) -> (F, u32) {
+    if let Some(n) = nan_operand(&[a, b]) {
+        return n;
+    }
+    let r = a * b;
+    let err = if !r.is_finite() || a == F::ZERO || b == F::ZERO {
+        0
+    }else if r == F::ZERO {
+        // the exact product is below the smallest subnormal
+        if a.is_sign_negative() != b.is_sign_negative() { -1 }else{ 1 }
+    }else if r.abs() < F::TINY {
+        // the smaller operand and the result are scaled
+        let (s, t) = if a.abs() < b.abs() { (a, b) }else{ (b, a) };
+        sign_of((s * F::SCALE).mul_add(t, -(r * F::SCALE)))
+    }else{
+        sign_of(a.mul_add(b, -r))
+    };
+    round(r, err, a.is_finite() && b.is_finite(), rm, enables)
+}
+
+fn div<F: FpVal>(a: F, b: F, rm: u32, enables: u32) -> (F, u32) {
+    if let Some(n) = nan_operand(&[a, b]) {
+        return n;
+    }
+    if b == F::ZERO && a != F::ZERO && a.is_finite() {
+        return (a / b, EXC_Z);
+    }
+    let r = a / b;
+    let err = if r.is_finite() && b.is_finite() && b != F::ZERO {
+        let rem = if a.abs() < F::TINY {
+            // the dividend and the smaller of the divisor and the result are scaled
+            if r.abs() < b.abs() {
+                (-(r * F::SCALE)).mul_add(b, a * F::SCALE)
+            }else{
+                (-r).mul_add(b * F::SCALE, a * F::SCALE)
+            }
+        }else{
+            (-r).mul_add(b, a)
+        };
+        sign_of(rem) * if b.is_sign_negative() { -1 }else{ 1 }
+    }else{
+        0
+    };
+    round(r, err, a.is_finite() && b.is_finite(), rm, enables)
+}
+
+fn sqrt<F: FpVal>(a: F, rm: u32, enables: u32) -> (F, u32) {
+    if let Some(n) = nan_operand(&[a]) {
+        return n;
+    }
+    if a < F::ZERO {
+        return (default_nan(), EXC_V);
+    }
+    let r = a.sqrt();
+    let err = if !r.is_finite() {
+        0
+    }else if a < F::TINY {
+        // the operand is scaled by SCALE^2 and the result by SCALE
+        let rs = r * F::SCALE;
+        sign_of((-rs).mul_add(rs, a * F::SCALE * F::SCALE))
+    }else{
+        sign_of((-r).mul_add(r, a))
+    };
+    round(r, err, true, rm, enables)
+}
+
+// c.cond.fmt : cond[0] unordered, cond[1] equal, cond[2] less than, cond[3] signaling for quiet NaNs
+fn compare<F: FpVal>(a: F, b: F, cond: u32) -> (bool, u32) {
+    let unordered = a.is_nan() || b.is_nan();
+    let exc = if is_snan(a) || is_snan(b) || (unordered && 0 != (cond & 8)) { EXC_V }else{ 0 };
+    let res = (0 != (cond & 4) && a < b) || (0 != (cond & 2) && a == b) || (0 != (cond & 1) && unordered);
+    (res, exc)
+}
+
+// conversion to a 32-bit (W) or 64-bit (L) integer, which is the maximum positive value for an invalid operation
+fn to_int<F: FpVal>(a: F, rm: u32, bits: u32) -> (u64, u32) {
+    let max = (1u64 << (bits - 1)) - 1;
+    if !a.is_finite() {
+        return (max, EXC_V);
+    }
+    let x = a.to_f64();
+    let r = match rm {
+        RM_ZERO  => x.trunc(),
+        RM_PLUS  => x.ceil(),
+        RM_MINUS => x.floor(),
+        _        => x.round_ties_even(),
+    };
+    let limit = (1u64 << (bits - 1)) as f64;
+    if r >= limit || r < -limit {
+        return (max, EXC_V);
+    }
+    let v = if bits == 32 { (r as i32) as u32 as u64 }else{ (r as i64) as u64 };
+    (v, if r != x { EXC_I }else{ 0 })
+}
+
+fn from_int<F: FpVal>(v: i64, rm: u32, enables: u32) -> (F, u32) {
+    let r = F::from_i64(v);
+    let err = ((v as i128) - (r.to_f64() as i128)).signum() as i32;
+    round(r, err, true, rm, enables)
+}
+
+fn cvt_s_d(a: f64, rm: u32, enables: u32) -> (f32, u32) {
+    if let Some((_, exc)) = nan_operand(&[a]) {
+        return (default_nan(), exc);
+    }
+    let r = a as f32;
+    let err = if r.is_finite() { sign_of(a - r as f64) }else{ 0 };
+    round(r, err, a.is_finite(), rm, enables)
+}
+
+fn cvt_d_s(a: f32) -> (f64, u32) {
+    if let Some((_, exc)) = nan_operand(&[a]) {
+        return (default_nan(), exc);
+    }
+    (a as f64, 0)
+}
+
+// raises the Coprocessor Unusable exception if the FPU is not available. Returns true if available.
+fn check_usable(ms: &mut MachineState) -> bool {
+    if !present(ms) || 0 == (c0_val!(ms.reg, cp0def::C0_STATUS) & (1<<cp0def::C0_STATUS_BIT_CU1)) {
+        exception::prepare_exception(ms, cp0def::EXCEPT_CODE_COPROCESSOR_UNAVAIL, 1);
+        return false;
+    }
+    true
+}
+
+pub fn present(ms: &MachineState) -> bool {
+    0 != (c0_val!(ms.reg, cp0def::C0_CONFIG1) & cp0def::C0_CONFIG1_FP)
+}
+
+fn fr(ms: &MachineState) -> bool {
+    0 != (c0_val!(ms.reg, cp0def::C0_STATUS) & (1<<cp0def::C0_STATUS_BIT_FR))
+}
+
+// an operation which the FPU does not implement (the kernel may emulate it)
+fn unimplemented(ms: &mut MachineState) {
+    ms.reg.fpu.signal(EXC_E);
+    exception::prepare_exception(ms, cp0def::EXCEPT_CODE_FP_EXCEPTION, 0);
+}
+
+// writes the result of an operation, or raises the floating point exception
+fn commit(ms: &mut MachineState, exc: u32, write: impl FnOnce(&mut Fpu)) {
+    if ms.reg.fpu.signal(exc) {
+        write(&mut ms.reg.fpu);
+        update_pc_next32!(ms);
+    }else{
+        exception::prepare_exception(ms, cp0def::EXCEPT_CODE_FP_EXCEPTION, 0);
+    }
+}
+
+fn read_fmt(ms: &MachineState, fmt: u32, n: usize) -> u64 {
+    match fmt {
+        FMT_S | FMT_W => ms.reg.fpu.get32(n) as u64,
+        _             => ms.reg.fpu.get64(fr(ms), n),
+    }
+}
+
+fn write_fmt(fpu: &mut Fpu, fr: bool, fmt: u32, n: usize, v: u64) {
+    match fmt {
+        FMT_S | FMT_W => fpu.set32(n, v as u32),
+        _             => fpu.set64(fr, n, v),
+    }
+}
+
+// arithmetic instructions of the S and D formats
+fn exec_arith<F: FpVal>(ms: &mut MachineState, inst: u32, fmt: u32) -> bool {
+    let ft    = ((inst>>16) & 0x1f) as usize;
+    let fs    = ((inst>>11) & 0x1f) as usize;
+    let fd    = ((inst>> 6) & 0x1f) as usize;
+    let funct =   inst      & 0x3f;
+    let fr    = fr(ms);
+    let rm    = ms.reg.fpu.rm();
+    let en    = ms.reg.fpu.enables();
+
+    let a = F::from_bits64(read_fmt(ms, fmt, fs));
+    let b = F::from_bits64(read_fmt(ms, fmt, ft));
+
+    let arith = |(r, exc): (F, u32)| (r.to_bits64(), exc);
+    let (res, exc) = match funct {
+        0x00 => arith(add(a, b, rm, en)),
+        0x01 => arith(add(a, -b, rm, en)),
+        0x02 => arith(mul(a, b, rm, en)),
+        0x03 => arith(div(a, b, rm, en)),
+        0x04 => arith(sqrt(a, rm, en)),
+        0x05 => (a.abs().to_bits64(), 0),
+        0x06 => (a.to_bits64(), 0),
+        0x07 => ((-a).to_bits64(), 0),
+        0x08..=0x0f => {
+            // round, trunc, ceil and floor to L (0x08-0x0b) and W (0x0c-0x0f)
+            let bits = if funct < 0x0c { 64 }else{ 32 };
+            let (v, exc) = to_int(a, funct & 3, bits);
+            return commit_fmt(ms, exc, if bits == 64 { FMT_L }else{ FMT_W }, fd, v);
+        }
+        0x11 => {
+            // movf.fmt, movt.fmt
+            let cc = (inst>>18) & 7;
+            if ms.reg.fpu.fcc(cc) == (0 != (inst & (1<<16))) {
+                write_fmt(&mut ms.reg.fpu, fr, fmt, fd, a.to_bits64());
+            }
+            update_pc_next32!(ms);
+            return true;
+        }
+        0x12 | 0x13 => {
+            // movz.fmt, movn.fmt
+            if (ms.reg.r[ft] == 0) == (funct == 0x12) {
+                write_fmt(&mut ms.reg.fpu, fr, fmt, fd, a.to_bits64());
+            }
+            update_pc_next32!(ms);
+            return true;
+        }
+        0x15 => arith(div(F::ONE, a, rm, en)),
+        0x16 => {
+            // rsqrt.fmt : the square root is rounded before the division
+            let (s, exc) = sqrt(a, rm, en);
+            if s.is_nan() {
+                (s.to_bits64(), exc)
+            }else{
+                let (r, exc2) = div(F::ONE, s, rm, en);
+                (r.to_bits64(), exc | exc2)
+            }
+        }
+        0x20 if fmt == FMT_D => {
+            let (r, exc) = cvt_s_d(f64::from_bits(a.to_bits64()), rm, en);
+            return commit_fmt(ms, exc, FMT_S, fd, r.to_bits() as u64);
+        }
+        0x21 if fmt == FMT_S => {
+            let (r, exc) = cvt_d_s(f32::from_bits(a.to_bits64() as u32));
+            return commit_fmt(ms, exc, FMT_D, fd, r.to_bits());
+        }
+        0x24 | 0x25 => {
+            let bits = if funct == 0x25 { 64 }else{ 32 };
+            let (v, exc) = to_int(a, rm, bits);
+            return commit_fmt(ms, exc, if bits == 64 { FMT_L }else{ FMT_W }, fd, v);
+        }
+        0x30..=0x3f => {
+            let cc = (inst>>8) & 7;
+            let (res, exc) = compare(a, b, funct & 0xf);
+            commit(ms, exc, |fpu| fpu.set_fcc(cc, res));
+            return true;
+        }
+        _ => {
+            unimplemented(ms);
+            return true;
+        }
+    };
+    commit_fmt(ms, exc, fmt, fd, res)
+}
+
+fn commit_fmt(ms: &mut MachineState, exc: u32, fmt: u32, fd: usize, v: u64) -> bool {
+    let fr = fr(ms);
+    commit(ms, exc, |fpu| write_fmt(fpu, fr, fmt, fd, v));
+    true
+}
+
+// conversions from the W and L formats
+fn exec_cvt_int(ms: &mut MachineState, inst: u32, fmt: u32) -> bool {
+    let fs    = ((inst>>11) & 0x1f) as usize;
+    let fd    = ((inst>> 6) & 0x1f) as usize;
+    let funct =   inst      & 0x3f;
+    let rm    = ms.reg.fpu.rm();
+    let en    = ms.reg.fpu.enables();
+
+    let v = if fmt == FMT_W { read_fmt(ms, fmt, fs) as u32 as i32 as i64 }else{ read_fmt(ms, fmt, fs) as i64 };
+    match funct {
+        0x20 => {
+            let (r, exc) = from_int::<f32>(v, rm, en);
+            commit_fmt(ms, exc, FMT_S, fd, r.to_bits() as u64)
+        }
+        0x21 => {
+            let (r, exc) = from_int::<f64>(v, rm, en);
+            commit_fmt(ms, exc, FMT_D, fd, r.to_bits())
+        }
+        _ => {
+            unimplemented(ms);
+            true
+        }
+    }
+}
+
+// COP1 (opcode 0x11)
+pub fn exec_cop1(ms: &mut MachineState, inst: u32) -> bool {
+    if ms.emu.debug { info!("{}", disasm::disasm_mips32(ms.reg.pc, inst)); }
+    if !check_usable(ms) {
+        return true;
+    }
+
+    let fmt = (inst>>21) & 0x1f;
+    let rt  = ((inst>>16) & 0x1f) as usize;
+    let fs  = ((inst>>11) & 0x1f) as usize;
+    let imm =   inst      & 0xffff;
+    let fr  = fr(ms);
+
+    match fmt {
+        0x00 => { ms.reg.r[rt] = ms.reg.fpu.get32(fs); }                  // mfc1
+        0x02 => { ms.reg.r[rt] = ms.reg.fpu.read_control(fs); }           // cfc1
+        0x03 => { ms.reg.r[rt] = ms.reg.fpu.get_hi(fr, fs); }             // mfhc1
+        0x04 => { ms.reg.fpu.set32(fs, ms.reg.r[rt]); }                   // mtc1
+        0x06 => {                                                         // ctc1
+            ms.reg.fpu.write_control(fs, ms.reg.r[rt]);
+            if ms.reg.fpu.trapped() {
+                exception::prepare_exception(ms, cp0def::EXCEPT_CODE_FP_EXCEPTION, 0);
+                return true;
+            }
+        }
+        0x07 => { ms.reg.fpu.set_hi(fr, fs, ms.reg.r[rt]); }              // mthc1
+        0x08 => {
+            // bc1f, bc1t, bc1fl, bc1tl
+            let cc     = (inst>>18) & 7;
+            let likely = 0 != (inst & (1<<17));
+            let taken  = ms.reg.fpu.fcc(cc) == (0 != (inst & (1<<16)));
+            if taken {
+                update_pc_next32_with_delayed_imm!(ms, ms.reg.pc + (sign_ext16!(imm) << 2) + 4 );
+            }else{
+                update_pc_next32!(ms);
+                if likely {
+                    update_pc_next32!(ms);
+                }
+            }
+            return true;
+        }
+        FMT_S => { return exec_arith::<f32>(ms, inst, fmt); }
+        FMT_D => { return exec_arith::<f64>(ms, inst, fmt); }
+        FMT_W | FMT_L => { return exec_cvt_int(ms, inst, fmt); }
+        _ => {
+            unknown_instruction!(inst, "op=0x11, coprocessor 1 instruction");
+        }
+    }
+    update_pc_next32!(ms);
+    true
+}
+
+fn load_double(ms: &mut MachineState, addr: u32) -> Result<u64, u32> {
+    if 0 != (addr & 7) {
+        return Err(cp0def::EXCEPT_CODE_ADDR_ERR_LOAD);
+    }
+    let hi = mem::load_word(ms, addr)?;
+    let lo = mem::load_word(ms, addr + 4)?;
+    Ok(((hi as u64) << 32) | lo as u64)
+}
+
+fn store_double(ms: &mut MachineState, addr: u32, v: u64) -> Result<(), u32> {
+    if 0 != (addr & 7) {
+        return Err(cp0def::EXCEPT_CODE_ADDR_ERR_STORE);
+    }
+    mem::store_word(ms, addr, (v >> 32) as u32)?;
+    mem::store_word(ms, addr + 4, v as u32)
+}
+
+// a load or store of an FPR: 4 or 8 bytes at addr
+fn load_store(ms: &mut MachineState, addr: u32, n: usize, double: bool, store: bool) {
+    let fr = fr(ms);
+    let res = match (double, store) {
+        (false, false) => mem::load_word(ms, addr).map(|v| ms.reg.fpu.set32(n, v)),
+        (true,  false) => load_double(ms, addr).map(|v| ms.reg.fpu.set64(fr, n, v)),
+        (false, true ) => mem::store_word(ms, addr, ms.reg.fpu.get32(n)),
+        (true,  true ) => store_double(ms, addr, ms.reg.fpu.get64(fr, n)),
+    };
+    match res {
+        Ok(())     => { update_pc_next32!(ms); }
+        Err(ecode) => { exception::prepare_exception(ms, ecode, addr); }
+    }
+}
+
+// lwc1, ldc1, swc1 and sdc1
+pub fn exec_load_store(ms: &mut MachineState, inst: u32) -> bool {
+    if ms.emu.debug { info!("{}", disasm::disasm_mips32(ms.reg.pc, inst)); }
+    if !check_usable(ms) {
+        return true;
+    }
+    let op   = (inst>>26) & 0x3f;
+    let base = ((inst>>21) & 0x1f) as usize;
+    let ft   = ((inst>>16) & 0x1f) as usize;
+    let addr = ms.reg.r[base] + sign_ext16!(inst & 0xffff);
+
+    let double = op == MIPS32_OP_LDC1 || op == MIPS32_OP_SDC1;
+    let store  = op == MIPS32_OP_SWC1 || op == MIPS32_OP_SDC1;
+    load_store(ms, addr, ft, double, store);
+    true
+}
+
+// COP1X (opcode 0x13) : indexed loads and stores, and multiply-add
+pub fn exec_cop1x(ms: &mut MachineState, inst: u32) -> bool {
+    if ms.emu.debug { info!("{}", disasm::disasm_mips32(ms.reg.pc, inst)); }
+    if !check_usable(ms) {
+        return true;
+    }
+    let base  = ((inst>>21) & 0x1f) as usize;
+    let index = ((inst>>16) & 0x1f) as usize;
+    let fs    = ((inst>>11) & 0x1f) as usize;
+    let fd    = ((inst>> 6) & 0x1f) as usize;
+    let funct =   inst      & 0x3f;
+    let addr  = ms.reg.r[base] + ms.reg.r[index];
+
+    match funct {
+        0x00 => load_store(ms, addr,      fd, false, false), // lwxc1
+        0x01 => load_store(ms, addr,      fd, true,  false), // ldxc1
+        0x05 => load_store(ms, addr & !7, fd, true,  false), // luxc1
+        0x08 => load_store(ms, addr,      fs, false, true ), // swxc1
+        0x09 => load_store(ms, addr,      fs, true,  true ), // sdxc1
+        0x0d => load_store(ms, addr & !7, fs, true,  true ), // suxc1
+        0x0f => { update_pc_next32!(ms); }                    // prefx
+        0x20 | 0x28 | 0x30 | 0x38 => { return exec_madd::<f32>(ms, inst, FMT_S); }
+        0x21 | 0x29 | 0x31 | 0x39 => { return exec_madd::<f64>(ms, inst, FMT_D); }
+        _ => {
+            unknown_instruction!(inst, "op=0x13, coprocessor 1 instruction");
+        }
+    }
+    true
+}
+
+// madd.fmt, msub.fmt, nmadd.fmt and nmsub.fmt (the product is rounded before the addition)
+fn exec_madd<F: FpVal>(ms: &mut MachineState, inst: u32, fmt: u32) -> bool {
+    let fr_ = ((inst>>21) & 0x1f) as usize;
+    let ft  = ((inst>>16) & 0x1f) as usize;
+    let fs  = ((inst>>11) & 0x1f) as usize;
+    let fd  = ((inst>> 6) & 0x1f) as usize;
+    let op  = (inst>>3) & 7;
+    let rm  = ms.reg.fpu.rm();
+    let en  = ms.reg.fpu.enables();
+
+    let a = F::from_bits64(read_fmt(ms, fmt, fs));
+    let b = F::from_bits64(read_fmt(ms, fmt, ft));
+    let c = F::from_bits64(read_fmt(ms, fmt, fr_));
+
+    let (p, exc1) = mul(a, b, rm, en);
+    let (r, exc2) = add(p, if op == 5 || op == 7 { -c }else{ c }, rm, en);
+    let r = if (op == 6 || op == 7) && !r.is_nan() { -r }else{ r };
+    commit_fmt(ms, exc1 | exc2, fmt, fd, r.to_bits64())
+}
+
+// movf and movt (SPECIAL, funct 0x01)
+pub fn exec_movci(ms: &mut MachineState, inst: u32) -> bool {
+    if ms.emu.debug { info!("{}", disasm::disasm_mips32(ms.reg.pc, inst)); }
+    if !check_usable(ms) {
+        return true;
+    }
+    let rs = ((inst>>21) & 0x1f) as usize;
+    let rd = ((inst>>11) & 0x1f) as usize;
+    let cc = (inst>>18) & 7;
+    if ms.reg.fpu.fcc(cc) == (0 != (inst & (1<<16))) {
+        ms.reg.r[rd] = ms.reg.r[rs];
+    }
+    update_pc_next32!(ms);
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EPS_S : f32 = 1.0 / (1<<24) as f32; // half an ulp of 1.0
+
+    #[test]
+    fn add_rounds_in_each_mode() {
+        assert_eq!(add(1.0f32, EPS_S, RM_NEAREST, 0), (1.0, EXC_I));
+        assert_eq!(add(1.0f32, EPS_S, RM_ZERO,    0), (1.0, EXC_I));
+        assert_eq!(add(1.0f32, EPS_S, RM_PLUS,    0), (1.0f32.next_up(), EXC_I));
+        assert_eq!(add(1.0f32, EPS_S, RM_MINUS,   0), (1.0, EXC_I));
+        assert_eq!(add(-1.0f32, -EPS_S, RM_ZERO,  0), (-1.0, EXC_I));
+        assert_eq!(add(-1.0f32, -EPS_S, RM_MINUS, 0), ((-1.0f32).next_down(), EXC_I));
+        assert_eq!(add(1.0f64, 2.0, RM_NEAREST, 0), (3.0, 0));
+    }
+
+    #[test]
+    fn exact_zero_sum_sign() {
+        let (r, exc) = add(1.0f64, -1.0, RM_NEAREST, 0);
+        assert!(r == 0.0 && !r.is_sign_negative() && exc == 0);
+        let (r, exc) = add(1.0f64, -1.0, RM_MINUS, 0);
+        assert!(r == 0.0 && r.is_sign_negative() && exc == 0);
+        let (r, _) = add(0.0f64, 0.0, RM_MINUS, 0);
+        assert!(!r.is_sign_negative());
+        let (r, _) = add(-0.0f64, 0.0, RM_MINUS, 0);
+        assert!(r.is_sign_negative());
+    }
+
+    #[test]
+    fn overflow_depends_on_rounding_mode() {
+        assert_eq!(mul(f32::MAX, 2.0, RM_NEAREST, 0), (f32::INFINITY, EXC_O | EXC_I));
+        assert_eq!(mul(f32::MAX, 2.0, RM_ZERO,    0), (f32::MAX, EXC_O | EXC_I));
+        assert_eq!(mul(f32::MAX, -2.0, RM_PLUS,   0), (-f32::MAX, EXC_O | EXC_I));
+        assert_eq!(mul(f32::MAX, -2.0, RM_MINUS,  0), (f32::NEG_INFINITY, EXC_O | EXC_I));
+        // an infinite operand is not an overflow
+        assert_eq!(add(f64::INFINITY, 1.0, RM_NEAREST, 0), (f64::INFINITY, 0));
+    }
+
+    #[test]
+    fn underflow_is_signaled_when_inexact_or_enabled() {
+        let half_min = f32::MIN_POSITIVE / 2.0;
+        assert_eq!(mul(f32::MIN_POSITIVE, 0.5, RM_NEAREST, 0), (half_min, 0));
+        assert_eq!(mul(f32::MIN_POSITIVE, 0.5, RM_NEAREST, EXC_U), (half_min, EXC_U));
+        assert_eq!(mul(f32::MIN_POSITIVE, f32::MIN_POSITIVE, RM_NEAREST, 0), (0.0, EXC_U | EXC_I));
+    }
+
+    // f32 rounding of an exact value given in f64 and whether it is inexact
+    fn round_exact(exact: f64, rm: u32) -> (f32, bool) {
+        let n = exact as f32;
+        if n as f64 == exact {
+            return (n, false);
+        }
+        let (lo, hi) = if (n as f64) < exact { (n, n.next_up()) }else{ (n.next_down(), n) };
+        let r = match rm {
+            RM_PLUS  => hi,
+            RM_MINUS => lo,
+            RM_ZERO  => if exact > 0.0 { lo }else{ hi },
+            _        => n,
+        };
+        (r, true)
+    }
+
+    #[test]
+    fn tiny_results_are_rounded_exactly() {
+        let mut x : u32 = 1;
+        let mut next = move || { x = x.wrapping_mul(1664525).wrapping_add(1013904223); x };
+        for _ in 0..20000 {
+            let a = f32::from_bits(next() % 0x0c000000 + 1) * if 0 != (next() & 1) { -1.0 }else{ 1.0 };
+            let b = f32::from_bits(next() % 0x20000000 + 0x20000000);
+            for rm in [RM_NEAREST, RM_ZERO, RM_PLUS, RM_MINUS] {
+                let (r, exc) = mul(a, b, rm, 0);
+                let (e, inexact) = round_exact(a as f64 * b as f64, rm);
+                assert!(r == e && (0 != (exc & EXC_I)) == inexact, "mul {:e} {:e} rm {}: {:e} {:e}", a, b, rm, r, e);
+
+                let (r, exc) = div(a, b.recip() * 0.75, rm, 0);
+                let q = div(a, b.recip() * 0.75, RM_NEAREST, 0).0;
+                let rem = a as f64 - q as f64 * (b.recip() * 0.75) as f64;
+                assert_eq!(0 != (exc & EXC_I), rem != 0.0, "div {:e} {:e}", a, b);
+                if rem == 0.0 { assert!(r == q); }
+
+                let (r, exc) = sqrt(a.abs(), rm, 0);
+                let q = (a.abs() as f64).sqrt() as f32;
+                let rem = a.abs() as f64 - q as f64 * q as f64;
+                assert_eq!(0 != (exc & EXC_I), rem != 0.0, "sqrt {:e}", a);
+                let (e, _) = round_exact(q as f64 * (1.0 + rem.signum() * 1e-12), rm);
+                assert!(rem == 0.0 || r == e, "sqrt {:e} rm {}: {:e} {:e}", a, rm, r, e);
+            }
+        }
+    }
+
+    #[test]
+    fn invalid_operations_and_division_by_zero() {
+        let nan = default_nan::<f64>().to_bits();
+        assert_eq!(div(1.0f64, 0.0, RM_NEAREST, 0), (f64::INFINITY, EXC_Z));
+        let (r, exc) = div(0.0f64, 0.0, RM_NEAREST, 0);
+        assert_eq!((r.to_bits(), exc), (nan, EXC_V));
+        let (r, exc) = sqrt(-1.0f64, RM_NEAREST, 0);
+        assert_eq!((r.to_bits(), exc), (nan, EXC_V));
+        assert_eq!(sqrt(4.0f64, RM_NEAREST, 0), (2.0, 0));
+        assert_eq!(div(1.0f64, 3.0, RM_NEAREST, 0).1, EXC_I);
+    }
+
+    #[test]
+    fn nan_operands_use_the_legacy_encoding() {
+        let snan = f32::from_bits(0x7fc00000); // the quiet bit set means signaling in MIPS32R2
+        let qnan = f32::from_bits(0x7fbfffff);
+        assert!(is_snan(snan) && !is_snan(qnan));
+        let (r, exc) = add(snan, 1.0, RM_NEAREST, 0);
+        assert_eq!((r.to_bits(), exc), (0x7fbfffff, EXC_V));
+        let (r, exc) = add(1.0, qnan, RM_NEAREST, 0);
+        assert_eq!((r.to_bits(), exc), (0x7fbfffff, 0));
+    }
+
+    #[test]
+    fn compare_conditions() {
+        let qnan = f64::from_bits(0x7ff7ffffffffffff);
+        assert_eq!(compare(1.0f64, 2.0, 4), (true, 0));          // c.olt
+        assert_eq!(compare(2.0f64, 2.0, 4), (false, 0));         // c.olt
+        assert_eq!(compare(2.0f64, 2.0, 6), (true, 0));          // c.ole
+        assert_eq!(compare(qnan, 2.0, 1), (true, 0));            // c.un
+        assert_eq!(compare(qnan, 2.0, 2), (false, 0));           // c.eq
+        assert_eq!(compare(qnan, 2.0, 8 | 2), (false, EXC_V));   // c.seq
+    }
+
+    #[test]
+    fn conversion_to_integers() {
+        assert_eq!(to_int(2.5f64, RM_NEAREST, 32), (2, EXC_I));
+        assert_eq!(to_int(3.5f64, RM_NEAREST, 32), (4, EXC_I));
+        assert_eq!(to_int(-1.5f64, RM_MINUS, 32), (0xfffffffe, EXC_I));
+        assert_eq!(to_int(-1.5f64, RM_ZERO, 32), (0xffffffff, EXC_I));
+        assert_eq!(to_int(7.0f32, RM_NEAREST, 64), (7, 0));
+        assert_eq!(to_int(1e10f64, RM_NEAREST, 32), (0x7fffffff, EXC_V));
+        assert_eq!(to_int(f64::NAN, RM_NEAREST, 64), (0x7fffffffffffffff, EXC_V));
+    }
+
+    #[test]
+    fn conversion_between_formats() {
+        assert_eq!(from_int::<f32>(16777217, RM_NEAREST, 0), (16777216.0, EXC_I));
+        assert_eq!(from_int::<f32>(16777217, RM_PLUS, 0), (16777218.0, EXC_I));
+        assert_eq!(from_int::<f64>(-5, RM_NEAREST, 0), (-5.0, 0));
+        assert_eq!(cvt_s_d(1.0 + 1.0 / (1u64<<30) as f64, RM_NEAREST, 0), (1.0, EXC_I));
+        assert_eq!(cvt_s_d(1.0 + 1.0 / (1u64<<30) as f64, RM_PLUS, 0), (1.0f32.next_up(), EXC_I));
+        assert_eq!(cvt_s_d(1e300, RM_NEAREST, 0), (f32::INFINITY, EXC_O | EXC_I));
+        assert_eq!(cvt_d_s(0.5), (0.5, 0));
+    }
+
+    #[test]
+    fn register_pairs_without_fr() {
+        let mut fpu = Fpu::new();
+        fpu.set64(false, 2, 0x1122334455667788);
+        assert_eq!((fpu.get32(2), fpu.get32(3)), (0x55667788, 0x11223344));
+        assert_eq!(fpu.get64(false, 2), 0x1122334455667788);
+        fpu.set64(true, 4, 0x1122334455667788);
+        assert_eq!(fpu.get32(4), 0x55667788);
+        assert_eq!(fpu.get_hi(true, 4), 0x11223344);
+    }
+}
@@ -0,0 +1,910 @@
+use crate::procstate::MachineState;
+use crate::cp0def;
+use crate::cp0;
+use crate::mem;
+use crate::mips;
+use crate::fpu;
+use crate::dsp;
+use crate::c0_val;
+use log::{info,error};
+
+use std::io;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+
+/*
+GDB remote serial protocol stub.
+
+The stub is polled from the main loop of the console emulator.
+While the target is running, the main loop calls check() before each instruction
+and poll() every host timer period to accept a client and to receive Ctrl-C (0x03).
+While the target is stopped, serve() processes packets until the client resumes the target.
+
+Breakpoints are kept in the stub (Z0/Z1) and the memory image is never patched,
+so that breakpoints can also be placed in the flash memory.
+*/
+
+const SIGINT  : u8 = 2;
+const SIGTRAP : u8 = 5;
+
+const PACKET_SIZE : usize = 0x4000;
+
+/*
+Register numbers follow the target description below.
+ 0-31 : general purpose registers
+ 32-37: status, lo, hi, badvaddr, cause, pc
+ 38-71: f0-f31, fcsr, fir
+ 72-  : other CP0 registers (GDB_CP0_EXTRA_REGS)
+ then : hi1, lo1, hi2, lo2, hi3, lo3 and dspctl of DSP ASE
+*/
+const GDB_REG_STATUS   : usize = 32;
+const GDB_REG_LO       : usize = 33;
+const GDB_REG_HI       : usize = 34;
+const GDB_REG_BADVADDR : usize = 35;
+const GDB_REG_CAUSE    : usize = 36;
+const GDB_REG_PC       : usize = 37;
+const GDB_REG_FPU_BASE : usize = 38;
+const GDB_REG_FPU_END  : usize = 69;
+const GDB_REG_FCSR     : usize = 70;
+const GDB_REG_FIR      : usize = 71;
+const GDB_REG_CP0_BASE : usize = 72;
+
+const GDB_CP0_EXTRA_REGS : [(&str, (u32,u32)); 22] = [
+    ("index",    cp0def::C0_INDEX   ),
+    ("random",   cp0def::C0_RANDOM  ),
+    ("entrylo0", cp0def::C0_ENTRYLO0),
+    ("entrylo1", cp0def::C0_ENTRYLO1),
+    ("context",  cp0def::C0_CONTEXT ),
+    ("pagemask", cp0def::C0_PAGEMASK),
+    ("wired",    cp0def::C0_WIRED   ),
+    ("hwrena",   cp0def::C0_HWRENA  ),
+    ("count",    cp0def::C0_COUNT   ),
+    ("entryhi",  cp0def::C0_ENTRYHI ),
+    ("compare",  cp0def::C0_COMPARE ),
+    ("intctl",   cp0def::C0_INTCTL  ),
+    ("srsctl",   cp0def::C0_SRSCTL  ),
+    ("srsmap",   cp0def::C0_SRSMAP  ),
+    ("epc",      cp0def::C0_EPC     ),
+    ("prid",     cp0def::C0_PRID    ),
+    ("ebase",    cp0def::C0_EBASE   ),
+    ("config",   cp0def::C0_CONFIG  ),
+    ("config1",  cp0def::C0_CONFIG1 ),
+    ("config2",  cp0def::C0_CONFIG2 ),
+    ("config3",  cp0def::C0_CONFIG3 ),
+    ("errorepc", cp0def::C0_ERROREPC),
+];
+
+const GDB_REG_DSP_BASE : usize = GDB_REG_CP0_BASE + GDB_CP0_EXTRA_REGS.len();
+const GDB_DSP_REGS : [&str; 7] = ["hi1", "lo1", "hi2", "lo2", "hi3", "lo3", "dspctl"];
+
+fn target_xml() -> String {
+    let mut xml = String::from(concat!(
+        "<?xml version=\"1.0\"?>",
+        "<!DOCTYPE target SYSTEM \"gdb-target.dtd\">",
+        "<target version=\"1.0\">",
+        "<architecture>mips</architecture>",
+        "<feature name=\"org.gnu.gdb.mips.cpu\">",
+    ));
+    for i in 0..32 {
+        xml += &format!("<reg name=\"r{}\" bitsize=\"32\" regnum=\"{}\"/>", i, i);
+    }
+    xml += "<reg name=\"lo\" bitsize=\"32\" regnum=\"33\"/>";
+    xml += "<reg name=\"hi\" bitsize=\"32\" regnum=\"34\"/>";
+    xml += "<reg name=\"pc\" bitsize=\"32\" regnum=\"37\"/>";
+    xml += "</feature>";
+
+    xml += "<feature name=\"org.gnu.gdb.mips.cp0\">";
+    xml += "<reg name=\"status\" bitsize=\"32\" regnum=\"32\"/>";
+    xml += "<reg name=\"badvaddr\" bitsize=\"32\" regnum=\"35\"/>";
+    xml += "<reg name=\"cause\" bitsize=\"32\" regnum=\"36\"/>";
+    xml += "</feature>";
+
+    xml += "<feature name=\"org.gnu.gdb.mips.fpu\">";
+    for i in 0..32 {
+        xml += &format!("<reg name=\"f{}\" bitsize=\"32\" type=\"ieee_single\" regnum=\"{}\"/>", i, GDB_REG_FPU_BASE + i);
+    }
+    xml += "<reg name=\"fcsr\" bitsize=\"32\" group=\"float\" regnum=\"70\"/>";
+    xml += "<reg name=\"fir\" bitsize=\"32\" group=\"float\" regnum=\"71\"/>";
+    xml += "</feature>";
+
+    xml += "<feature name=\"org.exrmips.cp0\">";
+    for (i, (name, _)) in GDB_CP0_EXTRA_REGS.iter().enumerate() {
+        xml += &format!("<reg name=\"{}\" bitsize=\"32\" group=\"system\" regnum=\"{}\"/>", name, GDB_REG_CP0_BASE + i);
+    }
+    xml += "</feature>";
+
+    xml += "<feature name=\"org.gnu.gdb.mips.dsp\">";
+    for (i, name) in GDB_DSP_REGS.iter().enumerate() {
+        xml += &format!("<reg name=\"{}\" bitsize=\"32\" regnum=\"{}\"/>", name, GDB_REG_DSP_BASE + i);
+    }
+    xml += "</feature>";
+    xml += "</target>";
+    xml
+}
+
+fn num_registers() -> usize { GDB_REG_DSP_BASE + GDB_DSP_REGS.len() }
+
+fn read_register(ms: &mut MachineState, regno: usize) -> Option<u32> {
+    match regno {
+        0..=31             => Some(ms.reg.r[regno]),
+        GDB_REG_STATUS     => Some(c0_val!(ms.reg, cp0def::C0_STATUS)),
+        GDB_REG_LO         => Some(ms.reg.lo),
+        GDB_REG_HI         => Some(ms.reg.hi),
+        GDB_REG_BADVADDR   => Some(c0_val!(ms.reg, cp0def::C0_BADVADDR)),
+        GDB_REG_CAUSE      => Some(c0_val!(ms.reg, cp0def::C0_CAUSE)),
+        GDB_REG_PC         => Some(ms.reg.pc),
+        GDB_REG_FPU_BASE..=GDB_REG_FPU_END => Some(ms.reg.fpu.get32(regno - GDB_REG_FPU_BASE)),
+        GDB_REG_FCSR       => Some(ms.reg.fpu.read_control(fpu::FCR_FCSR)),
+        GDB_REG_FIR        => Some(if fpu::present(ms) { fpu::FIR_VAL }else{ 0 }),
+        n if n >= GDB_REG_DSP_BASE => {
+            match n - GDB_REG_DSP_BASE {
+                6 => Some(ms.reg.dspcontrol),
+                i if i < 6 && i % 2 == 0 => Some(dsp::hi(&ms.reg, i/2 + 1)),
+                i if i < 6 => Some(dsp::lo(&ms.reg, i/2 + 1)),
+                _ => None,
+            }
+        }
+        _ => {
+            let rs = GDB_CP0_EXTRA_REGS.get(regno - GDB_REG_CP0_BASE)?.1;
+            if rs == cp0def::C0_COUNT {
+                Some(cp0::load_counter(ms))
+            }else{
+                Some(c0_val!(ms.reg, rs))
+            }
+        }
+    }
+}
+
+fn write_register(ms: &mut MachineState, regno: usize, val: u32) -> bool {
+    match regno {
+        0          => { }
+        1..=31     => { ms.reg.r[regno] = val; }
+        GDB_REG_STATUS   => { cp0::store(ms, cp0def::C0_STATUS, val); }
+        GDB_REG_LO       => { ms.reg.lo = val; }
+        GDB_REG_HI       => { ms.reg.hi = val; }
+        GDB_REG_BADVADDR => { c0_val!(ms.reg, cp0def::C0_BADVADDR) = val; }
+        GDB_REG_CAUSE    => { cp0::store(ms, cp0def::C0_CAUSE, val); }
+        GDB_REG_PC       => {
+            if ms.reg.pc != val {
+                ms.reg.pc = val;
+                ms.reg.delay_en = false;
+            }
+        }
+        GDB_REG_FPU_BASE..=GDB_REG_FPU_END => { ms.reg.fpu.set32(regno - GDB_REG_FPU_BASE, val); }
+        GDB_REG_FCSR     => { ms.reg.fpu.write_control(fpu::FCR_FCSR, val); }
+        GDB_REG_FIR      => { }
+        n if n >= GDB_REG_DSP_BASE => {
+            match n - GDB_REG_DSP_BASE {
+                6 => { ms.reg.dspcontrol = val; }
+                i if i < 6 && i % 2 == 0 => { dsp::set_hi(&mut ms.reg, i/2 + 1, val); }
+                i if i < 6 => { dsp::set_lo(&mut ms.reg, i/2 + 1, val); }
+                _ => { return false; }
+            }
+        }
+        _ => {
+            match GDB_CP0_EXTRA_REGS.get(regno - GDB_REG_CP0_BASE) {
+                Some((_, rs)) => { cp0::store(ms, *rs, val); }
+                None          => { return false; }
+            }
+        }
+    }
+    true
+}
+
+
+enum GdbListener {
+    Tcp(TcpListener),
+    Unix(UnixListener, PathBuf),
+}
+
+enum GdbConn {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl GdbConn {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            GdbConn::Tcp(s)  => s.set_nonblocking(nonblocking),
+            GdbConn::Unix(s) => s.set_nonblocking(nonblocking),
+        }
+    }
+}
+
+impl Read for GdbConn {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            GdbConn::Tcp(s)  => s.read(buf),
+            GdbConn::Unix(s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for GdbConn {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            GdbConn::Tcp(s)  => s.write(buf),
+            GdbConn::Unix(s) => s.write(buf),
+        }
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            GdbConn::Tcp(s)  => s.flush(),
+            GdbConn::Unix(s) => s.flush(),
+        }
+    }
+}
+
+impl Drop for GdbListener {
+    fn drop(&mut self) {
+        if let GdbListener::Unix(_, path) = self {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+pub struct GdbStub {
+    listener   : GdbListener,
+    conn       : Option<GdbConn>,
+    breakpoints: Vec<u32>,
+    stop_req   : Option<u8>, // signal to be reported at the next instruction boundary
+    stepping   : bool,
+    resuming   : bool,       // the instruction at the stopped PC is executed without checks
+    running    : bool,       // a stop reply is owed to the client
+    last_signal: u8,
+    no_ack     : bool,
+}
+
+impl GdbStub {
+    /*
+    Opens the listening socket.
+    spec is a port number ("1234"), an address and a port ("0.0.0.0:1234"),
+    or a path of a Unix domain socket ("unix:/tmp/exrmips.sock" or "/tmp/exrmips.sock").
+    */
+    pub fn bind(spec: &str) -> io::Result<Self> {
+        let listener = if let Some(path) = spec.strip_prefix("unix:") {
+            GdbListener::Unix(UnixListener::bind(path)?, PathBuf::from(path))
+        }else if spec.contains('/') {
+            GdbListener::Unix(UnixListener::bind(spec)?, PathBuf::from(spec))
+        }else if let Ok(port) = spec.parse::<u16>() {
+            GdbListener::Tcp(TcpListener::bind(("127.0.0.1", port))?)
+        }else{
+            GdbListener::Tcp(TcpListener::bind(spec)?)
+        };
+
+        match &listener {
+            GdbListener::Tcp(l)     => { l.set_nonblocking(true)?; info!("GDB stub is listening on {}\r", l.local_addr()?); }
+            GdbListener::Unix(l, p) => { l.set_nonblocking(true)?; info!("GDB stub is listening on {}\r", p.display()); }
+        }
+
+        Ok(Self {
+            listener,
+            conn: None,
+            breakpoints: Vec::new(),
+            stop_req: None,
+            stepping: false,
+            resuming: false,
+            running: false,
+            last_signal: SIGTRAP,
+            no_ack: false,
+        })
+    }
+
+    pub fn is_connected(&self) -> bool { self.conn.is_some() }
+
+    fn accept(&mut self) -> io::Result<()> {
+        let conn = match &self.listener {
+            GdbListener::Tcp(l) => {
+                let (s, addr) = l.accept()?;
+                s.set_nodelay(true)?;
+                info!("GDB connected from {}\r", addr);
+                GdbConn::Tcp(s)
+            }
+            GdbListener::Unix(l, p) => {
+                let (s, _) = l.accept()?;
+                info!("GDB connected on {}\r", p.display());
+                GdbConn::Unix(s)
+            }
+        };
+        conn.set_nonblocking(false)?;
+
+        // A newly attached client expects the target to be stopped.
+        // The stop reason is requested by the client with '?'.
+        self.conn        = Some(conn);
+        self.breakpoints.clear();
+        self.stop_req    = Some(SIGTRAP);
+        self.stepping    = false;
+        self.resuming    = false;
+        self.running     = false;
+        self.last_signal = SIGTRAP;
+        self.no_ack      = false;
+        Ok(())
+    }
+
+    /* Blocks until a client connects. */
+    pub fn wait_for_client(&mut self) {
+        info!("Waiting for GDB connection...\r");
+        loop {
+            match self.accept() {
+                Ok(()) => { return; }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => { std::thread::sleep(std::time::Duration::from_millis(10)); }
+                Err(e) => { error!("GDB accept error: {}\r", e); return; }
+            }
+        }
+    }
+
+    fn disconnect(&mut self) {
+        info!("GDB disconnected\r");
+        self.conn = None;
+        self.breakpoints.clear();
+        self.stop_req = None;
+        self.stepping = false;
+        self.resuming = false;
+        self.running  = false;
+    }
+
+    /*
+    Called every host timer period while the target is running.
+    Accepts a new client and receives the interrupt request (Ctrl-C).
+    */
+    pub fn poll(&mut self) {
+        let Some(conn) = self.conn.as_mut() else {
+            if let Err(e) = self.accept() {
+                if e.kind() != io::ErrorKind::WouldBlock { error!("GDB accept error: {}\r", e); }
+            }
+            return;
+        };
+
+        let mut buf = [0u8; 64];
+        if conn.set_nonblocking(true).is_err() { return; }
+        let res = conn.read(&mut buf);
+        let _ = conn.set_nonblocking(false);
+
+        match res {
+            Ok(0) => { self.disconnect(); }
+            Ok(n) => {
+                if buf[..n].contains(&0x03) {
+                    self.stop_req = Some(SIGINT);
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => { }
+            Err(_) => { self.disconnect(); }
+        }
+    }
+
+    /* Called before each instruction. Returns true if the target should stop. */
+    #[inline]
+    pub fn check(&mut self, pc: u32) -> bool {
+        if self.conn.is_none() {
+            return false;
+        }
+        if self.resuming {
+            self.resuming = false;
+            return false;
+        }
+        if self.stepping {
+            self.stepping = false;
+            self.last_signal = SIGTRAP;
+            return true;
+        }
+        if let Some(sig) = self.stop_req.take() {
+            self.last_signal = sig;
+            return true;
+        }
+        if !self.breakpoints.is_empty() && self.breakpoints.contains(&(pc & !1)) {
+            self.last_signal = SIGTRAP;
+            return true;
+        }
+        false
+    }
+
+    /*
+    Processes packets while the target is stopped.
+    Returns false if the client requests to terminate the emulator.
+    */
+    pub fn serve(&mut self, ms: &mut MachineState) -> bool {
+        if self.running {
+            self.running = false;
+            let reply = self.stop_reply(ms);
+            if self.send_packet(&reply).is_err() {
+                self.disconnect();
+                return true;
+            }
+        }
+
+        loop {
+            let packet = match self.recv_packet() {
+                Ok(p)  => p,
+                Err(_) => { self.disconnect(); return true; }
+            };
+
+            if ms.emu.debug {
+                info!("gdb <- {}\r", packet);
+            }
+
+            match self.handle_packet(ms, &packet) {
+                Action::Reply(reply) => {
+                    if self.send_packet(&reply).is_err() {
+                        self.disconnect();
+                        return true;
+                    }
+                }
+                Action::Resume => {
+                    self.running  = true;
+                    self.resuming = true;
+                    return true;
+                }
+                Action::Detach => {
+                    let _ = self.send_packet("OK");
+                    self.disconnect();
+                    return true;
+                }
+                Action::Kill => {
+                    info!("Terminated by GDB\r");
+                    self.conn = None;
+                    return false;
+                }
+            }
+        }
+    }
+
+    fn stop_reply(&self, ms: &mut MachineState) -> String {
+        let pc = read_register(ms, GDB_REG_PC).unwrap_or(0);
+        let sp = ms.reg.r[29];
+        format!("T{:02x}{:02x}:{};{:02x}:{};", self.last_signal, GDB_REG_PC, hex_u32(pc), 29, hex_u32(sp))
+    }
+
+    fn handle_packet(&mut self, ms: &mut MachineState, packet: &str) -> Action {
+        let (cmd, args) = packet.split_at( packet.chars().next().map_or(0, |c| c.len_utf8()) );
+
+        match cmd {
+            "?" => Action::Reply(format!("S{:02x}", self.last_signal)),
+            "g" => {
+                let mut s = String::with_capacity(num_registers()*8);
+                for i in 0..num_registers() {
+                    s += &hex_u32(read_register(ms, i).unwrap_or(0));
+                }
+                Action::Reply(s)
+            }
+            "G" => {
+                for (i, chunk) in args.as_bytes().chunks(8).enumerate() {
+                    match parse_hex_u32_be(chunk) {
+                        Some(v) => { write_register(ms, i, v); }
+                        None    => { return Action::Reply("E01".to_string()); }
+                    }
+                }
+                Action::Reply("OK".to_string())
+            }
+            "p" => {
+                match usize::from_str_radix(args, 16).ok().and_then(|n| read_register(ms, n)) {
+                    Some(v) => Action::Reply(hex_u32(v)),
+                    None    => Action::Reply("E01".to_string()),
+                }
+            }
+            "P" => {
+                let Some((n, v)) = args.split_once('=') else { return Action::Reply("E01".to_string()); };
+                match (usize::from_str_radix(n, 16), parse_hex_u32_be(v.as_bytes())) {
+                    (Ok(n), Some(v)) if write_register(ms, n, v) => Action::Reply("OK".to_string()),
+                    _ => Action::Reply("E01".to_string()),
+                }
+            }
+            "m" => {
+                let Some((addr, len)) = parse_addr_len(args) else { return Action::Reply("E01".to_string()); };
+                let mut s = String::new();
+                for i in 0..Ord::min(len, (PACKET_SIZE/2) as u32) {
+                    match mem::debug_read_byte(ms, addr.wrapping_add(i)) {
+                        Ok(d)  => { s += &format!("{:02x}", d); }
+                        Err(_) => { break; }
+                    }
+                }
+                if s.is_empty() && len != 0 {
+                    Action::Reply("E14".to_string())
+                }else{
+                    Action::Reply(s)
+                }
+            }
+            "M" => {
+                let Some((head, data)) = args.split_once(':') else { return Action::Reply("E01".to_string()); };
+                let Some((addr, len)) = parse_addr_len(head) else { return Action::Reply("E01".to_string()); };
+                let data = data.as_bytes();
+                if data.len() < (len as usize)*2 {
+                    return Action::Reply("E01".to_string());
+                }
+                for i in 0..len {
+                    let Some(d) = parse_hex_u8(&data[(i as usize)*2..(i as usize)*2+2]) else { return Action::Reply("E01".to_string()); };
+                    if mem::debug_write_byte(ms, addr.wrapping_add(i), d).is_err() {
+                        return Action::Reply("E14".to_string());
+                    }
+                }
+                Action::Reply("OK".to_string())
+            }
+            "Z" | "z" => {
+                // Z0/Z1: software and hardware breakpoints are handled in the same way
+                let mut it = args.split(',');
+                let kind = it.next().unwrap_or("");
+                let addr = it.next().and_then(|a| u32::from_str_radix(a, 16).ok());
+                match (kind, addr) {
+                    ("0" | "1", Some(addr)) => {
+                        let addr = addr & !1;
+                        if cmd == "Z" {
+                            if !self.breakpoints.contains(&addr) { self.breakpoints.push(addr); }
+                        }else{
+                            self.breakpoints.retain(|&a| a != addr);
+                        }
+                        Action::Reply("OK".to_string())
+                    }
+                    _ => Action::Reply(String::new()),
+                }
+            }
+            "c" | "s" => {
+                if let Ok(addr) = u32::from_str_radix(args, 16) {
+                    write_register(ms, GDB_REG_PC, addr);
+                }
+                self.stepping = cmd == "s";
+                Action::Resume
+            }
+            "D" => Action::Detach,
+            "k" => Action::Kill,
+            "H" => Action::Reply("OK".to_string()),
+            "T" => Action::Reply("OK".to_string()),
+            "q" => self.handle_query(args),
+            "Q" => {
+                if args == "StartNoAckMode" {
+                    self.no_ack = true;
+                    Action::Reply("OK".to_string())
+                }else{
+                    Action::Reply(String::new())
+                }
+            }
+            _ => Action::Reply(String::new()),
+        }
+    }
+
+    fn handle_query(&mut self, args: &str) -> Action {
+        if args.starts_with("Supported") {
+            return Action::Reply(format!("PacketSize={:x};qXfer:features:read+;QStartNoAckMode+", PACKET_SIZE));
+        }
+        if let Some(rest) = args.strip_prefix("Xfer:features:read:target.xml:") {
+            let Some((offset, len)) = rest.split_once(',') else { return Action::Reply("E01".to_string()); };
+            let (Ok(offset), Ok(len)) = (usize::from_str_radix(offset, 16), usize::from_str_radix(len, 16)) else {
+                return Action::Reply("E01".to_string());
+            };
+            let xml = target_xml();
+            if offset >= xml.len() {
+                return Action::Reply("l".to_string());
+            }
+            let end = Ord::min(offset + len, xml.len());
+            let prefix = if end == xml.len() { "l" }else{ "m" };
+            return Action::Reply(format!("{}{}", prefix, &xml[offset..end]));
+        }
+        match args {
+            "Attached"     => Action::Reply("1".to_string()),
+            "C"            => Action::Reply("QC1".to_string()),
+            "fThreadInfo"  => Action::Reply("m1".to_string()),
+            "sThreadInfo"  => Action::Reply("l".to_string()),
+            _              => Action::Reply(String::new()),
+        }
+    }
+
+    fn recv_packet(&mut self) -> io::Result<String> {
+        let no_ack = self.no_ack;
+        let conn = self.conn.as_mut().ok_or(io::ErrorKind::NotConnected)?;
+
+        loop {
+            // waits for the start of a packet
+            let mut c = read_byte(conn)?;
+            while c != b'$' {
+                c = read_byte(conn)?;
+            }
+
+            let mut data = Vec::new();
+            let mut sum: u8 = 0;
+            loop {
+                let c = read_byte(conn)?;
+                if c == b'#' { break; }
+                sum = sum.wrapping_add(c);
+                data.push(c);
+            }
+            let cs = [read_byte(conn)?, read_byte(conn)?];
+
+            if parse_hex_u8(&cs) == Some(sum) {
+                if !no_ack { conn.write_all(b"+")?; }
+                return Ok(String::from_utf8_lossy(&unescape(&data)).into_owned());
+            }
+            if !no_ack { conn.write_all(b"-")?; }
+        }
+    }
+
+    fn send_packet(&mut self, data: &str) -> io::Result<()> {
+        let no_ack = self.no_ack;
+        let conn = self.conn.as_mut().ok_or(io::ErrorKind::NotConnected)?;
+
+        let mut pkt = Vec::with_capacity(data.len() + 4);
+        let mut sum: u8 = 0;
+        pkt.push(b'$');
+        for &c in data.as_bytes() {
+            if c == b'$' || c == b'#' || c == b'}' || c == b'*' {
+                pkt.push(b'}');
+                pkt.push(c ^ 0x20);
+                sum = sum.wrapping_add(b'}').wrapping_add(c ^ 0x20);
+            }else{
+                pkt.push(c);
+                sum = sum.wrapping_add(c);
+            }
+        }
+        pkt.extend_from_slice(format!("#{:02x}", sum).as_bytes());
+
+        loop {
+            conn.write_all(&pkt)?;
+            conn.flush()?;
+            if no_ack { return Ok(()); }
+
+            // waits for the acknowledgement
+            loop {
+                match read_byte(conn)? {
+                    b'+' => { return Ok(()); }
+                    b'-' => { break; }
+                    _    => { }
+                }
+            }
+        }
+    }
+}
+
+enum Action {
+    Reply(String),
+    Resume,
+    Detach,
+    Kill,
+}
+
+fn read_byte(conn: &mut GdbConn) -> io::Result<u8> {
+    let mut b = [0u8; 1];
+    match conn.read(&mut b)? {
+        0 => Err(io::ErrorKind::UnexpectedEof.into()),
+        _ => Ok(b[0]),
+    }
+}
+
+fn unescape(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    let mut it = data.iter();
+    while let Some(&c) = it.next() {
+        if c == b'}' {
+            if let Some(&n) = it.next() { out.push(n ^ 0x20); }
+        }else{
+            out.push(c);
+        }
+    }
+    out
+}
+
+// Registers are transferred in the target byte order (big endian)
+fn hex_u32(v: u32) -> String { format!("{:08x}", v) }
+
+fn parse_hex_u8(s: &[u8]) -> Option<u8> {
+    u8::from_str_radix(std::str::from_utf8(s).ok()?, 16).ok()
+}
+
+fn parse_hex_u32_be(s: &[u8]) -> Option<u32> {
+    if s.len() != 8 { return None; }
+    u32::from_str_radix(std::str::from_utf8(s).ok()?, 16).ok()
+}
+
+fn parse_addr_len(s: &str) -> Option<(u32, u32)> {
+    let (addr, len) = s.split_once(',')?;
+    Some((u32::from_str_radix(addr, 16).ok()?, u32::from_str_radix(len, 16).ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dev_spiflash::SPI_FLASH_PARAM_S25FL164K;
+    use crate::exrmips::generate_machine_state;
+    use crate::snapshot::Writer;
+
+    // a stub connected to a client end of a socket pair
+    fn connected() -> (GdbStub, UnixStream) {
+        let mut stub = GdbStub::bind("127.0.0.1:0").unwrap();
+        let (a, b) = UnixStream::pair().unwrap();
+        stub.conn = Some(GdbConn::Unix(a));
+        (stub, b)
+    }
+
+    fn packet(data: &str) -> Vec<u8> {
+        let sum = data.bytes().fold(0u8, |s, c| s.wrapping_add(c));
+        format!("${}#{:02x}", data, sum).into_bytes()
+    }
+
+    fn read_n(client: &mut UnixStream, n: usize) -> Vec<u8> {
+        let mut buf = vec![0u8; n];
+        client.read_exact(&mut buf).unwrap();
+        buf
+    }
+
+    fn reply(stub: &mut GdbStub, ms: &mut MachineState, packet: &str) -> String {
+        match stub.handle_packet(ms, packet) {
+            Action::Reply(r) => r,
+            _ => panic!("no reply to {}", packet),
+        }
+    }
+
+    fn machine() -> MachineState {
+        generate_machine_state(&SPI_FLASH_PARAM_S25FL164K, &[0x3c, 0x08, 0xbf, 0xc0, 0x25, 0x08, 0x00, 0x10])
+    }
+
+    #[test]
+    fn packets_are_acknowledged() {
+        let (mut stub, mut client) = connected();
+
+        // noise before a packet is skipped and a packet with a wrong checksum is retransmitted
+        client.write_all(b"+\x03").unwrap();
+        client.write_all(b"$qC#00").unwrap();
+        client.write_all(&packet("qC")).unwrap();
+        assert_eq!(stub.recv_packet().unwrap(), "qC");
+        assert_eq!(read_n(&mut client, 2), b"-+");
+
+        // the packet is sent again on a nack
+        client.write_all(b"-+").unwrap();
+        stub.send_packet("OK").unwrap();
+        assert_eq!(read_n(&mut client, 12), b"$OK#9a$OK#9a");
+
+        // no acknowledgement in the no-ack mode
+        let mut ms = machine();
+        assert_eq!(reply(&mut stub, &mut ms, "QStartNoAckMode"), "OK");
+        client.write_all(&packet("g")).unwrap();
+        client.write_all(&packet("?")).unwrap();
+        assert_eq!(stub.recv_packet().unwrap(), "g");
+        assert_eq!(stub.recv_packet().unwrap(), "?");
+        stub.send_packet("S05").unwrap();
+        assert_eq!(read_n(&mut client, 7), b"$S05#b8");
+    }
+
+    #[test]
+    fn escaped_characters() {
+        let (mut stub, mut client) = connected();
+        stub.no_ack = true;
+
+        // '}' is sent as "}]", and the checksum covers the escaped bytes
+        stub.send_packet("a}b#c$*").unwrap();
+        let expected = b"$a}]b}\x03c}\x04}\x0a";
+        let sum = expected[1..].iter().fold(0u8, |s, c| s.wrapping_add(*c));
+        let mut out = expected.to_vec();
+        out.extend_from_slice(format!("#{:02x}", sum).as_bytes());
+        assert_eq!(read_n(&mut client, out.len()), out);
+
+        // escaped data from the client (e.g., of an X packet)
+        let data = b"X0,1:}]";
+        let sum = data.iter().fold(0u8, |s, c| s.wrapping_add(*c));
+        client.write_all(b"$").unwrap();
+        client.write_all(data).unwrap();
+        client.write_all(format!("#{:02x}", sum).as_bytes()).unwrap();
+        assert_eq!(stub.recv_packet().unwrap(), "X0,1:}");
+        assert_eq!(unescape(b"a}]}\x03"), b"a}#");
+    }
+
+    #[test]
+    fn registers_follow_the_target_description() {
+        let (mut stub, _client) = connected();
+        let mut ms = machine();
+        for i in 1..32 { ms.reg.r[i] = 0x1000 + i as u32; }
+        ms.reg.lo = 0x2000;
+        ms.reg.hi = 0x2001;
+        ms.reg.pc = 0x80001234;
+        c0_val!(ms.reg, cp0def::C0_BADVADDR) = 0x2002;
+        c0_val!(ms.reg, cp0def::C0_EPC)      = 0x2003;
+        ms.reg.fpu.set32(3, 0x3f800000);
+        ms.reg.dspcontrol = 0x2004;
+        dsp::set_lo(&mut ms.reg, 2, 0x2005);
+
+        // every register number of the description is transferred by g and p in that order
+        let xml = target_xml();
+        let mut regs : Vec<(String, usize)> = xml.split("<reg ").skip(1).map(|r| {
+            let attr = |a: &str| r.split(&format!("{}=\"", a)).nth(1).unwrap().split('"').next().unwrap().to_string();
+            (attr("name"), attr("regnum").parse().unwrap())
+        }).collect();
+        regs.sort_by_key(|r| r.1);
+        assert_eq!(regs.iter().map(|r| r.1).collect::<Vec<_>>(), (0..num_registers()).collect::<Vec<_>>());
+
+        let g = reply(&mut stub, &mut ms, "g");
+        assert_eq!(g.len(), num_registers()*8);
+        for (name, n) in regs.iter() {
+            let p = reply(&mut stub, &mut ms, &format!("p{:x}", n));
+            if name != "count" {
+                assert_eq!(p, &g[n*8..n*8+8], "{}", name);
+            }
+            let expected = match name.as_str() {
+                "r29"      => Some(0x101du32),
+                "lo"       => Some(0x2000),
+                "hi"       => Some(0x2001),
+                "pc"       => Some(0x80001234),
+                "badvaddr" => Some(0x2002),
+                "epc"      => Some(0x2003),
+                "f3"       => Some(0x3f800000),
+                "dspctl"   => Some(0x2004),
+                "lo2"      => Some(0x2005),
+                _          => None,
+            };
+            if let Some(v) = expected {
+                assert_eq!(p, format!("{:08x}", v), "{}", name);
+            }
+        }
+        assert_eq!(reply(&mut stub, &mut ms, &format!("p{:x}", num_registers())), "E01");
+
+        // P and G write registers in the same order
+        assert_eq!(reply(&mut stub, &mut ms, "P25=80002000"), "OK");
+        assert_eq!(ms.reg.pc, 0x80002000);
+        assert_eq!(reply(&mut stub, &mut ms, "P0=12345678"), "OK");
+        assert_eq!(ms.reg.r[0], 0);
+        assert_eq!(reply(&mut stub, &mut ms, "G0000000000000001aabbccdd"), "OK");
+        assert_eq!((ms.reg.r[1], ms.reg.r[2]), (1, 0xaabbccdd));
+        assert_eq!(reply(&mut stub, &mut ms, "G0000zz00"), "E01");
+    }
+
+    #[test]
+    fn breakpoints() {
+        let (mut stub, _client) = connected();
+        let mut ms = machine();
+        stub.stop_req = None;
+
+        assert_eq!(reply(&mut stub, &mut ms, "Z0,80001000,4"), "OK");
+        assert_eq!(reply(&mut stub, &mut ms, "Z1,80001005,2"), "OK"); // MIPS16e
+        assert_eq!(reply(&mut stub, &mut ms, "Z0,80001000,4"), "OK");
+        assert_eq!(stub.breakpoints, vec![0x80001000, 0x80001004]);
+        assert!(!stub.check(0x80000ffc));
+        assert!(stub.check(0x80001000));
+        assert!(stub.check(0x80001005));
+
+        assert_eq!(reply(&mut stub, &mut ms, "z0,80001000,4"), "OK");
+        assert!(!stub.check(0x80001000));
+        assert_eq!(reply(&mut stub, &mut ms, "z1,80001004,2"), "OK");
+        assert!(stub.breakpoints.is_empty());
+
+        // watchpoints are not supported by the stub
+        assert_eq!(reply(&mut stub, &mut ms, "Z2,80001000,4"), "");
+        assert_eq!(reply(&mut stub, &mut ms, "Z0,xyz,4"), "");
+    }
+
+    #[test]
+    fn memory_round_trip() {
+        let (mut stub, _client) = connected();
+        let mut ms = machine();
+
+        assert_eq!(reply(&mut stub, &mut ms, "M80000100,4:deadbeef"), "OK");
+        assert_eq!(&ms.mem.mem0[0x100..0x104], &[0xde, 0xad, 0xbe, 0xef]);
+        assert_eq!(reply(&mut stub, &mut ms, "ma0000100,4"), "deadbeef");
+        assert_eq!(reply(&mut stub, &mut ms, "m800000fe,4"), "ffffdead");
+        assert_eq!(reply(&mut stub, &mut ms, "M80000100,4:dead"), "E01");
+        assert_eq!(reply(&mut stub, &mut ms, "M80000100,1:zz"), "E01");
+
+        // the flash memory is readable but not writable
+        assert_eq!(reply(&mut stub, &mut ms, "mbfc00000,8"), "3c08bfc025080010");
+        assert_eq!(reply(&mut stub, &mut ms, "Mbfc00000,1:00"), "E14");
+
+        // mapped addresses without a TLB entry are not accessible (kuseg is unmapped while ERL is set)
+        c0_val!(ms.reg, cp0def::C0_STATUS) = 0;
+        assert_eq!(reply(&mut stub, &mut ms, "m00400000,4"), "E14");
+    }
+
+    #[test]
+    fn flash_read_keeps_the_spi_transaction() {
+        let (mut stub, _client) = connected();
+        let mut ms = machine();
+
+        // the guest is in the middle of a read command (e.g., bit-banged in the GPIO mode)
+        let flash = &mut ms.spi.workers[0];
+        flash.select();
+        for d in [0x03, 0x00, 0x00, 0x04] { flash.write(d); }
+        let mut before = Writer::new();
+        flash.save_state(&mut before);
+
+        assert_eq!(reply(&mut stub, &mut ms, "mbfc00000,4"), "3c08bfc0");
+
+        let mut after = Writer::new();
+        ms.spi.workers[0].save_state(&mut after);
+        assert!(before.buf == after.buf);
+        assert_eq!(ms.spi.workers[0].write(0), 0x25);
+    }
+}
@@ -16,11 +16,33 @@ mod dev_uart;
 mod dev_soc;
 mod dev_spi;
 mod dev_spiflash;
+mod dev_gmac;
 mod mainloop;
+mod disasm;
+mod breakpoint;
+mod elf;
+mod boot;
+mod snapshot;
+mod trace;
+mod symbols;
+mod syscall_trace;
+mod mmio_log;
+mod fpu;
+mod dsp;
+mod watch;
+mod perf;
 
 // native app. only
 mod time_trig;
 #[cfg(not(target_family = "wasm"))] mod stin;
+#[cfg(not(target_family = "wasm"))] mod gdbstub;
+#[cfg(not(target_family = "wasm"))] mod monitor;
+#[cfg(not(target_family = "wasm"))] mod flash_store;
+#[cfg(not(target_family = "wasm"))] mod net_backend;
+#[cfg(not(target_family = "wasm"))] mod net_slirp;
+#[cfg(not(target_family = "wasm"))] mod uart_backend;
+#[cfg(not(target_family = "wasm"))] mod script;
+#[cfg(not(target_family = "wasm"))] mod profiler;
 
 // wasm only 
 mod utils;
@@ -76,6 +98,8 @@ pub async fn mips_emu(){
 pub mod exrmips{
     pub use crate::dev_spiflash::SPI_FLASH_PARAM_S25FL164K;
     pub use crate::dev_spiflash::SPI_FLASH_PARAM_MX66U2G45G;
+    pub use crate::symbols::SymbolTable;
+    pub use crate::exception::IrqMode;
 
     use crate::dev_uart;
     use crate::procstate::{EmuSetting, Reg, MachineState};
@@ -85,45 +109,347 @@ pub mod exrmips{
     use crate::dev_soc::{IoGPIO, IoMisc};
     use crate::dev_spiflash::{SPIFlash, SPIFlashParam};
     use crate::dev_spi::IoSPI;
+    use crate::dev_gmac::IoGMAC;
+    use crate::breakpoint::BreakpointSet;
 
-    use crate::{cp0def, config, mips, dev_spiflash, mainloop};
+    use crate::{cp0def, config, mips, dev_spiflash, mainloop, disasm, elf, boot, snapshot, trace, syscall_trace, mmio_log, exception};
     use crate::time_trig;
     use crate::c0_val;
 
     #[cfg(not(target_family = "wasm"))]
-    use crate::stin;
+    use crate::{stin, gdbstub, flash_store, net_backend, uart_backend, profiler};
+    #[cfg(not(target_family = "wasm"))]
+    pub use crate::flash_store::FlashStoreMode;
+    #[cfg(not(target_family = "wasm"))]
+    pub use crate::mainloop::RunResult;
+    #[cfg(not(target_family = "wasm"))]
+    pub use crate::script::{Script, Step as ScriptStep, Clock as ScriptClock, SCRIPT_FAILURE_EXIT_CODE};
 
     pub async fn run_wasm(ms: &mut MachineState) { mainloop::run_wasm(ms).await; }
     
     #[cfg(not(target_family = "wasm"))]
-    pub fn run_term(ms: &mut MachineState) { mainloop::run_term(ms); }
+    pub fn run_term(ms: &mut MachineState, headless: bool) -> RunResult {
+        let result = mainloop::run_term(ms, headless);
+        if let Some(Err(e)) = ms.trace.as_mut().map(|t| t.flush()) {
+            log::error!("Can not write the trace : {}", e);
+        }
+        if let Some(Err(e)) = ms.syscall_trace.as_mut().map(|t| t.flush()) {
+            log::error!("Can not write the syscall trace : {}", e);
+        }
+        if let Some(Err(e)) = ms.mmio_log.as_mut().map(|l| l.flush()) {
+            log::error!("Can not write the MMIO log : {}", e);
+        }
+        if let Err(e) = flush_flash(ms) {
+            log::error!("Can not save the flash memory : {}", e);
+        }
+        result
+    }
+
+    /*
+    Makes modifications of the flash memory persistent.
+    WriteBack writes modified sectors back to path (the image file),
+    Output writes the whole image to path, and Overlay records modified sectors in an overlay file at path.
+    An existing overlay is applied to the flash memory immediately.
+    */
+    #[cfg(not(target_family = "wasm"))]
+    pub fn attach_flash_store(ms: &mut MachineState, path: &std::path::Path, mode: FlashStoreMode) -> std::io::Result<()> {
+        let store = match mode {
+            FlashStoreMode::Overlay => flash_store::FlashStore::open_overlay(path, ms.spi.workers[0].as_mut())?,
+            _                       => flash_store::FlashStore::new(path, mode),
+        };
+        ms.flash_store = Some(store);
+        Ok(())
+    }
+
+    // Writes modifications of the flash memory to the attached store. Returns the number of sectors written.
+    #[cfg(not(target_family = "wasm"))]
+    pub fn flush_flash(ms: &mut MachineState) -> std::io::Result<usize> {
+        match ms.flash_store.as_mut() {
+            Some(store) => store.flush(ms.spi.workers[0].as_mut()),
+            None        => Ok(0),
+        }
+    }
+
+    /*
+    Disassembles an instruction located at pc.
+    pc[0]=1 means a MIPS16e instruction; an extended one holds its first halfword in inst[31:16].
+    */
+    pub fn disassemble(pc: u32, inst: u32) -> String { disasm::disassemble(pc, inst) }
+
+    /*
+    Adds a breakpoint given as "ADDR[,REG==VAL][,hits=N]" (numbers in hexadecimal except N in decimal).
+    ADDR may be a symbol loaded by load_symbols with an offset (SYMBOL[+OFF]).
+    Returns the id of the breakpoint.
+    */
+    pub fn add_breakpoint(ms: &mut MachineState, spec: &str) -> Result<u32, String> { ms.emu.bp.add_breakpoint_spec(spec, &ms.symbols) }
+
+    /*
+    Adds a watchpoint given as "ADDR[+LEN][,r|w|rw][,phys]" (numbers in hexadecimal). ADDR may be a symbol.
+    Returns the id of the watchpoint.
+    */
+    pub fn add_watchpoint(ms: &mut MachineState, spec: &str) -> Result<u32, String> { ms.emu.bp.add_watchpoint_spec(spec, &ms.symbols) }
+
+    // Removes a breakpoint or a watchpoint. Returns false if the id is not found.
+    pub fn remove_breakpoint(ms: &mut MachineState, id: u32) -> bool { ms.emu.bp.remove(id) }
+
+    /*
+    Loads an ELF32 big-endian MIPS executable into DRAM and starts execution from its entry point
+    instead of the reset vector. args (up to 4 values) are set to a0..a3.
+    Returns the entry point.
+    */
+    pub fn load_elf(ms: &mut MachineState, data: &[u8], args: &[u32]) -> Result<u32, String> {
+        let (entry, _) = elf::load(ms, data)?;
+        elf::set_entry(ms, entry, args);
+        Ok(entry)
+    }
+
+    /*
+    Boots a Linux kernel (vmlinux or uncompressed uImage) directly without U-Boot.
+    The command line and the initrd are passed in the ath79 firmware argument convention.
+    Returns the entry point.
+    */
+    pub fn boot_kernel(ms: &mut MachineState, kernel: &[u8], initrd: Option<&[u8]>, cmdline: &str) -> Result<u32, String> {
+        boot::boot_kernel(ms, kernel, initrd, cmdline)
+    }
+
+    // Serializes the whole machine state into snapshot data
+    pub fn save_snapshot(ms: &mut MachineState) -> Vec<u8> { snapshot::save(ms) }
+
+    /*
+    Restores the machine state from snapshot data generated by save_snapshot.
+    The machine must have the same configuration (e.g., flash capacity) as the saved one.
+    */
+    pub fn load_snapshot(ms: &mut MachineState, data: &[u8]) -> Result<(), String> { snapshot::load(ms, data) }
+
+    // Saves the machine state to a snapshot file
+    #[cfg(not(target_family = "wasm"))]
+    pub fn save_snapshot_file(ms: &mut MachineState, path: &std::path::Path) -> std::io::Result<()> {
+        std::fs::write(path, snapshot::save(ms))
+    }
+
+    // Restores the machine state from a snapshot file
+    #[cfg(not(target_family = "wasm"))]
+    pub fn load_snapshot_file(ms: &mut MachineState, path: &std::path::Path) -> std::io::Result<()> {
+        let data = std::fs::read(path)?;
+        snapshot::load(ms, &data).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+    }
+
+    /*
+    Connects an Ethernet interface (0: GMAC0, 1: GMAC1) to a host backend
+    given as "tap:NAME", "pcap:FILE", "loopback[:FILE]", "user[,OPTIONS]" or "none".
+    */
+    #[cfg(not(target_family = "wasm"))]
+    pub fn attach_net(ms: &mut MachineState, index: usize, spec: &str) -> std::io::Result<()> {
+        let gmac = ms.gmac.get_mut(index).ok_or(std::io::Error::new(std::io::ErrorKind::InvalidInput, "no such Ethernet interface"))?;
+        gmac.backend = net_backend::open(spec)?;
+        Ok(())
+    }
+
+    /*
+    Connects a UART (0: UART0, 1: UART1) to a host backend given as "stdio", "null", "file:PATH",
+    "tcp:[HOST:]PORT", "unix:PATH", "pty" or "mux:SPEC,SPEC,...".
+    */
+    #[cfg(not(target_family = "wasm"))]
+    pub fn attach_serial(ms: &mut MachineState, index: usize, spec: &str) -> std::io::Result<()> {
+        let backend = uart_backend::open(spec, &ms.console)?;
+        let uart = ms.uart.get_mut(index).ok_or(std::io::Error::new(std::io::ErrorKind::InvalidInput, "no such UART"))?;
+        uart.rw = backend;
+        Ok(())
+    }
+
+    /*
+    Drives the console of a UART with a script (see script.rs for the format).
+    The output of the UART is still passed to its backend.
+    */
+    #[cfg(not(target_family = "wasm"))]
+    pub fn attach_script(ms: &mut MachineState, index: usize, script: Script) -> std::io::Result<()> {
+        let uart = ms.uart.get_mut(index).ok_or(std::io::Error::new(std::io::ErrorKind::InvalidInput, "no such UART"))?;
+        let inner = std::mem::replace(&mut uart.rw, Box::new(dev_uart::NullUART{}));
+        uart.rw = Box::new(script.console(inner));
+        ms.script = Some(script);
+        Ok(())
+    }
+
+    /*
+    Records a trace of the events given as a comma-separated list of "inst", "reg", "mem", "exc", "tlb", "int" or "all"
+    into a file (see trace.rs for the format). Tracing starts and stops at triggers given as "pc:ADDR" or "count:N".
+    */
+    #[cfg(not(target_family = "wasm"))]
+    pub fn attach_trace(ms: &mut MachineState, path: &std::path::Path, events: &str, start: Option<&str>, stop: Option<&str>) -> Result<(), String> {
+        let events = trace::parse_events(events)?;
+        let start  = start.map(trace::Trigger::parse).transpose()?;
+        let stop   = stop.map(trace::Trigger::parse).transpose()?;
+        let f = std::fs::File::create(path).map_err(|e| e.to_string())?;
+        let tracer = trace::Tracer::new(Box::new(std::io::BufWriter::new(f)), events, start, stop).map_err(|e| e.to_string())?;
+        ms.trace = Some(tracer);
+        Ok(())
+    }
+
+    /*
+    Logs Linux system calls of the guest (o32 ABI) with their return values to a file,
+    or to the standard error if path is "-".
+    */
+    #[cfg(not(target_family = "wasm"))]
+    pub fn attach_syscall_trace(ms: &mut MachineState, path: &std::path::Path) -> std::io::Result<()> {
+        let out : Box<dyn std::io::Write> = if path.as_os_str() == "-" {
+            Box::new(std::io::stderr())
+        }else{
+            Box::new(std::io::BufWriter::new(std::fs::File::create(path)?))
+        };
+        ms.syscall_trace = Some(syscall_trace::SyscallTracer::new(out));
+        Ok(())
+    }
+
+    /*
+    Logs accesses to I/O registers to a file (the standard error if path is "-"),
+    or only counts accesses to unimplemented registers for write_mmio_report if path is None.
+    */
+    #[cfg(not(target_family = "wasm"))]
+    pub fn attach_mmio_log(ms: &mut MachineState, path: Option<&std::path::Path>) -> std::io::Result<()> {
+        let out : Option<Box<dyn std::io::Write>> = match path {
+            Some(p) if p.as_os_str() == "-" => Some(Box::new(std::io::stderr())),
+            Some(p) => Some(Box::new(std::io::BufWriter::new(std::fs::File::create(p)?))),
+            None    => None,
+        };
+        ms.mmio_log = Some(mmio_log::MmioLog::new(out));
+        Ok(())
+    }
+
+    // Writes the table of unimplemented I/O registers touched by the guest to a file (the standard error if path is "-")
+    #[cfg(not(target_family = "wasm"))]
+    pub fn write_mmio_report(ms: &MachineState, path: &std::path::Path) -> std::io::Result<()> {
+        let log = match ms.mmio_log.as_ref() {
+            Some(l) => l,
+            None    => { return Ok(()); }
+        };
+        if path.as_os_str() == "-" {
+            log.write_report(&mut std::io::stderr())
+        }else{
+            let mut f = std::io::BufWriter::new(std::fs::File::create(path)?);
+            log.write_report(&mut f)
+        }
+    }
+
+    // Converts a trace file into text with symbols in syms
+    #[cfg(not(target_family = "wasm"))]
+    pub fn trace_to_text(path: &std::path::Path, out: &mut dyn std::io::Write, syms: &SymbolTable) -> std::io::Result<()> {
+        let mut f = std::io::BufReader::new(std::fs::File::open(path)?);
+        trace::to_text(&mut f, out, syms)
+    }
+
+    /*
+    Enables the floating point unit (CP1) by setting Config1.FP.
+    The 74Kc core of AR9342 has no FPU, so it is disabled by default.
+    */
+    pub fn enable_fpu(ms: &mut MachineState) {
+        c0_val!(ms.reg, cp0def::C0_CONFIG1) |= cp0def::C0_CONFIG1_FP;
+    }
+
+    /*
+    Selects the interrupt mode of the processor (Config3.VInt and Config3.VEIC) and the shadow register sets.
+    IrqMode::Compat, the default, only has the single interrupt vector.
+    */
+    pub fn set_irq_mode(ms: &mut MachineState, mode: IrqMode) { exception::set_irq_mode(ms, mode) }
+
+    /*
+    Routes the performance counter interrupt to the IP line given by IntCtl.IPPCI (IP3)
+    instead of bit 5 of the misc. interrupt of the SoC (used by ath79 Linux).
+    */
+    pub fn route_perf_irq_to_ip(ms: &mut MachineState) { ms.misc.perf_irq_ip = true; }
+
+    /*
+    Loads symbols of the guest program from an ELF file or a System.map/kallsyms dump.
+    Returns the number of symbols loaded.
+    */
+    pub fn load_symbols(ms: &mut MachineState, data: &[u8]) -> Result<usize, String> { ms.symbols.load(data) }
+
+    /*
+    Samples the PC every interval instructions, or at every host timer tick if interval is 0.
+    The result is written by write_profile.
+    */
+    #[cfg(not(target_family = "wasm"))]
+    pub fn attach_profiler(ms: &mut MachineState, interval: u64) {
+        ms.profiler = Some(profiler::Profiler::new(interval));
+    }
+
+    /*
+    Writes the flat profile and the call stacks in the folded format (for flame graph tools)
+    with the symbols loaded by load_symbols.
+    */
+    #[cfg(not(target_family = "wasm"))]
+    pub fn write_profile(ms: &MachineState, flat: Option<&std::path::Path>, folded: Option<&std::path::Path>) -> std::io::Result<()> {
+        let prof = match ms.profiler.as_ref() {
+            Some(p) => p,
+            None    => { return Ok(()); }
+        };
+        if let Some(path) = flat {
+            let mut f = std::io::BufWriter::new(std::fs::File::create(path)?);
+            prof.write_flat(&ms.symbols, &mut f)?;
+        }
+        if let Some(path) = folded {
+            let mut f = std::io::BufWriter::new(std::fs::File::create(path)?);
+            prof.write_folded(&ms.symbols, &mut f)?;
+        }
+        Ok(())
+    }
+
+    /*
+    Opens a GDB remote serial protocol server for the console emulator.
+    spec is a TCP port ("1234"), an address with a port ("0.0.0.0:1234") or a Unix socket path.
+    If wait is true, the emulator waits for a client before executing the first instruction.
+    */
+    #[cfg(not(target_family = "wasm"))]
+    pub fn attach_gdb(ms: &mut MachineState, spec: &str, wait: bool) -> std::io::Result<()> {
+        let mut stub = gdbstub::GdbStub::bind(spec)?;
+        if wait {
+            stub.wait_for_client();
+        }
+        ms.gdb = Some(stub);
+        Ok(())
+    }
 
     
     pub fn generate_machine_state(flash_param: &'static SPIFlashParam, bindata: &[u8]) -> MachineState {
 
         #[cfg(not(target_family = "wasm"))]
         let stin_obj = stin::spawn_stdin_channel();
+        #[cfg(not(target_family = "wasm"))]
+        let console = std::rc::Rc::new(stin_obj.0);
 
         let mut ms = MachineState { 
             reg: Reg::new(),
             mem: MemRegion::new(),
             tlb: [ TLBEntry::new(); config::NUM_TLB_ENTRY as usize ],
             tlbcache: [ config::NUM_TLB_ENTRY as u8; config::TLB_CACHE_SIZE ],
-            emu: EmuSetting { breakpoint:0, breakmask:0xffffffff, runafterbreak:0, breakcounter:0, nexec_insts:0, execrate:0, stopcount:0, debug:false },
-            uart: IoUART::new(), 
+            emu: EmuSetting { bp:BreakpointSet::new(), runafterbreak:0, breakcounter:0, nexec_insts:0, execrate:0, stopcount:0, debug:false },
+            #[cfg(not(target_family = "wasm"))]
+            uart: [IoUART::new(Box::new(dev_uart::NativeUARTConsole{receiver: std::rc::Rc::clone(&console)})), IoUART::new(Box::new(dev_uart::NullUART{}))],
+            #[cfg(target_family = "wasm")]
+            uart: [IoUART::new(Box::new(dev_uart::WasmUARTConsole{})), IoUART::new(Box::new(dev_uart::NullUART{}))],
             gpio: IoGPIO::new(),
             spi: IoSPI::new(),
             misc: IoMisc::new(),
+            gmac: [IoGMAC::new(0), IoGMAC::new(1)],
             sleep_req: false,
+            trace: None,
+            symbols: SymbolTable::new(),
+            syscall_trace: None,
+            mmio_log: None,
 
             #[cfg(not(target_family = "wasm"))]
-            stdin_ch: Box::new(dev_uart::NativeUARTConsole{receiver: stin_obj.0}),
-            #[cfg(target_family = "wasm")]
-            stdin_ch: Box::new(dev_uart::WasmUARTConsole{}),
+            console,
             #[cfg(not(target_family = "wasm"))]
             ctrlc_count: stin_obj.1,
             #[cfg(not(target_family = "wasm"))]
             time_trigger: time_trig::spawn_time_trigger(),
+            #[cfg(not(target_family = "wasm"))]
+            gdb: None,
+            #[cfg(not(target_family = "wasm"))]
+            flash_store: None,
+            #[cfg(not(target_family = "wasm"))]
+            script: None,
+            #[cfg(not(target_family = "wasm"))]
+            profiler: None,
         };
 
         // prepares memory region of flash memory size and copies the image into the region
@@ -10,7 +10,7 @@ use std::fs::File;
 use log::{info,error};
 
 use exrmips1::{exrmips, SPIFlashParam};
-use clap::{arg, command, value_parser};
+use clap::{arg, command, value_parser, ArgAction};
 use std::path::PathBuf;
 
 fn main() -> io::Result<()> {
@@ -20,8 +20,16 @@ fn main() -> io::Result<()> {
     ))
     .arg(
         arg!(
-            -b --breakpoint [addr]  "Enable breakpoint"
+            -b --breakpoint [addr]  "Enable breakpoint (ADDR or SYMBOL[+OFF], followed by [,REG==VAL][,hits=N], can be repeated)"
         ).required(false)
+        .action(ArgAction::Append)
+        .value_parser(value_parser!(String)),
+    )
+    .arg(
+        arg!(
+            -w --watch [addr]  "Enable watchpoint (ADDR[+LEN][,r|w|rw][,phys], can be repeated)"
+        ).required(false)
+        .action(ArgAction::Append)
         .value_parser(value_parser!(String)),
     )
     .arg(
@@ -36,19 +44,233 @@ fn main() -> io::Result<()> {
         ).required(false)
         .value_parser(value_parser!(u32)),
     )
+    .arg(
+        arg!(
+            --gdb [port]   "Starts GDB server on TCP port (or address:port, or Unix socket path)"
+        ).required(false)
+        .value_parser(value_parser!(String)),
+    )
+    .arg(arg!(
+        --"gdb-wait"  "Waits for GDB connection before starting execution"
+    ))
+    .arg(arg!(
+        --headless  "Runs without a terminal (no raw mode, logs to stderr) and exits with a status for reset (0), error (1) or halt (2)"
+    ))
+    .arg(arg!(
+        --"flash-writeback"  "Writes modifications of the flash memory back to the image file on exit"
+    ).conflicts_with_all(["flash-output", "flash-overlay"]))
+    .arg(
+        arg!(
+            --"flash-output" [file]   "Writes the flash memory image to a file on exit"
+        ).required(false)
+        .conflicts_with("flash-overlay")
+        .value_parser(value_parser!(PathBuf)),
+    )
+    .arg(
+        arg!(
+            --"flash-overlay" [file]   "Records modifications of the flash memory in a copy-on-write overlay file"
+        ).required(false)
+        .value_parser(value_parser!(PathBuf)),
+    )
+    .arg(
+        arg!(
+            --elf [file]   "Loads an ELF executable into DRAM and starts from its entry point"
+        ).required(false)
+        .value_parser(value_parser!(PathBuf)),
+    )
+    .arg(
+        arg!(
+            --"elf-args" [args]   "Values of a0..a3 for the ELF executable (comma-separated, in hexadecimal)"
+        ).required(false)
+        .value_parser(value_parser!(String)),
+    )
+    .arg(
+        arg!(
+            --kernel [file]   "Boots a Linux kernel (vmlinux or uncompressed uImage) directly without U-Boot"
+        ).required(false)
+        .value_parser(value_parser!(PathBuf)),
+    )
+    .arg(
+        arg!(
+            --initrd [file]   "Initial ramdisk for the kernel given with --kernel"
+        ).required(false)
+        .requires("kernel")
+        .value_parser(value_parser!(PathBuf)),
+    )
+    .arg(
+        arg!(
+            --append [cmdline]   "Kernel command line for the kernel given with --kernel"
+        ).required(false)
+        .requires("kernel")
+        .value_parser(value_parser!(String)),
+    )
+    .arg(
+        arg!(
+            --eth0 [backend]   "Connects eth0 (GMAC0) to a host backend (tap:NAME, pcap:FILE, loopback[:FILE], user[,hostfwd=...] or none)"
+        ).required(false)
+        .value_parser(value_parser!(String)),
+    )
+    .arg(
+        arg!(
+            --eth1 [backend]   "Connects eth1 (GMAC1, built-in switch) to a host backend"
+        ).required(false)
+        .value_parser(value_parser!(String)),
+    )
+    .arg(
+        arg!(
+            --serial0 [backend]   "Connects UART0 to a host backend (stdio, null, file:PATH, tcp:[HOST:]PORT, unix:PATH, pty or mux:SPEC,SPEC,...)"
+        ).required(false)
+        .value_parser(value_parser!(String)),
+    )
+    .arg(
+        arg!(
+            --serial1 [backend]   "Connects UART1 to a host backend (not connected by default)"
+        ).required(false)
+        .value_parser(value_parser!(String)),
+    )
+    .arg(
+        arg!(
+            --script [file]   "Drives the console (UART0) with an expect-style script"
+        ).required(false)
+        .value_parser(value_parser!(PathBuf)),
+    )
+    .arg(
+        arg!(
+            --"snapshot-load" [file]   "Restores the machine state from a snapshot file before starting execution"
+        ).required(false)
+        .conflicts_with_all(["elf", "kernel"])
+        .value_parser(value_parser!(PathBuf)),
+    )
+    .arg(
+        arg!(
+            --"snapshot-save" [file]   "Saves the machine state to a snapshot file on exit"
+        ).required(false)
+        .value_parser(value_parser!(PathBuf)),
+    )
+    .arg(
+        arg!(
+            --trace [file]   "Records a trace of instructions and events to a file"
+        ).required(false)
+        .value_parser(value_parser!(PathBuf)),
+    )
+    .arg(
+        arg!(
+            --"trace-events" [events]   "Events to trace (comma-separated list of inst, reg, mem, exc, tlb, int or all; default: inst,exc,int)"
+        ).required(false)
+        .value_parser(value_parser!(String)),
+    )
+    .arg(
+        arg!(
+            --"trace-start" [trigger]   "Starts tracing at pc:ADDR (hexadecimal) or count:N (number of executed instructions)"
+        ).required(false)
+        .value_parser(value_parser!(String)),
+    )
+    .arg(
+        arg!(
+            --"trace-stop" [trigger]   "Stops tracing at pc:ADDR (hexadecimal) or count:N (number of executed instructions)"
+        ).required(false)
+        .value_parser(value_parser!(String)),
+    )
+    .arg(
+        arg!(
+            --symbols [file]   "Loads symbols of the guest program from an ELF file or a System.map/kallsyms dump"
+        ).required(false)
+        .action(ArgAction::Append)
+        .value_parser(value_parser!(PathBuf)),
+    )
+    .arg(
+        arg!(
+            --profile [file]   "Samples the PC and writes a flat profile to a file on exit"
+        ).required(false)
+        .value_parser(value_parser!(PathBuf)),
+    )
+    .arg(
+        arg!(
+            --"profile-folded" [file]   "Writes sampled call stacks in the folded format for flame graph tools on exit"
+        ).required(false)
+        .value_parser(value_parser!(PathBuf)),
+    )
+    .arg(
+        arg!(
+            --"profile-interval" [n]   "Samples every n instructions (default: at every host timer tick)"
+        ).required(false)
+        .value_parser(value_parser!(u64)),
+    )
+    .arg(
+        arg!(
+            --strace [file]   "Logs Linux system calls of the guest with their return values to a file (\"-\" for stderr)"
+        ).required(false)
+        .value_parser(value_parser!(PathBuf)),
+    )
+    .arg(
+        arg!(
+            --"mmio-log" [file]   "Logs accesses to I/O registers with the PC, address, width and value to a file (\"-\" for stderr)"
+        ).required(false)
+        .value_parser(value_parser!(PathBuf)),
+    )
+    .arg(
+        arg!(
+            --"mmio-report" [file]   "Writes the unimplemented I/O registers touched by the guest to a file on exit (\"-\" for stderr)"
+        ).required(false)
+        .value_parser(value_parser!(PathBuf)),
+    )
+    .arg(
+        arg!(
+            --fpu  "Enables the floating point unit (CP1), which the 74Kc core of AR9342 does not have"
+        ).required(false)
+    )
+    .arg(
+        arg!(
+            --"irq-mode" [mode]   "Interrupt mode: compat (single vector), vi (vectored interrupts) or eic (external interrupt controller)"
+        ).required(false)
+        .value_parser(["compat", "vi", "eic"]),
+    )
+    .arg(
+        arg!(
+            --"perf-irq-ip"  "Routes the performance counter interrupt to IP3 (IntCtl.IPPCI) instead of the misc. interrupt of the SoC (as ath79 Linux expects)"
+        ).required(false)
+    )
+    .arg(
+        arg!(
+            --"trace-dump" [file]   "Converts a trace file into text on stdout and exits"
+        ).required(false)
+        .value_parser(value_parser!(PathBuf)),
+    )
     .arg(
         arg!(
             [FILE] "System image file"
         )
-        .required(true)
+        .required_unless_present_any(["elf", "kernel", "snapshot-load", "trace-dump"])
         .value_parser(value_parser!(PathBuf)),
     )
     .get_matches();
 
 
+    if let Some(trace_path) = matches.get_one::<PathBuf>("trace-dump") {
+        let mut syms = exrmips::SymbolTable::new();
+        for symbols_path in matches.get_many::<PathBuf>("symbols").unwrap_or_default() {
+            if let Err(e) = std::fs::read(symbols_path).map_err(|e| e.to_string()).and_then(|data| syms.load(&data)) {
+                eprintln!("Can not load symbols \"{}\" : {}", symbols_path.display(), e);
+            }
+        }
+        let stdout = io::stdout();
+        let mut out = io::BufWriter::new(stdout.lock());
+        if let Err(e) = exrmips::trace_to_text(trace_path, &mut out, &syms) {
+            // the output may be closed by a pager
+            if e.kind() != io::ErrorKind::BrokenPipe {
+                eprintln!("Can not read trace \"{}\" : {}", trace_path.display(), e);
+                std::process::exit(1);
+            }
+        }
+        return Ok(());
+    }
+
+    let headless = matches.get_flag("headless");
+
+    // In headless mode, stdout is left for the console output of the guest
     CombinedLogger::init(
         vec![
-            TermLogger::new(LevelFilter::Debug, Config::default(), TerminalMode::Mixed, ColorChoice::Auto),
+            TermLogger::new(LevelFilter::Debug, Config::default(), if headless { TerminalMode::Stderr }else{ TerminalMode::Mixed }, ColorChoice::Auto),
             WriteLogger::new(LevelFilter::Info, Config::default(), File::create("exrmips.log").unwrap()),
         ]
     ).unwrap();
@@ -84,14 +306,113 @@ fn main() -> io::Result<()> {
 
     let mut ms = exrmips::generate_machine_state(flash_param,bindata);
 
-    if let Some(breakpoint_str) = matches.get_one::<String>("breakpoint") {
-        match u32::from_str_radix(breakpoint_str, 16) {
-            Ok(addr) => {
-                ms.emu.breakpoint = addr;
-                info!("Breakpoint is enabled : 0x{:x}", addr);
+    let flash_store = if matches.get_flag("flash-writeback") {
+        match matches.get_one::<PathBuf>("FILE") {
+            Some(file_path) => Some((file_path, exrmips::FlashStoreMode::WriteBack)),
+            None => { error!("--flash-writeback requires an image file"); None }
+        }
+    }else if let Some(output_path) = matches.get_one::<PathBuf>("flash-output") {
+        Some((output_path, exrmips::FlashStoreMode::Output))
+    }else{
+        matches.get_one::<PathBuf>("flash-overlay").map(|overlay_path| (overlay_path, exrmips::FlashStoreMode::Overlay))
+    };
+    if let Some((path, mode)) = flash_store {
+        if let Err(e) = exrmips::attach_flash_store(&mut ms, path, mode) {
+            error!("Can not use \"{}\" for the flash memory : {}", path.display(), e);
+        }
+    }
+
+    for (n, name) in ["eth0", "eth1"].iter().enumerate() {
+        if let Some(spec) = matches.get_one::<String>(name) {
+            match exrmips::attach_net(&mut ms, n, spec) {
+                Ok(_)  => { info!("{} is connected to \"{}\"", name, spec); }
+                Err(e) => { error!("Can not connect {} to \"{}\" : {}", name, spec, e); }
             }
-            _ => {
-                error!("Breakpoint \"{}\" is incorrect and is ignored", breakpoint_str);
+        }
+    }
+
+    for (n, name) in ["serial0", "serial1"].iter().enumerate() {
+        if let Some(spec) = matches.get_one::<String>(name) {
+            match exrmips::attach_serial(&mut ms, n, spec) {
+                Ok(_)  => { info!("{} is connected to \"{}\"", name, spec); }
+                Err(e) => { error!("Can not connect {} to \"{}\" : {}", name, spec, e); }
+            }
+        }
+    }
+
+    if let Some(script_path) = matches.get_one::<PathBuf>("script") {
+        let script = std::fs::read_to_string(script_path).map_err(|e| e.to_string()).and_then(|text| exrmips::Script::parse(&text));
+        match script {
+            Ok(script) => { exrmips::attach_script(&mut ms, 0, script)?; }
+            Err(e) => {
+                error!("Can not use script \"{}\" : {}", script_path.display(), e);
+                std::process::exit(exrmips::SCRIPT_FAILURE_EXIT_CODE);
+            }
+        }
+    }
+
+    if matches.get_flag("fpu") {
+        exrmips::enable_fpu(&mut ms);
+    }
+
+    if matches.get_flag("perf-irq-ip") {
+        exrmips::route_perf_irq_to_ip(&mut ms);
+    }
+
+    match matches.get_one::<String>("irq-mode").map(|s| s.as_str()) {
+        Some("vi")  => { exrmips::set_irq_mode(&mut ms, exrmips::IrqMode::Vectored); }
+        Some("eic") => { exrmips::set_irq_mode(&mut ms, exrmips::IrqMode::External); }
+        _           => { }
+    }
+
+    for symbols_path in matches.get_many::<PathBuf>("symbols").unwrap_or_default() {
+        match std::fs::read(symbols_path).map_err(|e| e.to_string()).and_then(|data| exrmips::load_symbols(&mut ms, &data)) {
+            Ok(n)  => { info!("{} symbols are loaded from \"{}\"", n, symbols_path.display()); }
+            Err(e) => { error!("Can not load symbols \"{}\" : {}", symbols_path.display(), e); }
+        }
+    }
+
+    if let Some(elf_path) = matches.get_one::<PathBuf>("elf") {
+        let mut args : Vec<u32> = Vec::new();
+        if let Some(args_str) = matches.get_one::<String>("elf-args") {
+            for a in args_str.split(',') {
+                match u32::from_str_radix(a.trim().trim_start_matches("0x"), 16) {
+                    Ok(v) => { args.push(v); }
+                    _     => { error!("ELF argument \"{}\" is incorrect and is ignored", a); }
+                }
+            }
+        }
+        match std::fs::read(elf_path) {
+            Ok(data) => {
+                match exrmips::load_elf(&mut ms, &data, &args) {
+                    Ok(entry) => { info!("ELF file is loaded : entry 0x{:>08x}", entry); }
+                    Err(e)    => { error!("Can not load ELF file \"{}\" : {}", elf_path.display(), e); }
+                }
+            }
+            Err(e) => {
+                error!("Can not open ELF file \"{}\" : {}", elf_path.display(), e);
+            }
+        }
+    }
+
+    for breakpoint_str in matches.get_many::<String>("breakpoint").unwrap_or_default() {
+        match exrmips::add_breakpoint(&mut ms, breakpoint_str) {
+            Ok(id) => {
+                info!("Breakpoint {} is enabled : {}", id, breakpoint_str);
+            }
+            Err(e) => {
+                error!("Breakpoint \"{}\" is incorrect and is ignored ({})", breakpoint_str, e);
+            }
+        }
+    }
+
+    for watch_str in matches.get_many::<String>("watch").unwrap_or_default() {
+        match exrmips::add_watchpoint(&mut ms, watch_str) {
+            Ok(id) => {
+                info!("Watchpoint {} is enabled : {}", id, watch_str);
+            }
+            Err(e) => {
+                error!("Watchpoint \"{}\" is incorrect and is ignored ({})", watch_str, e);
             }
         }
     }
@@ -108,7 +429,100 @@ fn main() -> io::Result<()> {
         }
     }
 
-    exrmips::run_term(&mut ms);
+    if let Some(kernel_path) = matches.get_one::<PathBuf>("kernel") {
+        let cmdline = matches.get_one::<String>("append").map(|s| s.as_str()).unwrap_or("");
+        let initrd = match matches.get_one::<PathBuf>("initrd") {
+            Some(initrd_path) => {
+                match std::fs::read(initrd_path) {
+                    Ok(data) => Some(data),
+                    Err(e)   => { error!("Can not open initrd \"{}\" : {}", initrd_path.display(), e); None }
+                }
+            }
+            None => None,
+        };
+        match std::fs::read(kernel_path) {
+            Ok(data) => {
+                match exrmips::boot_kernel(&mut ms, &data, initrd.as_deref(), cmdline) {
+                    Ok(entry) => { info!("Kernel is loaded : entry 0x{:>08x}", entry); }
+                    Err(e)    => { error!("Can not boot kernel \"{}\" : {}", kernel_path.display(), e); }
+                }
+            }
+            Err(e) => {
+                error!("Can not open kernel \"{}\" : {}", kernel_path.display(), e);
+            }
+        }
+    }
+
+    if let Some(snapshot_path) = matches.get_one::<PathBuf>("snapshot-load") {
+        if let Err(e) = exrmips::load_snapshot_file(&mut ms, snapshot_path) {
+            error!("Can not load snapshot \"{}\" : {}", snapshot_path.display(), e);
+        }
+    }
+
+    if let Some(gdb_spec) = matches.get_one::<String>("gdb") {
+        if let Err(e) = exrmips::attach_gdb(&mut ms, gdb_spec, matches.get_flag("gdb-wait")) {
+            error!("Can not start GDB server on \"{}\" : {}", gdb_spec, e);
+        }
+    }
+
+    let profile_path = matches.get_one::<PathBuf>("profile");
+    let folded_path  = matches.get_one::<PathBuf>("profile-folded");
+    if profile_path.is_some() || folded_path.is_some() {
+        exrmips::attach_profiler(&mut ms, matches.get_one::<u64>("profile-interval").copied().unwrap_or(0));
+    }
+
+    if let Some(trace_path) = matches.get_one::<PathBuf>("trace") {
+        let events = matches.get_one::<String>("trace-events").map(|s| s.as_str()).unwrap_or("inst,exc,int");
+        let start  = matches.get_one::<String>("trace-start").map(|s| s.as_str());
+        let stop   = matches.get_one::<String>("trace-stop").map(|s| s.as_str());
+        match exrmips::attach_trace(&mut ms, trace_path, events, start, stop) {
+            Ok(_)  => { info!("Trace is recorded to \"{}\" (events: {})", trace_path.display(), events); }
+            Err(e) => { error!("Can not record trace \"{}\" : {}", trace_path.display(), e); }
+        }
+    }
+
+    if let Some(strace_path) = matches.get_one::<PathBuf>("strace") {
+        if let Err(e) = exrmips::attach_syscall_trace(&mut ms, strace_path) {
+            error!("Can not write the syscall trace to \"{}\" : {}", strace_path.display(), e);
+        }
+    }
 
+    let mmio_log_path    = matches.get_one::<PathBuf>("mmio-log");
+    let mmio_report_path = matches.get_one::<PathBuf>("mmio-report");
+    if mmio_log_path.is_some() || mmio_report_path.is_some() {
+        if let Err(e) = exrmips::attach_mmio_log(&mut ms, mmio_log_path.map(|p| p.as_path())) {
+            error!("Can not write the MMIO log : {}", e);
+        }
+    }
+
+    let result = exrmips::run_term(&mut ms, headless);
+
+    if let Err(e) = exrmips::write_profile(&ms, profile_path.map(|p| p.as_path()), folded_path.map(|p| p.as_path())) {
+        error!("Can not write the profile : {}", e);
+    }
+
+    if let Some(path) = mmio_report_path {
+        if let Err(e) = exrmips::write_mmio_report(&ms, path) {
+            error!("Can not write the MMIO report : {}", e);
+        }
+    }
+
+    if let Some(snapshot_path) = matches.get_one::<PathBuf>("snapshot-save") {
+        match exrmips::save_snapshot_file(&mut ms, snapshot_path) {
+            Ok(_)  => { info!("Snapshot is saved to \"{}\"", snapshot_path.display()); }
+            Err(e) => { error!("Can not save snapshot \"{}\" : {}", snapshot_path.display(), e); }
+        }
+    }
+
+    if let exrmips::RunResult::Exit(code) = result {
+        std::process::exit(code);
+    }
+    if headless {
+        std::process::exit(match result {
+            exrmips::RunResult::Error => 1,
+            exrmips::RunResult::Halt  => 2,
+            _ => 0,
+        });
+    }
     Ok(())
 }
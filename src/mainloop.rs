@@ -7,8 +7,15 @@ use crate::cp0def;
 use crate::cp0;
 use crate::mem;
 use crate::exception;
+use crate::perf;
 use crate::dev_uart;
+use crate::dev_gmac;
 use crate::procstate;
+use crate::disasm;
+#[cfg(not(target_family = "wasm"))]
+use crate::monitor;
+#[cfg(not(target_family = "wasm"))]
+use crate::script::ScriptStatus;
 use crate::c0_val;
 use crate::mode_is_exception;
 use log::info;
@@ -35,11 +42,15 @@ pub async fn run_wasm(ms: &mut MachineState) {
         ms.reg.r[0] = 0;
         inst = mem::fetch_instruction(ms);
 
-        if 0 == (ms.reg.pc & 1) {
+        let mips16 = 0 != (ms.reg.pc & 1);
+        if ! mips16 {
             if ! exec_mips32::exec(ms, inst) { break; }
         }else{
             if ! exec_mips16::exec(ms, inst) { break; }
         }
+        if ms.reg.perf_en != 0 {
+            perf::count_inst(ms, mips16);
+        }
 
         if ms.emu.nexec_insts > prev_exec_insts + 10000  {
             let currenttime :u64 = (Date::now() as u64)*1000;
@@ -59,7 +70,13 @@ pub async fn run_wasm(ms: &mut MachineState) {
                 ms.reg.c0_count_currenttime    = currenttime;
                 ms.reg.c0_count_ninst_in_ctime = ms.emu.nexec_insts;
             }
-            dev_uart::read_reg(&mut ms.uart, &mut ms.stdin_ch, dev_uart::IOADDR_UART0_BASE + dev_uart::UART_REG_LINESTAT); // to update internal state
+            for uart in ms.uart.iter_mut() {
+                dev_uart::read_reg(uart, dev_uart::UART_REG_LINESTAT); // to update internal state
+            }
+
+            for gmac in ms.gmac.iter_mut() {
+                dev_gmac::poll(gmac, &mut ms.mem);
+            }
 
             if ms.misc.reset_request {
                 break;
@@ -70,25 +87,68 @@ pub async fn run_wasm(ms: &mut MachineState) {
             c0_val!(ms.reg,cp0def::C0_CAUSE) |=  1<<cp0def::C0_CAUSE_BIT_TI;
         }
 
-        if 0!=ms.uart.int_enable && 0!=((1<<3) & ms.misc.int_mask & dev_soc::read_misc_int_status_reg(ms)) {
+        if 0!=(ms.misc.int_mask & dev_soc::read_misc_int_status_reg(ms)) {
             c0_val!(ms.reg,cp0def::C0_CAUSE) |=   (1<<6)<<cp0def::C0_CAUSE_BIT_IP;
         }else{
             c0_val!(ms.reg,cp0def::C0_CAUSE) &= !((1<<6)<<cp0def::C0_CAUSE_BIT_IP);
         }
 
-        let status = c0_val!(ms.reg, cp0def::C0_STATUS);
-        if 0!=(status & (1<<cp0def::C0_STATUS_BIT_IE)) && !mode_is_exception!(status) {
-            if 0 != (c0_val!(ms.reg, cp0def::C0_CAUSE) & status & cp0def::C0_CAUSE_IP_MASK) {
-                exception::prepare_interrupt(ms, (c0_val!(ms.reg, cp0def::C0_CAUSE) & cp0def::C0_CAUSE_IP_MASK) >> cp0def::C0_CAUSE_BIT_IP );
+        for (gmac, ipnum) in ms.gmac.iter().zip(dev_gmac::GMAC_IRQ_IPNUM) {
+            if gmac.irq {
+                c0_val!(ms.reg,cp0def::C0_CAUSE) |=   (1<<ipnum)<<cp0def::C0_CAUSE_BIT_IP;
+            }else{
+                c0_val!(ms.reg,cp0def::C0_CAUSE) &= !((1<<ipnum)<<cp0def::C0_CAUSE_BIT_IP);
             }
         }
 
+        // performance counter interrupt on the line of IntCtl.IPPCI (routed to the misc. interrupt by default)
+        if ms.misc.perf_irq_ip && 0 != (c0_val!(ms.reg,cp0def::C0_CAUSE) & (1<<cp0def::C0_CAUSE_BIT_PCI)) {
+            c0_val!(ms.reg,cp0def::C0_CAUSE) |=   (1<<cp0def::C0_INTCTL_PCI_INT_IPNUM)<<cp0def::C0_CAUSE_BIT_IP;
+        }else{
+            c0_val!(ms.reg,cp0def::C0_CAUSE) &= !((1<<cp0def::C0_INTCTL_PCI_INT_IPNUM)<<cp0def::C0_CAUSE_BIT_IP);
+        }
+
+        exception::check_interrupt(ms);
+
         ms.emu.nexec_insts+=1;
     }
 }
 
+// reason why the emulation is stopped
+#[cfg(not(target_family = "wasm"))]
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum RunResult {
+    Quit,   // quit from the monitor or the debugger, or the instruction count is reached
+    Reset,  // the guest requested a reset
+    Halt,   // the guest stopped with interrupts masked (e.g., halt or poweroff of Linux)
+    Error,  // an instruction could not be executed
+    Exit(i32), // exit with the status given by the script or the test device
+}
+
+// true if the instruction never finishes because no interrupt can be taken
+// (wait with all interrupts masked or a branch to itself with interrupts disabled)
+#[cfg(not(target_family = "wasm"))]
+fn is_halted(ms: &MachineState, m32mode: bool, inst: u32) -> bool {
+    let status = c0_val!(ms.reg, cp0def::C0_STATUS);
+    let masked = exception::irq_all_masked(ms);
+
+    if !m32mode || ms.reg.delay_en {
+        return false;
+    }
+    match inst {
+        0x42000020 => masked, // wait
+        0x1000ffff => masked || 0 == (status & (1<<cp0def::C0_STATUS_BIT_IE)) || mode_is_exception!(status), // b .
+        _ => false,
+    }
+}
+
+/*
+Runs the emulation on the terminal until it stops.
+In headless mode, the terminal is not set to raw mode
+and the emulation also stops when the guest halts.
+*/
 #[cfg(not(target_family = "wasm"))]
-pub fn run_term(ms: &mut MachineState) {
+pub fn run_term(ms: &mut MachineState, headless: bool) -> RunResult {
     let mut pointer : u32 = ms.reg.pc;
     let mut inst    : u32;
     let mut m32mode : bool;
@@ -99,52 +159,146 @@ pub fn run_term(ms: &mut MachineState) {
     let time_trig = Arc::clone(&ms.time_trigger); 
     let mut prev_ctrlc_num: usize     = 0;
     let mut prev_ctrlc_trig_time: u64 = 0;
+    let mut monitor_req = false;
+
+    let mut result = RunResult::Quit;
 
-    let _stdout = stdout().into_raw_mode().unwrap();
+    let _stdout = if headless { None }else{ Some(stdout().into_raw_mode().unwrap()) };
 
     ms.emu.nexec_insts = 0;
     ms.emu.stopcount   = 0;
+    ms.reg.c0_count_ninst_in_ctime = 0;
 
     ms.emu.debug = false;
 
+    // The time of the counter continues from the current value (e.g., restored from a snapshot)
+    let time_origin = ms.reg.c0_count_currenttime;
+
+    // The GDB stub is detached from the machine state while running
+    // so that it can be served with a mutable reference to the machine state.
+    let mut gdb = ms.gdb.take();
+    let mut script = ms.script.take();
+    let mut profiler = ms.profiler.take();
+
     while ms.emu.stopcount == 0 || (ms.emu.stopcount > 0 && ms.emu.stopcount >= ms.emu.nexec_insts) {
         ms.reg.r[0] = 0;
 
         inst = mem::fetch_instruction(ms);
         pointer = ms.reg.pc;
+
+        if let Some(stub) = gdb.as_mut() {
+            if stub.check(pointer) {
+                if ! stub.serve(ms) {
+                    break;
+                }
+                // registers and memory may have been modified by the debugger
+                ms.reg.r[0] = 0;
+                inst = mem::fetch_instruction(ms);
+                pointer = ms.reg.pc;
+            }
+        }
         m32mode = if 0==(pointer&1) { true }else{ false };
 
         if ms.emu.debug {
             info!("================================== \r");
-            info!("pointer: {:>08x}  insts = {:>08x} \r", pointer, inst );
+            info!("pointer: {:>08x}{}  insts = {:>08x}  {}\r", pointer, ms.symbols.annotate(pointer), inst, disasm::disassemble(pointer, inst));
             procstate::log_print_reg32(&ms.reg);
         }
-        if ((pointer&ms.emu.breakmask) == (ms.emu.breakpoint&ms.emu.breakmask) && ms.emu.stopcount==0) || (ms.emu.breakcounter != 0 && ms.emu.breakcounter == ms.emu.nexec_insts) {
-            info!("Breakpoint\r");
+        let bp_hit = if ms.emu.stopcount == 0 { ms.emu.bp.check_pc(&ms.reg, pointer) }else{ None };
+        if bp_hit.is_some() || (ms.emu.breakcounter != 0 && ms.emu.breakcounter == ms.emu.nexec_insts) {
+            match bp_hit {
+                Some(id) => { info!("Breakpoint {} at 0x{:>08x}{}\r", id, pointer, ms.symbols.annotate(pointer)); }
+                None     => { info!("Breakpoint at 0x{:>08x}{}\r", pointer, ms.symbols.annotate(pointer)); }
+            }
             info!("================================== \r");
-            info!("pointer: {:>08x}  insts = {:>08x} \r", pointer, inst);
+            info!("pointer: {:>08x}{}  insts = {:>08x}  {}\r", pointer, ms.symbols.annotate(pointer), inst, disasm::disassemble(pointer, inst));
             procstate::log_print_reg32(&ms.reg);
     
-            ms.emu.debug = true;
-            ms.emu.stopcount = ms.emu.nexec_insts + ms.emu.runafterbreak;
+            if ms.emu.runafterbreak != 0 {
+                // executes the specified number of instructions with debug outputs and terminates
+                ms.emu.debug = true;
+                ms.emu.stopcount = ms.emu.nexec_insts + ms.emu.runafterbreak;
+            }else{
+                monitor_req = true;
+            }
+        }
+
+        if monitor_req {
+            monitor_req = false;
+            match monitor::run(ms) {
+                monitor::MonitorResult::Quit => { break; }
+                monitor::MonitorResult::Continue => {
+                    // Ctrl+C inputs in the monitor are ignored
+                    prev_ctrlc_num = ctrlc_num.load(atomic::Ordering::Relaxed);
+                }
+            }
         }
 
 /*
 saveInstPointer(pointer);
 */
+        if let Some(t) = ms.trace.as_mut() {
+            t.fetch(&ms.reg, ms.emu.nexec_insts, pointer, inst);
+        }
+        if let Some(p) = profiler.as_mut() {
+            p.on_instruction(ms, pointer);
+        }
+
+        if headless && is_halted(ms, m32mode, inst) {
+            // A reset request is processed in the next timer period, which the guest may wait for in a loop
+            if ms.misc.reset_request {
+                info!("System reset ...\r");
+                result = RunResult::Reset;
+            }else{
+                info!("The guest is halted (pc: 0x{:>08x})\r", pointer);
+                result = RunResult::Halt;
+            }
+            break;
+        }
+
         if m32mode {
             if ! exec_mips32::exec(ms, inst) {
+                result = RunResult::Error;
                 break;
             }
         }else{
             if ! exec_mips16::exec(ms, inst) {
+                result = RunResult::Error;
                 break;
             }
         }
 
+        if ms.reg.perf_en != 0 {
+            perf::count_inst(ms, !m32mode);
+        }
+
+        if let Some(t) = ms.trace.as_mut() {
+            t.retire(&ms.reg);
+        }
+
+        if let Some(code) = ms.misc.exit_request.take() {
+            info!("Exit is requested by the guest (status {})\r", code);
+            result = RunResult::Exit(code);
+            break;
+        }
+
+        if let Some(hit) = ms.emu.bp.watch_hit.take() {
+            if ms.emu.stopcount == 0 {
+                info!("Watchpoint {} : {} {} byte(s) at 0x{:>08x}{} (paddr 0x{:>08x}){} by the instruction at 0x{:>08x}{}\r",
+                    hit.id, if hit.write { "store" }else{ "load" }, hit.width, hit.vaddr, ms.symbols.annotate(hit.vaddr), hit.paddr,
+                    if hit.write { format!(", data 0x{:x}", hit.data) }else{ String::new() }, pointer, ms.symbols.annotate(pointer));
+                if ms.emu.runafterbreak != 0 {
+                    ms.emu.debug = true;
+                    ms.emu.stopcount = ms.emu.nexec_insts + ms.emu.runafterbreak;
+                }else{
+                    monitor_req = true;
+                }
+            }
+        }
+
         if time_trig.load(atomic::Ordering::Relaxed)  {
             time_trig.swap(false, atomic::Ordering::Relaxed);
-            let currenttime :u64 = start.elapsed().as_micros() as u64;
+            let currenttime :u64 = time_origin + start.elapsed().as_micros() as u64;
 
             // Calculating the instruction execution rate
             ms.emu.execrate = (ms.emu.nexec_insts - prev_exec_insts)*((1000*1000 / config::SYSTEM_TIMER_INTERVAL_IN_USEC) as u64);
@@ -153,14 +307,32 @@ saveInstPointer(pointer);
             // Updating the CoProcessor0 Counter
             ms.reg.c0_count_currenttime    = currenttime;
             ms.reg.c0_count_ninst_in_ctime = ms.emu.nexec_insts;
-            dev_uart::read_reg(&mut ms.uart, &mut ms.stdin_ch, dev_uart::IOADDR_UART0_BASE + dev_uart::UART_REG_LINESTAT); // to update internal state
+            for uart in ms.uart.iter_mut() {
+                dev_uart::read_reg(uart, dev_uart::UART_REG_LINESTAT); // to update internal state
+            }
+
+            for gmac in ms.gmac.iter_mut() {
+                dev_gmac::poll(gmac, &mut ms.mem);
+            }
+
+            if let Some(stub) = gdb.as_mut() {
+                stub.poll();
+            }
+
+            if let Some(p) = profiler.as_mut() {
+                p.on_tick(ms);
+            }
 
+            if let Some(ScriptStatus::Exit(code)) = script.as_mut().map(|s| s.poll(ms)) {
+                result = RunResult::Exit(code);
+                break;
+            }
 
             // Checking Ctrl+C inputs.
             if ctrlc_num.load(atomic::Ordering::Relaxed) != prev_ctrlc_num {
                 // time between two Ctrl+C keyins is shorter than 1000ms, then enter the monitor
                 if currenttime - prev_ctrlc_trig_time < 1000*1000 {
-                    ms.misc.reset_request = true;
+                    monitor_req = true;
                     prev_ctrlc_trig_time = 0;
                 }else{
                     prev_ctrlc_trig_time = currenttime;
@@ -168,15 +340,16 @@ saveInstPointer(pointer);
                 prev_ctrlc_num = ctrlc_num.load(atomic::Ordering::Relaxed);
             }
             if prev_ctrlc_trig_time != 0 &&  currenttime - prev_ctrlc_trig_time >= 1000*1000 {
-                dev_uart::request_send_break(&mut ms.uart);
+                dev_uart::request_send_break(&mut ms.uart[0]);
                 prev_ctrlc_trig_time = 0;
             }
 
 
 
             if ms.misc.reset_request {
-                print!("\r\n\r\n");
+                if !headless { print!("\r\n\r\n"); }
                 info!("System reset ...\r");
+                result = RunResult::Reset;
                 break;
             }
         }
@@ -185,21 +358,87 @@ saveInstPointer(pointer);
             c0_val!(ms.reg,cp0def::C0_CAUSE) |=  1<<cp0def::C0_CAUSE_BIT_TI;
         }
 
-        if 0!=ms.uart.int_enable && 0!=((1<<3) & ms.misc.int_mask & dev_soc::read_misc_int_status_reg(ms)) {
+        if 0!=(ms.misc.int_mask & dev_soc::read_misc_int_status_reg(ms)) {
             c0_val!(ms.reg,cp0def::C0_CAUSE) |=   (1<<6)<<cp0def::C0_CAUSE_BIT_IP;
         }else{
             c0_val!(ms.reg,cp0def::C0_CAUSE) &= !((1<<6)<<cp0def::C0_CAUSE_BIT_IP);
         }
 
-        let status = c0_val!(ms.reg, cp0def::C0_STATUS);
-        if 0!=(status & (1<<cp0def::C0_STATUS_BIT_IE)) && !mode_is_exception!(status) {
-            if 0 != (c0_val!(ms.reg, cp0def::C0_CAUSE) & status & cp0def::C0_CAUSE_IP_MASK) {
-                exception::prepare_interrupt(ms, (c0_val!(ms.reg, cp0def::C0_CAUSE) & cp0def::C0_CAUSE_IP_MASK) >> cp0def::C0_CAUSE_BIT_IP );
+        for (gmac, ipnum) in ms.gmac.iter().zip(dev_gmac::GMAC_IRQ_IPNUM) {
+            if gmac.irq {
+                c0_val!(ms.reg,cp0def::C0_CAUSE) |=   (1<<ipnum)<<cp0def::C0_CAUSE_BIT_IP;
+            }else{
+                c0_val!(ms.reg,cp0def::C0_CAUSE) &= !((1<<ipnum)<<cp0def::C0_CAUSE_BIT_IP);
             }
         }
 
+        // performance counter interrupt on the line of IntCtl.IPPCI (routed to the misc. interrupt by default)
+        if ms.misc.perf_irq_ip && 0 != (c0_val!(ms.reg,cp0def::C0_CAUSE) & (1<<cp0def::C0_CAUSE_BIT_PCI)) {
+            c0_val!(ms.reg,cp0def::C0_CAUSE) |=   (1<<cp0def::C0_INTCTL_PCI_INT_IPNUM)<<cp0def::C0_CAUSE_BIT_IP;
+        }else{
+            c0_val!(ms.reg,cp0def::C0_CAUSE) &= !((1<<cp0def::C0_INTCTL_PCI_INT_IPNUM)<<cp0def::C0_CAUSE_BIT_IP);
+        }
+
+        exception::check_interrupt(ms);
+
         ms.emu.nexec_insts+=1;
     }
 
-    info!("pointer 0x{:>x}\r", pointer);
+    ms.gdb = gdb;
+    ms.script = script;
+    ms.profiler = profiler;
+
+    info!("pointer 0x{:>x}{}\r", pointer, ms.symbols.annotate(pointer));
+    result
+}
+
+#[cfg(all(test, not(target_family = "wasm")))]
+mod tests {
+    use super::*;
+    use crate::dev_spiflash::SPI_FLASH_PARAM_S25FL164K;
+    use crate::exrmips::generate_machine_state;
+
+    const WAIT   : u32 = 0x42000020;
+    const B_SELF : u32 = 0x1000ffff; // b .
+    const IE  : u32 = 1<<cp0def::C0_STATUS_BIT_IE;
+    const EXL : u32 = 1<<cp0def::C0_STATUS_BIT_EXL;
+    const IM2 : u32 = 1<<(cp0def::C0_STATUS_BIT_IM + 2);
+
+    fn halted(status: u32, inst: u32) -> bool {
+        let mut ms = generate_machine_state(&SPI_FLASH_PARAM_S25FL164K, &[]);
+        c0_val!(ms.reg, cp0def::C0_STATUS) = status;
+        is_halted(&ms, true, inst)
+    }
+
+    #[test]
+    fn wait_with_interrupts_masked() {
+        assert!(halted(0, WAIT));
+        assert!(halted(IE, WAIT));
+        assert!(halted(IE | EXL, WAIT));
+        // an interrupt can wake up the processor even with interrupts disabled
+        assert!(!halted(IM2, WAIT));
+        assert!(!halted(IM2 | IE, WAIT));
+    }
+
+    #[test]
+    fn branch_to_itself() {
+        assert!(halted(IM2, B_SELF));
+        assert!(halted(IM2 | IE | EXL, B_SELF));
+        assert!(halted(IE, B_SELF));
+        // a busy loop waiting for an interrupt
+        assert!(!halted(IM2 | IE, B_SELF));
+    }
+
+    #[test]
+    fn other_cases() {
+        assert!(!halted(0, 0x00000000)); // nop
+        assert!(!halted(0, 0x1000fffe)); // b .-4
+
+        let mut ms = generate_machine_state(&SPI_FLASH_PARAM_S25FL164K, &[]);
+        c0_val!(ms.reg, cp0def::C0_STATUS) = 0;
+        // the same code in MIPS16e mode or in a delay slot
+        assert!(!is_halted(&ms, false, WAIT));
+        ms.reg.delay_en = true;
+        assert!(!is_halted(&ms, true, WAIT));
+    }
 }
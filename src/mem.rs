@@ -4,9 +4,12 @@ use crate::exec_mips16;
 use crate::dev_uart;
 use crate::dev_soc;
 use crate::dev_spi;
+use crate::dev_gmac;
 use crate::cp0def;
 use crate::tlb;
 use crate::exception;
+use crate::watch;
+use crate::perf;
 use crate::kseg01_to_paddr;
 use crate::mode_is_in_error;
 use crate::mode_is_exception;
@@ -218,6 +221,10 @@ fn get_phy_addr(ms : &mut MachineState, addr: u32, is_write: bool) -> Result<u32
 
 pub fn fetch_instruction(ms : &mut MachineState) -> u32{
 
+    if (ms.reg.watch_en != 0 || 0 != (c0_val!(ms.reg, cp0def::C0_CAUSE) & (1<<cp0def::C0_CAUSE_BIT_WP))) && watch::check_fetch(ms) {
+        return fetch_instruction(ms);
+    }
+
     if (ms.reg.pc & 3) == 2 {
         // If the lower 2 bits of PC is 10, PC value is misaligned.
         //   ...00 is valid MIPS32 address.
@@ -329,6 +336,9 @@ fn wrdata_align(width : u32, addr : u32, val : u32) -> u32{
 }
 
 fn load_memory(ms : &mut MachineState, vaddr : u32, acc_width : u32) -> Result<u32,u32> { 
+    if 0 != (ms.reg.watch_en & watch::WATCH_R) && watch::check(ms, vaddr, watch::WATCH_R) {
+        return Err(cp0def::EXCEPT_CODE_WATCH);
+    }
     let asid : u32 = c0_val!(ms.reg, cp0def::C0_ENTRYHI) & cp0def::C0_ENTRYHI_ASID_MASK;
     let mode : u32 = c0_val!(ms.reg, cp0def::C0_STATUS)  & (cp0def::C0_STATUS_KSU_MASK | (1<<cp0def::C0_STATUS_BIT_ERL) | (1<<cp0def::C0_STATUS_BIT_EXL));
 
@@ -358,6 +368,49 @@ fn load_memory(ms : &mut MachineState, vaddr : u32, acc_width : u32) -> Result<u
         }
     }
 
+    if 0 != (ms.reg.perf_en & (1<<perf::EV_LOADS)) {
+        perf::count(ms, 1<<perf::EV_LOADS);
+    }
+    if ms.emu.bp.wp_active {
+        ms.emu.bp.check_access(vaddr, paddr, acc_width, false, 0);
+    }
+
+    let val = load_phys_memory(ms, paddr, acc_width)?;
+    if let Some(t) = ms.trace.as_mut() {
+        t.mem(vaddr, paddr, acc_width, val, false);
+    }
+    if ms.mmio_log.is_some() && is_mmio(ms, paddr) {
+        if let Some(l) = ms.mmio_log.as_mut() {
+            l.access(ms.reg.pc, paddr, acc_width, val, false);
+        }
+    }
+    Ok(val)
+}
+
+// I/O registers, i.e., neither DRAM nor the flash memory mapped to the ROM area
+fn is_mmio(ms : &MachineState, paddr : u32) -> bool {
+    if (config::RAM_AREA_ADDR..config::RAM_AREA_ADDR+config::RAM_AREA_SIZE).contains(&paddr) {
+        return false;
+    }
+    if (config::ROM_AREA_ADDR..config::ROM_AREA_ADDR+config::ROM_AREA_SIZE).contains(&paddr) {
+        // SPI registers, or the whole area in GPIO mode
+        return 0 != (dev_spi::read_reg(&ms.spi, dev_spi::SPI_FUNC_SEL_REG) & 1) ||
+            (dev_spi::SPI0_BASE_ADDRESS..dev_spi::SPI0_BASE_ADDRESS+dev_spi::SPI_ADDR_SIZE).contains(&paddr);
+    }
+    true
+}
+
+// Records an access to an I/O register without an emulation in the MMIO log. Returns the value read (0).
+fn unimplemented_reg(ms : &mut MachineState, paddr : u32, store : bool, data : u32) -> u32 {
+    if ms.mmio_log.is_some() && is_mmio(ms, paddr) {
+        if let Some(l) = ms.mmio_log.as_mut() {
+            l.unimplemented(paddr, store, data);
+        }
+    }
+    0
+}
+
+fn load_phys_memory(ms : &mut MachineState, paddr : u32, acc_width : u32) -> Result<u32,u32> {
     let align_addr :u32 = paddr & !(3 as u32);
 
     if paddr >= config::RAM_AREA_ADDR && paddr < config::RAM_AREA_ADDR+config::RAM_AREA_SIZE {
@@ -378,7 +431,7 @@ fn load_memory(ms : &mut MachineState, vaddr : u32, acc_width : u32) -> Result<u
             if paddr >= dev_spi::SPI0_BASE_ADDRESS && paddr < dev_spi::SPI0_BASE_ADDRESS+dev_spi::SPI_ADDR_SIZE {
                 return Ok( accsize_align(acc_width, paddr, dev_spi::read_reg(&ms.spi, align_addr)) );
             }else{
-                return Ok(0);
+                return Ok(unimplemented_reg(ms, paddr, false, 0));
             }
         }else{
             // GPIO mode is disabled. SPI flash memory data is mapped to this region.
@@ -391,10 +444,16 @@ fn load_memory(ms : &mut MachineState, vaddr : u32, acc_width : u32) -> Result<u
             return Ok(val);
         }
 
-    }else if paddr >= dev_uart::IOADDR_UART0_BASE && paddr < dev_uart::IOADDR_UART0_BASE+dev_uart::IOADDR_UART_SIZE {
+    }else if let Some(n) = dev_uart::uart_index(paddr) {
         // UART
 
-        return Ok( accsize_align(acc_width, paddr, dev_uart::read_reg(&mut ms.uart, &mut ms.stdin_ch, align_addr) as u32));
+        return Ok( accsize_align(acc_width, paddr, dev_uart::read_reg(&mut ms.uart[n], align_addr) as u32));
+
+    }else if (paddr & !dev_gmac::GMAC_ADDR_MASK) == dev_gmac::GMAC0_BASE_ADDRESS || (paddr & !dev_gmac::GMAC_ADDR_MASK) == dev_gmac::GMAC1_BASE_ADDRESS {
+        // Ethernet MAC
+
+        let n = if (paddr & !dev_gmac::GMAC_ADDR_MASK) == dev_gmac::GMAC0_BASE_ADDRESS { 0 }else{ 1 };
+        return Ok( accsize_align(acc_width, paddr, dev_gmac::read_reg(&mut ms.gmac[n], align_addr)) );
 
     }else if paddr >= dev_soc::GPIO_BASE_REG && paddr < dev_soc::GPIO_BASE_REG+0x100 {
         // GPIO
@@ -402,7 +461,7 @@ fn load_memory(ms : &mut MachineState, vaddr : u32, acc_width : u32) -> Result<u
         return match align_addr - dev_soc::GPIO_BASE_REG {
             0x00 /*GPIO_OE */ => Ok(accsize_align(acc_width, paddr, ms.gpio.oe)),
             0x08 /*GPIO_OUT*/ => Ok(accsize_align(acc_width, paddr, ms.gpio.out)),
-            _                 => Ok(0)
+            _                 => Ok(unimplemented_reg(ms, paddr, false, 0))
         };
 
     }else if paddr >= dev_soc::RTC_BASE_REG && paddr < dev_soc::RTC_BASE_REG+0x5c {
@@ -410,7 +469,7 @@ fn load_memory(ms : &mut MachineState, vaddr : u32, acc_width : u32) -> Result<u
 
         return match align_addr - dev_soc::RTC_BASE_REG {
             0x44 => Ok( accsize_align(acc_width, paddr, 2) ),
-            _    => Ok(0),
+            _    => Ok(unimplemented_reg(ms, paddr, false, 0)),
         };
 
     }else if paddr >= dev_soc::RST_BASE_REG && paddr < dev_soc::RST_BASE_REG+0x100 {
@@ -421,7 +480,7 @@ fn load_memory(ms : &mut MachineState, vaddr : u32, acc_width : u32) -> Result<u
             dev_soc::RST_BOOTSTRAP_REG             => Ok( accsize_align(acc_width, paddr, (7<<8) | (1<<2) | (1<<4)) ), // Reference clock : 40MHz
             dev_soc::RST_REVISION_ID_REG           => Ok( accsize_align(acc_width, paddr, dev_soc::RST_REVISION_ID_MAJOR_AR9342_VAL | 3) ), // SOC index (AR9342)
             dev_soc::RST_MISC_INTERRUPT_MASK_REG   => Ok( accsize_align(acc_width, paddr, ms.misc.int_mask) ),
-            _                                      => Ok(0)
+            _                                      => Ok(unimplemented_reg(ms, paddr, false, 0))
         };
 
     }else if paddr >= dev_soc::PLL_BASE_REG && paddr < dev_soc::PLL_BASE_REG+0x100 {
@@ -429,7 +488,7 @@ fn load_memory(ms : &mut MachineState, vaddr : u32, acc_width : u32) -> Result<u
 
         return match align_addr {
             dev_soc::PLL_CPU_DDR_CLK_CTRL_REG => Ok( accsize_align(acc_width, paddr, 1<<20) ), // CPU clock from CPU PLL
-            _                                 => Ok(0)
+            _                                 => Ok(unimplemented_reg(ms, paddr, false, 0))
         };
 
     }else if paddr >= dev_soc::PLL_SRIF_CPU_DPLL_BASE_REG && paddr < dev_soc::PLL_SRIF_CPU_DPLL_BASE_REG+0x100 {
@@ -438,15 +497,25 @@ fn load_memory(ms : &mut MachineState, vaddr : u32, acc_width : u32) -> Result<u
         return match align_addr {
             dev_soc::PLL_SRIF_CPU_DPLL1_REG => Ok( accsize_align(acc_width, paddr, (1<<27 /*refdiv*/) + (10<<18 /*nint*/) + (0 /*nfrac*/)) ),
             dev_soc::PLL_SRIF_CPU_DPLL2_REG => Ok( accsize_align(acc_width, paddr, (1<<30) + (0<<13 /*outdiv*/)) ),
-            _                               => Ok(0)
+            _                               => Ok(unimplemented_reg(ms, paddr, false, 0))
         };
-    }
 
+    }else if paddr >= dev_soc::TEST_BASE_REG && paddr < dev_soc::TEST_BASE_REG+dev_soc::TEST_REG_SIZE {
+        // Test device
+
+        return match align_addr {
+            dev_soc::TEST_ID_REG => Ok( accsize_align(acc_width, paddr, dev_soc::TEST_ID_VAL) ),
+            _                    => Ok(unimplemented_reg(ms, paddr, false, 0))
+        };
+    }
 
-    Ok(0)
+    Ok(unimplemented_reg(ms, paddr, false, 0))
 }
 
 fn store_memory(ms : &mut MachineState, vaddr : u32, acc_width: u32, data : u32) -> Result<(),u32> {
+    if 0 != (ms.reg.watch_en & watch::WATCH_W) && watch::check(ms, vaddr, watch::WATCH_W) {
+        return Err(cp0def::EXCEPT_CODE_WATCH);
+    }
     let asid : u32 = c0_val!(ms.reg, cp0def::C0_ENTRYHI) & cp0def::C0_ENTRYHI_ASID_MASK;
     let mode : u32 = c0_val!(ms.reg, cp0def::C0_STATUS)  & (cp0def::C0_STATUS_KSU_MASK | (1<<cp0def::C0_STATUS_BIT_ERL) | (1<<cp0def::C0_STATUS_BIT_EXL));
 
@@ -477,7 +546,26 @@ fn store_memory(ms : &mut MachineState, vaddr : u32, acc_width: u32, data : u32)
         }
     }
 
+    if 0 != (ms.reg.perf_en & (1<<perf::EV_STORES)) {
+        perf::count(ms, 1<<perf::EV_STORES);
+    }
+    if ms.emu.bp.wp_active {
+        ms.emu.bp.check_access(vaddr, paddr, acc_width, true, data);
+    }
+    if let Some(t) = ms.trace.as_mut() {
+        t.mem(vaddr, paddr, acc_width, data, true);
+    }
+
+    store_phys_memory(ms, paddr, acc_width, data)?;
+    if ms.mmio_log.is_some() && is_mmio(ms, paddr) {
+        if let Some(l) = ms.mmio_log.as_mut() {
+            l.access(ms.reg.pc, paddr, acc_width, data, true);
+        }
+    }
+    Ok(())
+}
 
+fn store_phys_memory(ms : &mut MachineState, paddr : u32, acc_width: u32, data : u32) -> Result<(),u32> {
     let align_addr :u32 = paddr & !(3 as u32);
 
     if paddr >= config::RAM_AREA_ADDR && paddr < config::RAM_AREA_ADDR+config::RAM_AREA_SIZE {
@@ -510,10 +598,16 @@ fn store_memory(ms : &mut MachineState, vaddr : u32, acc_width: u32, data : u32)
         dev_spi::write_reg(&mut ms.spi, align_addr, aligned_data );
         return Ok(());
 
-    }else if paddr >= dev_uart::IOADDR_UART0_BASE && paddr < dev_uart::IOADDR_UART0_BASE+dev_uart::IOADDR_UART_SIZE {
+    }else if let Some(n) = dev_uart::uart_index(paddr) {
         // UART
 
-        dev_uart::write_reg(&mut ms.uart, &mut ms.stdin_ch, align_addr, wrdata_align(acc_width, paddr, data) as u8);
+        dev_uart::write_reg(&mut ms.uart[n], align_addr, wrdata_align(acc_width, paddr, data) as u8);
+        return Ok(());
+    }else if (paddr & !dev_gmac::GMAC_ADDR_MASK) == dev_gmac::GMAC0_BASE_ADDRESS || (paddr & !dev_gmac::GMAC_ADDR_MASK) == dev_gmac::GMAC1_BASE_ADDRESS {
+        // Ethernet MAC
+
+        let n = if (paddr & !dev_gmac::GMAC_ADDR_MASK) == dev_gmac::GMAC0_BASE_ADDRESS { 0 }else{ 1 };
+        dev_gmac::write_reg(&mut ms.gmac[n], &mut ms.mem, align_addr, wrdata_align(acc_width, paddr, data));
         return Ok(());
     }else if paddr >= dev_soc::GPIO_BASE_REG && paddr < dev_soc::GPIO_BASE_REG+0x100 {
         // GPIO
@@ -522,15 +616,20 @@ fn store_memory(ms : &mut MachineState, vaddr : u32, acc_width: u32, data : u32)
             0x08 /*GPIO_OUT*/ => { ms.gpio.out = wrdata_align(acc_width, paddr, data);  }
             0x0C /*GPIO_SET*/ => { ms.gpio.out|= wrdata_align(acc_width, paddr, data);  }
             0x10 /*GPIO_CLR*/ => { ms.gpio.out&=!wrdata_align(acc_width, paddr, data);  }
-            _                 => { }
+            _                 => { unimplemented_reg(ms, paddr, true, data); }
         }
     }else if paddr >= dev_soc::RST_BASE_REG && paddr < dev_soc::RST_BASE_REG+0x100 {
         // RESET
         match align_addr {
             dev_soc::RST_MISC_INTERRUPT_MASK_REG => { ms.misc.int_mask = data; }
             dev_soc::RST_RESET_REG               => { ms.misc.reset_request = if 0!=(data & (1<<24)) { true }else{ false }; /* FULL CHIP RESET */ }
-            _  => { }
+            _  => { unimplemented_reg(ms, paddr, true, data); }
         }
+    }else if paddr >= dev_soc::TEST_BASE_REG && paddr < dev_soc::TEST_BASE_REG+dev_soc::TEST_REG_SIZE {
+        // Test device
+        dev_soc::write_test_reg(ms, align_addr, wrdata_align(acc_width, paddr, data));
+    }else{
+        unimplemented_reg(ms, paddr, true, data);
     }
 
     Ok(())
@@ -568,4 +667,33 @@ pub fn store_word(ms : &mut MachineState, addr : u32, data :u32) -> Result<(),u3
         return Err( cp0def::EXCEPT_CODE_ADDR_ERR_STORE );
     }
     return store_memory(ms, addr, 4, data);
-}
\ No newline at end of file
+}
+/*
+Memory access for debuggers.
+
+The virtual address is translated in the same way as load and store instructions
+of the current mode, but no exception is raised and I/O registers are never touched.
+Only DRAM and the flash memory image are visible. Writes are limited to DRAM.
+The error value is the exception code which the access would raise.
+*/
+pub fn debug_read_byte(ms : &mut MachineState, vaddr : u32) -> Result<u8,u32> {
+    let paddr = get_phy_addr(ms, vaddr, false)?;
+
+    if paddr >= config::ROM_AREA_ADDR && paddr < config::ROM_AREA_ADDR+config::ROM_AREA_SIZE {
+        // The image is read directly so that a SPI transaction of the guest in progress is not disturbed
+        let spi_addr = (paddr - config::ROM_AREA_ADDR) as usize;
+        return Ok( ms.spi.workers[0].image().and_then(|img| img.get(spi_addr).copied()).unwrap_or(0xff) );
+    }
+    Ok( read_phys_mem_byte(ms, paddr) )
+}
+
+pub fn debug_write_byte(ms : &mut MachineState, vaddr : u32, data : u8) -> Result<(),u32> {
+    // Translated as a read so that breakpoints can be placed in clean (read-only) pages
+    let paddr = get_phy_addr(ms, vaddr, false)?;
+
+    if paddr >= config::RAM_AREA_ADDR && paddr < config::RAM_AREA_ADDR+config::RAM_AREA_SIZE {
+        write_phys_mem_byte(ms, paddr, data);
+        return Ok(());
+    }
+    Err( cp0def::EXCEPT_CODE_ADDR_ERR_STORE )
+}
@@ -0,0 +1,219 @@
+use crate::dev_soc;
+use crate::dev_uart;
+use crate::dev_gmac;
+use crate::dev_spi;
+use log::error;
+
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+
+/*
+Log of accesses to memory-mapped I/O registers.
+
+Every load and store outside DRAM and the mapped flash memory is written to the log with the PC,
+the physical address, the width and the value:
+
+  80012345 load  4 18040004 => 00000000 GPIO *
+
+Accesses to registers without an emulation (reads returning 0 and ignored writes) are marked with "*"
+and counted per register, so that the registers needed by a new firmware are found by the report.
+*/
+
+// device blocks shown in the log and the report
+const BLOCKS : &[(u32, u32, &str)] = &[
+    (dev_uart::IOADDR_UART0_BASE,          dev_uart::IOADDR_UART_SIZE, "UART0"),
+    (dev_soc::USB_CFG_BASE_REG,            0x10000,                    "USB_CFG"),
+    (dev_soc::GPIO_BASE_REG,               0x10000,                    "GPIO"),
+    (dev_soc::PLL_BASE_REG,                0x10000,                    "PLL"),
+    (dev_soc::RST_BASE_REG,                0x10000,                    "RST"),
+    (dev_soc::GMAC_BASE_REG,               0x10000,                    "GMAC_CFG"),
+    (dev_soc::PCIE_RC0_CTRL_BASE_REG,      0x10000,                    "PCIE_RC0"),
+    (dev_soc::RTC_BASE_REG,                0x1000,                     "RTC"),
+    (dev_soc::PLL_SRIF_BASE_REG,           0x1000,                     "PLL_SRIF"),
+    (dev_soc::PCIE_RC1_CTRL_BASE_REG,      0x10000,                    "PCIE_RC1"),
+    (dev_uart::IOADDR_UART1_BASE,          dev_uart::IOADDR_UART_SIZE, "UART1"),
+    (dev_soc::TEST_BASE_REG,               dev_soc::TEST_REG_SIZE,     "TEST"),
+    (dev_gmac::GMAC0_BASE_ADDRESS,         dev_gmac::GMAC_ADDR_SIZE,   "GMAC0"),
+    (dev_gmac::GMAC1_BASE_ADDRESS,         dev_gmac::GMAC_ADDR_SIZE,   "GMAC1"),
+    (dev_spi::SPI0_BASE_ADDRESS,           dev_spi::SPI_ADDR_SIZE,     "SPI"),
+];
+
+// name of the device block, or the 64K-byte region of APB_BASE_REG for an unknown block
+pub fn block_name(paddr: u32) -> String {
+    match BLOCKS.iter().find(|(base, size, _)| paddr >= *base && paddr - *base < *size) {
+        Some((_, _, name))                      => name.to_string(),
+        None if paddr >= dev_soc::APB_BASE_REG => format!("APB+{:>06x}", (paddr & !0xffff) - dev_soc::APB_BASE_REG),
+        None                                    => format!("{:>08x}", paddr & !0xffff),
+    }
+}
+
+#[derive(Default)]
+struct RegStat {
+    reads  : u64,
+    writes : u64,
+    value  : u32, // last written value
+}
+
+pub struct MmioLog {
+    out     : Option<Box<dyn Write>>,
+    unimpl  : BTreeMap<u32, RegStat>, // per register address
+    pending : bool,                   // the current access hits an unimplemented register
+}
+
+impl MmioLog {
+    // Without out, accesses are only counted for the report
+    pub fn new(out: Option<Box<dyn Write>>) -> Self {
+        Self { out, unimpl: BTreeMap::new(), pending: false }
+    }
+
+    // called by a device for an access to a register without an emulation
+    pub fn unimplemented(&mut self, paddr: u32, store: bool, value: u32) {
+        let stat = self.unimpl.entry(paddr & !3).or_default();
+        if store {
+            stat.writes += 1;
+            stat.value   = value;
+        }else{
+            stat.reads  += 1;
+        }
+        self.pending = true;
+    }
+
+    // called after an access to an I/O register
+    pub fn access(&mut self, pc: u32, paddr: u32, width: u32, value: u32, store: bool) {
+        let mark = if self.pending { " *" }else{ "" };
+        self.pending = false;
+        let out = match self.out.as_mut() {
+            Some(out) => out,
+            None      => { return; }
+        };
+        let r = if store {
+            writeln!(out, "{:>08x} store {} {:>08x} <= {:>08x} {}{}", pc, width, paddr, value, block_name(paddr), mark)
+        }else{
+            writeln!(out, "{:>08x} load  {} {:>08x} => {:>08x} {}{}", pc, width, paddr, value, block_name(paddr), mark)
+        };
+        if let Err(e) = r {
+            error!("MMIO log: can not write the log : {}\r", e);
+            self.out = None;
+        }
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        match self.out.as_mut() {
+            Some(out) => out.flush(),
+            None      => Ok(()),
+        }
+    }
+
+    // Writes the table of the unimplemented registers touched by the guest, grouped by device blocks
+    pub fn write_report(&self, out: &mut dyn Write) -> io::Result<()> {
+        writeln!(out, "# unimplemented I/O registers touched by the guest")?;
+        if self.unimpl.is_empty() {
+            writeln!(out, "# (none)")?;
+            return Ok(());
+        }
+        writeln!(out, "# block     address       reads     writes  last written")?;
+        let mut prev_block = String::new();
+        for (addr, stat) in self.unimpl.iter() {
+            let block = block_name(*addr);
+            if block != prev_block {
+                if !prev_block.is_empty() {
+                    writeln!(out)?;
+                }
+                prev_block = block.clone();
+            }
+            let value = if stat.writes != 0 { format!("{:>08x}", stat.value) }else{ "-".to_string() };
+            writeln!(out, "{:<10} {:>08x} {:>10} {:>10}  {}", block, addr, stat.reads, stat.writes, value)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dev_spiflash::SPI_FLASH_PARAM_S25FL164K;
+    use crate::exrmips::generate_machine_state;
+    use crate::mem;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    struct Output(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Output {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> { self.0.borrow_mut().extend_from_slice(buf); Ok(buf.len()) }
+        fn flush(&mut self) -> io::Result<()> { Ok(()) }
+    }
+
+    fn report(log: &MmioLog) -> String {
+        let mut out = Vec::new();
+        log.write_report(&mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn block_names() {
+        assert_eq!(block_name(dev_uart::IOADDR_UART0_BASE + 4), "UART0");
+        assert_eq!(block_name(dev_soc::RST_RESET_REG), "RST");
+        assert_eq!(block_name(dev_soc::TEST_PUTC_REG), "TEST");
+        assert_eq!(block_name(dev_gmac::GMAC1_BASE_ADDRESS + 0x184), "GMAC1");
+        assert_eq!(block_name(0x18012340), "APB+010000");
+        assert_eq!(block_name(0x10000004), "10000000");
+    }
+
+    #[test]
+    fn log_lines() {
+        let out = Rc::new(RefCell::new(Vec::new()));
+        let mut log = MmioLog::new(Some(Box::new(Output(Rc::clone(&out)))));
+        log.access(0x80012344, dev_soc::RST_RESET_REG, 4, 0x100, true);
+        log.unimplemented(0x18040044, false, 0);
+        log.access(0x80012348, 0x18040044, 4, 0, false);
+        log.unimplemented(0x18012343, true, 0x5a);
+        log.access(0x8001234c, 0x18012343, 1, 0x5a, true);
+        log.access(0x80012350, dev_uart::IOADDR_UART0_BASE + 0x14, 4, 0x60, false);
+        log.flush().unwrap();
+        assert_eq!(String::from_utf8(out.borrow().clone()).unwrap(), concat!(
+            "80012344 store 4 1806001c <= 00000100 RST\n",
+            "80012348 load  4 18040044 => 00000000 GPIO *\n",
+            "8001234c store 1 18012343 <= 0000005a APB+010000 *\n",
+            "80012350 load  4 18020014 => 00000060 UART0\n",
+        ));
+    }
+
+    #[test]
+    fn report_per_register() {
+        let mut log = MmioLog::new(None);
+        assert_eq!(report(&log), "# unimplemented I/O registers touched by the guest\n# (none)\n");
+
+        log.unimplemented(0x18040048, false, 0);
+        log.unimplemented(0x18012340, true, 1);
+        log.unimplemented(0x18040044, true, 0x12);
+        log.unimplemented(0x18040046, true, 0x34); // the same word
+        log.unimplemented(0x18040048, false, 0);
+        log.unimplemented(0x18012340, false, 0);
+        log.access(0, 0x18012340, 4, 0, false);
+        assert_eq!(report(&log), concat!(
+            "# unimplemented I/O registers touched by the guest\n",
+            "# block     address       reads     writes  last written\n",
+            "APB+010000 18012340          1          1  00000001\n",
+            "\n",
+            "GPIO       18040044          0          2  00000034\n",
+            "GPIO       18040048          2          0  -\n",
+        ));
+    }
+
+    #[test]
+    fn accesses_of_the_guest() {
+        let mut ms = generate_machine_state(&SPI_FLASH_PARAM_S25FL164K, &[]);
+        ms.mmio_log = Some(MmioLog::new(None));
+        // DRAM, an emulated register and a register without an emulation
+        mem::store_word(&mut ms, 0xa0001000, 1).unwrap();
+        mem::load_word(&mut ms, dev_soc::RST_REVISION_ID_REG | 0xa0000000).unwrap();
+        assert_eq!(mem::load_word(&mut ms, 0xb8010004), Ok(0));
+        mem::store_word(&mut ms, 0xb8010004, 7).unwrap();
+        assert_eq!(report(ms.mmio_log.as_ref().unwrap()), concat!(
+            "# unimplemented I/O registers touched by the guest\n",
+            "# block     address       reads     writes  last written\n",
+            "APB+010000 18010004          1          1  00000007\n",
+        ));
+    }
+}
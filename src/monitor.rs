@@ -0,0 +1,382 @@
+use crate::procstate::MachineState;
+use crate::procstate;
+use crate::config;
+use crate::cp0def;
+use crate::cp0;
+use crate::mem;
+use crate::mips;
+use crate::disasm;
+use crate::snapshot;
+use crate::c0_val;
+use log::info;
+
+use std::io::{stdout, Write};
+use std::{thread, time};
+use std::sync::mpsc::TryRecvError;
+
+/*
+Interactive monitor of the console emulator.
+
+The monitor is entered with two Ctrl+C inputs in a second or when a breakpoint is hit.
+Execution is paused until "continue", "step" or "quit" is entered.
+Numbers are given in hexadecimal except breakpoint ids and step counts, which are decimal.
+Addresses may also be given as symbols (SYMBOL[+OFF]) loaded with --symbols.
+*/
+
+pub enum MonitorResult {
+    Continue,
+    Quit,
+}
+
+const HELP : &[&str] = &[
+    "regs | r                  dump general purpose registers",
+    "cp0                       dump CP0 registers",
+    "tlb                       dump TLB entries",
+    "x    <vaddr> [len]        hexdump memory at a virtual address",
+    "xp   <paddr> [len]        hexdump memory at a physical address",
+    "dis  [vaddr] [n]          disassemble n instructions (default: pc, 10)",
+    "break | b [addr] [cond]   set a breakpoint (cond: REG==VAL, hits=N) or list breakpoints",
+    "watch | w <addr[+len]> [r|w|rw] [phys]",
+    "                          set a watchpoint on a virtual (or physical) address range",
+    "delete | d <id>           delete a breakpoint or a watchpoint",
+    "enable | disable <id>     enable or disable a breakpoint or a watchpoint",
+    "clear                     delete all breakpoints and watchpoints",
+    "step | s [n]              execute n instructions (default: 1)",
+    "continue | c              continue execution",
+    "saveflash <file>          save the flash memory image to a file",
+    "snapshot <file>           save the machine state to a snapshot file (--snapshot-load to restore)",
+    "sync                      write modifications of the flash memory (--flash-writeback/output/overlay)",
+    "quit | q                  terminate the emulator",
+];
+
+const CP0_REGS : [(&str, (u32,u32)); 26] = [
+    ("status",   cp0def::C0_STATUS  ),
+    ("cause",    cp0def::C0_CAUSE   ),
+    ("epc",      cp0def::C0_EPC     ),
+    ("badvaddr", cp0def::C0_BADVADDR),
+    ("errorepc", cp0def::C0_ERROREPC),
+    ("count",    cp0def::C0_COUNT   ),
+    ("compare",  cp0def::C0_COMPARE ),
+    ("entryhi",  cp0def::C0_ENTRYHI ),
+    ("entrylo0", cp0def::C0_ENTRYLO0),
+    ("entrylo1", cp0def::C0_ENTRYLO1),
+    ("index",    cp0def::C0_INDEX   ),
+    ("random",   cp0def::C0_RANDOM  ),
+    ("wired",    cp0def::C0_WIRED   ),
+    ("pagemask", cp0def::C0_PAGEMASK),
+    ("context",  cp0def::C0_CONTEXT ),
+    ("hwrena",   cp0def::C0_HWRENA  ),
+    ("intctl",   cp0def::C0_INTCTL  ),
+    ("srsctl",   cp0def::C0_SRSCTL  ),
+    ("srsmap",   cp0def::C0_SRSMAP  ),
+    ("ebase",    cp0def::C0_EBASE   ),
+    ("prid",     cp0def::C0_PRID    ),
+    ("config",   cp0def::C0_CONFIG  ),
+    ("config1",  cp0def::C0_CONFIG1 ),
+    ("config2",  cp0def::C0_CONFIG2 ),
+    ("config3",  cp0def::C0_CONFIG3 ),
+    ("lladdr",   cp0def::C0_LLADDR  ),
+];
+
+// The terminal is in raw mode. Lines are terminated with CR+LF.
+macro_rules! mprintln {
+    ( $($arg:tt)* ) => ({ print!($($arg)*); print!("\r\n"); })
+}
+
+fn parse_num(s: &str) -> Option<u32> {
+    let s = s.trim_start_matches("0x").trim_start_matches("0X");
+    u32::from_str_radix(s, 16).ok()
+}
+
+// a decimal count (e.g., of instructions to step)
+fn parse_count(s: &str) -> Option<u64> {
+    s.parse::<u64>().ok()
+}
+
+// a number or a symbol (a symbol is preferred to a number without "0x")
+fn parse_addr(ms: &MachineState, s: &str) -> Option<u32> {
+    if s.starts_with("0x") || s.starts_with("0X") {
+        return parse_num(s);
+    }
+    ms.symbols.resolve(s).or_else(|| parse_num(s))
+}
+
+// None when the input is closed (e.g., EOF of stdin given by a pipe or a file)
+fn read_line(ms: &mut MachineState) -> Option<String> {
+    let mut line = String::new();
+
+    stdout().flush().unwrap();
+    loop {
+        match ms.console.try_recv() {
+            Ok(b'\r') | Ok(b'\n') => {
+                print!("\r\n");
+                stdout().flush().unwrap();
+                return Some(line);
+            }
+            Ok(0x7f) | Ok(0x08) => {
+                if line.pop().is_some() {
+                    print!("\x08 \x08");
+                    stdout().flush().unwrap();
+                }
+            }
+            Ok(c) if (0x20..0x7f).contains(&c) => {
+                line.push(c as char);
+                print!("{}", c as char);
+                stdout().flush().unwrap();
+            }
+            Ok(_)  => { }
+            Err(TryRecvError::Disconnected) => { return None; }
+            Err(TryRecvError::Empty) => { thread::sleep(time::Duration::from_millis(10)); }
+        }
+    }
+}
+
+fn print_regs(ms: &MachineState) {
+    mprintln!("pc = {:>08x}  hi = {:>08x}  lo = {:>08x}", ms.reg.pc, ms.reg.hi, ms.reg.lo);
+    for i in 0..8 {
+        let mut line = String::new();
+        for j in 0..4 {
+            let n = j*8 + i;
+            line += &format!("{:>5} = {:>08x}   ", mips::REGSTR[n], ms.reg.r[n]);
+        }
+        mprintln!("{}", line.trim_end());
+    }
+}
+
+fn print_cp0(ms: &mut MachineState) {
+    for (i, (name, rs)) in CP0_REGS.iter().enumerate() {
+        let val = if *rs == cp0def::C0_COUNT { cp0::load_counter(ms) }else{ c0_val!(ms.reg, *rs) };
+        print!("{:>8} = {:>08x}   ", name, val);
+        if i % 4 == 3 { print!("\r\n"); }
+    }
+    if !CP0_REGS.len().is_multiple_of(4) { print!("\r\n"); }
+}
+
+fn print_tlb(ms: &MachineState) {
+    mprintln!("idx  entryhi  pagemask entrylo0 entrylo1");
+    for (i, e) in ms.tlb.iter().enumerate() {
+        mprintln!("{:>3}  {:>08x} {:>08x} {:>08x} {:>08x}", i, e.entryhi, e.field_pmask, e.entrylo0, e.entrylo1);
+    }
+}
+
+fn dump_virt(ms: &mut MachineState, start: u32, len: u32) {
+    let mut data : Vec<u8> = Vec::with_capacity(len as usize);
+    for i in 0..len {
+        match mem::debug_read_byte(ms, start.wrapping_add(i)) {
+            Ok(d)      => { data.push(d); }
+            Err(ecode) => {
+                mprintln!("address 0x{:>08x} is not accessible (exception code {})", start.wrapping_add(i), ecode);
+                break;
+            }
+        }
+    }
+    procstate::dump_mem(&data, start, start, data.len() as u32);
+}
+
+fn dump_phys(ms: &MachineState, start: u32, len: u32) {
+    if (config::RAM_AREA_ADDR..config::RAM_AREA_ADDR + config::RAM_AREA_SIZE).contains(&start) {
+        procstate::dump_mem(&ms.mem.mem0, config::RAM_AREA_ADDR, start, len);
+    }else if (config::ROM_AREA_ADDR..config::ROM_AREA_ADDR + config::ROM_AREA_SIZE).contains(&start) {
+        match ms.spi.workers[0].image() {
+            Some(image) => { procstate::dump_mem(image, config::ROM_AREA_ADDR, start, len); }
+            None        => { mprintln!("no flash memory"); }
+        }
+    }else{
+        mprintln!("physical address 0x{:>08x} is not a memory area", start);
+    }
+}
+
+fn read_inst(ms: &mut MachineState, addr: u32, width: u32) -> Option<u32> {
+    let mut inst : u32 = 0;
+    for i in 0..width {
+        inst = (inst<<8) | mem::debug_read_byte(ms, addr.wrapping_add(i)).ok()? as u32;
+    }
+    Some(inst)
+}
+
+fn disassemble(ms: &mut MachineState, start: u32, n: u32) {
+    let mut addr = start;
+    for i in 0..n {
+        // labels at the beginning of functions
+        match ms.symbols.lookup(addr) {
+            Some((s, 0))             => { mprintln!("<{}>:", s.name); }
+            Some((s, off)) if i == 0 => { mprintln!("<{}+0x{:x}>:", s.name, off); }
+            _ => { }
+        }
+        let mips16 = 0 != (addr & 1);
+        let Some(mut inst) = read_inst(ms, addr & !1, if mips16 { 2 }else{ 4 }) else {
+            mprintln!("{:>08x}: ????????", addr);
+            return;
+        };
+        if mips16 {
+            let mut width = 2;
+            if disasm::mips16_is_32bit(inst) {
+                match read_inst(ms, (addr & !1).wrapping_add(2), 2) {
+                    Some(low) => { inst = (inst<<16) | low; width = 4; }
+                    None      => { mprintln!("{:>08x}: ????????", addr); return; }
+                }
+            }
+            let raw = if width == 4 { format!("{:>04x} {:>04x}", inst>>16, inst&0xffff) }else{ format!("{:>04x}     ", inst) };
+            mprintln!("{:>08x}: {}  {}", addr, raw, disasm::disassemble(addr, inst));
+            addr = addr.wrapping_add(width);
+        }else{
+            mprintln!("{:>08x}: {:>08x}   {}", addr, inst, disasm::disassemble(addr, inst));
+            addr = addr.wrapping_add(4);
+        }
+    }
+}
+
+fn list_breakpoints(ms: &MachineState) {
+    if ms.emu.bp.breakpoints.is_empty() && ms.emu.bp.watchpoints.is_empty() {
+        mprintln!("no breakpoints or watchpoints");
+    }
+    for b in ms.emu.bp.breakpoints.iter() { mprintln!("{}{}", b, ms.symbols.annotate(b.addr)); }
+    for w in ms.emu.bp.watchpoints.iter() { mprintln!("{}{}", w, ms.symbols.annotate(w.start)); }
+}
+
+fn save_flash(ms: &MachineState, path: &str) {
+    match ms.spi.workers[0].image() {
+        Some(image) => {
+            match std::fs::write(path, image) {
+                Ok(())  => { mprintln!("flash image is saved to \"{}\" ({} bytes)", path, image.len()); }
+                Err(e)  => { mprintln!("can not write \"{}\": {}", path, e); }
+            }
+        }
+        None => { mprintln!("no flash memory"); }
+    }
+}
+
+pub fn run(ms: &mut MachineState) -> MonitorResult {
+    info!("Entering monitor (pc: 0x{:>08x}{})\r", ms.reg.pc, ms.symbols.annotate(ms.reg.pc));
+    mprintln!("");
+    mprintln!("exrmips monitor: pc = {:>08x}, nexec = {}  (\"help\" for commands)", ms.reg.pc, ms.emu.nexec_insts);
+    disassemble(ms, ms.reg.pc, 1);
+
+    loop {
+        print!("(mon) ");
+        let line = match read_line(ms) {
+            Some(line) => line,
+            None => {
+                info!("The console input is closed; quitting from the monitor\r");
+                return MonitorResult::Quit;
+            }
+        };
+        let args : Vec<&str> = line.split_whitespace().collect();
+        if args.is_empty() {
+            continue;
+        }
+
+        let arg1 = args.get(1).and_then(|s| parse_addr(ms, s));
+        let arg2 = args.get(2).and_then(|s| parse_num(s));
+
+        match args[0] {
+            "help" | "h" | "?" => { for l in HELP { mprintln!("{}", l); } }
+            "regs" | "r"       => { print_regs(ms); }
+            "cp0"              => { print_cp0(ms); }
+            "tlb"              => { print_tlb(ms); }
+            "x" | "xp" => {
+                match arg1 {
+                    Some(addr) => {
+                        let len = arg2.unwrap_or(0x40);
+                        if args[0] == "x" { dump_virt(ms, addr, len); }else{ dump_phys(ms, addr, len); }
+                    }
+                    None => { mprintln!("usage: {} <addr> [len]", args[0]); }
+                }
+            }
+            "dis" => {
+                let addr = arg1.unwrap_or(ms.reg.pc);
+                disassemble(ms, addr, arg2.unwrap_or(10));
+            }
+            "break" | "b" | "watch" | "w" => {
+                if args.len() == 1 {
+                    list_breakpoints(ms);
+                }else{
+                    // the remaining arguments are joined into a specification string
+                    let spec = args[1..].join(",");
+                    let res = if args[0].starts_with('b') { ms.emu.bp.add_breakpoint_spec(&spec, &ms.symbols) }else{ ms.emu.bp.add_watchpoint_spec(&spec, &ms.symbols) };
+                    match res {
+                        Ok(id) => { mprintln!("{} {} is set", if args[0].starts_with('b') { "breakpoint" }else{ "watchpoint" }, id); }
+                        Err(e) => { mprintln!("{}", e); }
+                    }
+                }
+            }
+            "delete" | "d" | "enable" | "disable" => {
+                let id = args.get(1).and_then(|s| s.parse::<u32>().ok());
+                match id {
+                    Some(id) => {
+                        let found = match args[0] {
+                            "enable"  => ms.emu.bp.set_enabled(id, true),
+                            "disable" => ms.emu.bp.set_enabled(id, false),
+                            _         => ms.emu.bp.remove(id),
+                        };
+                        if !found { mprintln!("no breakpoint or watchpoint {}", id); }
+                    }
+                    None => { mprintln!("usage: {} <id>", args[0]); }
+                }
+            }
+            "clear" => {
+                ms.emu.bp.clear();
+                mprintln!("all breakpoints and watchpoints are deleted");
+            }
+            "step" | "s" => {
+                match args.get(1).map(|s| parse_count(s)).unwrap_or(Some(1)) {
+                    Some(n) => {
+                        ms.emu.breakcounter = ms.emu.nexec_insts + n.max(1);
+                        return MonitorResult::Continue;
+                    }
+                    None => { mprintln!("usage: {} [n] (n in decimal)", args[0]); }
+                }
+            }
+            "continue" | "c" => {
+                return MonitorResult::Continue;
+            }
+            "saveflash" => {
+                match args.get(1) {
+                    Some(path) => { save_flash(ms, path); }
+                    None       => { mprintln!("usage: saveflash <file>"); }
+                }
+            }
+            "snapshot" => {
+                match args.get(1) {
+                    Some(path) => {
+                        match std::fs::write(path, snapshot::save(ms)) {
+                            Ok(_)  => { mprintln!("snapshot is saved to \"{}\"", path); }
+                            Err(e) => { mprintln!("can not write \"{}\": {}", path, e); }
+                        }
+                    }
+                    None => { mprintln!("usage: snapshot <file>"); }
+                }
+            }
+            "sync" => {
+                match ms.flash_store.as_mut() {
+                    Some(store) => {
+                        match store.flush(ms.spi.workers[0].as_mut()) {
+                            Ok(n)  => { mprintln!("{} sectors are written to \"{}\"", n, store.path().display()); }
+                            Err(e) => { mprintln!("can not write \"{}\": {}", store.path().display(), e); }
+                        }
+                    }
+                    None => { mprintln!("flash memory is not persistent (no --flash-writeback, --flash-output or --flash-overlay)"); }
+                }
+            }
+            "quit" | "q" => {
+                return MonitorResult::Quit;
+            }
+            _ => { mprintln!("unknown command \"{}\"", args[0]); }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn step_counts_are_decimal() {
+        assert_eq!(parse_count("10"), Some(10));
+        assert_eq!(parse_count("1"), Some(1));
+        assert_eq!(parse_count("0x10"), None);
+        assert_eq!(parse_count("a"), None);
+        assert_eq!(parse_count("main"), None);
+        assert_eq!(parse_count("-1"), None);
+    }
+}
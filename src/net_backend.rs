@@ -0,0 +1,160 @@
+use crate::dev_gmac::NetBackend;
+use log::error;
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/*
+Host backends of the emulated Ethernet interfaces.
+
+  tap:NAME        : Linux TAP interface (the interface must be created and configured by the user)
+  pcap:FILE       : transmitted frames are recorded in a pcap file and no frame is received
+  loopback[:FILE] : transmitted frames are received again (and recorded in a pcap file if given)
+  user[,hostfwd=...] : user-mode network stack with DHCP and NAT (see net_slirp.rs)
+  none            : not connected
+*/
+
+const PCAP_MAGIC         : u32 = 0xa1b2c3d4;
+const PCAP_LINKTYPE_ETH  : u32 = 1;
+const PCAP_SNAPLEN       : u32 = 65535;
+
+// writer of the classic pcap file format
+pub struct PcapWriter {
+    f : BufWriter<File>,
+}
+
+impl PcapWriter {
+    pub fn create(path: &str) -> io::Result<Self> {
+        let mut f = BufWriter::new(File::create(path)?);
+        f.write_all(&PCAP_MAGIC.to_le_bytes())?;
+        f.write_all(&2u16.to_le_bytes())?; // version 2.4
+        f.write_all(&4u16.to_le_bytes())?;
+        f.write_all(&0u32.to_le_bytes())?; // thiszone
+        f.write_all(&0u32.to_le_bytes())?; // sigfigs
+        f.write_all(&PCAP_SNAPLEN.to_le_bytes())?;
+        f.write_all(&PCAP_LINKTYPE_ETH.to_le_bytes())?;
+        f.flush()?;
+        Ok(Self { f })
+    }
+
+    pub fn write(&mut self, frame: &[u8]) {
+        let t = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let mut rec : Vec<u8> = Vec::with_capacity(16 + frame.len());
+        rec.extend_from_slice(&(t.as_secs() as u32).to_le_bytes());
+        rec.extend_from_slice(&t.subsec_micros().to_le_bytes());
+        rec.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+        rec.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+        rec.extend_from_slice(frame);
+        // Records are flushed immediately so that the file can be read while the emulator runs
+        if let Err(e) = self.f.write_all(&rec).and_then(|_| self.f.flush()) {
+            error!("Can not write a pcap record : {}\r", e);
+        }
+    }
+}
+
+pub struct PcapBackend {
+    pcap : PcapWriter,
+}
+
+impl NetBackend for PcapBackend {
+    fn send(&mut self, frame: &[u8]) { self.pcap.write(frame); }
+    fn recv(&mut self) -> Option<Vec<u8>> { None }
+}
+
+pub struct LoopbackBackend {
+    queue : VecDeque<Vec<u8>>,
+    pcap  : Option<PcapWriter>,
+}
+
+impl NetBackend for LoopbackBackend {
+    fn send(&mut self, frame: &[u8]) {
+        if let Some(p) = self.pcap.as_mut() {
+            p.write(frame);
+        }
+        self.queue.push_back(frame.to_vec());
+    }
+    fn recv(&mut self) -> Option<Vec<u8>> { self.queue.pop_front() }
+}
+
+#[cfg(target_os = "linux")]
+pub struct TapBackend {
+    f   : File,
+    buf : Vec<u8>,
+}
+
+#[cfg(target_os = "linux")]
+impl TapBackend {
+    pub fn open(name: &str) -> io::Result<Self> {
+        use std::os::unix::io::AsRawFd;
+
+        const IFF_TAP   : libc::c_short = 0x0002;
+        const IFF_NO_PI : libc::c_short = 0x1000;
+        const TUNSETIFF : libc::c_ulong = 0x400454ca;
+
+        let f = std::fs::OpenOptions::new().read(true).write(true).open("/dev/net/tun")?;
+
+        let mut ifr = [0u8; 40]; // struct ifreq
+        if name.len() >= libc::IFNAMSIZ {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "interface name is too long"));
+        }
+        ifr[..name.len()].copy_from_slice(name.as_bytes());
+        ifr[libc::IFNAMSIZ..libc::IFNAMSIZ+2].copy_from_slice(&(IFF_TAP | IFF_NO_PI).to_ne_bytes());
+
+        let fd = f.as_raw_fd();
+        unsafe {
+            if libc::ioctl(fd, TUNSETIFF as _, ifr.as_mut_ptr()) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let flags = libc::fcntl(fd, libc::F_GETFL);
+            if libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(Self { f, buf: vec![0u8; 65536] })
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl NetBackend for TapBackend {
+    fn send(&mut self, frame: &[u8]) {
+        if let Err(e) = self.f.write(frame) {
+            error!("TAP: can not send a frame : {}\r", e);
+        }
+    }
+    fn recv(&mut self) -> Option<Vec<u8>> {
+        use std::io::Read;
+        match self.f.read(&mut self.buf) {
+            Ok(n) if n > 0 => Some(self.buf[..n].to_vec()),
+            _              => None,
+        }
+    }
+}
+
+// opens a backend given as "tap:NAME", "pcap:FILE", "loopback[:FILE]", "user[,OPTIONS]" or "none"
+pub fn open(spec: &str) -> io::Result<Box<dyn NetBackend>> {
+    if let Some(opts) = spec.strip_prefix("user") {
+        if opts.is_empty() || opts.starts_with(',') {
+            return Ok(Box::new(crate::net_slirp::Slirp::new(opts)?));
+        }
+    }
+    let (kind, arg) = match spec.split_once(':') {
+        Some((k, a)) => (k, Some(a)),
+        None         => (spec, None),
+    };
+    match (kind, arg) {
+        #[cfg(target_os = "linux")]
+        ("tap", Some(name)) => Ok(Box::new(TapBackend::open(name)?)),
+        ("pcap", Some(path)) => Ok(Box::new(PcapBackend { pcap: PcapWriter::create(path)? })),
+        ("loopback", path) => {
+            let pcap = match path {
+                Some(p) => Some(PcapWriter::create(p)?),
+                None    => None,
+            };
+            Ok(Box::new(LoopbackBackend { queue: VecDeque::new(), pcap }))
+        }
+        ("none", None) => Ok(Box::new(crate::dev_gmac::NullNetBackend{})),
+        _ => Err(io::Error::new(io::ErrorKind::InvalidInput, format!("unknown network backend \"{}\"", spec))),
+    }
+}
@@ -0,0 +1,950 @@
+use crate::dev_gmac::NetBackend;
+use log::{info, error};
+
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, Shutdown, SocketAddr, SocketAddrV4, TcpListener, TcpStream, UdpSocket};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/*
+User-mode network backend (slirp-style).
+
+The guest is connected to a virtual network without any privilege on the host:
+  10.0.2.2  : gateway (the host itself, mapped to 127.0.0.1)
+  10.0.2.3  : DNS server (forwarded to the first nameserver of /etc/resolv.conf)
+  10.0.2.15 : address given to the guest by the built-in DHCP server
+
+ARP and ICMP echo requests for the gateway and the DNS server are answered by the backend.
+TCP connections and UDP datagrams from the guest are relayed through host sockets (NAT).
+Port forwarding from the host to the guest is given as
+  hostfwd=tcp:[HOSTADDR]:HOSTPORT-[GUESTADDR]:GUESTPORT (or udp:...)
+
+Specification string: "user[,hostfwd=...][,hostfwd=...]"
+*/
+
+const NET_GATEWAY : [u8; 4] = [10, 0, 2, 2];
+const NET_DNS     : [u8; 4] = [10, 0, 2, 3];
+const NET_GUEST   : [u8; 4] = [10, 0, 2, 15];
+const NET_MASK    : [u8; 4] = [255, 255, 255, 0];
+const GATEWAY_MAC : [u8; 6] = [0x52, 0x55, 0x0a, 0x00, 0x02, 0x02];
+const BROADCAST_MAC : [u8; 6] = [0xff; 6];
+
+const ETHERTYPE_IPV4 : u16 = 0x0800;
+const ETHERTYPE_ARP  : u16 = 0x0806;
+const IPPROTO_ICMP   : u8  = 1;
+const IPPROTO_TCP    : u8  = 6;
+const IPPROTO_UDP    : u8  = 17;
+
+const DHCP_SERVER_PORT : u16 = 67;
+const DHCP_CLIENT_PORT : u16 = 68;
+const DHCP_MAGIC       : u32 = 0x63825363;
+const DHCP_LEASE_TIME  : u32 = 86400;
+
+const TCP_FIN : u8 = 0x01;
+const TCP_SYN : u8 = 0x02;
+const TCP_RST : u8 = 0x04;
+const TCP_PSH : u8 = 0x08;
+const TCP_ACK : u8 = 0x10;
+
+const TCP_MSS          : usize = 1460;
+const TCP_WINDOW       : usize = 65535;
+const TCP_RTO          : Duration = Duration::from_secs(1);
+const TCP_CONNECT_TIMEOUT : Duration = Duration::from_secs(10);
+const UDP_FLOW_TIMEOUT : Duration = Duration::from_secs(120);
+
+// the first source port on the gateway used for connections forwarded to the guest
+const HOSTFWD_PORT_BASE : u16 = 49152;
+
+fn read16(d: &[u8], off: usize) -> u16 { u16::from_be_bytes([d[off], d[off+1]]) }
+fn read32(d: &[u8], off: usize) -> u32 { u32::from_be_bytes([d[off], d[off+1], d[off+2], d[off+3]]) }
+fn ip4(d: &[u8], off: usize) -> [u8; 4] { [d[off], d[off+1], d[off+2], d[off+3]] }
+
+// Internet checksum of the data with an initial (partial) sum
+fn checksum(init: u32, data: &[u8]) -> u16 {
+    let mut sum = init;
+    for c in data.chunks(2) {
+        sum += if c.len() == 2 { read16(c, 0) as u32 }else{ (c[0] as u32) << 8 };
+    }
+    while (sum >> 16) != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+// partial checksum of the pseudo header of TCP and UDP
+fn pseudo_header_sum(src: [u8; 4], dst: [u8; 4], proto: u8, len: usize) -> u32 {
+    read16(&src, 0) as u32 + read16(&src, 2) as u32 + read16(&dst, 0) as u32 + read16(&dst, 2) as u32 + proto as u32 + len as u32
+}
+
+// host address which a guest address in the virtual network stands for
+fn host_addr(ip: [u8; 4], dns: Ipv4Addr) -> Ipv4Addr {
+    match ip {
+        NET_GATEWAY => Ipv4Addr::LOCALHOST,
+        NET_DNS     => dns,
+        _           => Ipv4Addr::from(ip),
+    }
+}
+
+// the first nameserver of /etc/resolv.conf, which is used for DNS queries of the guest
+fn host_dns_server() -> Ipv4Addr {
+    let conf = std::fs::read_to_string("/etc/resolv.conf").unwrap_or_default();
+    conf.lines()
+        .filter_map(|l| l.trim().strip_prefix("nameserver"))
+        .filter_map(|a| a.trim().parse::<Ipv4Addr>().ok())
+        .next()
+        .unwrap_or(Ipv4Addr::LOCALHOST)
+}
+
+#[derive(PartialEq)]
+enum TcpState {
+    Connecting,  // connecting to the host (SYN from the guest is not answered yet)
+    SynSent,     // SYN is sent to the guest for a forwarded connection
+    SynReceived, // SYN-ACK is sent to the guest
+    Established,
+}
+
+struct TcpConn {
+    guest_ip    : [u8; 4],
+    guest_port  : u16,
+    remote_ip   : [u8; 4],  // address of the peer seen by the guest
+    remote_port : u16,
+    state       : TcpState,
+    connecting  : Option<Receiver<io::Result<TcpStream>>>,
+    stream      : Option<TcpStream>,
+
+    iss         : u32,
+    data_seq    : u32,      // sequence number of unacked[0]
+    unacked     : Vec<u8>,  // data from the host not yet acknowledged by the guest
+    sent        : usize,    // bytes of unacked sent to the guest
+    snd_wnd     : usize,    // window of the guest
+    mss         : usize,    // MSS of the guest
+    rcv_nxt     : u32,
+    to_host     : Vec<u8>,  // data from the guest not yet written to the host
+
+    host_eof    : bool,
+    fin_sent    : bool,
+    fin_acked   : bool,
+    guest_fin   : bool,
+    shutdown    : bool,     // the host socket is shut down for writing
+    last_sent   : Instant,
+    closed      : bool,
+}
+
+impl TcpConn {
+    fn new(guest_ip: [u8; 4], guest_port: u16, remote_ip: [u8; 4], remote_port: u16, state: TcpState, iss: u32) -> Self {
+        Self {
+            guest_ip, guest_port, remote_ip, remote_port, state,
+            connecting: None, stream: None,
+            iss, data_seq: iss.wrapping_add(1), unacked: Vec::new(), sent: 0, snd_wnd: TCP_WINDOW, mss: 536,
+            rcv_nxt: 0, to_host: Vec::new(),
+            host_eof: false, fin_sent: false, fin_acked: false, guest_fin: false, shutdown: false,
+            last_sent: Instant::now(), closed: false,
+        }
+    }
+
+    fn matches(&self, guest_port: u16, remote_ip: [u8; 4], remote_port: u16) -> bool {
+        self.guest_port == guest_port && self.remote_ip == remote_ip && self.remote_port == remote_port
+    }
+
+    fn window(&self) -> u16 {
+        TCP_WINDOW.saturating_sub(self.to_host.len()) as u16
+    }
+}
+
+struct UdpFlow {
+    guest_ip    : [u8; 4],
+    guest_port  : u16,
+    remote_ip   : [u8; 4],
+    remote_port : u16,
+    sock        : UdpSocket,
+    last_used   : Instant,
+}
+
+struct HostFwdTcp {
+    listener   : TcpListener,
+    guest_ip   : [u8; 4],
+    guest_port : u16,
+}
+
+struct HostFwdUdp {
+    sock       : UdpSocket,
+    guest_ip   : [u8; 4],
+    guest_port : u16,
+    src_port   : u16,               // source port on the gateway seen by the guest
+    peer       : Option<SocketAddr>,
+}
+
+pub struct Slirp {
+    guest_mac   : [u8; 6],
+    dns         : Ipv4Addr,
+    out         : VecDeque<Vec<u8>>, // frames to the guest
+    tcp         : Vec<TcpConn>,
+    udp         : Vec<UdpFlow>,
+    fwd_tcp     : Vec<HostFwdTcp>,
+    fwd_udp     : Vec<HostFwdUdp>,
+    ip_id       : u16,
+    next_port   : u16,
+    iss         : u32,
+    buf         : Vec<u8>,
+}
+
+// parses "tcp:[HOSTADDR]:HOSTPORT-[GUESTADDR]:GUESTPORT"
+fn parse_hostfwd(s: &str) -> io::Result<(bool, SocketAddr, [u8; 4], u16)> {
+    let bad = || io::Error::new(io::ErrorKind::InvalidInput, format!("incorrect hostfwd \"{}\"", s));
+    let (proto, rest) = s.split_once(':').ok_or_else(bad)?;
+    let is_tcp = match proto { "tcp" | "" => true, "udp" => false, _ => { return Err(bad()); } };
+    let (host, guest) = rest.split_once('-').ok_or_else(bad)?;
+    let (haddr, hport) = host.rsplit_once(':').ok_or_else(bad)?;
+    let (gaddr, gport) = guest.rsplit_once(':').ok_or_else(bad)?;
+    let haddr : Ipv4Addr = if haddr.is_empty() { Ipv4Addr::LOCALHOST }else{ haddr.parse().map_err(|_| bad())? };
+    let gaddr : Ipv4Addr = if gaddr.is_empty() { Ipv4Addr::from(NET_GUEST) }else{ gaddr.parse().map_err(|_| bad())? };
+    let hport : u16 = hport.parse().map_err(|_| bad())?;
+    let gport : u16 = gport.parse().map_err(|_| bad())?;
+    Ok((is_tcp, SocketAddr::V4(SocketAddrV4::new(haddr, hport)), gaddr.octets(), gport))
+}
+
+impl Slirp {
+    // opens the backend with options after "user" (e.g., "hostfwd=tcp::2222-:22")
+    pub fn new(opts: &str) -> io::Result<Self> {
+        let mut s = Self {
+            guest_mac: BROADCAST_MAC,
+            dns: host_dns_server(),
+            out: VecDeque::new(),
+            tcp: Vec::new(),
+            udp: Vec::new(),
+            fwd_tcp: Vec::new(),
+            fwd_udp: Vec::new(),
+            ip_id: 0,
+            next_port: HOSTFWD_PORT_BASE,
+            iss: 0x10000000,
+            buf: vec![0u8; 65536],
+        };
+        for opt in opts.split(',').filter(|o| !o.is_empty()) {
+            match opt.split_once('=') {
+                Some(("hostfwd", spec)) => {
+                    let (is_tcp, haddr, gaddr, gport) = parse_hostfwd(spec)?;
+                    if is_tcp {
+                        let listener = TcpListener::bind(haddr)?;
+                        listener.set_nonblocking(true)?;
+                        s.fwd_tcp.push(HostFwdTcp { listener, guest_ip: gaddr, guest_port: gport });
+                    }else{
+                        let sock = UdpSocket::bind(haddr)?;
+                        sock.set_nonblocking(true)?;
+                        let src_port = s.alloc_port();
+                        s.fwd_udp.push(HostFwdUdp { sock, guest_ip: gaddr, guest_port: gport, src_port, peer: None });
+                    }
+                    info!("Forwarding {} {} to {}:{}", if is_tcp { "tcp" }else{ "udp" }, haddr, Ipv4Addr::from(gaddr), gport);
+                }
+                _ => { return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("unknown option \"{}\"", opt))); }
+            }
+        }
+        Ok(s)
+    }
+
+    fn alloc_port(&mut self) -> u16 {
+        let p = self.next_port;
+        self.next_port = if p == u16::MAX { HOSTFWD_PORT_BASE }else{ p + 1 };
+        p
+    }
+
+    fn send_eth(&mut self, dst: [u8; 6], ethertype: u16, payload: &[u8]) {
+        let mut f = Vec::with_capacity(14 + payload.len());
+        f.extend_from_slice(&dst);
+        f.extend_from_slice(&GATEWAY_MAC);
+        f.extend_from_slice(&ethertype.to_be_bytes());
+        f.extend_from_slice(payload);
+        self.out.push_back(f);
+    }
+
+    fn send_ip(&mut self, src: [u8; 4], dst: [u8; 4], proto: u8, payload: &[u8]) {
+        let mut p = Vec::with_capacity(20 + payload.len());
+        self.ip_id = self.ip_id.wrapping_add(1);
+        p.extend_from_slice(&[0x45, 0]);
+        p.extend_from_slice(&((20 + payload.len()) as u16).to_be_bytes());
+        p.extend_from_slice(&self.ip_id.to_be_bytes());
+        p.extend_from_slice(&[0x40, 0, 64, proto, 0, 0]); // don't fragment, TTL 64
+        p.extend_from_slice(&src);
+        p.extend_from_slice(&dst);
+        let sum = checksum(0, &p);
+        p[10..12].copy_from_slice(&sum.to_be_bytes());
+        p.extend_from_slice(payload);
+        let mac = if dst == [255; 4] { BROADCAST_MAC }else{ self.guest_mac };
+        self.send_eth(mac, ETHERTYPE_IPV4, &p);
+    }
+
+    fn send_udp(&mut self, src: [u8; 4], sport: u16, dst: [u8; 4], dport: u16, data: &[u8]) {
+        let len = 8 + data.len();
+        let mut u = Vec::with_capacity(len);
+        u.extend_from_slice(&sport.to_be_bytes());
+        u.extend_from_slice(&dport.to_be_bytes());
+        u.extend_from_slice(&(len as u16).to_be_bytes());
+        u.extend_from_slice(&[0, 0]);
+        u.extend_from_slice(data);
+        let sum = match checksum(pseudo_header_sum(src, dst, IPPROTO_UDP, len), &u) { 0 => 0xffff, s => s };
+        u[6..8].copy_from_slice(&sum.to_be_bytes());
+        self.send_ip(src, dst, IPPROTO_UDP, &u);
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn send_tcp(&mut self, src: [u8; 4], sport: u16, dst: [u8; 4], dport: u16, seq: u32, ack: u32, flags: u8, wnd: u16, data: &[u8]) {
+        let opt_len = if 0 != (flags & TCP_SYN) { 4 }else{ 0 };
+        let len = 20 + opt_len + data.len();
+        let mut t = Vec::with_capacity(len);
+        t.extend_from_slice(&sport.to_be_bytes());
+        t.extend_from_slice(&dport.to_be_bytes());
+        t.extend_from_slice(&seq.to_be_bytes());
+        t.extend_from_slice(&ack.to_be_bytes());
+        t.push(((20 + opt_len) as u8 / 4) << 4);
+        t.push(flags);
+        t.extend_from_slice(&wnd.to_be_bytes());
+        t.extend_from_slice(&[0, 0, 0, 0]);
+        if opt_len != 0 {
+            t.extend_from_slice(&[2, 4]); // MSS
+            t.extend_from_slice(&(TCP_MSS as u16).to_be_bytes());
+        }
+        t.extend_from_slice(data);
+        let sum = checksum(pseudo_header_sum(src, dst, IPPROTO_TCP, len), &t);
+        t[16..18].copy_from_slice(&sum.to_be_bytes());
+        self.send_ip(src, dst, IPPROTO_TCP, &t);
+    }
+
+    fn send_tcp_conn(&mut self, i: usize, seq: u32, flags: u8, data: &[u8]) {
+        let c = &self.tcp[i];
+        let (src, sport, dst, dport, ack, wnd) = (c.remote_ip, c.remote_port, c.guest_ip, c.guest_port, c.rcv_nxt, c.window());
+        self.send_tcp(src, sport, dst, dport, seq, ack, flags, wnd, data);
+        self.tcp[i].last_sent = Instant::now();
+    }
+
+    fn handle_arp(&mut self, p: &[u8]) {
+        if p.len() < 28 || read16(p, 6) != 1 {
+            return;
+        }
+        let sender_mac : [u8; 6] = p[8..14].try_into().unwrap();
+        let sender_ip = ip4(p, 14);
+        let target_ip = ip4(p, 24);
+        if target_ip == NET_GATEWAY || target_ip == NET_DNS {
+            self.guest_mac = sender_mac;
+            let mut r = Vec::with_capacity(28);
+            r.extend_from_slice(&[0, 1, 8, 0, 6, 4, 0, 2]); // Ethernet, IPv4, reply
+            r.extend_from_slice(&GATEWAY_MAC);
+            r.extend_from_slice(&target_ip);
+            r.extend_from_slice(&sender_mac);
+            r.extend_from_slice(&sender_ip);
+            self.send_eth(sender_mac, ETHERTYPE_ARP, &r);
+        }
+    }
+
+    fn handle_dhcp(&mut self, d: &[u8]) {
+        if d.len() < 240 || d[0] != 1 || read32(d, 236) != DHCP_MAGIC {
+            return;
+        }
+        // DHCP message type
+        let mut msg_type = 0;
+        let mut i = 240;
+        while i + 1 < d.len() && d[i] != 255 {
+            if d[i] == 0 { i += 1; continue; }
+            if d[i] == 53 && i + 2 < d.len() { msg_type = d[i+2]; }
+            i += 2 + d[i+1] as usize;
+        }
+        let reply_type = match msg_type {
+            1 => 2, // DISCOVER -> OFFER
+            3 => 5, // REQUEST  -> ACK
+            _ => { return; }
+        };
+
+        let mut r = vec![0u8; 240];
+        r[0] = 2;                   // BOOTREPLY
+        r[1] = 1;                   // Ethernet
+        r[2] = 6;
+        r[4..8].copy_from_slice(&d[4..8]);      // xid
+        r[10..12].copy_from_slice(&d[10..12]);  // flags
+        r[16..20].copy_from_slice(&NET_GUEST);  // yiaddr
+        r[20..24].copy_from_slice(&NET_GATEWAY);// siaddr
+        r[28..44].copy_from_slice(&d[28..44]);  // chaddr
+        r[236..240].copy_from_slice(&DHCP_MAGIC.to_be_bytes());
+        r.extend_from_slice(&[53, 1, reply_type]);
+        r.extend_from_slice(&[54, 4]); r.extend_from_slice(&NET_GATEWAY);
+        r.extend_from_slice(&[51, 4]); r.extend_from_slice(&DHCP_LEASE_TIME.to_be_bytes());
+        r.extend_from_slice(&[1, 4]);  r.extend_from_slice(&NET_MASK);
+        r.extend_from_slice(&[3, 4]);  r.extend_from_slice(&NET_GATEWAY);
+        r.extend_from_slice(&[6, 4]);  r.extend_from_slice(&NET_DNS);
+        r.push(255);
+        r.resize(300, 0);
+
+        self.guest_mac = d[28..34].try_into().unwrap();
+        if reply_type == 5 {
+            info!("DHCP: {} is assigned to the guest\r", Ipv4Addr::from(NET_GUEST));
+        }
+        self.send_udp(NET_GATEWAY, DHCP_SERVER_PORT, [255; 4], DHCP_CLIENT_PORT, &r);
+    }
+
+    fn handle_icmp(&mut self, src: [u8; 4], dst: [u8; 4], p: &[u8]) {
+        // Echo requests to the virtual hosts are answered
+        if p.len() < 8 || p[0] != 8 || (dst != NET_GATEWAY && dst != NET_DNS) {
+            return;
+        }
+        let mut r = p.to_vec();
+        r[0] = 0; // echo reply
+        r[2..4].copy_from_slice(&[0, 0]);
+        let sum = checksum(0, &r);
+        r[2..4].copy_from_slice(&sum.to_be_bytes());
+        self.send_ip(dst, src, IPPROTO_ICMP, &r);
+    }
+
+    fn handle_udp(&mut self, src: [u8; 4], dst: [u8; 4], u: &[u8]) {
+        if u.len() < 8 {
+            return;
+        }
+        let sport = read16(u, 0);
+        let dport = read16(u, 2);
+        let len   = (read16(u, 4) as usize).clamp(8, u.len());
+        let data  = &u[8..len];
+
+        if dport == DHCP_SERVER_PORT {
+            self.handle_dhcp(data);
+            return;
+        }
+        // replies to datagrams forwarded from the host
+        if dst == NET_GATEWAY {
+            if let Some(f) = self.fwd_udp.iter().find(|f| f.src_port == dport && f.guest_port == sport) {
+                if let Some(peer) = f.peer {
+                    let _ = f.sock.send_to(data, peer);
+                }
+                return;
+            }
+        }
+
+        let now = Instant::now();
+        let flow = match self.udp.iter().position(|f| f.guest_port == sport && f.remote_ip == dst && f.remote_port == dport) {
+            Some(i) => i,
+            None => {
+                let sock = match UdpSocket::bind("0.0.0.0:0").and_then(|s| { s.set_nonblocking(true)?; Ok(s) }) {
+                    Ok(s)  => s,
+                    Err(e) => { error!("UDP: can not open a socket : {}\r", e); return; }
+                };
+                self.udp.push(UdpFlow { guest_ip: src, guest_port: sport, remote_ip: dst, remote_port: dport, sock, last_used: now });
+                self.udp.len() - 1
+            }
+        };
+        let f = &mut self.udp[flow];
+        f.last_used = now;
+        let _ = f.sock.send_to(data, SocketAddrV4::new(host_addr(dst, self.dns), dport));
+    }
+
+    fn handle_tcp(&mut self, src: [u8; 4], dst: [u8; 4], t: &[u8]) {
+        if t.len() < 20 {
+            return;
+        }
+        let sport = read16(t, 0);
+        let dport = read16(t, 2);
+        let seq   = read32(t, 4);
+        let ack   = read32(t, 8);
+        let off   = ((t[12] >> 4) as usize * 4).clamp(20, t.len());
+        let flags = t[13];
+        let wnd   = read16(t, 14) as usize;
+        let data  = &t[off..];
+
+        let idx = self.tcp.iter().position(|c| c.matches(sport, dst, dport));
+        let i = match idx {
+            Some(i) => i,
+            None => {
+                if 0 != (flags & TCP_RST) {
+                    return;
+                }
+                if flags & (TCP_SYN | TCP_ACK) != TCP_SYN {
+                    // no connection
+                    let (rseq, rack, rflags) = if 0 != (flags & TCP_ACK) { (ack, 0, TCP_RST) }else{ (0, seq.wrapping_add(data.len() as u32 + 1), TCP_RST | TCP_ACK) };
+                    self.send_tcp(dst, dport, src, sport, rseq, rack, rflags, 0, &[]);
+                    return;
+                }
+                // a new connection from the guest
+                self.iss = self.iss.wrapping_add(0x10000);
+                let mut c = TcpConn::new(src, sport, dst, dport, TcpState::Connecting, self.iss);
+                c.rcv_nxt = seq.wrapping_add(1);
+                c.snd_wnd = wnd;
+                c.mss = tcp_mss_option(&t[20..off]).unwrap_or(536).min(TCP_MSS);
+                let addr = SocketAddr::V4(SocketAddrV4::new(host_addr(dst, self.dns), dport));
+                let (tx, rx) = mpsc::channel();
+                thread::spawn(move || { let _ = tx.send(TcpStream::connect_timeout(&addr, TCP_CONNECT_TIMEOUT)); });
+                c.connecting = Some(rx);
+                self.tcp.push(c);
+                return;
+            }
+        };
+
+        if 0 != (flags & TCP_RST) {
+            self.tcp[i].closed = true;
+            return;
+        }
+
+        match self.tcp[i].state {
+            TcpState::Connecting => { return; }
+            TcpState::SynSent => {
+                // SYN-ACK from the guest for a forwarded connection
+                if flags & (TCP_SYN | TCP_ACK) == (TCP_SYN | TCP_ACK) && ack == self.tcp[i].iss.wrapping_add(1) {
+                    let c = &mut self.tcp[i];
+                    c.rcv_nxt = seq.wrapping_add(1);
+                    c.snd_wnd = wnd;
+                    c.mss = tcp_mss_option(&t[20..off]).unwrap_or(536).min(TCP_MSS);
+                    c.state = TcpState::Established;
+                    let s = c.data_seq;
+                    self.send_tcp_conn(i, s, TCP_ACK, &[]);
+                }
+                return;
+            }
+            TcpState::SynReceived => {
+                if 0 != (flags & TCP_SYN) {
+                    // retransmitted SYN
+                    let iss = self.tcp[i].iss;
+                    self.send_tcp_conn(i, iss, TCP_SYN | TCP_ACK, &[]);
+                    return;
+                }
+                if 0 != (flags & TCP_ACK) && ack == self.tcp[i].iss.wrapping_add(1) {
+                    self.tcp[i].state = TcpState::Established;
+                }
+            }
+            TcpState::Established => { }
+        }
+        if self.tcp[i].state != TcpState::Established {
+            return;
+        }
+
+        let c = &mut self.tcp[i];
+        c.snd_wnd = wnd;
+        if 0 != (flags & TCP_ACK) {
+            // data acknowledged by the guest
+            let acked = ack.wrapping_sub(c.data_seq) as usize;
+            if acked <= c.unacked.len() {
+                c.unacked.drain(..acked);
+                c.sent = c.sent.saturating_sub(acked);
+                c.data_seq = ack;
+                c.last_sent = Instant::now();
+            }else if c.fin_sent && acked == c.unacked.len() + 1 {
+                c.data_seq = ack;
+                c.unacked.clear();
+                c.sent = 0;
+                c.fin_acked = true;
+            }
+        }
+
+        let mut need_ack = false;
+        if !data.is_empty() || 0 != (flags & TCP_FIN) {
+            if seq == c.rcv_nxt && !c.guest_fin {
+                c.to_host.extend_from_slice(data);
+                c.rcv_nxt = c.rcv_nxt.wrapping_add(data.len() as u32);
+                if 0 != (flags & TCP_FIN) {
+                    c.rcv_nxt = c.rcv_nxt.wrapping_add(1);
+                    c.guest_fin = true;
+                }
+            }
+            // out-of-order segments are dropped and the expected sequence number is acknowledged
+            need_ack = true;
+        }
+        if need_ack {
+            let s = c.data_seq.wrapping_add(c.sent as u32);
+            self.send_tcp_conn(i, s, TCP_ACK, &[]);
+        }
+    }
+
+    fn handle_ipv4(&mut self, p: &[u8]) {
+        if p.len() < 20 || (p[0] >> 4) != 4 {
+            return;
+        }
+        let ihl = (p[0] & 0xf) as usize * 4;
+        let total = (read16(p, 2) as usize).min(p.len());
+        if ihl < 20 || total < ihl {
+            return;
+        }
+        // fragments are not supported
+        if (read16(p, 6) & 0x3fff) != 0 {
+            return;
+        }
+        let proto = p[9];
+        let src = ip4(p, 12);
+        let dst = ip4(p, 16);
+        let payload = &p[ihl..total];
+        match proto {
+            IPPROTO_ICMP => self.handle_icmp(src, dst, payload),
+            IPPROTO_UDP  => self.handle_udp(src, dst, payload),
+            IPPROTO_TCP  => self.handle_tcp(src, dst, payload),
+            _            => { }
+        }
+    }
+
+    // accepts connections and datagrams forwarded to the guest
+    fn poll_hostfwd(&mut self) {
+        for n in 0..self.fwd_tcp.len() {
+            while let Ok((stream, peer)) = self.fwd_tcp[n].listener.accept() {
+                if stream.set_nonblocking(true).is_err() {
+                    continue;
+                }
+                info!("Forwarded connection from {}\r", peer);
+                self.iss = self.iss.wrapping_add(0x10000);
+                let port = self.alloc_port();
+                let fwd = &self.fwd_tcp[n];
+                let mut c = TcpConn::new(fwd.guest_ip, fwd.guest_port, NET_GATEWAY, port, TcpState::SynSent, self.iss);
+                c.stream = Some(stream);
+                self.tcp.push(c);
+                let i = self.tcp.len() - 1;
+                let iss = self.tcp[i].iss;
+                self.send_tcp_conn(i, iss, TCP_SYN, &[]);
+            }
+        }
+        for n in 0..self.fwd_udp.len() {
+            while let Ok((len, peer)) = self.fwd_udp[n].sock.recv_from(&mut self.buf) {
+                let f = &mut self.fwd_udp[n];
+                f.peer = Some(peer);
+                let (gip, gport, sport) = (f.guest_ip, f.guest_port, f.src_port);
+                let data = self.buf[..len].to_vec();
+                self.send_udp(NET_GATEWAY, sport, gip, gport, &data);
+            }
+        }
+    }
+
+    fn poll_udp(&mut self) {
+        let now = Instant::now();
+        for n in 0..self.udp.len() {
+            while let Ok((len, _)) = self.udp[n].sock.recv_from(&mut self.buf) {
+                let f = &mut self.udp[n];
+                f.last_used = now;
+                let (rip, rport, gip, gport) = (f.remote_ip, f.remote_port, f.guest_ip, f.guest_port);
+                let data = self.buf[..len].to_vec();
+                self.send_udp(rip, rport, gip, gport, &data);
+            }
+        }
+        self.udp.retain(|f| now.duration_since(f.last_used) < UDP_FLOW_TIMEOUT);
+    }
+
+    fn poll_tcp(&mut self) {
+        for i in 0..self.tcp.len() {
+            // completion of a connection to the host
+            if self.tcp[i].state == TcpState::Connecting {
+                let result = match self.tcp[i].connecting.as_ref().map(|rx| rx.try_recv()) {
+                    Some(Ok(r))                        => r,
+                    Some(Err(TryRecvError::Empty))     => { continue; }
+                    _                                  => Err(io::Error::other("connection thread failed")),
+                };
+                match result.and_then(|s| { s.set_nonblocking(true)?; Ok(s) }) {
+                    Ok(s) => {
+                        let c = &mut self.tcp[i];
+                        c.stream = Some(s);
+                        c.connecting = None;
+                        c.state = TcpState::SynReceived;
+                        let iss = c.iss;
+                        self.send_tcp_conn(i, iss, TCP_SYN | TCP_ACK, &[]);
+                    }
+                    Err(_) => {
+                        // connection refused
+                        let c = &self.tcp[i];
+                        let (src, sport, dst, dport, ack) = (c.remote_ip, c.remote_port, c.guest_ip, c.guest_port, c.rcv_nxt);
+                        self.send_tcp(src, sport, dst, dport, 0, ack, TCP_RST | TCP_ACK, 0, &[]);
+                        self.tcp[i].closed = true;
+                    }
+                }
+                continue;
+            }
+            if self.tcp[i].state == TcpState::SynSent {
+                if self.tcp[i].last_sent.elapsed() >= TCP_RTO {
+                    let iss = self.tcp[i].iss;
+                    self.send_tcp_conn(i, iss, TCP_SYN, &[]);
+                }
+                continue;
+            }
+            if self.tcp[i].state != TcpState::Established {
+                continue;
+            }
+            self.poll_tcp_conn(i);
+        }
+        self.tcp.retain(|c| !c.closed);
+    }
+
+    fn poll_tcp_conn(&mut self, i: usize) {
+        let c = &mut self.tcp[i];
+        let stream = match c.stream.as_mut() {
+            Some(s) => s,
+            None    => { return; }
+        };
+        let mut error = false;
+
+        // guest -> host
+        if !c.to_host.is_empty() {
+            match stream.write(&c.to_host) {
+                Ok(n)  => { c.to_host.drain(..n); }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => { }
+                Err(_) => { error = true; }
+            }
+        }
+        if c.guest_fin && c.to_host.is_empty() && !c.shutdown {
+            let _ = stream.shutdown(Shutdown::Write);
+            c.shutdown = true;
+        }
+
+        // host -> guest
+        while !c.host_eof && c.unacked.len() < TCP_WINDOW {
+            let room = TCP_WINDOW - c.unacked.len();
+            let mut buf = vec![0u8; room.min(16384)];
+            match stream.read(&mut buf) {
+                Ok(0)  => { c.host_eof = true; }
+                Ok(n)  => { c.unacked.extend_from_slice(&buf[..n]); }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => { break; }
+                Err(_) => { error = true; break; }
+            }
+        }
+
+        if error {
+            let s = c.data_seq.wrapping_add(c.sent as u32);
+            self.send_tcp_conn(i, s, TCP_RST | TCP_ACK, &[]);
+            self.tcp[i].closed = true;
+            return;
+        }
+
+        // retransmission (go-back-N) when the guest does not acknowledge
+        if (c.sent > 0 || (c.fin_sent && !c.fin_acked)) && c.last_sent.elapsed() >= TCP_RTO {
+            c.sent = 0;
+            c.fin_sent = false;
+        }
+
+        // sends data within the window of the guest
+        loop {
+            let c = &self.tcp[i];
+            let limit = c.unacked.len().min(c.snd_wnd);
+            if c.sent >= limit {
+                break;
+            }
+            let len = (limit - c.sent).min(c.mss);
+            let seq = c.data_seq.wrapping_add(c.sent as u32);
+            let data = c.unacked[c.sent .. c.sent + len].to_vec();
+            self.tcp[i].sent += len;
+            self.send_tcp_conn(i, seq, TCP_ACK | TCP_PSH, &data);
+        }
+
+        let c = &self.tcp[i];
+        if c.host_eof && !c.fin_sent && c.sent == c.unacked.len() {
+            let seq = c.data_seq.wrapping_add(c.sent as u32);
+            self.tcp[i].fin_sent = true;
+            self.send_tcp_conn(i, seq, TCP_FIN | TCP_ACK, &[]);
+        }
+
+        let c = &mut self.tcp[i];
+        if c.fin_acked && c.guest_fin && c.shutdown {
+            c.closed = true;
+        }
+    }
+
+    fn poll(&mut self) {
+        self.poll_hostfwd();
+        self.poll_udp();
+        self.poll_tcp();
+    }
+}
+
+// MSS option of a TCP header
+fn tcp_mss_option(opts: &[u8]) -> Option<usize> {
+    let mut i = 0;
+    while i < opts.len() {
+        match opts[i] {
+            0 => { break; }
+            1 => { i += 1; }
+            2 if i + 3 < opts.len() => { return Some(read16(opts, i + 2) as usize); }
+            _ => {
+                if i + 1 >= opts.len() || opts[i+1] < 2 { break; }
+                i += opts[i+1] as usize;
+            }
+        }
+    }
+    None
+}
+
+impl NetBackend for Slirp {
+    fn send(&mut self, frame: &[u8]) {
+        if frame.len() < 14 {
+            return;
+        }
+        let payload = &frame[14..];
+        match read16(frame, 12) {
+            ETHERTYPE_ARP  => self.handle_arp(payload),
+            ETHERTYPE_IPV4 => {
+                if self.guest_mac == BROADCAST_MAC {
+                    self.guest_mac = frame[6..12].try_into().unwrap();
+                }
+                self.handle_ipv4(payload)
+            }
+            _ => { }
+        }
+    }
+
+    fn recv(&mut self) -> Option<Vec<u8>> {
+        if self.out.is_empty() {
+            self.poll();
+        }
+        self.out.pop_front()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GUEST_MAC : [u8; 6] = [0x00, 0x03, 0x7f, 0x12, 0x34, 0x56];
+
+    fn eth(ethertype: u16, payload: &[u8]) -> Vec<u8> {
+        let mut f = BROADCAST_MAC.to_vec();
+        f.extend_from_slice(&GUEST_MAC);
+        f.extend_from_slice(&ethertype.to_be_bytes());
+        f.extend_from_slice(payload);
+        f
+    }
+
+    fn ipv4(src: [u8; 4], dst: [u8; 4], proto: u8, payload: &[u8]) -> Vec<u8> {
+        let mut p = vec![0x45, 0];
+        p.extend_from_slice(&((20 + payload.len()) as u16).to_be_bytes());
+        p.extend_from_slice(&[0, 1, 0x40, 0, 64, proto, 0, 0]);
+        p.extend_from_slice(&src);
+        p.extend_from_slice(&dst);
+        let sum = checksum(0, &p);
+        p[10..12].copy_from_slice(&sum.to_be_bytes());
+        p.extend_from_slice(payload);
+        p
+    }
+
+    fn dhcp(msg_type: u8) -> Vec<u8> {
+        let mut d = vec![0u8; 240];
+        d[0] = 1;
+        d[4..8].copy_from_slice(&[0xde, 0xad, 0xbe, 0xef]);
+        d[28..34].copy_from_slice(&GUEST_MAC);
+        d[236..240].copy_from_slice(&DHCP_MAGIC.to_be_bytes());
+        d.extend_from_slice(&[0, 53, 1, msg_type, 255]);
+        let mut u = DHCP_CLIENT_PORT.to_be_bytes().to_vec();
+        u.extend_from_slice(&DHCP_SERVER_PORT.to_be_bytes());
+        u.extend_from_slice(&((8 + d.len()) as u16).to_be_bytes());
+        u.extend_from_slice(&[0, 0]);
+        u.extend_from_slice(&d);
+        eth(ETHERTYPE_IPV4, &ipv4([0; 4], [255; 4], IPPROTO_UDP, &u))
+    }
+
+    // DHCP option in a reply
+    fn option(d: &[u8], code: u8) -> Option<&[u8]> {
+        let mut i = 240;
+        while i + 1 < d.len() && d[i] != 255 {
+            if d[i] == code { return Some(&d[i+2..i+2+d[i+1] as usize]); }
+            i += 2 + d[i+1] as usize;
+        }
+        None
+    }
+
+    #[test]
+    fn checksums() {
+        // example of RFC 1071
+        assert_eq!(checksum(0, &[0x00, 0x01, 0xf2, 0x03, 0xf4, 0xf5, 0xf6, 0xf7]), !0xddf2);
+        assert_eq!(checksum(0, &[0x01]), !0x0100);
+        assert_eq!(checksum(0xffff, &[]), 0);
+
+        // a header with its checksum sums to zero
+        let p = ipv4(NET_GUEST, NET_GATEWAY, IPPROTO_UDP, &[]);
+        assert_eq!(checksum(0, &p), 0);
+        assert_eq!(pseudo_header_sum([10, 0, 2, 15], [10, 0, 2, 2], IPPROTO_UDP, 8), 0x0a00 + 0x020f + 0x0a00 + 0x0202 + 17 + 8);
+    }
+
+    #[test]
+    fn hostfwd_specs() {
+        let (tcp, haddr, gaddr, gport) = parse_hostfwd("tcp::2222-:22").unwrap();
+        assert!(tcp && haddr == "127.0.0.1:2222".parse().unwrap() && gaddr == NET_GUEST && gport == 22);
+        let (tcp, haddr, gaddr, gport) = parse_hostfwd("udp:0.0.0.0:5353-10.0.2.16:53").unwrap();
+        assert!(!tcp && haddr == "0.0.0.0:5353".parse().unwrap() && gaddr == [10, 0, 2, 16] && gport == 53);
+        assert!(parse_hostfwd("::8080-:80").unwrap().0);
+
+        for s in ["sctp::1-:2", "tcp::2222", "tcp:2222-:22", "tcp::x-:22", "tcp::1-:70000", "tcp:host:1-:2"] {
+            assert!(parse_hostfwd(s).is_err(), "{}", s);
+        }
+        assert!(Slirp::new("hostfwd=tcp::2222").is_err());
+        assert!(Slirp::new("restrict=on").is_err());
+    }
+
+    #[test]
+    fn mss_option() {
+        assert_eq!(tcp_mss_option(&[2, 4, 0x05, 0xb4]), Some(1460));
+        assert_eq!(tcp_mss_option(&[1, 1, 3, 3, 7, 2, 4, 0x02, 0x18]), Some(536));
+        assert_eq!(tcp_mss_option(&[0, 2, 4, 0x05, 0xb4]), None);
+        assert_eq!(tcp_mss_option(&[3, 0, 2, 4, 0x05, 0xb4]), None);
+        assert_eq!(tcp_mss_option(&[2, 4, 0x05]), None);
+    }
+
+    #[test]
+    fn arp_reply() {
+        let mut s = Slirp::new("").unwrap();
+        let mut a = vec![0, 1, 8, 0, 6, 4, 0, 1];
+        a.extend_from_slice(&GUEST_MAC);
+        a.extend_from_slice(&NET_GUEST);
+        a.extend_from_slice(&[0; 6]);
+        a.extend_from_slice(&NET_GATEWAY);
+        s.send(&eth(ETHERTYPE_ARP, &a));
+
+        let r = s.recv().unwrap();
+        assert_eq!(&r[0..6], &GUEST_MAC);
+        assert_eq!(read16(&r, 12), ETHERTYPE_ARP);
+        assert_eq!(read16(&r, 14 + 6), 2);
+        assert_eq!(&r[14+8..14+14], &GATEWAY_MAC);
+        assert_eq!(ip4(&r, 14 + 14), NET_GATEWAY);
+        assert_eq!(ip4(&r, 14 + 24), NET_GUEST);
+        assert!(s.recv().is_none());
+
+        // other addresses are not answered
+        a[24..28].copy_from_slice(&[10, 0, 2, 99]);
+        s.send(&eth(ETHERTYPE_ARP, &a));
+        assert!(s.recv().is_none());
+    }
+
+    #[test]
+    fn dhcp_offer_and_ack() {
+        let mut s = Slirp::new("").unwrap();
+        for (request, reply) in [(1, 2), (3, 5)] {
+            s.send(&dhcp(request));
+            let r = s.recv().unwrap();
+            assert_eq!(&r[0..6], &BROADCAST_MAC);
+            let ip = &r[14..];
+            assert_eq!(checksum(0, &ip[0..20]), 0);
+            assert_eq!((ip[9], ip4(ip, 12), ip4(ip, 16)), (IPPROTO_UDP, NET_GATEWAY, [255; 4]));
+            let u = &ip[20..];
+            assert_eq!((read16(u, 0), read16(u, 2)), (DHCP_SERVER_PORT, DHCP_CLIENT_PORT));
+            assert_eq!(checksum(pseudo_header_sum(NET_GATEWAY, [255; 4], IPPROTO_UDP, u.len()), u), 0);
+
+            let d = &u[8..];
+            assert_eq!((d[0], &d[4..8], ip4(d, 16)), (2, &[0xde, 0xad, 0xbe, 0xef][..], NET_GUEST));
+            assert_eq!(&d[28..34], &GUEST_MAC);
+            assert_eq!(option(d, 53), Some(&[reply][..]));
+            assert_eq!(option(d, 1), Some(&NET_MASK[..]));
+            assert_eq!(option(d, 3), Some(&NET_GATEWAY[..]));
+            assert_eq!(option(d, 6), Some(&NET_DNS[..]));
+        }
+
+        // DHCPRELEASE is not answered
+        s.send(&dhcp(7));
+        assert!(s.recv().is_none());
+    }
+
+    #[test]
+    fn icmp_echo() {
+        let mut s = Slirp::new("").unwrap();
+        let mut icmp = vec![8, 0, 0, 0, 0x12, 0x34, 0, 1, b'p', b'i', b'n', b'g'];
+        let sum = checksum(0, &icmp);
+        icmp[2..4].copy_from_slice(&sum.to_be_bytes());
+        s.send(&eth(ETHERTYPE_IPV4, &ipv4(NET_GUEST, NET_DNS, IPPROTO_ICMP, &icmp)));
+
+        let r = s.recv().unwrap();
+        assert_eq!(&r[0..6], &GUEST_MAC);
+        let ip = &r[14..];
+        assert_eq!((ip[9], ip4(ip, 12), ip4(ip, 16)), (IPPROTO_ICMP, NET_DNS, NET_GUEST));
+        let e = &ip[20..];
+        assert_eq!(e[0], 0);
+        assert_eq!(&e[4..], &icmp[4..]);
+        assert_eq!(checksum(0, e), 0);
+
+        // fragments are dropped
+        let mut p = ipv4(NET_GUEST, NET_GATEWAY, IPPROTO_ICMP, &icmp);
+        p[6] = 0x20; // more fragments
+        s.send(&eth(ETHERTYPE_IPV4, &p));
+        assert!(s.recv().is_none());
+    }
+}
@@ -0,0 +1,273 @@
+use crate::procstate::MachineState;
+use crate::cp0def;
+use crate::mips;
+use crate::c0_val;
+
+/*
+Performance counters of CP0 (advertised by Config1.PC).
+
+PerfCtl n : M (another counter follows), Event (bits 14..5), IE (interrupt enable)
+            and the modes to count in, U (user), S (supervisor), K (kernel) and EXL (exception level)
+PerfCnt n : 32-bit counter
+
+The events are the same on all counters:
+  0 : cycles (one per instruction)
+  1 : instructions
+  2 : taken branches and jumps
+  3 : TLB refill exceptions
+  4 : exceptions and interrupts
+  5 : loads
+  6 : stores
+  7 : MIPS16e instructions
+
+While the MSB of a counter with IE is set, Cause.PCI is set and the interrupt is requested
+on the performance counter bit of the misc. interrupt status of the SoC,
+or on the IP line of IntCtl.IPPCI instead when IoMisc.perf_irq_ip is set.
+*/
+
+pub const NUM_PERF : usize = 4;
+
+pub const EV_CYCLES     : u32 = 0;
+pub const EV_INSTS      : u32 = 1;
+pub const EV_BRANCHES   : u32 = 2;
+pub const EV_TLB_REFILL : u32 = 3;
+pub const EV_EXCEPTIONS : u32 = 4;
+pub const EV_LOADS      : u32 = 5;
+pub const EV_STORES     : u32 = 6;
+pub const EV_MIPS16     : u32 = 7;
+
+const PERFCTL_BIT_M     : u32 = 31;
+const PERFCTL_BIT_EVENT : u32 = 5;
+const PERFCTL_EVENT_MASK: u32 = 0x3ff<<PERFCTL_BIT_EVENT;
+const PERFCTL_BIT_IE    : u32 = 4;
+const PERFCTL_U         : u32 = 1<<3;
+const PERFCTL_S         : u32 = 1<<2;
+const PERFCTL_K         : u32 = 1<<1;
+const PERFCTL_EXL       : u32 = 1<<0;
+const PERFCTL_MODES     : u32 = PERFCTL_U | PERFCTL_S | PERFCTL_K | PERFCTL_EXL;
+
+const PERFCTL : [(u32,u32); NUM_PERF] = [cp0def::C0_PERFCTL0, cp0def::C0_PERFCTL1, cp0def::C0_PERFCTL2, cp0def::C0_PERFCTL3];
+const PERFCNT : [(u32,u32); NUM_PERF] = [cp0def::C0_PERFCNT0, cp0def::C0_PERFCNT1, cp0def::C0_PERFCNT2, cp0def::C0_PERFCNT3];
+
+// recomputes the events counted by any counter (bit n for the event n), which are checked before counting
+pub fn update(ms: &mut MachineState) {
+    ms.reg.perf_en = PERFCTL.iter().fold(0, |en, rs| {
+        let ctl   = c0_val!(ms.reg, *rs);
+        let event = (ctl & PERFCTL_EVENT_MASK) >> PERFCTL_BIT_EVENT;
+        if 0 != (ctl & PERFCTL_MODES) && event < 32 { en | (1<<event) }else{ en }
+    });
+}
+
+// Cause.PCI is set while a counter with IE has the MSB set
+fn update_pci(ms: &mut MachineState) {
+    let pci = PERFCTL.iter().zip(PERFCNT.iter()).any(|(ctl, cnt)| {
+        0 != (c0_val!(ms.reg, *ctl) & (1<<PERFCTL_BIT_IE)) && 0 != (c0_val!(ms.reg, *cnt) & 0x80000000)
+    });
+    if pci {
+        c0_val!(ms.reg, cp0def::C0_CAUSE) |=   1<<cp0def::C0_CAUSE_BIT_PCI;
+    }else{
+        c0_val!(ms.reg, cp0def::C0_CAUSE) &= !(1<<cp0def::C0_CAUSE_BIT_PCI);
+    }
+}
+
+pub fn store_ctl(ms: &mut MachineState, rs: (u32,u32), val: u32) {
+    c0_val!(ms.reg, rs) = val & (PERFCTL_EVENT_MASK | (1<<PERFCTL_BIT_IE) | PERFCTL_MODES);
+    update(ms);
+    update_pci(ms);
+}
+
+pub fn load_ctl(ms: &MachineState, rs: (u32,u32)) -> u32 {
+    let more = if (rs.1 as usize) / 2 < NUM_PERF - 1 { 1<<PERFCTL_BIT_M }else{ 0 };
+    c0_val!(ms.reg, rs) | more
+}
+
+pub fn store_cnt(ms: &mut MachineState, rs: (u32,u32), val: u32) {
+    c0_val!(ms.reg, rs) = val;
+    update_pci(ms);
+}
+
+// true if PerfCtl ctl counts in the current mode
+fn mode_enabled(ctl: u32, status: u32) -> bool {
+    if 0 != (status & (1<<cp0def::C0_STATUS_BIT_ERL)) {
+        false
+    }else if 0 != (status & (1<<cp0def::C0_STATUS_BIT_EXL)) {
+        0 != (ctl & PERFCTL_EXL)
+    }else{
+        match (status & cp0def::C0_STATUS_KSU_MASK) >> cp0def::C0_STATUS_BIT_KSU {
+            0 => 0 != (ctl & PERFCTL_K),
+            1 => 0 != (ctl & PERFCTL_S),
+            _ => 0 != (ctl & PERFCTL_U),
+        }
+    }
+}
+
+// Counts the events (bit n for the event n) by one
+pub fn count(ms: &mut MachineState, events: u32) {
+    let status = c0_val!(ms.reg, cp0def::C0_STATUS);
+    let mut msb_changed = false;
+    for (ctl, cnt) in PERFCTL.iter().zip(PERFCNT.iter()) {
+        let c     = c0_val!(ms.reg, *ctl);
+        let event = (c & PERFCTL_EVENT_MASK) >> PERFCTL_BIT_EVENT;
+        if event < 32 && 0 != (events & (1<<event)) && mode_enabled(c, status) {
+            let v = c0_val!(ms.reg, *cnt).wrapping_add(1);
+            c0_val!(ms.reg, *cnt) = v;
+            // the MSB changes when the counter reaches 0x80000000 or wraps to zero
+            msb_changed |= v == 0x80000000 || v == 0;
+        }
+    }
+    if msb_changed {
+        update_pci(ms);
+    }
+}
+
+// Counts the events of an executed instruction
+pub fn count_inst(ms: &mut MachineState, mips16: bool) {
+    let mut events = (1<<EV_CYCLES) | (1<<EV_INSTS);
+    if ms.reg.delay_en {
+        // only a taken branch or jump leaves its delay slot pending
+        events |= 1<<EV_BRANCHES;
+    }
+    if mips16 {
+        events |= 1<<EV_MIPS16;
+    }
+    if 0 != (ms.reg.perf_en & events) {
+        count(ms, events);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dev_spiflash::SPI_FLASH_PARAM_S25FL164K;
+    use crate::exrmips::generate_machine_state;
+
+    const STATUS_USER : u32 = 2<<cp0def::C0_STATUS_BIT_KSU;
+    const STATUS_EXL  : u32 = 1<<cp0def::C0_STATUS_BIT_EXL;
+    const STATUS_ERL  : u32 = 1<<cp0def::C0_STATUS_BIT_ERL;
+
+    fn machine(status: u32) -> MachineState {
+        let mut ms = generate_machine_state(&SPI_FLASH_PARAM_S25FL164K, &[]);
+        c0_val!(ms.reg, cp0def::C0_STATUS) = status;
+        ms
+    }
+
+    fn ctl(event: u32, modes: u32) -> u32 {
+        (event<<PERFCTL_BIT_EVENT) | modes
+    }
+
+    fn counts(ms: &MachineState) -> [u32; NUM_PERF] {
+        PERFCNT.map(|rs| c0_val!(ms.reg, rs))
+    }
+
+    fn pci(ms: &MachineState) -> bool {
+        0 != (c0_val!(ms.reg, cp0def::C0_CAUSE) & (1<<cp0def::C0_CAUSE_BIT_PCI))
+    }
+
+    #[test]
+    fn events_in_each_mode() {
+        let mut ms = machine(0);
+        store_ctl(&mut ms, PERFCTL[0], ctl(EV_LOADS, PERFCTL_K));
+        store_ctl(&mut ms, PERFCTL[1], ctl(EV_LOADS, PERFCTL_U));
+        store_ctl(&mut ms, PERFCTL[2], ctl(EV_LOADS, PERFCTL_EXL));
+        store_ctl(&mut ms, PERFCTL[3], ctl(EV_STORES, PERFCTL_MODES));
+
+        count(&mut ms, 1<<EV_LOADS);
+        assert_eq!(counts(&ms), [1, 0, 0, 0]);
+
+        c0_val!(ms.reg, cp0def::C0_STATUS) = STATUS_USER;
+        count(&mut ms, 1<<EV_LOADS);
+        assert_eq!(counts(&ms), [1, 1, 0, 0]);
+
+        // EXL takes precedence over KSU
+        c0_val!(ms.reg, cp0def::C0_STATUS) = STATUS_USER | STATUS_EXL;
+        count(&mut ms, (1<<EV_LOADS) | (1<<EV_STORES));
+        assert_eq!(counts(&ms), [1, 1, 1, 1]);
+
+        // nothing is counted at the error level
+        c0_val!(ms.reg, cp0def::C0_STATUS) = STATUS_ERL;
+        count(&mut ms, (1<<EV_LOADS) | (1<<EV_STORES));
+        assert_eq!(counts(&ms), [1, 1, 1, 1]);
+    }
+
+    #[test]
+    fn instruction_events() {
+        let mut ms = machine(0);
+        store_ctl(&mut ms, PERFCTL[0], ctl(EV_INSTS, PERFCTL_K));
+        store_ctl(&mut ms, PERFCTL[1], ctl(EV_BRANCHES, PERFCTL_K));
+        store_ctl(&mut ms, PERFCTL[2], ctl(EV_MIPS16, PERFCTL_K));
+        store_ctl(&mut ms, PERFCTL[3], ctl(EV_CYCLES, PERFCTL_K));
+
+        count_inst(&mut ms, false);
+        ms.reg.delay_en = true;
+        count_inst(&mut ms, false);
+        ms.reg.delay_en = false;
+        count_inst(&mut ms, true);
+        assert_eq!(counts(&ms), [3, 1, 1, 3]);
+    }
+
+    #[test]
+    fn overflow_sets_pci_with_ie() {
+        let mut ms = machine(0);
+        store_ctl(&mut ms, PERFCTL[0], ctl(EV_INSTS, PERFCTL_K));
+        store_cnt(&mut ms, PERFCNT[0], 0x7fffffff);
+        count_inst(&mut ms, false);
+        assert_eq!(counts(&ms)[0], 0x80000000);
+        assert!(!pci(&ms));
+
+        // IE is checked when it is set with the MSB already set
+        store_ctl(&mut ms, PERFCTL[0], ctl(EV_INSTS, PERFCTL_K) | (1<<PERFCTL_BIT_IE));
+        assert!(pci(&ms));
+        store_cnt(&mut ms, PERFCNT[0], 0);
+        assert!(!pci(&ms));
+
+        store_cnt(&mut ms, PERFCNT[0], 0x7ffffffe);
+        count_inst(&mut ms, false);
+        assert!(!pci(&ms));
+        count_inst(&mut ms, false);
+        assert!(pci(&ms));
+
+        // PCI stays set while the MSB is set and is cleared by the handler rewriting the counter
+        count_inst(&mut ms, false);
+        assert!(pci(&ms));
+        store_cnt(&mut ms, PERFCNT[0], 0);
+        assert!(!pci(&ms));
+
+        // the request ends when the counter wraps to zero
+        store_cnt(&mut ms, PERFCNT[0], 0xffffffff);
+        assert!(pci(&ms));
+        count_inst(&mut ms, false);
+        assert_eq!(counts(&ms)[0], 0);
+        assert!(!pci(&ms));
+    }
+
+    #[test]
+    fn control_registers() {
+        let mut ms = machine(0);
+        store_ctl(&mut ms, PERFCTL[1], 0xffffffff);
+        assert_eq!(c0_val!(ms.reg, PERFCTL[1]), PERFCTL_EVENT_MASK | (1<<PERFCTL_BIT_IE) | PERFCTL_MODES);
+        assert_eq!(load_ctl(&ms, PERFCTL[0]) & (1<<PERFCTL_BIT_M), 1<<PERFCTL_BIT_M);
+        assert_eq!(load_ctl(&ms, PERFCTL[2]) & (1<<PERFCTL_BIT_M), 1<<PERFCTL_BIT_M);
+        assert_eq!(load_ctl(&ms, PERFCTL[3]) & (1<<PERFCTL_BIT_M), 0);
+    }
+
+    #[test]
+    fn enabled_events_are_rebuilt() {
+        let mut ms = machine(0);
+        assert_eq!(ms.reg.perf_en, 0);
+
+        // e.g., registers restored from a snapshot
+        c0_val!(ms.reg, PERFCTL[0]) = ctl(EV_LOADS, PERFCTL_U);
+        c0_val!(ms.reg, PERFCTL[2]) = ctl(EV_MIPS16, PERFCTL_EXL);
+        c0_val!(ms.reg, PERFCTL[3]) = ctl(EV_STORES, 0);     // no mode
+        c0_val!(ms.reg, PERFCTL[1]) = ctl(40, PERFCTL_K);    // unknown event
+        update(&mut ms);
+        assert_eq!(ms.reg.perf_en, (1<<EV_LOADS) | (1<<EV_MIPS16));
+
+        // instructions are not counted when no counter selects their events
+        store_ctl(&mut ms, PERFCTL[0], 0);
+        store_ctl(&mut ms, PERFCTL[2], ctl(EV_TLB_REFILL, PERFCTL_K));
+        assert_eq!(ms.reg.perf_en, 1<<EV_TLB_REFILL);
+        count_inst(&mut ms, true);
+        assert_eq!(counts(&ms), [0, 0, 0, 0]);
+    }
+}
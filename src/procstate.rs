@@ -1,4 +1,5 @@
 use crate::addr_cache::AddrCache;
+use crate::fpu::Fpu;
 use crate::config;
 use crate::dev_uart;
 use crate::mips;
@@ -9,8 +10,26 @@ use crate::dev_uart::IoUART;
 use crate::dev_soc::IoGPIO;
 use crate::dev_soc::IoMisc;
 use crate::dev_spi::IoSPI;
+use crate::dev_gmac::{IoGMAC, GMAC_NUM};
+use crate::breakpoint::BreakpointSet;
+use crate::trace::Tracer;
+use crate::symbols::SymbolTable;
+use crate::syscall_trace::SyscallTracer;
+use crate::mmio_log::MmioLog;
+#[cfg(not(target_family = "wasm"))]
+use crate::gdbstub::GdbStub;
+#[cfg(not(target_family = "wasm"))]
+use crate::flash_store::FlashStore;
+#[cfg(not(target_family = "wasm"))]
+use crate::script::Script;
+#[cfg(not(target_family = "wasm"))]
+use crate::profiler::Profiler;
 
 use std::sync::Arc;
+#[cfg(not(target_family = "wasm"))]
+use std::rc::Rc;
+#[cfg(not(target_family = "wasm"))]
+use std::sync::mpsc::Receiver;
 use std::sync::atomic;
 
 use crate::c0_val;
@@ -23,11 +42,17 @@ impl Reg {
     pub fn new() -> Self {
         Self { 
             r  : [0; 32], 
+            srs : [[0; 32]; config::NUM_SHADOW_SETS],
             pc : 0,
             pc_delay : 0,
             pc_prev_jump : 0,
             hi : 0,
             lo : 0,
+            ac_hi : [0; 3],
+            ac_lo : [0; 3],
+            dspcontrol : 0,
+            watch_en : 0,
+            perf_en  : 0,
         
             delay_en : false,
             ll_sc : false,
@@ -40,18 +65,25 @@ impl Reg {
             dr_cache: [AddrCache::new(), AddrCache::new()],
             dw_cache: [AddrCache::new(), AddrCache::new()],
             cp0 : [0; 1<<(mips::CP_REG_BITS + mips::CP_SEL_BITS)],
+            fpu : Fpu::new(),
         }
     }
 }
 
 pub struct Reg {
     pub r : [u32; 32],
+    pub srs : [[u32; 32]; config::NUM_SHADOW_SETS], /* shadow register sets (the current set is in r) */
     pub pc : u32,
 
     pub pc_delay : u32,
     pub pc_prev_jump : u32,
     pub hi : u32,
     pub lo : u32,
+    pub ac_hi : [u32; 3], /* ac1..ac3 of DSP ASE (ac0 is hi and lo) */
+    pub ac_lo : [u32; 3],
+    pub dspcontrol : u32,
+    pub watch_en : u32, /* I, R and W enables of all WatchLo (see watch::update) */
+    pub perf_en  : u32, /* events counted by any PerfCtl (see perf::update) */
 
     pub delay_en : bool,
     pub ll_sc : bool,
@@ -62,6 +94,7 @@ pub struct Reg {
     pub c0_compare_long : u64,         /* long version of c0_compare */
 
     pub cp0 : [u32; 1<<(mips::CP_REG_BITS + mips::CP_SEL_BITS)],
+    pub fpu : Fpu,
 
     pub pc_cache: AddrCache,
     pub dr_cache: [AddrCache; 2],
@@ -69,8 +102,7 @@ pub struct Reg {
 }
 
 pub struct EmuSetting{
-    pub breakpoint : u32,
-    pub breakmask  : u32,
+    pub bp : BreakpointSet,
     pub runafterbreak : u64,
     pub breakcounter  : u64,
     pub nexec_insts   : u64,
@@ -84,17 +116,31 @@ pub struct MachineState {
     pub mem : MemRegion,
     pub tlb : [TLBEntry; config::NUM_TLB_ENTRY as usize],
     pub tlbcache: [u8; config::TLB_CACHE_SIZE],
-    pub uart: IoUART,
+    pub uart: [IoUART; dev_uart::UART_NUM],
     pub misc: IoMisc,
     pub gpio: IoGPIO,
     pub spi : IoSPI,
+    pub gmac: [IoGMAC; GMAC_NUM],
     pub emu : EmuSetting,
     pub sleep_req : bool,
-    pub stdin_ch  : Box<dyn dev_uart::UartReadWrite>,
+    pub trace : Option<Tracer>,
+    pub symbols : SymbolTable,
+    pub syscall_trace : Option<SyscallTracer>,
+    pub mmio_log : Option<MmioLog>,
+    #[cfg(not(target_family = "wasm"))]
+    pub console : Rc<Receiver<u8>>, // key inputs of the terminal
     #[cfg(not(target_family = "wasm"))]
     pub ctrlc_count : Arc<atomic::AtomicUsize>,
     #[cfg(not(target_family = "wasm"))]
     pub time_trigger: Arc<atomic::AtomicBool>,
+    #[cfg(not(target_family = "wasm"))]
+    pub gdb : Option<GdbStub>,
+    #[cfg(not(target_family = "wasm"))]
+    pub flash_store : Option<FlashStore>,
+    #[cfg(not(target_family = "wasm"))]
+    pub script : Option<Script>,
+    #[cfg(not(target_family = "wasm"))]
+    pub profiler : Option<Profiler>,
 }
 
 pub fn log_print_reg32(reg: &Reg){
@@ -105,14 +151,23 @@ pub fn log_print_reg32(reg: &Reg){
     info!("r[24..31]={:>08x} {:>08x} {:>08x} {:>08x} {:>08x} {:>08x} {:>08x} {:>08x}\r", reg.r[24], reg.r[25], reg.r[26], reg.r[27], reg.r[28], reg.r[29], reg.r[30], reg.r[31]);
 }
 
-pub fn dump_mem(mem : &Box<[u8]>, start: u32, len: u32){
+/*
+Prints a hexdump of the region [start, start+len).
+The first byte of mem is located at the address base. Bytes out of mem are left blank.
+*/
+pub fn dump_mem(mem : &[u8], base: u32, start: u32, len: u32){
+    let end : u64 = start as u64 + len as u64;
 
-    for i in (start>>4)..(start+len)>>4 {
+    for i in (start as u64 >> 4)..((end + 15) >> 4) {
         print!("{:>08x}:", i<<4);
-        for j in 0..16{
-            print!(" {:>02x}", mem[ ((i<<4) + j) as usize]);
+        for j in 0..16 {
+            let addr : u64 = (i<<4) + j;
+            if addr >= start as u64 && addr < end && addr >= base as u64 && addr - (base as u64) < mem.len() as u64 {
+                print!(" {:>02x}", mem[ (addr - base as u64) as usize ]);
+            }else{
+                print!("   ");
+            }
         }
-        println!("");
+        print!("\r\n");
     }
 }
-
@@ -0,0 +1,297 @@
+use crate::procstate::MachineState;
+use crate::symbols::SymbolTable;
+use crate::mem;
+
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+/*
+Sampling PC profiler.
+
+The PC is sampled every interval instructions, or at every host timer tick when interval is 0.
+The call stack of a sample is recovered with the usual heuristics for MIPS code without frame information:
+the prologue of the function ("addiu sp,sp,-N" and "sw ra,OFF(sp)") is searched backward from the PC,
+and the return address is taken from the stack frame, or from $ra for the innermost function.
+*/
+
+const MAX_DEPTH : usize = 64;
+// maximum number of instructions searched for a prologue
+const MAX_SCAN  : u32 = 4096;
+
+const INST_ADDIU_SP_SP : u32 = 0x27bd0000; // addiu sp,sp,IMM
+const INST_SW_RA_SP    : u32 = 0xafbf0000; // sw    ra,IMM(sp)
+const INST_JR_RA       : u32 = 0x03e00008; // jr    ra
+
+const REG_SP : usize = 29;
+const REG_RA : usize = 31;
+
+pub struct Profiler {
+    interval : u64,
+    samples  : HashMap<u32, u64>,
+    stacks   : HashMap<Vec<u32>, u64>, // from the outermost caller to the sampled PC
+    total    : u64,
+}
+
+fn read_word(ms: &mut MachineState, vaddr: u32) -> Option<u32> {
+    let mut v : u32 = 0;
+    for i in 0..4 {
+        v = (v << 8) | mem::debug_read_byte(ms, vaddr.wrapping_add(i)).ok()? as u32;
+    }
+    Some(v)
+}
+
+// size of the stack frame and the offset of the saved $ra in the function containing pc
+fn analyze_prologue(ms: &mut MachineState, pc: u32) -> Option<(u32, Option<u32>)> {
+    // The search does not go beyond the beginning of the function if its symbol is known
+    let scan = match ms.symbols.lookup(pc) {
+        Some((_, offset)) => (offset / 4).min(MAX_SCAN),
+        None              => MAX_SCAN,
+    };
+    let mut ra_offset = None;
+    for i in 1..=scan {
+        let inst = read_word(ms, pc.wrapping_sub(4*i))?;
+        if inst == INST_JR_RA {
+            // the end of the previous function
+            return Some((0, None));
+        }
+        if inst & 0xffff0000 == INST_SW_RA_SP {
+            ra_offset = Some(inst & 0xffff);
+        }
+        if inst & 0xffff0000 == INST_ADDIU_SP_SP && 0 != (inst & 0x8000) {
+            return Some(((inst as u16 as i16).unsigned_abs() as u32, ra_offset));
+        }
+    }
+    if scan < MAX_SCAN { Some((0, None)) }else{ None }
+}
+
+fn same_function(ms: &MachineState, a: u32, b: u32) -> bool {
+    match (ms.symbols.lookup(a), ms.symbols.lookup(b)) {
+        (Some((s, _)), Some((t, _))) => s.addr == t.addr,
+        _ => false,
+    }
+}
+
+fn unwind(ms: &mut MachineState, pc: u32) -> Vec<u32> {
+    let mut frames = vec![pc];
+    if 0 != (pc & 1) {
+        // prologues of MIPS16e code are not analyzed
+        return frames;
+    }
+    let mut pc = pc;
+    let mut sp = ms.reg.r[REG_SP];
+    while frames.len() < MAX_DEPTH {
+        let innermost = frames.len() == 1;
+        let (ra, saved) = match analyze_prologue(ms, pc) {
+            Some((size, Some(offset))) => {
+                let ra = read_word(ms, sp.wrapping_add(offset));
+                sp = sp.wrapping_add(size);
+                (ra, true)
+            }
+            Some((size, None)) if innermost => { sp = sp.wrapping_add(size); (Some(ms.reg.r[REG_RA]), false) }
+            None if innermost => (Some(ms.reg.r[REG_RA]), false),
+            _ => (None, false),
+        };
+        match ra {
+            // $ra of the outermost function without a frame may point to itself
+            Some(ra) if !saved && same_function(ms, pc, ra.wrapping_sub(8)) => { break; }
+            Some(ra) if ra >= 8 && 0 == (ra & 3) && ra != pc => {
+                // the call instruction
+                pc = ra - 8;
+                frames.push(pc);
+            }
+            _ => { break; }
+        }
+    }
+    frames.reverse();
+    frames
+}
+
+impl Profiler {
+    pub fn new(interval: u64) -> Self {
+        Self { interval, samples: HashMap::new(), stacks: HashMap::new(), total: 0 }
+    }
+
+    fn sample(&mut self, ms: &mut MachineState, pc: u32) {
+        *self.samples.entry(pc).or_insert(0) += 1;
+        let stack = unwind(ms, pc);
+        *self.stacks.entry(stack).or_insert(0) += 1;
+        self.total += 1;
+    }
+
+    // called before executing the instruction at pc
+    pub fn on_instruction(&mut self, ms: &mut MachineState, pc: u32) {
+        if self.interval != 0 && ms.emu.nexec_insts.is_multiple_of(self.interval) {
+            self.sample(ms, pc);
+        }
+    }
+
+    // called at every host timer tick
+    pub fn on_tick(&mut self, ms: &mut MachineState) {
+        if self.interval == 0 {
+            let pc = ms.reg.pc;
+            self.sample(ms, pc);
+        }
+    }
+
+    fn name(syms: &SymbolTable, addr: u32) -> String {
+        match syms.lookup(addr) {
+            Some((s, _)) => s.name.clone(),
+            None         => format!("0x{:>08x}", addr),
+        }
+    }
+
+    /*
+    Writes the flat profile: samples per function (per address for addresses without a symbol)
+    followed by the hottest addresses.
+    */
+    pub fn write_flat(&self, syms: &SymbolTable, out: &mut dyn Write) -> io::Result<()> {
+        let mut funcs : HashMap<String, u64> = HashMap::new();
+        for (addr, n) in self.samples.iter() {
+            *funcs.entry(Self::name(syms, *addr)).or_insert(0) += n;
+        }
+        let mut funcs : Vec<(String, u64)> = funcs.into_iter().collect();
+        funcs.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+
+        let percent = |n: u64| 100.0 * n as f64 / self.total.max(1) as f64;
+
+        writeln!(out, "# {} samples", self.total)?;
+        writeln!(out, "#  samples       %  function")?;
+        for (name, n) in funcs.iter() {
+            writeln!(out, "{:>10} {:>6.2}%  {}", n, percent(*n), name)?;
+        }
+
+        let mut addrs : Vec<(u32, u64)> = self.samples.iter().map(|(a, n)| (*a, *n)).collect();
+        addrs.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        writeln!(out)?;
+        writeln!(out, "#  samples       %  address")?;
+        for (addr, n) in addrs.iter().take(50) {
+            let sym = match syms.lookup(*addr) {
+                Some((s, off)) => format!("  {}+0x{:x}", s.name, off),
+                None           => String::new(),
+            };
+            writeln!(out, "{:>10} {:>6.2}%  {:>08x}{}", n, percent(*n), addr, sym)?;
+        }
+        Ok(())
+    }
+
+    // Writes the call stacks in the folded format ("caller;callee COUNT") for flame graph tools
+    pub fn write_folded(&self, syms: &SymbolTable, out: &mut dyn Write) -> io::Result<()> {
+        let mut folded : HashMap<String, u64> = HashMap::new();
+        for (stack, n) in self.stacks.iter() {
+            let names : Vec<String> = stack.iter().map(|a| Self::name(syms, *a)).collect();
+            *folded.entry(names.join(";")).or_insert(0) += n;
+        }
+        let mut folded : Vec<(String, u64)> = folded.into_iter().collect();
+        folded.sort();
+        for (stack, n) in folded.iter() {
+            writeln!(out, "{} {}", stack, n)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dev_spiflash::SPI_FLASH_PARAM_S25FL164K;
+    use crate::exrmips::generate_machine_state;
+
+    const SYMBOLS : &[u8] = b"80000000 T _text\n80001000 t helper\n80002000 T start_kernel\n";
+
+    fn put_code(ms: &mut MachineState, vaddr: u32, code: &[u32]) {
+        let start = (vaddr & 0x1fffffff) as usize;
+        for (i, w) in code.iter().enumerate() {
+            ms.mem.mem0[start + 4*i .. start + 4*i + 4].copy_from_slice(&w.to_be_bytes());
+        }
+    }
+
+    fn report(prof: &Profiler, syms: &SymbolTable, folded: bool) -> String {
+        let mut out = Vec::new();
+        if folded {
+            prof.write_folded(syms, &mut out).unwrap();
+        }else{
+            prof.write_flat(syms, &mut out).unwrap();
+        }
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn flat_profile() {
+        let mut ms = generate_machine_state(&SPI_FLASH_PARAM_S25FL164K, &[]);
+        ms.symbols.load(SYMBOLS).unwrap();
+        let mut prof = Profiler::new(0);
+        for pc in [0x80001010, 0x00400000, 0x80001010, 0x80002000, 0x00400000, 0x80001020, 0x80000000, 0x80001010, 0x00400000] {
+            ms.reg.pc = pc;
+            prof.on_tick(&mut ms);
+        }
+
+        // functions and addresses are sorted by the number of samples and then by the name or the address
+        assert_eq!(report(&prof, &ms.symbols, false), concat!(
+            "# 9 samples\n",
+            "#  samples       %  function\n",
+            "         4  44.44%  helper\n",
+            "         3  33.33%  0x00400000\n",
+            "         1  11.11%  _text\n",
+            "         1  11.11%  start_kernel\n",
+            "\n",
+            "#  samples       %  address\n",
+            "         3  33.33%  00400000\n",
+            "         3  33.33%  80001010  helper+0x10\n",
+            "         1  11.11%  80000000  _text+0x0\n",
+            "         1  11.11%  80001020  helper+0x20\n",
+            "         1  11.11%  80002000  start_kernel+0x0\n",
+        ));
+
+        // raw addresses without the symbols
+        let flat = report(&prof, &SymbolTable::new(), false);
+        assert!(flat.contains("         3  33.33%  0x80001010\n         1  11.11%  0x80000000\n"));
+        assert!(!flat.contains("helper"));
+    }
+
+    #[test]
+    fn interval() {
+        let mut ms = generate_machine_state(&SPI_FLASH_PARAM_S25FL164K, &[]);
+        let mut prof = Profiler::new(3);
+        for n in 0..10 {
+            ms.emu.nexec_insts = n;
+            prof.on_instruction(&mut ms, 0x80000000 + 4*n as u32);
+            prof.on_tick(&mut ms);
+        }
+        // samples at the instructions 0, 3, 6 and 9 (ticks are ignored)
+        assert_eq!(prof.total, 4);
+        assert_eq!(prof.samples.get(&0x8000000c), Some(&1));
+        assert_eq!(prof.samples.get(&0x80000010), None);
+    }
+
+    #[test]
+    fn call_stacks() {
+        let mut ms = generate_machine_state(&SPI_FLASH_PARAM_S25FL164K, &[]);
+        ms.symbols.load(b"80003000 T outer\n80004000 T inner\n80005000 T main\n").unwrap();
+        // main calls outer (with a stack frame), and outer calls inner (a leaf function without a frame)
+        put_code(&mut ms, 0x80005000, &[0, 0, 0x0c000c00, 0]);
+        put_code(&mut ms, 0x80003000, &[0x27bdffe0, 0xafbf001c, 0, 0, 0x0c001000, 0]);
+        put_code(&mut ms, 0x80004000, &[0, 0, 0, INST_JR_RA, 0]);
+        put_code(&mut ms, 0x80010000 + 28, &[0x80005010]);
+
+        let mut prof = Profiler::new(0);
+        ms.reg.r[REG_SP] = 0x80010000;
+        ms.reg.r[REG_RA] = 0x80003018;
+        for pc in [0x80004008, 0x80004008, 0x8000400c] {
+            ms.reg.pc = pc;
+            prof.on_tick(&mut ms);
+        }
+        // $ra of the innermost function is used before it is saved
+        ms.reg.r[REG_RA] = 0x80005010;
+        ms.reg.pc = 0x80003000;
+        prof.on_tick(&mut ms);
+        ms.reg.r[REG_RA] = 0;
+        ms.reg.pc = 0x00400000;
+        prof.on_tick(&mut ms);
+
+        assert_eq!(report(&prof, &ms.symbols, true), concat!(
+            "0x00400000 1\n",
+            "main;outer 1\n",
+            "main;outer;inner 3\n",
+        ));
+    }
+}
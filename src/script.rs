@@ -0,0 +1,403 @@
+use crate::procstate::MachineState;
+use crate::dev_uart::UartReadWrite;
+use crate::config;
+use crate::cp0;
+use log::{info, error};
+use regex::bytes::Regex;
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+/*
+Expect-style automation of a serial console.
+
+A script is a text file with one command per line ('#' starts a comment line):
+  expect REGEX        : waits until the console output matches REGEX
+  send TEXT           : sends TEXT (escape sequences \n, \r, \t, \\ and \xHH are available)
+  sendline TEXT       : sends TEXT and the Enter key (CR)
+  fail REGEX          : the script fails when the console output matches REGEX afterwards (e.g., "Kernel panic")
+  timeout SECS [emu]  : timeout of the following expect commands in host time (default: 60 seconds)
+                        or in emulated time with "emu"
+  sleep SECS [emu]    : waits for SECS seconds in host time or in emulated time
+  exit CODE           : stops the emulator with the exit status CODE
+
+The emulation continues after the last command unless the script exits.
+When an expect command times out or a fail pattern matches, the emulator stops with SCRIPT_FAILURE_EXIT_CODE.
+*/
+
+pub const SCRIPT_FAILURE_EXIT_CODE : i32 = 3;
+
+const DEFAULT_TIMEOUT : Duration = Duration::from_secs(60);
+
+// the part of the output checked again with new output so that a pattern spanning them matches
+const SCAN_OVERLAP   : usize = 4096;
+// maximum size of the output kept for matching
+const MAX_OUTPUT     : usize = 1024*1024;
+
+// number of counter cycles per usec
+const COUNT_PER_USEC : u64 = (config::FREQ_CPU/(config::CPU_FREQ_COUNT_RESOLUTION*1000*1000)) as u64;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Clock {
+    Host,       // time of the host
+    Emulated,   // time of the guest (CP0 counter)
+}
+
+// commands of a script for the library API
+#[derive(Clone, Debug)]
+pub enum Step {
+    Expect(String),
+    Fail(String),
+    Send(Vec<u8>),
+    Timeout(Duration, Clock),
+    Sleep(Duration, Clock),
+    Exit(i32),
+}
+
+enum Op {
+    Expect(Regex),
+    Fail(Regex),
+    Send(Vec<u8>),
+    Timeout(Duration, Clock),
+    Sleep(Duration, Clock),
+    Exit(i32),
+}
+
+pub enum ScriptStatus {
+    Running,
+    Done,       // all the commands are executed
+    Exit(i32),  // exit command, or failure with SCRIPT_FAILURE_EXIT_CODE
+}
+
+// console data shared between the script and the UART backend
+struct ScriptIo {
+    output : Vec<u8>,
+    input  : VecDeque<u8>,
+}
+
+pub struct Script {
+    ops      : Vec<(usize, Op)>, // line number (or step number) and the command
+    pos      : usize,
+    io       : Rc<RefCell<ScriptIo>>,
+    scanned  : usize,            // size of the output already checked
+    timeout  : (Duration, Clock),
+    deadline : Option<(u64, Clock)>,
+    fails    : Vec<Regex>,
+    start    : Instant,
+}
+
+// UART backend which passes the console to the script and to the original backend
+pub struct ScriptConsole {
+    io    : Rc<RefCell<ScriptIo>>,
+    inner : Box<dyn UartReadWrite>,
+}
+
+impl UartReadWrite for ScriptConsole {
+    fn read(&mut self)   -> Result<u8,()> {
+        match self.io.borrow_mut().input.pop_front() {
+            Some(d) => Ok(d),
+            None    => self.inner.read(),
+        }
+    }
+    fn write(&mut self, data : char)  -> Result<(),()> {
+        self.io.borrow_mut().output.push(data as u8);
+        self.inner.write(data)
+    }
+}
+
+fn compile(pattern: &str) -> Result<Regex, String> {
+    Regex::new(pattern).map_err(|e| format!("incorrect regular expression \"{}\" : {}", pattern, e))
+}
+
+// converts escape sequences in a text to send
+fn unescape(s: &str) -> Result<Vec<u8>, String> {
+    let mut v = Vec::new();
+    let mut chars = s.bytes();
+    while let Some(c) = chars.next() {
+        if c != b'\\' {
+            v.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some(b'n')  => v.push(b'\n'),
+            Some(b'r')  => v.push(b'\r'),
+            Some(b't')  => v.push(b'\t'),
+            Some(b'\\') => v.push(b'\\'),
+            Some(b'x')  => {
+                let h : Vec<u8> = chars.by_ref().take(2).collect();
+                let d = std::str::from_utf8(&h).ok().and_then(|h| u8::from_str_radix(h, 16).ok());
+                v.push(d.ok_or(format!("incorrect escape sequence in \"{}\"", s))?);
+            }
+            _ => { return Err(format!("incorrect escape sequence in \"{}\"", s)); }
+        }
+    }
+    Ok(v)
+}
+
+fn parse_time(arg: &str) -> Result<(Duration, Clock), String> {
+    let mut it = arg.split_whitespace();
+    let secs = it.next().and_then(|s| s.parse::<f64>().ok()).filter(|s| *s >= 0.0).ok_or(format!("incorrect time \"{}\"", arg))?;
+    let clock = match it.next() {
+        None        => Clock::Host,
+        Some("emu") => Clock::Emulated,
+        Some(c)     => { return Err(format!("unknown clock \"{}\"", c)); }
+    };
+    Ok((Duration::from_secs_f64(secs), clock))
+}
+
+impl Script {
+    pub fn new(steps: Vec<Step>) -> Result<Self, String> {
+        let mut ops = Vec::new();
+        for (i, step) in steps.into_iter().enumerate() {
+            ops.push((i+1, match step {
+                Step::Expect(p)     => Op::Expect(compile(&p)?),
+                Step::Fail(p)       => Op::Fail(compile(&p)?),
+                Step::Send(d)       => Op::Send(d),
+                Step::Timeout(d, c) => Op::Timeout(d, c),
+                Step::Sleep(d, c)   => Op::Sleep(d, c),
+                Step::Exit(code)    => Op::Exit(code),
+            }));
+        }
+        Ok(Self::with_ops(ops))
+    }
+
+    // parses a script file
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut ops = Vec::new();
+        for (i, line) in text.lines().enumerate() {
+            let line_trimmed = line.trim_start();
+            if line_trimmed.is_empty() || line_trimmed.starts_with('#') {
+                continue;
+            }
+            let (cmd, arg) = line_trimmed.split_once(' ').unwrap_or((line_trimmed, ""));
+            let op = Self::parse_command(cmd, arg).map_err(|e| format!("line {}: {}", i+1, e))?;
+            ops.push((i+1, op));
+        }
+        Ok(Self::with_ops(ops))
+    }
+
+    fn parse_command(cmd: &str, arg: &str) -> Result<Op, String> {
+        Ok(match cmd {
+            "expect"   => Op::Expect(compile(arg)?),
+            "fail"     => Op::Fail(compile(arg)?),
+            "send"     => Op::Send(unescape(arg)?),
+            "sendline" => { let mut d = unescape(arg)?; d.push(b'\r'); Op::Send(d) }
+            "timeout"  => { let (d, c) = parse_time(arg)?; Op::Timeout(d, c) }
+            "sleep"    => { let (d, c) = parse_time(arg)?; Op::Sleep(d, c) }
+            "exit"     => Op::Exit(arg.trim().parse::<i32>().map_err(|_| format!("incorrect exit code \"{}\"", arg))?),
+            _          => { return Err(format!("unknown command \"{}\"", cmd)); }
+        })
+    }
+
+    fn with_ops(ops: Vec<(usize, Op)>) -> Self {
+        Self {
+            ops,
+            pos: 0,
+            io: Rc::new(RefCell::new(ScriptIo { output: Vec::new(), input: VecDeque::new() })),
+            scanned: 0,
+            timeout: (DEFAULT_TIMEOUT, Clock::Host),
+            deadline: None,
+            fails: Vec::new(),
+            start: Instant::now(),
+        }
+    }
+
+    // makes a UART backend for the script which also passes the console to inner
+    pub fn console(&self, inner: Box<dyn UartReadWrite>) -> ScriptConsole {
+        ScriptConsole { io: Rc::clone(&self.io), inner }
+    }
+
+    fn now(start: Instant, ms: &mut MachineState, clock: Clock) -> u64 {
+        match clock {
+            Clock::Host     => start.elapsed().as_micros() as u64,
+            Clock::Emulated => cp0::load_counter_long(ms) / COUNT_PER_USEC,
+        }
+    }
+
+    // checks the deadline, which is set at the first check
+    fn expired(deadline: &mut Option<(u64, Clock)>, start: Instant, ms: &mut MachineState, d: Duration, clock: Clock) -> bool {
+        let now = Self::now(start, ms, clock);
+        match *deadline {
+            Some((t, c)) => now >= t && c == clock,
+            None => {
+                *deadline = Some((now + d.as_micros() as u64, clock));
+                false
+            }
+        }
+    }
+
+    fn failure(&self, line: usize, msg: String) -> ScriptStatus {
+        error!("Script: line {}: {}\r", line, msg);
+        ScriptStatus::Exit(SCRIPT_FAILURE_EXIT_CODE)
+    }
+
+    // proceeds the script with the console output so far
+    pub fn poll(&mut self, ms: &mut MachineState) -> ScriptStatus {
+        let io = Rc::clone(&self.io);
+        let mut io = io.borrow_mut();
+
+        // checks the fail patterns with new output
+        let new_output = self.scanned != io.output.len();
+        if new_output {
+            let from = self.scanned.saturating_sub(SCAN_OVERLAP);
+            if let Some(re) = self.fails.iter().find(|re| re.is_match(&io.output[from..])) {
+                let line = self.ops.get(self.pos.saturating_sub(1)).map(|(l, _)| *l).unwrap_or(0);
+                return self.failure(line, format!("the output matched the fail pattern \"{}\"", re.as_str()));
+            }
+        }
+
+        while let Some((line, op)) = self.ops.get(self.pos) {
+            let line = *line;
+            match op {
+                Op::Expect(re) => {
+                    // Output before the previous match is not checked again
+                    let from = self.scanned.saturating_sub(SCAN_OVERLAP);
+                    let m = if new_output || self.deadline.is_none() { re.find(&io.output[from..]).map(|m| from + m.end()) }else{ None };
+                    match m {
+                        Some(end) => {
+                            io.output.drain(..end);
+                            self.scanned  = 0;
+                            self.deadline = None;
+                            self.pos += 1;
+                            continue;
+                        }
+                        None => {
+                            let (d, c) = self.timeout;
+                            if Self::expired(&mut self.deadline, self.start, ms, d, c) {
+                                return self.failure(line, format!("timeout while waiting for \"{}\"", re.as_str()));
+                            }
+                            break;
+                        }
+                    }
+                }
+                Op::Fail(re) => { self.fails.push(re.clone()); }
+                Op::Send(d)  => { io.input.extend(d.iter()); }
+                Op::Timeout(d, c) => { self.timeout = (*d, *c); }
+                Op::Sleep(d, c) => {
+                    let (d, c) = (*d, *c);
+                    if !Self::expired(&mut self.deadline, self.start, ms, d, c) {
+                        break;
+                    }
+                    self.deadline = None;
+                }
+                Op::Exit(code) => {
+                    info!("Script: exit {}\r", code);
+                    return ScriptStatus::Exit(*code);
+                }
+            }
+            self.pos += 1;
+        }
+
+        let len = io.output.len();
+        if len > MAX_OUTPUT {
+            io.output.drain(..len - MAX_OUTPUT);
+        }
+        self.scanned = io.output.len();
+
+        if self.pos >= self.ops.len() { ScriptStatus::Done }else{ ScriptStatus::Running }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dev_uart::NullUART;
+    use crate::dev_spiflash::SPI_FLASH_PARAM_S25FL164K;
+    use crate::exrmips::generate_machine_state;
+
+    fn write_str(con: &mut ScriptConsole, s: &str) {
+        for c in s.chars() { con.write(c).unwrap(); }
+    }
+
+    fn read_all(con: &mut ScriptConsole) -> Vec<u8> {
+        std::iter::from_fn(|| con.read().ok()).collect()
+    }
+
+    #[test]
+    fn escape_sequences() {
+        assert_eq!(unescape("root"), Ok(b"root".to_vec()));
+        assert_eq!(unescape(r"a\n\r\t\\b"), Ok(b"a\n\r\t\\b".to_vec()));
+        assert_eq!(unescape(r"\x03\x7f\xFF"), Ok(vec![0x03, 0x7f, 0xff]));
+        assert!(unescape(r"\q").is_err());
+        assert!(unescape("abc\\").is_err());
+        assert!(unescape(r"\x").is_err());
+        assert_eq!(unescape(r"a\x4"), Ok(vec![b'a', 0x04]));
+        assert!(unescape(r"\xzz").is_err());
+    }
+
+    #[test]
+    fn times() {
+        assert_eq!(parse_time("10"), Ok((Duration::from_secs(10), Clock::Host)));
+        assert_eq!(parse_time(" 0.5 emu"), Ok((Duration::from_millis(500), Clock::Emulated)));
+        assert!(parse_time("").is_err());
+        assert!(parse_time("-1").is_err());
+        assert!(parse_time("1 guest").is_err());
+    }
+
+    #[test]
+    fn commands() {
+        let s = Script::parse("# login\n\nexpect login:\n  sendline root\\tx\nsend \\x03\ntimeout 5 emu\nsleep 1\nfail Kernel panic\nexit 2\n").unwrap();
+        let lines : Vec<usize> = s.ops.iter().map(|(l, _)| *l).collect();
+        assert_eq!(lines, vec![3, 4, 5, 6, 7, 8, 9]);
+        assert!(matches!(&s.ops[0].1, Op::Expect(re) if re.as_str() == "login:"));
+        assert!(matches!(&s.ops[1].1, Op::Send(d) if d == b"root\tx\r"));
+        assert!(matches!(&s.ops[2].1, Op::Send(d) if d == &[0x03]));
+        assert!(matches!(s.ops[3].1, Op::Timeout(d, Clock::Emulated) if d == Duration::from_secs(5)));
+        assert!(matches!(s.ops[4].1, Op::Sleep(d, Clock::Host) if d == Duration::from_secs(1)));
+        assert!(matches!(&s.ops[5].1, Op::Fail(re) if re.as_str() == "Kernel panic"));
+        assert!(matches!(s.ops[6].1, Op::Exit(2)));
+
+        assert_eq!(Script::parse("expect a\nwait 1\n").err(), Some("line 2: unknown command \"wait\"".to_string()));
+        assert!(Script::parse("expect (").err().is_some_and(|e| e.starts_with("line 1: incorrect regular expression")));
+        assert!(Script::parse("exit x").is_err());
+        assert!(Script::parse("send \\").is_err());
+        assert!(Script::new(vec![Step::Fail("[".to_string())]).is_err());
+    }
+
+    #[test]
+    fn console_interaction() {
+        let mut ms = generate_machine_state(&SPI_FLASH_PARAM_S25FL164K, &[]);
+        let mut s = Script::parse("expect login:\nsendline root\nexpect # $\nsend ls\\n\nexit 0\n").unwrap();
+        let mut con = s.console(Box::new(NullUART{}));
+
+        assert!(matches!(s.poll(&mut ms), ScriptStatus::Running));
+        write_str(&mut con, "OpenWrt log");
+        assert!(matches!(s.poll(&mut ms), ScriptStatus::Running));
+        assert!(read_all(&mut con).is_empty());
+
+        // a pattern spanning two chunks of output matches
+        write_str(&mut con, "in: ");
+        assert!(matches!(s.poll(&mut ms), ScriptStatus::Running));
+        assert_eq!(read_all(&mut con), b"root\r");
+
+        write_str(&mut con, "root@OpenWrt:~# ");
+        assert!(matches!(s.poll(&mut ms), ScriptStatus::Exit(0)));
+        assert_eq!(read_all(&mut con), b"ls\n");
+    }
+
+    #[test]
+    fn fail_pattern() {
+        let mut ms = generate_machine_state(&SPI_FLASH_PARAM_S25FL164K, &[]);
+        let mut s = Script::new(vec![Step::Fail("Kernel panic".to_string()), Step::Expect("never".to_string())]).unwrap();
+        let mut con = s.console(Box::new(NullUART{}));
+
+        assert!(matches!(s.poll(&mut ms), ScriptStatus::Running));
+        write_str(&mut con, "Kernel pa");
+        assert!(matches!(s.poll(&mut ms), ScriptStatus::Running));
+        write_str(&mut con, "nic - not syncing");
+        assert!(matches!(s.poll(&mut ms), ScriptStatus::Exit(SCRIPT_FAILURE_EXIT_CODE)));
+    }
+
+    #[test]
+    fn script_without_exit_is_done() {
+        let mut ms = generate_machine_state(&SPI_FLASH_PARAM_S25FL164K, &[]);
+        let mut s = Script::new(vec![Step::Send(b"x".to_vec()), Step::Timeout(Duration::ZERO, Clock::Host)]).unwrap();
+        assert!(matches!(s.poll(&mut ms), ScriptStatus::Done));
+
+        let mut s = Script::new(vec![Step::Timeout(Duration::ZERO, Clock::Host), Step::Expect("never".to_string())]).unwrap();
+        assert!(matches!(s.poll(&mut ms), ScriptStatus::Running));
+        assert!(matches!(s.poll(&mut ms), ScriptStatus::Exit(SCRIPT_FAILURE_EXIT_CODE)));
+    }
+}
@@ -0,0 +1,470 @@
+use crate::procstate::MachineState;
+use crate::config;
+use crate::cp0;
+use crate::watch;
+use crate::perf;
+use crate::dev_gmac;
+use crate::dev_uart::IoUART;
+use log::info;
+
+/*
+Snapshot of the whole machine state.
+
+A snapshot consists of a header and tagged sections (all values in big-endian):
+  "EXRMSNAP", version (u32),
+  then for each section: tag (4 bytes), length (u32) and the contents.
+
+  "REG " : GPRs, pc, delay slot state, hi, lo, CP0 counter, compare and CP0 registers
+  "DRAM" : DRAM contents
+  "TLB " : EntryHi, EntryLo0, EntryLo1 and PageMask of the TLB entries
+  "FPU " : floating point registers (64 bits each) and FCSR
+  "DSP " : HI and LO of the accumulators ac1..ac3 and DSPControl
+  "SRS " : GPRs of the shadow register sets (the current set is in "REG ")
+  "UARn" : registers of the UART n
+  "GPIO" : GPIO registers
+  "MISC" : interrupt mask of the misc. registers
+  "SPI " : SPI controller registers
+  "GMCn" : registers of the Ethernet MAC n and its MDIO devices (the descriptor rings are in DRAM)
+  "SPWn" : state of the SPI device n (e.g., the flash memory and its contents)
+
+The counter of CP0 is saved as a cycle count and it continues from that value after loading.
+The address translation caches, the TLB lookup cache, the watchpoint enables
+and the events of the performance counters are rebuilt on load.
+*/
+
+const SNAPSHOT_MAGIC   : &[u8; 8] = b"EXRMSNAP";
+const SNAPSHOT_VERSION : u32      = 1;
+
+// number of counter cycles per usec
+const COUNT_PER_USEC : u64 = (config::FREQ_CPU/(config::CPU_FREQ_COUNT_RESOLUTION*1000*1000)) as u64;
+
+// serializer of snapshot data
+pub struct Writer {
+    pub buf : Vec<u8>,
+}
+
+impl Writer {
+    pub fn new() -> Self { Self { buf: Vec::new() } }
+
+    pub fn u8(&mut self, v: u8)     { self.buf.push(v); }
+    pub fn bool(&mut self, v: bool) { self.buf.push(v as u8); }
+    pub fn u32(&mut self, v: u32)   { self.buf.extend_from_slice(&v.to_be_bytes()); }
+    pub fn u64(&mut self, v: u64)   { self.buf.extend_from_slice(&v.to_be_bytes()); }
+    pub fn bytes(&mut self, v: &[u8]) {
+        self.u32(v.len() as u32);
+        self.buf.extend_from_slice(v);
+    }
+
+    // writes a section with the contents generated by f
+    fn section(&mut self, tag: &[u8; 4], f: impl FnOnce(&mut Writer)) {
+        let mut w = Writer::new();
+        f(&mut w);
+        self.buf.extend_from_slice(tag);
+        self.bytes(&w.buf);
+    }
+}
+
+// deserializer of snapshot data
+pub struct Reader<'a> {
+    data : &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(data: &'a [u8]) -> Self { Self { data } }
+
+    pub fn is_empty(&self) -> bool { self.data.is_empty() }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8], String> {
+        if self.data.len() < n {
+            return Err("truncated snapshot".to_string());
+        }
+        let (head, rest) = self.data.split_at(n);
+        self.data = rest;
+        Ok(head)
+    }
+
+    pub fn u8(&mut self)   -> Result<u8, String>   { Ok(self.take(1)?[0]) }
+    pub fn bool(&mut self) -> Result<bool, String> { Ok(self.u8()? != 0) }
+    pub fn u32(&mut self)  -> Result<u32, String>  { Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap())) }
+    pub fn u64(&mut self)  -> Result<u64, String>  { Ok(u64::from_be_bytes(self.take(8)?.try_into().unwrap())) }
+    pub fn bytes(&mut self) -> Result<&'a [u8], String> {
+        let n = self.u32()? as usize;
+        self.take(n)
+    }
+
+    // reads bytes into dst, which must have the same length as the saved data
+    pub fn bytes_into(&mut self, dst: &mut [u8], what: &str) -> Result<(), String> {
+        let src = self.bytes()?;
+        if src.len() != dst.len() {
+            return Err(format!("size of {} differs (0x{:x} bytes in the snapshot, 0x{:x} bytes in the machine)", what, src.len(), dst.len()));
+        }
+        dst.copy_from_slice(src);
+        Ok(())
+    }
+}
+
+fn save_reg(ms: &mut MachineState, w: &mut Writer) {
+    for r in ms.reg.r.iter() { w.u32(*r); }
+    w.u32(ms.reg.pc);
+    w.u32(ms.reg.pc_delay);
+    w.u32(ms.reg.pc_prev_jump);
+    w.u32(ms.reg.hi);
+    w.u32(ms.reg.lo);
+    w.bool(ms.reg.delay_en);
+    w.bool(ms.reg.ll_sc);
+    w.u64(cp0::load_counter_long(ms));
+    w.u64(ms.reg.c0_compare_long);
+    w.u32(ms.reg.cp0.len() as u32);
+    for c in ms.reg.cp0.iter() { w.u32(*c); }
+}
+
+fn load_reg(ms: &mut MachineState, r: &mut Reader) -> Result<(), String> {
+    for i in 0..32 { ms.reg.r[i] = r.u32()?; }
+    ms.reg.pc           = r.u32()?;
+    ms.reg.pc_delay     = r.u32()?;
+    ms.reg.pc_prev_jump = r.u32()?;
+    ms.reg.hi           = r.u32()?;
+    ms.reg.lo           = r.u32()?;
+    ms.reg.delay_en     = r.bool()?;
+    ms.reg.ll_sc        = r.bool()?;
+
+    // The counter is rebased so that it continues from the saved value
+    let count = r.u64()?;
+    ms.reg.c0_count_basetime       = 0;
+    ms.reg.c0_count_currenttime    = count / COUNT_PER_USEC;
+    ms.reg.c0_count_ninst_in_ctime = 0;
+    ms.emu.nexec_insts             = 0;
+    ms.reg.c0_compare_long         = r.u64()?;
+
+    let n = r.u32()? as usize;
+    if n != ms.reg.cp0.len() {
+        return Err(format!("number of CP0 registers differs ({} in the snapshot)", n));
+    }
+    for i in 0..n { ms.reg.cp0[i] = r.u32()?; }
+    Ok(())
+}
+
+fn save_tlb(ms: &MachineState, w: &mut Writer) {
+    w.u32(ms.tlb.len() as u32);
+    for e in ms.tlb.iter() {
+        w.u32(e.entryhi);
+        w.u32(e.entrylo0);
+        w.u32(e.entrylo1);
+        w.u32(e.field_pmask);
+    }
+}
+
+fn load_tlb(ms: &mut MachineState, r: &mut Reader) -> Result<(), String> {
+    let n = r.u32()? as usize;
+    if n != ms.tlb.len() {
+        return Err(format!("number of TLB entries differs ({} in the snapshot)", n));
+    }
+    for i in 0..n {
+        let (hi, lo0, lo1, pmask) = (r.u32()?, r.u32()?, r.u32()?, r.u32()?);
+        ms.tlb[i].set(hi, lo0, lo1, pmask);
+    }
+    Ok(())
+}
+
+fn save_uart(u: &IoUART, w: &mut Writer) {
+    w.bool(u.buffered);
+    w.u8(u.int_enable);
+    w.u8(u.int_ident);
+    w.u8(u.line_control);
+    w.u8(u.modem_control);
+    w.u8(u.divisor[0]);
+    w.u8(u.divisor[1]);
+    w.u8(u.buf);
+    w.u8(u.scratch);
+    w.bool(u.break_request);
+}
+
+fn load_uart(u: &mut IoUART, r: &mut Reader) -> Result<(), String> {
+    u.buffered      = r.bool()?;
+    u.int_enable    = r.u8()?;
+    u.int_ident     = r.u8()?;
+    u.line_control  = r.u8()?;
+    u.modem_control = r.u8()?;
+    u.divisor[0]    = r.u8()?;
+    u.divisor[1]    = r.u8()?;
+    u.buf           = r.u8()?;
+    u.scratch       = r.u8()?;
+    u.break_request = r.bool()?;
+    Ok(())
+}
+
+fn save_spi(ms: &MachineState, w: &mut Writer) {
+    let s = &ms.spi;
+    for v in [s.function_select, s.control, s.io_control, s.read_data_addr, s.shift_dataout, s.shift_count, s.shift_datain] {
+        w.u32(v);
+    }
+}
+
+fn load_spi(ms: &mut MachineState, r: &mut Reader) -> Result<(), String> {
+    let s = &mut ms.spi;
+    s.function_select = r.u32()?;
+    s.control         = r.u32()?;
+    s.io_control      = r.u32()?;
+    s.read_data_addr  = r.u32()?;
+    s.shift_dataout   = r.u32()?;
+    s.shift_count     = r.u32()?;
+    s.shift_datain    = r.u32()?;
+    Ok(())
+}
+
+fn save_fpu(ms: &MachineState, w: &mut Writer) {
+    for v in ms.reg.fpu.fpr.iter() {
+        w.u64(*v);
+    }
+    w.u32(ms.reg.fpu.fcsr);
+}
+
+fn load_fpu(ms: &mut MachineState, r: &mut Reader) -> Result<(), String> {
+    for v in ms.reg.fpu.fpr.iter_mut() {
+        *v = r.u64()?;
+    }
+    ms.reg.fpu.fcsr = r.u32()?;
+    Ok(())
+}
+
+fn save_dsp(ms: &MachineState, w: &mut Writer) {
+    for i in 0..3 {
+        w.u32(ms.reg.ac_hi[i]);
+        w.u32(ms.reg.ac_lo[i]);
+    }
+    w.u32(ms.reg.dspcontrol);
+}
+
+fn load_dsp(ms: &mut MachineState, r: &mut Reader) -> Result<(), String> {
+    for i in 0..3 {
+        ms.reg.ac_hi[i] = r.u32()?;
+        ms.reg.ac_lo[i] = r.u32()?;
+    }
+    ms.reg.dspcontrol = r.u32()?;
+    Ok(())
+}
+
+fn save_srs(ms: &MachineState, w: &mut Writer) {
+    w.u32(ms.reg.srs.len() as u32);
+    for v in ms.reg.srs.iter().flatten() {
+        w.u32(*v);
+    }
+}
+
+fn load_srs(ms: &mut MachineState, r: &mut Reader) -> Result<(), String> {
+    let n = r.u32()? as usize;
+    if n != ms.reg.srs.len() {
+        return Err(format!("number of shadow register sets differs ({} in the snapshot)", n));
+    }
+    for v in ms.reg.srs.iter_mut().flatten() {
+        *v = r.u32()?;
+    }
+    Ok(())
+}
+
+// serializes the machine state
+pub fn save(ms: &mut MachineState) -> Vec<u8> {
+    let mut w = Writer::new();
+    w.buf.extend_from_slice(SNAPSHOT_MAGIC);
+    w.u32(SNAPSHOT_VERSION);
+
+    let mut reg = Writer::new();
+    save_reg(ms, &mut reg);
+    w.section(b"REG ", |w| w.buf.extend_from_slice(&reg.buf));
+    w.section(b"DRAM", |w| w.bytes(&ms.mem.mem0));
+    w.section(b"TLB ", |w| save_tlb(ms, w));
+    w.section(b"FPU ", |w| save_fpu(ms, w));
+    w.section(b"DSP ", |w| save_dsp(ms, w));
+    w.section(b"SRS ", |w| save_srs(ms, w));
+    for (i, uart) in ms.uart.iter().enumerate() {
+        w.section(&[b'U', b'A', b'R', b'0' + i as u8], |w| save_uart(uart, w));
+    }
+    w.section(b"GPIO", |w| { w.u32(ms.gpio.oe); w.u32(ms.gpio.out); });
+    w.section(b"MISC", |w| w.u32(ms.misc.int_mask));
+    w.section(b"SPI ", |w| save_spi(ms, w));
+    for (i, gmac) in ms.gmac.iter().enumerate() {
+        w.section(&[b'G', b'M', b'C', b'0' + i as u8], |w| dev_gmac::save_state(gmac, w));
+    }
+    for (i, worker) in ms.spi.workers.iter().enumerate() {
+        w.section(&[b'S', b'P', b'W', b'0' + i as u8], |w| worker.save_state(w));
+    }
+    w.buf
+}
+
+// restores the machine state from snapshot data
+pub fn load(ms: &mut MachineState, data: &[u8]) -> Result<(), String> {
+    let mut r = Reader::new(data);
+    if r.take(SNAPSHOT_MAGIC.len()).ok() != Some(SNAPSHOT_MAGIC.as_slice()) {
+        return Err("not a snapshot file".to_string());
+    }
+    let version = r.u32()?;
+    if version != SNAPSHOT_VERSION {
+        return Err(format!("snapshot version {} is not supported", version));
+    }
+
+    while !r.is_empty() {
+        let tag = r.take(4)?;
+        let mut s = Reader::new(r.bytes()?);
+        match tag {
+            b"REG " => load_reg(ms, &mut s)?,
+            b"DRAM" => s.bytes_into(&mut ms.mem.mem0, "DRAM")?,
+            b"TLB " => load_tlb(ms, &mut s)?,
+            b"FPU " => load_fpu(ms, &mut s)?,
+            b"DSP " => load_dsp(ms, &mut s)?,
+            b"SRS " => load_srs(ms, &mut s)?,
+            b"GPIO" => { ms.gpio.oe = s.u32()?; ms.gpio.out = s.u32()?; }
+            b"MISC" => { ms.misc.int_mask = s.u32()?; }
+            b"SPI " => load_spi(ms, &mut s)?,
+            [b'U', b'A', b'R', n] if (b'0'..b'0' + ms.uart.len() as u8).contains(n) => {
+                load_uart(&mut ms.uart[(n - b'0') as usize], &mut s)?;
+            }
+            [b'G', b'M', b'C', n] if (b'0'..b'0' + ms.gmac.len() as u8).contains(n) => {
+                dev_gmac::load_state(&mut ms.gmac[(n - b'0') as usize], &mut s)?;
+            }
+            [b'S', b'P', b'W', n] if (b'0'..b'0' + ms.spi.workers.len() as u8).contains(n) => {
+                ms.spi.workers[(n - b'0') as usize].load_state(&mut s)?;
+            }
+            _ => { return Err(format!("unknown section \"{}\"", String::from_utf8_lossy(tag))); }
+        }
+    }
+
+    // The caches are rebuilt from the restored state
+    ms.tlbcache.fill(config::NUM_TLB_ENTRY as u8);
+    ms.reg.pc_cache.clear();
+    ms.reg.dr_cache[0].clear();
+    ms.reg.dr_cache[1].clear();
+    ms.reg.dw_cache[0].clear();
+    ms.reg.dw_cache[1].clear();
+    watch::update(ms);
+    perf::update(ms);
+    ms.misc.reset_request = false;
+
+    info!("Snapshot is loaded : pc 0x{:>08x}", ms.reg.pc);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cp0def;
+    use crate::mips;
+    use crate::c0_val;
+    use crate::dev_spiflash::SPI_FLASH_PARAM_S25FL164K;
+    use crate::exrmips::generate_machine_state;
+
+    fn machine() -> MachineState {
+        generate_machine_state(&SPI_FLASH_PARAM_S25FL164K, &[0x12, 0x34, 0x56, 0x78])
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut ms = machine();
+        for i in 1..32 { ms.reg.r[i] = 0x01010101 * i as u32; }
+        ms.reg.pc = 0x80001234;
+        ms.reg.hi = 0xdeadbeef;
+        ms.reg.lo = 0xcafef00d;
+        ms.reg.c0_count_currenttime = 1000;
+        ms.reg.c0_compare_long = 0x123456789;
+        c0_val!(ms.reg, cp0def::C0_EPC)      = 0x80000400;
+        c0_val!(ms.reg, cp0def::C0_PERFCTL0) = 1<<5 | 1<<1;
+        ms.mem.mem0[0x100..0x104].copy_from_slice(&[1, 2, 3, 4]);
+        ms.tlb[3].set(0x00402000, 0x00001017, 0x00001057, 0x1800);
+        ms.reg.fpu.fpr[5] = 0x400921fb54442d18;
+        ms.reg.fpu.fcsr   = 0x01000003;
+        ms.reg.ac_hi[1]   = 0xffffffff;
+        ms.reg.ac_lo[2]   = 0x55aa55aa;
+        ms.reg.dspcontrol = 0x00ff2020;
+        ms.reg.srs[2][29] = 0x8000fff0;
+        ms.uart[0].scratch = 0x5a;
+        ms.gpio.out       = 0x0000a5a5;
+        ms.misc.int_mask  = 0x00000022;
+        ms.spi.control    = 0x243;
+
+        let data = save(&mut ms);
+        let mut restored = machine();
+        load(&mut restored, &data).unwrap();
+
+        assert_eq!(restored.reg.r, ms.reg.r);
+        assert_eq!(restored.reg.pc, 0x80001234);
+        assert_eq!((restored.reg.hi, restored.reg.lo), (0xdeadbeef, 0xcafef00d));
+        assert_eq!(cp0::load_counter_long(&mut restored), cp0::load_counter_long(&mut ms));
+        assert_eq!(restored.reg.c0_compare_long, 0x123456789);
+        assert_eq!(restored.reg.cp0, ms.reg.cp0);
+        assert_eq!(&restored.mem.mem0[0x100..0x104], &[1, 2, 3, 4]);
+        assert_eq!((restored.tlb[3].entryhi, restored.tlb[3].entrylo0, restored.tlb[3].entrylo1), (0x00402000, 0x00001017, 0x00001057));
+        assert_eq!(restored.reg.fpu.fpr, ms.reg.fpu.fpr);
+        assert_eq!(restored.reg.fpu.fcsr, 0x01000003);
+        assert_eq!((restored.reg.ac_hi, restored.reg.ac_lo), (ms.reg.ac_hi, ms.reg.ac_lo));
+        assert_eq!(restored.reg.dspcontrol, 0x00ff2020);
+        assert_eq!(restored.reg.srs, ms.reg.srs);
+        assert_eq!(restored.uart[0].scratch, 0x5a);
+        assert_eq!(restored.gpio.out, 0x0000a5a5);
+        assert_eq!(restored.misc.int_mask, 0x00000022);
+        assert_eq!(restored.spi.control, 0x243);
+
+        // the events of the performance counters are rebuilt
+        assert_eq!(restored.reg.perf_en, 1<<perf::EV_INSTS);
+
+        // the restored machine is saved as the same snapshot
+        assert!(save(&mut restored) == data);
+    }
+
+    #[test]
+    fn flash_contents_are_restored() {
+        let mut ms = machine();
+        let data = save(&mut ms);
+        let mut restored = generate_machine_state(&SPI_FLASH_PARAM_S25FL164K, &[]);
+        load(&mut restored, &data).unwrap();
+        assert_eq!(&restored.spi.workers[0].image().unwrap()[0..4], &[0x12, 0x34, 0x56, 0x78]);
+    }
+
+    #[test]
+    fn broken_snapshots_are_rejected() {
+        let mut ms = machine();
+        let data = save(&mut ms);
+
+        assert_eq!(load(&mut ms, b"NOTASNAP\0\0\0\x01"), Err("not a snapshot file".to_string()));
+        assert_eq!(load(&mut ms, b"EXRM"), Err("not a snapshot file".to_string()));
+
+        let mut w = Writer::new();
+        w.buf.extend_from_slice(SNAPSHOT_MAGIC);
+        w.u32(SNAPSHOT_VERSION + 1);
+        assert_eq!(load(&mut ms, &w.buf), Err(format!("snapshot version {} is not supported", SNAPSHOT_VERSION + 1)));
+
+        assert_eq!(load(&mut ms, &data[..data.len() - 1]), Err("truncated snapshot".to_string()));
+
+        let mut w = Writer::new();
+        w.buf.extend_from_slice(SNAPSHOT_MAGIC);
+        w.u32(SNAPSHOT_VERSION);
+        w.section(b"XYZ ", |w| w.u32(0));
+        assert_eq!(load(&mut ms, &w.buf), Err("unknown section \"XYZ \"".to_string()));
+
+        let mut w = Writer::new();
+        w.buf.extend_from_slice(SNAPSHOT_MAGIC);
+        w.u32(SNAPSHOT_VERSION);
+        w.section(b"DRAM", |w| w.bytes(&[0; 16]));
+        assert!(load(&mut ms, &w.buf).unwrap_err().starts_with("size of DRAM differs"));
+    }
+
+    #[test]
+    fn reader_and_writer() {
+        let mut w = Writer::new();
+        w.u8(0xa5);
+        w.bool(true);
+        w.u32(0x12345678);
+        w.u64(0x0123456789abcdef);
+        w.bytes(b"abc");
+        assert_eq!(&w.buf[0..6], &[0xa5, 1, 0x12, 0x34, 0x56, 0x78]);
+
+        let mut r = Reader::new(&w.buf);
+        assert_eq!(r.u8(), Ok(0xa5));
+        assert_eq!(r.bool(), Ok(true));
+        assert_eq!(r.u32(), Ok(0x12345678));
+        assert_eq!(r.u64(), Ok(0x0123456789abcdef));
+        let mut dst = [0; 3];
+        r.bytes_into(&mut dst, "test").unwrap();
+        assert_eq!(&dst, b"abc");
+        assert!(r.is_empty());
+        assert_eq!(r.u8(), Err("truncated snapshot".to_string()));
+    }
+}
@@ -15,6 +15,19 @@ pub fn spawn_stdin_channel() -> (Receiver<u8>, Arc<atomic::AtomicUsize>) {
     let (tx, rx) = mpsc::channel::<u8>();
     let ctrlc_count : Arc<atomic::AtomicUsize> = Arc::new(atomic::AtomicUsize::new(0));
     let ctrlc_num = Arc::clone(&ctrlc_count);
+    if ! is_tty(&std::io::stdin()) {
+        // Input from a pipe or a file is passed to the guest as it is (including Ctrl+C)
+        // and the thread ends at the end of the input.
+        thread::spawn(move || {
+            for d in std::io::stdin().lock().bytes() {
+                match d {
+                    Ok(d) => { if tx.send(d).is_err() { break; } }
+                    _     => { break; }
+                }
+            }
+        });
+        return (rx, ctrlc_count);
+    }
     thread::spawn(move || {
         let mut stdin_bytes = async_stdin().bytes();
         loop {
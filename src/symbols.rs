@@ -0,0 +1,150 @@
+use crate::elf;
+
+/*
+Symbol table of the guest program.
+
+Symbols are loaded from the symbol table of an ELF file (e.g., vmlinux or u-boot)
+or from a text dump in the System.map or /proc/kallsyms format ("ADDR TYPE NAME [MODULE]").
+An address is resolved to the nearest symbol at or below it, within the size of the symbol
+(or within MAX_UNSIZED_SPAN bytes for a symbol without a size, such as those of System.map).
+Symbols are used for breakpoints given by name and for addresses in logs, the monitor, traces and profiles.
+*/
+
+// the largest offset from a symbol without a size (larger than most functions of Linux)
+const MAX_UNSIZED_SPAN : u32 = 0x10000;
+
+pub struct Symbol {
+    pub addr : u32,
+    pub size : u32, // 0 if unknown (the symbol extends to the next one, up to MAX_UNSIZED_SPAN)
+    pub name : String,
+}
+
+#[derive(Default)]
+pub struct SymbolTable {
+    syms : Vec<Symbol>, // sorted by the address
+}
+
+impl SymbolTable {
+    pub fn new() -> Self {
+        Self { syms: Vec::new() }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.syms.is_empty()
+    }
+
+    // Adds symbols of an ELF file or a System.map/kallsyms dump. Returns the number of symbols added.
+    pub fn load(&mut self, data: &[u8]) -> Result<usize, String> {
+        let mut syms = if elf::is_elf(data) {
+            elf::ElfFile::parse(data)?.symbols()?
+        }else{
+            Self::parse_map(&String::from_utf8_lossy(data))?
+        };
+        let n = syms.len();
+        self.syms.extend(syms.drain(..).map(|(addr, size, name)| Symbol { addr, size, name }));
+        self.syms.sort_by_key(|s| s.addr);
+        Ok(n)
+    }
+
+    fn parse_map(text: &str) -> Result<Vec<(u32, u32, String)>, String> {
+        let mut syms = Vec::new();
+        for (i, line) in text.lines().enumerate() {
+            let mut it = line.split_whitespace();
+            let (addr, kind, name) = match (it.next(), it.next(), it.next()) {
+                (Some(a), Some(k), Some(n)) => (a, k, n),
+                (None, _, _) => { continue; }
+                _ => { return Err(format!("line {}: incorrect symbol \"{}\"", i+1, line)); }
+            };
+            let addr = u32::from_str_radix(addr, 16).map_err(|_| format!("line {}: incorrect address \"{}\"", i+1, addr))?;
+            // absolute and undefined symbols are not located in the program
+            if matches!(kind, "a" | "A" | "U" | "w" | "v") {
+                continue;
+            }
+            syms.push((addr, 0, name.to_string()));
+        }
+        if syms.is_empty() {
+            return Err("no symbol is found".to_string());
+        }
+        Ok(syms)
+    }
+
+    pub fn find(&self, name: &str) -> Option<u32> {
+        self.syms.iter().find(|s| s.name == name).map(|s| s.addr)
+    }
+
+    // address given as "NAME" or "NAME+OFF" (offset in hexadecimal)
+    pub fn resolve(&self, spec: &str) -> Option<u32> {
+        let spec = spec.trim();
+        match spec.split_once('+') {
+            Some((name, off)) => {
+                let off = u32::from_str_radix(off.trim_start_matches("0x"), 16).ok()?;
+                Some(self.find(name)?.wrapping_add(off))
+            }
+            None => self.find(spec),
+        }
+    }
+
+    // " <NAME+0xOFF>" for an address with a symbol, or an empty string
+    pub fn annotate(&self, addr: u32) -> String {
+        match self.lookup(addr) {
+            Some((s, 0))   => format!(" <{}>", s.name),
+            Some((s, off)) => format!(" <{}+0x{:x}>", s.name, off),
+            None           => String::new(),
+        }
+    }
+
+    // symbol containing addr and the offset from it
+    pub fn lookup(&self, addr: u32) -> Option<(&Symbol, u32)> {
+        let addr = addr & !1; // MIPS16e
+        let i = self.syms.partition_point(|s| s.addr <= addr);
+        let s = self.syms.get(i.checked_sub(1)?)?;
+        let offset = addr - s.addr;
+        let size = if s.size != 0 { s.size }else{ MAX_UNSIZED_SPAN };
+        if offset >= size {
+            return None;
+        }
+        Some((s, offset))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn system_map() {
+        let mut syms = SymbolTable::new();
+        let n = syms.load(b"80002000 T start_kernel\n\n80000000 T _text\n00000000 A _abs\n80001000 t helper [mod]\n").unwrap();
+        assert_eq!(n, 3);
+        assert_eq!(syms.find("_text"), Some(0x80000000));
+        assert_eq!(syms.find("_abs"), None);
+        assert_eq!(syms.resolve("helper+0x10"), Some(0x80001010));
+        assert_eq!(syms.resolve(" start_kernel+8 "), Some(0x80002008));
+        assert_eq!(syms.resolve("nothing"), None);
+        assert_eq!(syms.resolve("helper+zz"), None);
+
+        assert_eq!(SymbolTable::new().load(b"8000 T").err(), Some("line 1: incorrect symbol \"8000 T\"".to_string()));
+        assert!(SymbolTable::new().load(b"zz T name\n").is_err());
+        assert!(SymbolTable::new().load(b"\n").is_err());
+    }
+
+    #[test]
+    fn lookup_within_the_symbol() {
+        let mut syms = SymbolTable::new();
+        syms.load(b"80000000 T _text\n80001000 t helper\n").unwrap();
+        assert!(syms.lookup(0x7ffffffc).is_none());
+        assert_eq!(syms.annotate(0x80000000), " <_text>");
+        assert_eq!(syms.annotate(0x80000ffc), " <_text+0xffc>");
+        assert_eq!(syms.annotate(0x80001005), " <helper+0x4>"); // MIPS16e
+        assert_eq!(syms.annotate(0x80001000 + MAX_UNSIZED_SPAN - 4), format!(" <helper+0x{:x}>", MAX_UNSIZED_SPAN - 4));
+
+        // an address far beyond the last symbol without a size belongs to no symbol
+        assert_eq!(syms.annotate(0x80001000 + MAX_UNSIZED_SPAN), "");
+        assert_eq!(syms.annotate(0xbfc00380), "");
+
+        // a symbol with a size covers only that size
+        syms.syms.push(Symbol { addr: 0x80100000, size: 0x20, name: "sized".to_string() });
+        assert_eq!(syms.annotate(0x8010001c), " <sized+0x1c>");
+        assert_eq!(syms.annotate(0x80100020), "");
+    }
+}
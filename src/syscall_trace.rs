@@ -0,0 +1,395 @@
+use crate::procstate::MachineState;
+use crate::cp0def;
+use crate::mem;
+use crate::c0_val;
+use crate::mips;
+use log::error;
+
+use std::collections::HashMap;
+use std::io::Write;
+
+/*
+Tracer of Linux system calls (o32 ABI) of the guest, similar to strace.
+
+A call is logged when the syscall exception is taken: the number is in v0 (4000 + n),
+the arguments are in a0..a3 and the 5th and later ones are on the user stack (sp+16, sp+20).
+The return value is logged when the exception handler returns to the instruction
+after the syscall with eret in the same address space (ASID). v0 is the result, or errno when a3 is not 0.
+
+  [asid 0x1c] open("/etc/config/system", 0x0, 0o0)
+  [asid 0x1c] <... open resumed> = 3
+*/
+
+const SYSCALL_BASE : u32 = 4000;
+
+// maximum length of strings and buffers shown
+const MAX_STR_LEN  : u32 = 64;
+// calls waiting for the return are forgotten when too many (e.g., by exec or signal handlers)
+const MAX_PENDING  : usize = 1024;
+
+const REG_V0 : usize = 2;
+const REG_A0 : usize = 4;
+const REG_A3 : usize = 7;
+const REG_SP : usize = 29;
+
+/*
+Names and argument formats of the system calls from 4000.
+  d: signed integer, x: hexadecimal, o: octal (mode), s: string,
+  b: buffer whose length is the next argument
+"-" is for a call without arguments, and an empty format shows a0..a3 in hexadecimal.
+*/
+const SYSCALLS : &[(&str, &str)] = &[
+    ("syscall", "x"), ("exit", "d"), ("fork", ""), ("read", "dxd"), ("write", "dbd"),
+    ("open", "sxo"), ("close", "d"), ("waitpid", "dxx"), ("creat", "so"), ("link", "ss"),
+    ("unlink", "s"), ("execve", "sxx"), ("chdir", "s"), ("time", "x"), ("mknod", "sox"),
+    ("chmod", "so"), ("lchown", "sdd"), ("break", ""), ("unused18", ""), ("lseek", "ddd"),
+    ("getpid", "-"), ("mount", "ssxx"), ("umount", "s"), ("setuid", "d"), ("getuid", "-"),
+    ("stime", "x"), ("ptrace", "ddxx"), ("alarm", "d"), ("unused28", ""), ("pause", "-"),
+    ("utime", "sx"), ("stty", ""), ("gtty", ""), ("access", "so"), ("nice", "d"),
+    ("ftime", ""), ("sync", "-"), ("kill", "dd"), ("rename", "ss"), ("mkdir", "so"),
+    ("rmdir", "s"), ("dup", "d"), ("pipe", "x"), ("times", "x"), ("prof", ""),
+    ("brk", "x"), ("setgid", "d"), ("getgid", "-"), ("signal", "dx"), ("geteuid", "-"),
+    ("getegid", "-"), ("acct", "s"), ("umount2", "sx"), ("lock", ""), ("ioctl", "dxx"),
+    ("fcntl", "dxx"), ("mpx", ""), ("setpgid", "dd"), ("ulimit", ""), ("unused59", ""),
+    ("umask", "o"), ("chroot", "s"), ("ustat", "xx"), ("dup2", "dd"), ("getppid", "-"),
+    ("getpgrp", "-"), ("setsid", "-"), ("sigaction", "dxx"), ("sgetmask", "-"), ("ssetmask", "x"),
+    ("setreuid", "dd"), ("setregid", "dd"), ("sigsuspend", "x"), ("sigpending", "x"), ("sethostname", "bd"),
+    ("setrlimit", "dx"), ("getrlimit", "dx"), ("getrusage", "dx"), ("gettimeofday", "xx"), ("settimeofday", "xx"),
+    ("getgroups", "dx"), ("setgroups", "dx"), ("reserved82", ""), ("symlink", "ss"), ("unused84", ""),
+    ("readlink", "sxd"), ("uselib", "s"), ("swapon", "sx"), ("reboot", "xxxx"), ("readdir", "dxd"),
+    ("mmap", "xdxxdd"), ("munmap", "xd"), ("truncate", "sd"), ("ftruncate", "dd"), ("fchmod", "do"),
+    ("fchown", "ddd"), ("getpriority", "dd"), ("setpriority", "ddd"), ("profil", ""), ("statfs", "sx"),
+    ("fstatfs", "dx"), ("ioperm", ""), ("socketcall", "dx"), ("syslog", "dxd"), ("setitimer", "dxx"),
+    ("getitimer", "dx"), ("stat", "sx"), ("lstat", "sx"), ("fstat", "dx"), ("unused109", ""),
+    ("iopl", ""), ("vhangup", "-"), ("idle", ""), ("vm86", ""), ("wait4", "dxxx"),
+    ("swapoff", "s"), ("sysinfo", "x"), ("ipc", "dxxxxx"), ("fsync", "d"), ("sigreturn", "-"),
+    ("clone", "xxxxx"), ("setdomainname", "bd"), ("uname", "x"), ("modify_ldt", ""), ("adjtimex", "x"),
+    ("mprotect", "xdx"), ("sigprocmask", "dxx"), ("create_module", ""), ("init_module", "xds"), ("delete_module", "sx"),
+    ("get_kernel_syms", ""), ("quotactl", "xsdx"), ("getpgid", "d"), ("fchdir", "d"), ("bdflush", "dx"),
+    ("sysfs", "dxx"), ("personality", "x"), ("afs_syscall", ""), ("setfsuid", "d"), ("setfsgid", "d"),
+    ("_llseek", "dxxxd"), ("getdents", "dxd"), ("_newselect", "dxxxx"), ("flock", "dx"), ("msync", "xdx"),
+    ("readv", "dxd"), ("writev", "dxd"), ("cacheflush", "xdx"), ("cachectl", "xdx"), ("sysmips", "xxx"),
+    ("unused150", ""), ("getsid", "d"), ("fdatasync", "d"), ("_sysctl", "x"), ("mlock", "xd"),
+    ("munlock", "xd"), ("mlockall", "x"), ("munlockall", "-"), ("sched_setparam", "dx"), ("sched_getparam", "dx"),
+    ("sched_setscheduler", "ddx"), ("sched_getscheduler", "d"), ("sched_yield", "-"), ("sched_get_priority_max", "d"), ("sched_get_priority_min", "d"),
+    ("sched_rr_get_interval", "dx"), ("nanosleep", "xx"), ("mremap", "xddxx"), ("accept", "dxx"), ("bind", "dxd"),
+    ("connect", "dxd"), ("getpeername", "dxx"), ("getsockname", "dxx"), ("getsockopt", "dddxx"), ("listen", "dd"),
+    ("recv", "dxdx"), ("recvfrom", "dxdxxx"), ("recvmsg", "dxx"), ("send", "dbdx"), ("sendmsg", "dxx"),
+    ("sendto", "dbdxxd"), ("setsockopt", "dddxd"), ("shutdown", "dd"), ("socket", "ddd"), ("socketpair", "dddx"),
+    ("setresuid", "ddd"), ("getresuid", "xxx"), ("query_module", ""), ("poll", "xdd"), ("nfsservctl", ""),
+    ("setresgid", "ddd"), ("getresgid", "xxx"), ("prctl", "dxxxx"), ("rt_sigreturn", "-"), ("rt_sigaction", "dxxd"),
+    ("rt_sigprocmask", "dxxd"), ("rt_sigpending", "xd"), ("rt_sigtimedwait", "xxxd"), ("rt_sigqueueinfo", "ddx"), ("rt_sigsuspend", "xd"),
+    ("pread64", "dxdxxx"), ("pwrite64", "dbdxxx"), ("chown", "sdd"), ("getcwd", "xd"), ("capget", "xx"),
+    ("capset", "xx"), ("sigaltstack", "xx"), ("sendfile", "ddxd"), ("getpmsg", ""), ("putpmsg", ""),
+    ("mmap2", "xdxxdx"), ("truncate64", "sxxx"), ("ftruncate64", "dxxx"), ("stat64", "sx"), ("lstat64", "sx"),
+    ("fstat64", "dx"), ("pivot_root", "ss"), ("mincore", "xdx"), ("madvise", "xdd"), ("getdents64", "dxd"),
+    ("fcntl64", "dxx"), ("reserved221", ""), ("gettid", "-"), ("readahead", "dxxxd"), ("setxattr", "ssxdx"),
+    ("lsetxattr", "ssxdx"), ("fsetxattr", "dsxdx"), ("getxattr", "ssxd"), ("lgetxattr", "ssxd"), ("fgetxattr", "dsxd"),
+    ("listxattr", "sxd"), ("llistxattr", "sxd"), ("flistxattr", "dxd"), ("removexattr", "ss"), ("lremovexattr", "ss"),
+    ("fremovexattr", "ds"), ("tkill", "dd"), ("sendfile64", "ddxd"), ("futex", "xdxxxx"), ("sched_setaffinity", "ddx"),
+    ("sched_getaffinity", "ddx"), ("io_setup", "dx"), ("io_destroy", "x"), ("io_getevents", "xddxx"), ("io_submit", "xdx"),
+    ("io_cancel", "xxx"), ("exit_group", "d"), ("lookup_dcookie", ""), ("epoll_create", "d"), ("epoll_ctl", "dddx"),
+    ("epoll_wait", "dxdd"), ("remap_file_pages", "xdxdx"), ("set_tid_address", "x"), ("restart_syscall", "-"), ("fadvise64", "dxxxxd"),
+    ("statfs64", "sdx"), ("fstatfs64", "ddx"), ("timer_create", "dxx"), ("timer_settime", "dxxx"), ("timer_gettime", "dx"),
+    ("timer_getoverrun", "d"), ("timer_delete", "d"), ("clock_settime", "dx"), ("clock_gettime", "dx"), ("clock_getres", "dx"),
+    ("clock_nanosleep", "dxxx"), ("tgkill", "ddd"), ("utimes", "sx"), ("mbind", "xdxxxx"), ("get_mempolicy", "xxxxx"),
+    ("set_mempolicy", "dxx"), ("mq_open", "sxox"), ("mq_unlink", "s"), ("mq_timedsend", "dxdxx"), ("mq_timedreceive", "dxdxx"),
+    ("mq_notify", "dx"), ("mq_getsetattr", "dxx"), ("vserver", ""), ("waitid", "ddxxx"), ("unused279", ""),
+    ("add_key", "ssxdd"), ("request_key", "sssd"), ("keyctl", "dxxxx"), ("set_thread_area", "x"), ("inotify_init", "-"),
+    ("inotify_add_watch", "dsx"), ("inotify_rm_watch", "dd"), ("migrate_pages", "ddxx"), ("openat", "dsxo"), ("mkdirat", "dso"),
+    ("mknodat", "dsox"), ("fchownat", "dsddx"), ("futimesat", "dsx"), ("fstatat64", "dsxx"), ("unlinkat", "dsx"),
+    ("renameat", "dsds"), ("linkat", "dsdsx"), ("symlinkat", "sds"), ("readlinkat", "dsxd"), ("fchmodat", "dso"),
+    ("faccessat", "dso"), ("pselect6", "dxxxxx"), ("ppoll", "xdxxd"), ("unshare", "x"), ("splice", "dxdxdx"),
+    ("sync_file_range", "dxxxxx"), ("tee", "dddx"), ("vmsplice", "dxdx"), ("move_pages", "ddxxxx"), ("set_robust_list", "xd"),
+    ("get_robust_list", "dxx"), ("kexec_load", "xdxx"), ("getcpu", "xxx"), ("epoll_pwait", "dxddxd"), ("ioprio_set", "ddd"),
+    ("ioprio_get", "dd"), ("utimensat", "dsxx"), ("signalfd", "dxd"), ("timerfd", ""), ("eventfd", "d"),
+    ("fallocate", "dxxxxx"), ("timerfd_create", "dx"), ("timerfd_gettime", "dx"), ("timerfd_settime", "dxxx"), ("signalfd4", "dxdx"),
+    ("eventfd2", "dx"), ("epoll_create1", "x"), ("dup3", "ddx"), ("pipe2", "xx"), ("inotify_init1", "x"),
+    ("preadv", "dxdxx"), ("pwritev", "dxdxx"), ("rt_tgsigqueueinfo", "dddx"), ("perf_event_open", "xdddx"), ("accept4", "dxxx"),
+    ("recvmmsg", "dxdxx"), ("fanotify_init", "xx"), ("fanotify_mark", "dxxxxd"), ("prlimit64", "ddxx"), ("name_to_handle_at", "dsxxx"),
+    ("open_by_handle_at", "dxx"), ("clock_adjtime", "dx"), ("syncfs", "d"), ("sendmmsg", "dxdx"), ("setns", "dx"),
+    ("process_vm_readv", "dxdxdx"), ("process_vm_writev", "dxdxdx"), ("kcmp", "ddddd"), ("finit_module", "dsx"), ("sched_setattr", "dxx"),
+    ("sched_getattr", "dxdx"), ("renameat2", "dsdsx"), ("seccomp", "dxx"), ("getrandom", "xdx"), ("memfd_create", "sx"),
+    ("bpf", "dxd"), ("execveat", "dsxxx"), ("userfaultfd", "x"), ("membarrier", "dx"), ("mlock2", "xdx"),
+    ("copy_file_range", "dxdxdx"), ("preadv2", "dxdxxx"), ("pwritev2", "dxdxxx"), ("pkey_mprotect", "xdxd"), ("pkey_alloc", "xx"),
+    ("pkey_free", "d"), ("statx", "dsxxx"), ("rseq", "xdxx"), ("io_pgetevents", "xddxxx"), ("", ""),
+    ("", ""), ("", ""), ("", ""), ("", ""), ("", ""),
+    ("", ""), ("", ""), ("", ""), ("", ""), ("", ""),
+    ("", ""), ("", ""), ("", ""), ("", ""), ("", ""),
+    ("", ""), ("", ""), ("", ""), ("", ""), ("", ""),
+    ("", ""), ("", ""), ("", ""), ("semget", "xdx"), ("semctl", "dddx"),
+    ("shmget", "xdx"), ("shmctl", "ddx"), ("shmat", "dxx"), ("shmdt", "x"), ("msgget", "xx"),
+    ("msgsnd", "dxdx"), ("msgrcv", "dxddx"), ("msgctl", "ddx"), ("clock_gettime64", "dx"), ("clock_settime64", "dx"),
+    ("clock_adjtime64", "dx"), ("clock_getres_time64", "dx"), ("clock_nanosleep_time64", "dxxx"), ("timer_gettime64", "dx"), ("timer_settime64", "dxxx"),
+    ("timerfd_gettime64", "dx"), ("timerfd_settime64", "dxxx"), ("utimensat_time64", "dsxx"), ("pselect6_time64", "dxxxxx"), ("ppoll_time64", "xdxxd"),
+    ("", ""), ("io_pgetevents_time64", "xddxxx"), ("recvmmsg_time64", "dxdxx"), ("mq_timedsend_time64", "dxdxx"), ("mq_timedreceive_time64", "dxdxx"),
+    ("semtimedop_time64", "dxdx"), ("rt_sigtimedwait_time64", "xxxd"), ("futex_time64", "xdxxxx"), ("sched_rr_get_interval_time64", "dx"), ("pidfd_send_signal", "ddxx"),
+    ("io_uring_setup", "dx"), ("io_uring_enter", "dddxxd"), ("io_uring_register", "ddxd"), ("open_tree", "dsx"), ("move_mount", "dsdsx"),
+    ("fsopen", "sx"), ("fsconfig", "ddsxd"), ("fsmount", "dxx"), ("fspick", "dsx"), ("pidfd_open", "dx"),
+    ("clone3", "xd"), ("close_range", "ddx"), ("openat2", "dsxd"), ("pidfd_getfd", "ddx"), ("faccessat2", "dsox"),
+];
+
+// names of errno values common to all the architectures
+const ERRNO_NAMES : [&str; 35] = [
+    "", "EPERM", "ENOENT", "ESRCH", "EINTR", "EIO", "ENXIO", "E2BIG", "ENOEXEC", "EBADF",
+    "ECHILD", "EAGAIN", "ENOMEM", "EACCES", "EFAULT", "ENOTBLK", "EBUSY", "EEXIST", "EXDEV", "ENODEV",
+    "ENOTDIR", "EISDIR", "EINVAL", "ENFILE", "EMFILE", "ENOTTY", "ETXTBSY", "EFBIG", "ENOSPC", "ESPIPE",
+    "EROFS", "EMLINK", "EPIPE", "EDOM", "ERANGE",
+];
+const ERRNO_ENOSYS : u32 = 89; // MIPS specific
+
+pub struct SyscallTracer {
+    out     : Box<dyn Write>,
+    pending : HashMap<(u32, u32), u32>, // (ASID, return address) -> syscall number
+}
+
+fn syscall_name(nr: u32) -> String {
+    match nr.checked_sub(SYSCALL_BASE).and_then(|n| SYSCALLS.get(n as usize)) {
+        Some((name, _)) if !name.is_empty() => name.to_string(),
+        _ => format!("syscall_{}", nr),
+    }
+}
+
+fn read_word(ms: &mut MachineState, vaddr: u32) -> Option<u32> {
+    let mut v : u32 = 0;
+    for i in 0..4 {
+        v = (v << 8) | mem::debug_read_byte(ms, vaddr.wrapping_add(i)).ok()? as u32;
+    }
+    Some(v)
+}
+
+// a string (or a buffer of len bytes) in the guest memory with escape sequences
+fn read_string(ms: &mut MachineState, vaddr: u32, len: Option<u32>) -> String {
+    if vaddr == 0 {
+        return "NULL".to_string();
+    }
+    let mut s = String::from("\"");
+    let limit = len.unwrap_or(MAX_STR_LEN).min(MAX_STR_LEN);
+    for i in 0..limit {
+        let c = match mem::debug_read_byte(ms, vaddr.wrapping_add(i)) {
+            Ok(c) => c,
+            Err(_) => { return format!("0x{:x}", vaddr); }
+        };
+        if c == 0 && len.is_none() {
+            s.push('"');
+            return s;
+        }
+        match c {
+            b'"'  => s.push_str("\\\""),
+            b'\\' => s.push_str("\\\\"),
+            b'\n' => s.push_str("\\n"),
+            b'\r' => s.push_str("\\r"),
+            b'\t' => s.push_str("\\t"),
+            0x20..=0x7e => s.push(c as char),
+            _     => s.push_str(&format!("\\x{:02x}", c)),
+        }
+    }
+    s.push('"');
+    if len.is_none_or(|l| l > limit) {
+        s.push_str("...");
+    }
+    s
+}
+
+impl SyscallTracer {
+    pub fn new(out: Box<dyn Write>) -> Self {
+        Self { out, pending: HashMap::new() }
+    }
+
+    fn asid(ms: &MachineState) -> u32 {
+        c0_val!(ms.reg, cp0def::C0_ENTRYHI) & cp0def::C0_ENTRYHI_ASID_MASK
+    }
+
+    fn arg(ms: &mut MachineState, n: usize) -> u32 {
+        if n < 4 {
+            ms.reg.r[REG_A0 + n]
+        }else{
+            let sp = ms.reg.r[REG_SP];
+            read_word(ms, sp.wrapping_add(4 * n as u32)).unwrap_or(0)
+        }
+    }
+
+    fn write_line(&mut self, line: String) {
+        if let Err(e) = self.out.write_all(line.as_bytes()) {
+            error!("Syscall trace: can not write : {}\r", e);
+        }
+    }
+
+    // called when the syscall instruction at pc raises the exception
+    fn enter(&mut self, ms: &mut MachineState, pc: u32) {
+        let nr   = ms.reg.r[REG_V0];
+        let asid = Self::asid(ms);
+        let fmt  = nr.checked_sub(SYSCALL_BASE).and_then(|n| SYSCALLS.get(n as usize)).map(|(_, f)| *f).unwrap_or("");
+        let fmt  = if fmt.is_empty() { "xxxx" }else if fmt == "-" { "" }else{ fmt };
+
+        let mut args = Vec::new();
+        for (i, f) in fmt.chars().enumerate() {
+            let a = Self::arg(ms, i);
+            args.push(match f {
+                'd' => format!("{}", a as i32),
+                'o' => format!("0o{:o}", a),
+                's' => read_string(ms, a, None),
+                'b' => { let len = Self::arg(ms, i + 1); read_string(ms, a, Some(len)) }
+                _   => format!("0x{:x}", a),
+            });
+        }
+        self.write_line(format!("[asid 0x{:02x}] {}({})\n", asid, syscall_name(nr), args.join(", ")));
+
+        if self.pending.len() >= MAX_PENDING {
+            self.pending.clear();
+        }
+        self.pending.insert((asid, pc.wrapping_add(4)), nr);
+    }
+
+    // called after eret
+    fn leave(&mut self, ms: &mut MachineState) {
+        let asid = Self::asid(ms);
+        if let Some(nr) = self.pending.remove(&(asid, ms.reg.pc)) {
+            let v0 = ms.reg.r[REG_V0];
+            let result = if ms.reg.r[REG_A3] != 0 {
+                let name = match v0 {
+                    ERRNO_ENOSYS => "ENOSYS",
+                    _ => ERRNO_NAMES.get(v0 as usize).copied().unwrap_or(""),
+                };
+                format!("-1 {} (errno {})", name, v0)
+            }else if (v0 as i32) < 0 || v0 > 0xffff {
+                format!("0x{:x}", v0)
+            }else{
+                format!("{}", v0)
+            };
+            self.write_line(format!("[asid 0x{:02x}] <... {} resumed> = {}\n", asid, syscall_name(nr), result));
+        }
+    }
+
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        self.out.flush()
+    }
+}
+
+// hook of the syscall exception (the PC still points to the syscall instruction)
+pub fn on_syscall(ms: &mut MachineState) {
+    if let Some(mut t) = ms.syscall_trace.take() {
+        let pc = ms.reg.pc;
+        t.enter(ms, pc);
+        ms.syscall_trace = Some(t);
+    }
+}
+
+// hook of eret (the PC is the return address)
+pub fn on_eret(ms: &mut MachineState) {
+    if let Some(mut t) = ms.syscall_trace.take() {
+        t.leave(ms);
+        ms.syscall_trace = Some(t);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dev_spiflash::SPI_FLASH_PARAM_S25FL164K;
+    use crate::exrmips::generate_machine_state;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    struct Output(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Output {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> { self.0.borrow_mut().extend_from_slice(buf); Ok(buf.len()) }
+        fn flush(&mut self) -> std::io::Result<()> { Ok(()) }
+    }
+
+    fn setup() -> (MachineState, Rc<RefCell<Vec<u8>>>) {
+        let mut ms = generate_machine_state(&SPI_FLASH_PARAM_S25FL164K, &[]);
+        let out = Rc::new(RefCell::new(Vec::new()));
+        ms.syscall_trace = Some(SyscallTracer::new(Box::new(Output(Rc::clone(&out)))));
+        c0_val!(ms.reg, cp0def::C0_ENTRYHI) = 0x1c;
+        (ms, out)
+    }
+
+    fn put(ms: &mut MachineState, vaddr: u32, data: &[u8]) {
+        let start = (vaddr & 0x1fffffff) as usize;
+        ms.mem.mem0[start .. start + data.len()].copy_from_slice(data);
+    }
+
+    // syscall at pc with v0 and a0..a3
+    fn syscall(ms: &mut MachineState, pc: u32, nr: u32, args: [u32; 4]) {
+        ms.reg.pc = pc;
+        ms.reg.r[REG_V0] = nr;
+        ms.reg.r[REG_A0 .. REG_A0 + 4].copy_from_slice(&args);
+        on_syscall(ms);
+    }
+
+    // eret to pc with the result in v0 and a3
+    fn eret(ms: &mut MachineState, pc: u32, v0: u32, a3: u32) {
+        ms.reg.pc = pc;
+        ms.reg.r[REG_V0] = v0;
+        ms.reg.r[REG_A3] = a3;
+        on_eret(ms);
+    }
+
+    fn take(out: &Rc<RefCell<Vec<u8>>>) -> String {
+        String::from_utf8(out.borrow_mut().split_off(0)).unwrap()
+    }
+
+    #[test]
+    fn arguments() {
+        let (mut ms, out) = setup();
+        put(&mut ms, 0x80100000, b"/etc/config/system\0");
+        put(&mut ms, 0x80100100, b"hi \"x\"\n\x01rest");
+
+        syscall(&mut ms, 0x00400100, 4005, [0x80100000, 0x241, 0o644, 0]);
+        assert_eq!(take(&out), "[asid 0x1c] open(\"/etc/config/system\", 0x241, 0o644)\n");
+        syscall(&mut ms, 0x00400100, 4004, [1, 0x80100100, 8, 0]);
+        assert_eq!(take(&out), "[asid 0x1c] write(1, \"hi \\\"x\\\"\\n\\x01\", 8)\n");
+        syscall(&mut ms, 0x00400100, 4004, [(-1i32) as u32, 0, 8, 0]);
+        assert_eq!(take(&out), "[asid 0x1c] write(-1, NULL, 8)\n");
+        syscall(&mut ms, 0x00400100, 4020, [1, 2, 3, 4]);
+        assert_eq!(take(&out), "[asid 0x1c] getpid()\n");
+        syscall(&mut ms, 0x00400100, 3999, [1, 2, 3, 0xffffffff]);
+        assert_eq!(take(&out), "[asid 0x1c] syscall_3999(0x1, 0x2, 0x3, 0xffffffff)\n");
+
+        // the 5th and 6th arguments on the user stack
+        ms.reg.r[REG_SP] = 0x80200000;
+        put(&mut ms, 0x80200010, &[0, 0, 0, 3, 0, 0, 0x10, 0]);
+        syscall(&mut ms, 0x00400100, 4210, [0, 0x1000, 3, 0x802]);
+        assert_eq!(take(&out), "[asid 0x1c] mmap2(0x0, 4096, 0x3, 0x802, 3, 0x1000)\n");
+    }
+
+    #[test]
+    fn results() {
+        let (mut ms, out) = setup();
+        put(&mut ms, 0x80100000, b"/nothing\0");
+        syscall(&mut ms, 0x00400100, 4005, [0x80100000, 0, 0, 0]);
+        syscall(&mut ms, 0x00400200, 4020, [0, 0, 0, 0]);
+        take(&out);
+
+        // a return to another address or in another address space is not the end of the call
+        eret(&mut ms, 0x00400100, 0, 0);
+        c0_val!(ms.reg, cp0def::C0_ENTRYHI) = 0x1d;
+        eret(&mut ms, 0x00400104, 0, 0);
+        assert_eq!(take(&out), "");
+
+        c0_val!(ms.reg, cp0def::C0_ENTRYHI) = 0x1c;
+        eret(&mut ms, 0x00400204, 42, 0);
+        assert_eq!(take(&out), "[asid 0x1c] <... getpid resumed> = 42\n");
+        eret(&mut ms, 0x00400104, 2, 1);
+        assert_eq!(take(&out), "[asid 0x1c] <... open resumed> = -1 ENOENT (errno 2)\n");
+        // only once
+        eret(&mut ms, 0x00400104, 2, 1);
+        assert_eq!(take(&out), "");
+
+        ms.reg.r[REG_SP] = 0x80200000;
+        put(&mut ms, 0x80200010, &[0xff, 0xff, 0xff, 0xff, 0, 0, 0, 0]);
+        syscall(&mut ms, 0x00400100, 4210, [0, 0x1000, 3, 0x22]);
+        eret(&mut ms, 0x00400104, 0x77ff0000, 0);
+        syscall(&mut ms, 0x00400100, 4999, [0, 0, 0, 0]);
+        eret(&mut ms, 0x00400104, ERRNO_ENOSYS, 1);
+        assert_eq!(take(&out), concat!(
+            "[asid 0x1c] mmap2(0x0, 4096, 0x3, 0x22, -1, 0x0)\n",
+            "[asid 0x1c] <... mmap2 resumed> = 0x77ff0000\n",
+            "[asid 0x1c] syscall_4999(0x0, 0x0, 0x0, 0x0)\n",
+            "[asid 0x1c] <... syscall_4999 resumed> = -1 ENOSYS (errno 89)\n",
+        ));
+    }
+}
@@ -40,6 +40,26 @@ impl TLBEntry {
             lo         : [TLBPhyAddr { field_pfn: 0, field_dirty: false, field_valid: false}; 2]
         }
     }
+
+    // sets the entry and its decoded fields from the values of EntryHi, EntryLo0, EntryLo1 and PageMask
+    pub fn set(&mut self, entryhi : u32, entrylo0 : u32, entrylo1 : u32, pagemask : u32){
+        self.entryhi  = entryhi;
+        self.entrylo0 = entrylo0;
+        self.entrylo1 = entrylo1;
+
+        self.field_vpn2 = entryhi & cp0def::C0_ENTRYHI_VPN2_MASK;
+        self.field_asid = entryhi & cp0def::C0_ENTRYHI_ASID_MASK;
+        self.field_g    = if 0 != (entrylo0 & entrylo1 & 1) { true }else{ false };
+        self.field_pmask= pagemask;
+
+        self.lo[0].field_valid  = if 0 != (entrylo0 & 2) { true }else{ false };
+        self.lo[0].field_dirty  = if 0 != (entrylo0 & 4) { true }else{ false };
+        self.lo[0].field_pfn    = (entrylo0<<6) & 0xfffff000;
+
+        self.lo[1].field_valid  = if 0 != (entrylo1 & 2) { true }else{ false };
+        self.lo[1].field_dirty  = if 0 != (entrylo1 & 4) { true }else{ false };
+        self.lo[1].field_pfn    = (entrylo1<<6) & 0xfffff000;
+    }
 }
 
 fn tlb_write(ms: &mut MachineState, index : u32){
@@ -59,22 +79,11 @@ fn tlb_write(ms: &mut MachineState, index : u32){
     ms.tlbcache[((ms.tlb[idx].entryhi >> 12) as usize) & (config::TLB_CACHE_SIZE-1)] = config::TLB_CACHE_SIZE as u8;
     ms.tlbcache[((entryhi             >> 12) as usize) & (config::TLB_CACHE_SIZE-1)] = idx as u8;
 
-    ms.tlb[idx].entryhi  = entryhi;
-    ms.tlb[idx].entrylo0 = entrylo0;
-    ms.tlb[idx].entrylo1 = entrylo1;
-
-    ms.tlb[idx].field_vpn2 = entryhi & cp0def::C0_ENTRYHI_VPN2_MASK;
-    ms.tlb[idx].field_asid = entryhi & cp0def::C0_ENTRYHI_ASID_MASK;
-    ms.tlb[idx].field_g    = if 0 != (entrylo0 & entrylo1 & 1) { true }else{ false };
-    ms.tlb[idx].field_pmask= c0_val!(ms.reg, cp0def::C0_PAGEMASK);
+    ms.tlb[idx].set(entryhi, entrylo0, entrylo1, c0_val!(ms.reg, cp0def::C0_PAGEMASK));
 
-    ms.tlb[idx].lo[0].field_valid  = if 0 != (entrylo0 & 2) { true }else{ false };
-    ms.tlb[idx].lo[0].field_dirty  = if 0 != (entrylo0 & 4) { true }else{ false };
-    ms.tlb[idx].lo[0].field_pfn    = (entrylo0<<6) & 0xfffff000;
-
-    ms.tlb[idx].lo[1].field_valid  = if 0 != (entrylo1 & 2) { true }else{ false };
-    ms.tlb[idx].lo[1].field_dirty  = if 0 != (entrylo1 & 4) { true }else{ false };
-    ms.tlb[idx].lo[1].field_pfn    = (entrylo1<<6) & 0xfffff000;
+    if let Some(t) = ms.trace.as_mut() {
+        t.tlb_write(idx as u32, entryhi, entrylo0, entrylo1, c0_val!(ms.reg, cp0def::C0_PAGEMASK));
+    }
 }
 
 pub fn write_with_index(ms : &mut MachineState){
@@ -0,0 +1,387 @@
+use crate::procstate::Reg;
+use crate::disasm;
+use crate::symbols::SymbolTable;
+use log::error;
+
+use std::io::{self, Read, Write};
+
+/*
+Instruction and exception tracer.
+
+A trace file starts with TRACE_MAGIC and the 32-bit mask of the recorded events,
+followed by records of an event type byte and its fields (big-endian):
+  TRACE_INST : instruction count (64 bits), PC, instruction word
+  TRACE_REG  : register number (8 bits, 32: HI, 33: LO), new value
+  TRACE_MEM  : width and direction (8 bits, bit 7: store), virtual address, physical address, value
+  TRACE_EXC  : exception code (8 bits), C0_CAUSE, C0_EPC, C0_BADVADDR, new PC
+  TRACE_TLB  : TLB index (8 bits), EntryHi, EntryLo0, EntryLo1, PageMask
+  TRACE_INT  : pending interrupts (8 bits, C0_CAUSE.IP), C0_CAUSE, C0_EPC, new PC
+
+Register writes are found by comparing the registers before and after an instruction.
+Records of the other events belong to the last TRACE_INST record.
+*/
+
+pub const TRACE_MAGIC : &[u8; 8] = b"EXRMTRC1";
+
+pub const TRACE_INST : u8 = 1;
+pub const TRACE_REG  : u8 = 2;
+pub const TRACE_MEM  : u8 = 3;
+pub const TRACE_EXC  : u8 = 4;
+pub const TRACE_TLB  : u8 = 5;
+pub const TRACE_INT  : u8 = 6;
+
+const EVENT_NAMES : [(&str, u8); 6] = [
+    ("inst", TRACE_INST), ("reg", TRACE_REG), ("mem", TRACE_MEM),
+    ("exc",  TRACE_EXC),  ("tlb", TRACE_TLB), ("int", TRACE_INT),
+];
+
+const MEM_STORE_BIT : u8 = 0x80;
+
+const REG_HI : u8 = 32;
+const REG_LO : u8 = 33;
+
+// all the events
+pub const TRACE_EVENTS_ALL : u32 = (1<<TRACE_INST) | (1<<TRACE_REG) | (1<<TRACE_MEM) | (1<<TRACE_EXC) | (1<<TRACE_TLB) | (1<<TRACE_INT);
+
+/*
+Parses a comma-separated list of event names ("inst", "reg", "mem", "exc", "tlb", "int" or "all")
+into a mask of events.
+*/
+pub fn parse_events(spec: &str) -> Result<u32, String> {
+    let mut mask = 0;
+    for name in spec.split(',').map(|s| s.trim()) {
+        if name == "all" {
+            mask |= TRACE_EVENTS_ALL;
+            continue;
+        }
+        let (_, ev) = EVENT_NAMES.iter().find(|(n, _)| *n == name).ok_or(format!("unknown trace event \"{}\"", name))?;
+        mask |= 1 << ev;
+    }
+    Ok(mask)
+}
+
+// condition to start or stop tracing
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Trigger {
+    Pc(u32),    // the instruction at the address is fetched
+    Count(u64), // the number of executed instructions reaches the value
+}
+
+impl Trigger {
+    // parses "pc:ADDR" (hexadecimal) or "count:N"
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let num = |s: &str, radix| u64::from_str_radix(s.trim_start_matches("0x"), radix).map_err(|_| format!("incorrect trigger \"{}\"", spec));
+        match spec.split_once(':') {
+            Some(("pc", a))    => Ok(Trigger::Pc(num(a, 16)? as u32)),
+            Some(("count", n)) => Ok(Trigger::Count(num(n, 10)?)),
+            _ => Err(format!("incorrect trigger \"{}\" (pc:ADDR or count:N)", spec)),
+        }
+    }
+
+    fn check(&self, count: u64, pc: u32) -> bool {
+        match *self {
+            Trigger::Pc(a)    => a == pc,
+            Trigger::Count(n) => n == count,
+        }
+    }
+}
+
+pub struct Tracer {
+    out     : Box<dyn Write>,
+    events  : u32,
+    start   : Option<Trigger>,
+    stop    : Option<Trigger>,
+    active  : bool,
+    stopped : bool,
+    prev_r  : [u32; 32],
+    prev_hi : u32,
+    prev_lo : u32,
+}
+
+impl Tracer {
+    /*
+    Starts a trace of the events (a mask generated by parse_events).
+    Without start, tracing starts from the first instruction. Once stopped, it is not restarted.
+    */
+    pub fn new(mut out: Box<dyn Write>, events: u32, start: Option<Trigger>, stop: Option<Trigger>) -> io::Result<Self> {
+        out.write_all(TRACE_MAGIC)?;
+        out.write_all(&events.to_be_bytes())?;
+        Ok(Self {
+            out, events, start, stop,
+            active: start.is_none(),
+            stopped: false,
+            prev_r: [0; 32],
+            prev_hi: 0,
+            prev_lo: 0,
+        })
+    }
+
+    fn enabled(&self, ev: u8) -> bool {
+        self.active && 0 != (self.events & (1<<ev))
+    }
+
+    fn record(&mut self, ev: u8, head: u8, fields: &[u32]) {
+        let mut buf = Vec::with_capacity(2 + 4*fields.len());
+        buf.push(ev);
+        buf.push(head);
+        for f in fields {
+            buf.extend_from_slice(&f.to_be_bytes());
+        }
+        if let Err(e) = self.out.write_all(&buf) {
+            error!("Trace: can not write the trace : {}\r", e);
+            self.active  = false;
+            self.stopped = true;
+        }
+    }
+
+    // called before executing the instruction inst at pc
+    pub fn fetch(&mut self, reg: &Reg, count: u64, pc: u32, inst: u32) {
+        if self.stopped {
+            return;
+        }
+        if !self.active && self.start.is_some_and(|t| t.check(count, pc)) {
+            self.active = true;
+        }
+        if self.active && self.stop.is_some_and(|t| t.check(count, pc)) {
+            self.active  = false;
+            self.stopped = true;
+            let _ = self.out.flush();
+            return;
+        }
+
+        if self.enabled(TRACE_INST) {
+            let mut buf = [0u8; 17];
+            buf[0] = TRACE_INST;
+            buf[1..9].copy_from_slice(&count.to_be_bytes());
+            buf[9..13].copy_from_slice(&pc.to_be_bytes());
+            buf[13..17].copy_from_slice(&inst.to_be_bytes());
+            if let Err(e) = self.out.write_all(&buf) {
+                error!("Trace: can not write the trace : {}\r", e);
+                self.active  = false;
+                self.stopped = true;
+            }
+        }
+        if self.enabled(TRACE_REG) {
+            self.prev_r  = reg.r;
+            self.prev_hi = reg.hi;
+            self.prev_lo = reg.lo;
+        }
+    }
+
+    // called after executing the instruction
+    pub fn retire(&mut self, reg: &Reg) {
+        if !self.enabled(TRACE_REG) {
+            return;
+        }
+        for i in 1..32 {
+            if reg.r[i] != self.prev_r[i] {
+                self.record(TRACE_REG, i as u8, &[reg.r[i]]);
+            }
+        }
+        if reg.hi != self.prev_hi { self.record(TRACE_REG, REG_HI, &[reg.hi]); }
+        if reg.lo != self.prev_lo { self.record(TRACE_REG, REG_LO, &[reg.lo]); }
+    }
+
+    pub fn mem(&mut self, vaddr: u32, paddr: u32, width: u32, value: u32, store: bool) {
+        if self.enabled(TRACE_MEM) {
+            self.record(TRACE_MEM, width as u8 | if store { MEM_STORE_BIT }else{ 0 }, &[vaddr, paddr, value]);
+        }
+    }
+
+    pub fn exception(&mut self, ecode: u32, cause: u32, epc: u32, badvaddr: u32, pc: u32) {
+        if self.enabled(TRACE_EXC) {
+            self.record(TRACE_EXC, ecode as u8, &[cause, epc, badvaddr, pc]);
+        }
+    }
+
+    pub fn tlb_write(&mut self, index: u32, entryhi: u32, entrylo0: u32, entrylo1: u32, pagemask: u32) {
+        if self.enabled(TRACE_TLB) {
+            self.record(TRACE_TLB, index as u8, &[entryhi, entrylo0, entrylo1, pagemask]);
+        }
+    }
+
+    pub fn interrupt(&mut self, pending: u32, cause: u32, epc: u32, pc: u32) {
+        if self.enabled(TRACE_INT) {
+            self.record(TRACE_INT, pending as u8, &[cause, epc, pc]);
+        }
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+fn reg_name(n: u8) -> String {
+    match n {
+        REG_HI => "hi".to_string(),
+        REG_LO => "lo".to_string(),
+        _      => format!("r{}", n),
+    }
+}
+
+/*
+Converts a trace into text, one line per record.
+Records following an instruction are indented. Code addresses are shown with symbols in syms.
+*/
+pub fn to_text(input: &mut dyn Read, out: &mut dyn Write, syms: &SymbolTable) -> io::Result<()> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+
+    let mut magic = [0u8; 8];
+    input.read_exact(&mut magic).map_err(|_| invalid("not a trace file"))?;
+    if &magic != TRACE_MAGIC {
+        return Err(invalid("not a trace file"));
+    }
+    let mut events = [0u8; 4];
+    input.read_exact(&mut events)?;
+    let events = u32::from_be_bytes(events);
+    let names : Vec<&str> = EVENT_NAMES.iter().filter(|(_, ev)| 0 != (events & (1<<ev))).map(|(n, _)| *n).collect();
+    writeln!(out, "# events: {}", names.join(","))?;
+
+    let mut ev = [0u8; 1];
+    loop {
+        if 0 == input.read(&mut ev)? {
+            break;
+        }
+        if ev[0] == TRACE_INST {
+            let mut buf = [0u8; 16];
+            input.read_exact(&mut buf)?;
+            let count = u64::from_be_bytes(buf[0..8].try_into().unwrap());
+            let pc    = u32::from_be_bytes(buf[8..12].try_into().unwrap());
+            let inst  = u32::from_be_bytes(buf[12..16].try_into().unwrap());
+            writeln!(out, "{:>10} {:>08x}: {:>08x}  {:<40}{}", count, pc, inst, disasm::disassemble(pc, inst), syms.annotate(pc))?;
+            continue;
+        }
+
+        let nfields = match ev[0] {
+            TRACE_REG => 1,
+            TRACE_MEM => 3,
+            TRACE_EXC => 4,
+            TRACE_TLB => 4,
+            TRACE_INT => 3,
+            _         => { return Err(invalid(&format!("unknown record type {}", ev[0]))); }
+        };
+        let mut head = [0u8; 1];
+        input.read_exact(&mut head)?;
+        let mut f = [0u32; 4];
+        for v in f.iter_mut().take(nfields) {
+            let mut buf = [0u8; 4];
+            input.read_exact(&mut buf)?;
+            *v = u32::from_be_bytes(buf);
+        }
+        let head = head[0];
+
+        match ev[0] {
+            TRACE_REG => writeln!(out, "           {:<4} <= {:>08x}", reg_name(head), f[0])?,
+            TRACE_MEM => writeln!(out, "           {} {} vaddr {:>08x} paddr {:>08x} {} {:>08x}",
+                if 0 != (head & MEM_STORE_BIT) { "store" }else{ "load " }, head & !MEM_STORE_BIT, f[0], f[1],
+                if 0 != (head & MEM_STORE_BIT) { "<=" }else{ "=>" }, f[2])?,
+            TRACE_EXC => writeln!(out, "           exception {:>2} cause {:>08x} epc {:>08x}{} badvaddr {:>08x} -> {:>08x}", head, f[0], f[1], syms.annotate(f[1]), f[2], f[3])?,
+            TRACE_TLB => writeln!(out, "           tlbwrite [{:>2}] entryhi {:>08x} entrylo0 {:>08x} entrylo1 {:>08x} pagemask {:>08x}", head, f[0], f[1], f[2], f[3])?,
+            _         => writeln!(out, "           interrupt ip {:>02x} cause {:>08x} epc {:>08x}{} -> {:>08x}", head, f[0], f[1], syms.annotate(f[1]), f[2])?,
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    // trace output kept in memory
+    #[derive(Clone, Default)]
+    struct Buffer(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> { self.0.borrow_mut().extend_from_slice(buf); Ok(buf.len()) }
+        fn flush(&mut self) -> io::Result<()> { Ok(()) }
+    }
+
+    fn text(trace: &[u8], syms: &SymbolTable) -> String {
+        let mut out = Vec::new();
+        to_text(&mut &trace[..], &mut out, syms).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn events() {
+        assert_eq!(parse_events("inst"), Ok(1<<TRACE_INST));
+        assert_eq!(parse_events("reg, mem"), Ok((1<<TRACE_REG) | (1<<TRACE_MEM)));
+        assert_eq!(parse_events("all,inst"), Ok(TRACE_EVENTS_ALL));
+        assert!(parse_events("inst,foo").is_err());
+
+        assert_eq!(Trigger::parse("pc:0x80000400"), Ok(Trigger::Pc(0x80000400)));
+        assert_eq!(Trigger::parse("pc:bfc00000"), Ok(Trigger::Pc(0xbfc00000)));
+        assert_eq!(Trigger::parse("count:100"), Ok(Trigger::Count(100)));
+        assert!(Trigger::parse("count:1a").is_err());
+        assert!(Trigger::parse("line:1").is_err());
+    }
+
+    #[test]
+    fn encode_and_convert() {
+        let buf = Buffer::default();
+        let mut t = Tracer::new(Box::new(buf.clone()), TRACE_EVENTS_ALL, None, None).unwrap();
+        let mut reg = Reg::new();
+
+        t.fetch(&reg, 7, 0x80001000, 0x24020001); // addiu v0,zero,1
+        reg.r[2] = 1;
+        reg.lo   = 0x1234;
+        t.retire(&reg);
+        t.fetch(&reg, 8, 0x80001004, 0xac820010); // sw v0,16(a0)
+        t.mem(0x80000010, 0x10, 4, 1, true);
+        t.mem(0x80000014, 0x14, 2, 0xbeef, false);
+        t.exception(2, 0x00000008, 0x80001004, 0x00000010, 0x80000000);
+        t.tlb_write(3, 0x00402000, 0x1017, 0x1057, 0x1800);
+        t.interrupt(0x80, 0x40008000, 0x80001008, 0x80000180);
+        t.retire(&reg);
+
+        let mut syms = SymbolTable::new();
+        syms.load(b"80001000 T main\n").unwrap();
+        let s = text(&buf.0.borrow(), &syms);
+        let lines : Vec<&str> = s.lines().collect();
+        assert_eq!(lines.len(), 10);
+        assert_eq!(lines[0], "# events: inst,reg,mem,exc,tlb,int");
+        assert!(lines[1].starts_with("         7 80001000: 24020001  li      $v0, 0x1 ") && lines[1].ends_with(" <main>"), "{}", lines[1]);
+        assert_eq!(lines[2], "           r2   <= 00000001");
+        assert_eq!(lines[3], "           lo   <= 00001234");
+        assert!(lines[4].starts_with("         8 80001004: ac820010  sw      $v0, 0x10($a0) ") && lines[4].ends_with(" <main+0x4>"), "{}", lines[4]);
+        assert_eq!(lines[5], "           store 4 vaddr 80000010 paddr 00000010 <= 00000001");
+        assert_eq!(lines[6], "           load  2 vaddr 80000014 paddr 00000014 => 0000beef");
+        assert_eq!(lines[7], "           exception  2 cause 00000008 epc 80001004 <main+0x4> badvaddr 00000010 -> 80000000");
+        assert_eq!(lines[8], "           tlbwrite [ 3] entryhi 00402000 entrylo0 00001017 entrylo1 00001057 pagemask 00001800");
+        assert_eq!(lines[9], "           interrupt ip 80 cause 40008000 epc 80001008 <main+0x8> -> 80000180");
+    }
+
+    #[test]
+    fn interrupt_record() {
+        let buf = Buffer::default();
+        let mut t = Tracer::new(Box::new(buf.clone()), 1<<TRACE_INT, None, None).unwrap();
+        t.fetch(&Reg::new(), 0, 0x80001000, 0);
+        t.interrupt(0x80, 0x40008000, 0x80001008, 0x80000180);
+        assert_eq!(text(&buf.0.borrow(), &SymbolTable::new()),
+            "# events: int\n           interrupt ip 80 cause 40008000 epc 80001008 -> 80000180\n");
+    }
+
+    #[test]
+    fn triggers() {
+        let buf = Buffer::default();
+        let mut t = Tracer::new(Box::new(buf.clone()), 1<<TRACE_INST, Some(Trigger::Pc(0x80001004)), Some(Trigger::Count(3))).unwrap();
+        let reg = Reg::new();
+        for (count, pc) in [(0, 0x80001000), (1, 0x80001004), (2, 0x80001008), (3, 0x8000100c), (4, 0x80001004)] {
+            t.fetch(&reg, count, pc, 0);
+        }
+        let s = text(&buf.0.borrow(), &SymbolTable::new());
+        let pcs : Vec<&str> = s.lines().skip(1).map(|l| &l[11..19]).collect();
+        assert_eq!(pcs, vec!["80001004", "80001008"]);
+    }
+
+    #[test]
+    fn broken_traces() {
+        let mut out = Vec::new();
+        assert!(to_text(&mut &b"EXRMTRC0\0\0\0\x02"[..], &mut out, &SymbolTable::new()).is_err());
+        assert!(to_text(&mut &b"EXRM"[..], &mut out, &SymbolTable::new()).is_err());
+        assert!(to_text(&mut &b"EXRMTRC1\0\0\0\x02\x09\0"[..], &mut out, &SymbolTable::new()).is_err());
+        assert!(to_text(&mut &b"EXRMTRC1\0\0\0\x02\x01\0\0"[..], &mut out, &SymbolTable::new()).is_err());
+    }
+}
@@ -0,0 +1,387 @@
+use crate::dev_uart::{UartReadWrite, NativeUARTConsole, NullUART};
+use log::{info, error};
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, SocketAddr};
+use std::os::unix::io::FromRawFd;
+use std::os::unix::net::UnixListener;
+use std::rc::Rc;
+use std::sync::mpsc::Receiver;
+
+/*
+Host backends of the emulated UARTs.
+
+  stdio              : the terminal of the emulator (default for UART0)
+  null               : not connected (default for UART1)
+  file:PATH          : output is written to a file and no input is given
+  tcp:[HOST:]PORT    : TCP server (telnet-style, e.g., "telnet localhost 4000")
+  unix:PATH          : Unix domain socket server (e.g., "socat - UNIX-CONNECT:PATH")
+  pty                : a host pseudo-terminal (/dev/pts/N) for terminal programs (e.g., picocom)
+  mux:SPEC,SPEC,...  : output is copied to all backends and input is taken from any of them
+*/
+
+const TELNET_IAC  : u8 = 255;
+const TELNET_SB   : u8 = 250;
+const TELNET_SE   : u8 = 240;
+const TELNET_WILL : u8 = 251;
+const TELNET_DONT : u8 = 254;
+const TELNET_OPT_ECHO : u8 = 1;
+const TELNET_OPT_SGA  : u8 = 3;
+
+pub struct FileBackend {
+    f      : File, // not buffered so that a prompt without a newline appears in the file
+    failed : bool, // an error has been reported (reported only once)
+}
+
+impl UartReadWrite for FileBackend {
+    fn read(&mut self)   -> Result<u8,()> { Err(()) }
+    fn write(&mut self, data : char)  -> Result<(),()> {
+        // Output is dropped when the file can not be written (e.g., the disk is full)
+        if let Err(e) = self.f.write_all(&[data as u8]) {
+            if !self.failed {
+                error!("Serial port: can not write to the file ({})\r", e);
+                self.failed = true;
+            }
+        }
+        Ok(())
+    }
+}
+
+enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+trait Stream : Read + Write { }
+impl<T: Read + Write> Stream for T { }
+
+// state of the telnet command parser
+#[derive(PartialEq)]
+enum Telnet {
+    Data,
+    Cr,          // CR was received (LF or NUL after it is dropped)
+    Iac,
+    Option,      // an option code follows WILL, WONT, DO or DONT
+    Sub,         // in a subnegotiation
+    SubIac,
+}
+
+// Server of a stream socket. Only one client is connected at a time.
+pub struct SocketBackend {
+    listener : Listener,
+    client   : Option<Box<dyn Stream>>,
+    telnet   : bool,
+    state    : Telnet,
+    input    : VecDeque<u8>,
+}
+
+impl SocketBackend {
+    fn accept(&mut self) {
+        let client : io::Result<(Box<dyn Stream>, String)> = match &self.listener {
+            Listener::Tcp(l)  => l.accept().and_then(|(s, a)| { s.set_nonblocking(true)?; s.set_nodelay(true)?; Ok((Box::new(s) as Box<dyn Stream>, a.to_string())) }),
+            Listener::Unix(l) => l.accept().and_then(|(s, _)| { s.set_nonblocking(true)?; Ok((Box::new(s) as Box<dyn Stream>, "unix socket".to_string())) }),
+        };
+        if let Ok((mut s, peer)) = client {
+            info!("Serial port: {} is connected\r", peer);
+            if self.telnet {
+                // character mode without local echo
+                let _ = s.write_all(&[TELNET_IAC, TELNET_WILL, TELNET_OPT_ECHO, TELNET_IAC, TELNET_WILL, TELNET_OPT_SGA]);
+            }
+            self.client = Some(s);
+            self.state  = Telnet::Data;
+        }
+    }
+
+    // removes telnet commands from the received data
+    fn filter(&mut self, d: u8) {
+        if !self.telnet {
+            self.input.push_back(d);
+            return;
+        }
+        self.state = match (&self.state, d) {
+            (Telnet::Data, TELNET_IAC) | (Telnet::Cr, TELNET_IAC) => Telnet::Iac,
+            (Telnet::Cr, 0) | (Telnet::Cr, b'\n') => Telnet::Data,
+            (Telnet::Data, _) | (Telnet::Cr, _) => {
+                self.input.push_back(d);
+                if d == b'\r' { Telnet::Cr }else{ Telnet::Data }
+            }
+            (Telnet::Iac, TELNET_IAC) => { self.input.push_back(d); Telnet::Data }
+            (Telnet::Iac, TELNET_SB) => Telnet::Sub,
+            (Telnet::Iac, TELNET_WILL..=TELNET_DONT) => Telnet::Option,
+            (Telnet::Iac, _) | (Telnet::Option, _) => Telnet::Data,
+            (Telnet::Sub, TELNET_IAC) => Telnet::SubIac,
+            (Telnet::Sub, _) => Telnet::Sub,
+            (Telnet::SubIac, TELNET_SE) => Telnet::Data,
+            (Telnet::SubIac, _) => Telnet::Sub,
+        };
+    }
+
+    fn poll(&mut self) {
+        if self.client.is_none() {
+            self.accept();
+        }
+        let mut buf = [0u8; 256];
+        let n = match self.client.as_mut().map(|c| c.read(&mut buf)) {
+            Some(Ok(0)) => { self.disconnect(); return; }
+            Some(Ok(n)) => n,
+            Some(Err(e)) if e.kind() != io::ErrorKind::WouldBlock => { self.disconnect(); return; }
+            _ => { return; }
+        };
+        for d in buf[..n].iter() {
+            self.filter(*d);
+        }
+    }
+
+    fn disconnect(&mut self) {
+        info!("Serial port: the client is disconnected\r");
+        self.client = None;
+    }
+}
+
+impl UartReadWrite for SocketBackend {
+    fn read(&mut self)   -> Result<u8,()> {
+        if self.input.is_empty() {
+            self.poll();
+        }
+        self.input.pop_front().ok_or(())
+    }
+    fn write(&mut self, data : char)  -> Result<(),()> {
+        if self.client.is_none() {
+            self.accept();
+        }
+        let d = data as u8;
+        let res = match self.client.as_mut() {
+            Some(c) if self.telnet && d == TELNET_IAC => c.write_all(&[d, d]),
+            Some(c) => c.write_all(&[d]),
+            None    => Ok(()),
+        };
+        // Output is dropped while the client does not read it
+        match res {
+            Err(e) if e.kind() != io::ErrorKind::WouldBlock => { self.disconnect(); }
+            _ => { }
+        }
+        Ok(())
+    }
+}
+
+pub struct PtyBackend {
+    master : File,
+}
+
+impl PtyBackend {
+    pub fn open() -> io::Result<Self> {
+        unsafe {
+            let fd = libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY);
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let master = File::from_raw_fd(fd);
+            if libc::grantpt(fd) < 0 || libc::unlockpt(fd) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            // raw mode (the line discipline of the slave side is configured through the master)
+            let mut tio : libc::termios = std::mem::zeroed();
+            if libc::tcgetattr(fd, &mut tio) == 0 {
+                libc::cfmakeraw(&mut tio);
+                libc::tcsetattr(fd, libc::TCSANOW, &tio);
+            }
+            let flags = libc::fcntl(fd, libc::F_GETFL);
+            if libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            let name = libc::ptsname(fd);
+            if name.is_null() {
+                return Err(io::Error::last_os_error());
+            }
+            info!("Serial port: {} is opened", std::ffi::CStr::from_ptr(name).to_string_lossy());
+            Ok(Self { master })
+        }
+    }
+}
+
+impl UartReadWrite for PtyBackend {
+    fn read(&mut self)   -> Result<u8,()> {
+        // EIO is returned while no program opens the slave side
+        let mut d = [0u8; 1];
+        match self.master.read(&mut d) {
+            Ok(1) => Ok(d[0]),
+            _     => Err(()),
+        }
+    }
+    fn write(&mut self, data : char)  -> Result<(),()> {
+        // Output is dropped when the buffer of the terminal is full
+        let _ = self.master.write(&[data as u8]);
+        Ok(())
+    }
+}
+
+pub struct MuxBackend {
+    backends : Vec<Box<dyn UartReadWrite>>,
+    next     : usize,
+}
+
+impl UartReadWrite for MuxBackend {
+    fn read(&mut self)   -> Result<u8,()> {
+        // backends are polled in turn so that one of them does not block the others
+        for _ in 0..self.backends.len() {
+            let n = self.next;
+            self.next = (self.next + 1) % self.backends.len();
+            if let Ok(d) = self.backends[n].read() {
+                return Ok(d);
+            }
+        }
+        Err(())
+    }
+    fn write(&mut self, data : char)  -> Result<(),()> {
+        for b in self.backends.iter_mut() {
+            if b.write(data).is_err() {
+                error!("Serial port: can not write to a backend\r");
+            }
+        }
+        Ok(())
+    }
+}
+
+fn tcp_addr(arg: &str) -> io::Result<SocketAddr> {
+    let addr = if arg.contains(':') { arg.to_string() }else{ format!("127.0.0.1:{}", arg) };
+    addr.parse().map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, format!("incorrect address \"{}\"", arg)))
+}
+
+/*
+Opens a backend given as "stdio", "null", "file:PATH", "tcp:[HOST:]PORT", "unix:PATH", "pty"
+or "mux:SPEC,SPEC,...". The console is used for "stdio".
+*/
+pub fn open(spec: &str, console: &Rc<Receiver<u8>>) -> io::Result<Box<dyn UartReadWrite>> {
+    let (kind, arg) = match spec.split_once(':') {
+        Some((k, a)) => (k, Some(a)),
+        None         => (spec, None),
+    };
+    match (kind, arg) {
+        ("stdio", None) => Ok(Box::new(NativeUARTConsole { receiver: Rc::clone(console) })),
+        ("null", None)  => Ok(Box::new(NullUART {})),
+        ("file", Some(path)) => Ok(Box::new(FileBackend { f: File::create(path)?, failed: false })),
+        ("tcp", Some(addr)) => {
+            let l = TcpListener::bind(tcp_addr(addr)?)?;
+            l.set_nonblocking(true)?;
+            info!("Serial port: waiting for a connection on {}", l.local_addr()?);
+            Ok(Box::new(SocketBackend { listener: Listener::Tcp(l), client: None, telnet: true, state: Telnet::Data, input: VecDeque::new() }))
+        }
+        ("unix", Some(path)) => {
+            let _ = std::fs::remove_file(path);
+            let l = UnixListener::bind(path)?;
+            l.set_nonblocking(true)?;
+            info!("Serial port: waiting for a connection on {}", path);
+            Ok(Box::new(SocketBackend { listener: Listener::Unix(l), client: None, telnet: false, state: Telnet::Data, input: VecDeque::new() }))
+        }
+        ("pty", None) => Ok(Box::new(PtyBackend::open()?)),
+        ("mux", Some(list)) => {
+            let backends = list.split(',').filter(|s| !s.is_empty()).map(|s| open(s, console)).collect::<io::Result<Vec<_>>>()?;
+            if backends.is_empty() {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "no backend is given for mux"));
+            }
+            Ok(Box::new(MuxBackend { backends, next: 0 }))
+        }
+        _ => Err(io::Error::new(io::ErrorKind::InvalidInput, format!("unknown serial backend \"{}\"", spec))),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    fn telnet_backend() -> SocketBackend {
+        let l = TcpListener::bind("127.0.0.1:0").unwrap();
+        SocketBackend { listener: Listener::Tcp(l), client: None, telnet: true, state: Telnet::Data, input: VecDeque::new() }
+    }
+
+    fn filtered(data: &[u8]) -> Vec<u8> {
+        let mut b = telnet_backend();
+        for d in data {
+            b.filter(*d);
+        }
+        b.input.into_iter().collect()
+    }
+
+    #[test]
+    fn telnet_commands() {
+        assert_eq!(filtered(b"ls -l"), b"ls -l");
+        // escaped IAC
+        assert_eq!(filtered(&[b'a', TELNET_IAC, TELNET_IAC, b'b']), &[b'a', TELNET_IAC, b'b']);
+        // option negotiations (IAC DO ECHO, IAC WONT SGA) and a command without an option (IAC NOP)
+        assert_eq!(filtered(&[TELNET_IAC, 253, TELNET_OPT_ECHO, b'x', TELNET_IAC, 252, TELNET_OPT_SGA, TELNET_IAC, 241, b'y']), b"xy");
+        // an option code equal to IAC is not taken as a command
+        assert_eq!(filtered(&[TELNET_IAC, TELNET_WILL, TELNET_IAC, b'z']), b"z");
+        // subnegotiation (terminal type) including an escaped IAC
+        assert_eq!(filtered(&[b'a', TELNET_IAC, TELNET_SB, 24, 0, b'x', TELNET_IAC, TELNET_IAC, b't', TELNET_IAC, TELNET_SE, b'b']), b"ab");
+    }
+
+    #[test]
+    fn telnet_newlines() {
+        // NUL or LF after CR is dropped
+        assert_eq!(filtered(b"a\r\0b\r\nc"), b"a\rb\rc");
+        assert_eq!(filtered(b"\r\r\n\n"), b"\r\r\n");
+        assert_eq!(filtered(&[b'\r', TELNET_IAC, TELNET_IAC, 0]), &[b'\r', TELNET_IAC, 0]);
+
+        // the data is kept as it is on a raw socket
+        let mut b = telnet_backend();
+        b.telnet = false;
+        for d in [b'\r', 0, TELNET_IAC, TELNET_IAC] {
+            b.filter(d);
+        }
+        assert_eq!(b.input, [b'\r', 0, TELNET_IAC, TELNET_IAC]);
+    }
+
+    fn open_err(spec: &str) -> String {
+        let (_tx, rx) = std::sync::mpsc::channel();
+        open(spec, &Rc::new(rx)).err().unwrap().to_string()
+    }
+
+    #[test]
+    fn specs() {
+        assert_eq!(open_err("serial"), "unknown serial backend \"serial\"");
+        assert_eq!(open_err("null:x"), "unknown serial backend \"null:x\"");
+        assert_eq!(open_err("file"), "unknown serial backend \"file\"");
+        assert_eq!(open_err("mux:"), "no backend is given for mux");
+        assert_eq!(open_err("mux:null,,stdio:1"), "unknown serial backend \"stdio:1\"");
+        assert_eq!(open_err("tcp:localhost"), "incorrect address \"localhost\"");
+
+        assert_eq!(tcp_addr("4000").unwrap(), "127.0.0.1:4000".parse().unwrap());
+        assert_eq!(tcp_addr("0.0.0.0:4001").unwrap(), "0.0.0.0:4001".parse().unwrap());
+
+        let (_tx, rx) = std::sync::mpsc::channel();
+        assert!(open("mux:null,tcp:0", &Rc::new(rx)).is_ok());
+    }
+
+    // backend with the input given in advance
+    struct Input(VecDeque<u8>, Rc<RefCell<Vec<u8>>>);
+
+    impl UartReadWrite for Input {
+        fn read(&mut self)   -> Result<u8,()> { self.0.pop_front().ok_or(()) }
+        fn write(&mut self, data : char)  -> Result<(),()> { self.1.borrow_mut().push(data as u8); Ok(()) }
+    }
+
+    #[test]
+    fn mux() {
+        let out = Rc::new(RefCell::new(Vec::new()));
+        let mut m = MuxBackend { backends: vec![
+            Box::new(Input(b"abc".iter().copied().collect(), Rc::clone(&out))),
+            Box::new(NullUART {}),
+            Box::new(Input(b"12".iter().copied().collect(), Rc::clone(&out))),
+        ], next: 0 };
+
+        // input is taken from the backends in turn
+        let input : Vec<u8> = std::iter::from_fn(|| m.read().ok()).collect();
+        assert_eq!(input, b"a1b2c");
+        assert!(m.read().is_err());
+
+        // output is copied to all backends
+        m.write('x').unwrap();
+        assert_eq!(*out.borrow(), b"xx");
+    }
+}
@@ -0,0 +1,230 @@
+use crate::procstate::MachineState;
+use crate::cp0def;
+use crate::exception;
+use crate::mips;
+use crate::c0_val;
+use crate::mode_is_exception;
+
+/*
+Watchpoint registers of CP0 (advertised by Config1.WR).
+
+WatchLo n : VAddr (bits 31..3) and the enables of the references to watch, I (fetch), R (load) and W (store)
+WatchHi n : M (another pair follows), G (any ASID), ASID, Mask (bits 11..3 of the address to ignore)
+            and the I, R and W status bits, which are set by a match and cleared by writing 1
+
+A reference to the doubleword of VAddr raises a Watch exception before it completes.
+When Status.EXL or ERL is set, the exception is deferred by setting Cause.WP
+and it is taken once both are cleared.
+*/
+
+pub const NUM_WATCH : usize = 4;
+
+pub const WATCH_I : u32 = 1<<2; // instruction fetch
+pub const WATCH_R : u32 = 1<<1; // load
+pub const WATCH_W : u32 = 1<<0; // store
+const WATCH_IRW   : u32 = WATCH_I | WATCH_R | WATCH_W;
+
+const WATCHHI_BIT_M     : u32 = 31;
+const WATCHHI_BIT_G     : u32 = 30;
+const WATCHHI_BIT_ASID  : u32 = 16;
+const WATCHHI_MASK_MASK : u32 = 0x1ff<<3;
+
+const WATCHLO : [(u32,u32); NUM_WATCH] = [cp0def::C0_WATCHLO0, cp0def::C0_WATCHLO1, cp0def::C0_WATCHLO2, cp0def::C0_WATCHLO3];
+const WATCHHI : [(u32,u32); NUM_WATCH] = [cp0def::C0_WATCHHI0, cp0def::C0_WATCHHI1, cp0def::C0_WATCHHI2, cp0def::C0_WATCHHI3];
+
+// recomputes the enables of all pairs, which are checked before the (slower) matching
+pub fn update(ms: &mut MachineState) {
+    ms.reg.watch_en = WATCHLO.iter().fold(0, |en, rs| en | (c0_val!(ms.reg, *rs) & WATCH_IRW));
+}
+
+pub fn store_watchlo(ms: &mut MachineState, rs: (u32,u32), val: u32) {
+    c0_val!(ms.reg, rs) = val;
+    update(ms);
+}
+
+pub fn store_watchhi(ms: &mut MachineState, rs: (u32,u32), val: u32) {
+    let status = c0_val!(ms.reg, rs) & WATCH_IRW & !val;
+    c0_val!(ms.reg, rs) = status | (val & ((1<<WATCHHI_BIT_G) | (0xff<<WATCHHI_BIT_ASID) | WATCHHI_MASK_MASK));
+}
+
+pub fn load_watchhi(ms: &MachineState, rs: (u32,u32)) -> u32 {
+    let more = if (rs.1 as usize) < NUM_WATCH - 1 { 1<<WATCHHI_BIT_M }else{ 0 };
+    c0_val!(ms.reg, rs) | more
+}
+
+// pairs matching a reference of the kind at vaddr (bit n for the pair n)
+fn matches(ms: &MachineState, vaddr: u32, kind: u32) -> u32 {
+    let asid = c0_val!(ms.reg, cp0def::C0_ENTRYHI) & cp0def::C0_ENTRYHI_ASID_MASK;
+    let mut hit = 0;
+    for n in 0..NUM_WATCH {
+        let lo = c0_val!(ms.reg, WATCHLO[n]);
+        let hi = c0_val!(ms.reg, WATCHHI[n]);
+        let ignored = (hi & WATCHHI_MASK_MASK) | 7;
+        if  0 != (lo & kind) && (vaddr | ignored) == (lo | ignored) &&
+            (0 != (hi & (1<<WATCHHI_BIT_G)) || ((hi >> WATCHHI_BIT_ASID) & 0xff) == asid) {
+            hit |= 1<<n;
+        }
+    }
+    hit
+}
+
+/*
+Checks a reference of the kind (WATCH_I, WATCH_R or WATCH_W) at vaddr and sets the status bits of the matching pairs.
+Returns true if a Watch exception is to be raised now (false if nothing matches or the exception is deferred).
+*/
+pub fn check(ms: &mut MachineState, vaddr: u32, kind: u32) -> bool {
+    let hit = matches(ms, vaddr, kind);
+    if hit == 0 {
+        return false;
+    }
+    for (n, rs) in WATCHHI.iter().enumerate() {
+        if 0 != (hit & (1<<n)) {
+            c0_val!(ms.reg, *rs) |= kind;
+        }
+    }
+    if mode_is_exception!(c0_val!(ms.reg, cp0def::C0_STATUS)) {
+        c0_val!(ms.reg, cp0def::C0_CAUSE) |= 1<<cp0def::C0_CAUSE_BIT_WP;
+        return false;
+    }
+    true
+}
+
+/*
+Checks the instruction fetch at PC and the deferred Watch exception.
+Returns true if a Watch exception is raised (PC is moved to the exception vector).
+*/
+pub fn check_fetch(ms: &mut MachineState) -> bool {
+    let deferred = 0 != (c0_val!(ms.reg, cp0def::C0_CAUSE) & (1<<cp0def::C0_CAUSE_BIT_WP)) &&
+                   !mode_is_exception!(c0_val!(ms.reg, cp0def::C0_STATUS));
+    let pc = ms.reg.pc & !1;
+    if deferred || (0 != (ms.reg.watch_en & WATCH_I) && check(ms, pc, WATCH_I)) {
+        exception::prepare_exception(ms, cp0def::EXCEPT_CODE_WATCH, 0);
+        return true;
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dev_spiflash::SPI_FLASH_PARAM_S25FL164K;
+    use crate::exrmips::generate_machine_state;
+
+    const EXL : u32 = 1<<cp0def::C0_STATUS_BIT_EXL;
+    const ERL : u32 = 1<<cp0def::C0_STATUS_BIT_ERL;
+    const WP  : u32 = 1<<cp0def::C0_CAUSE_BIT_WP;
+
+    fn setup() -> MachineState {
+        let mut ms = generate_machine_state(&SPI_FLASH_PARAM_S25FL164K, &[]);
+        c0_val!(ms.reg, cp0def::C0_STATUS) = 0;
+        c0_val!(ms.reg, cp0def::C0_ENTRYHI) = 5;
+        ms
+    }
+
+    fn exccode(ms: &MachineState) -> u32 {
+        (c0_val!(ms.reg, cp0def::C0_CAUSE) & cp0def::C0_CAUSE_EXCCODE_MASK) >> cp0def::C0_CAUSE_BIT_EXCCODE
+    }
+
+    #[test]
+    fn address_and_kind() {
+        let mut ms = setup();
+        store_watchlo(&mut ms, WATCHLO[0], 0x80001008 | WATCH_W);
+        store_watchhi(&mut ms, WATCHHI[0], 1<<WATCHHI_BIT_G);
+        assert_eq!(ms.reg.watch_en, WATCH_W);
+
+        // any byte of the doubleword, and only the enabled kinds of references
+        assert_eq!(matches(&ms, 0x8000100f, WATCH_W), 1);
+        assert_eq!(matches(&ms, 0x80001010, WATCH_W), 0);
+        assert_eq!(matches(&ms, 0x80001007, WATCH_W), 0);
+        assert_eq!(matches(&ms, 0x80001008, WATCH_R), 0);
+        assert_eq!(matches(&ms, 0x80001008, WATCH_I), 0);
+
+        // the bits of the address in Mask are ignored
+        store_watchlo(&mut ms, WATCHLO[1], 0x80002008 | WATCH_R | WATCH_I);
+        store_watchhi(&mut ms, WATCHHI[1], (1<<WATCHHI_BIT_G) | 0xff0);
+        assert_eq!(ms.reg.watch_en, WATCH_W | WATCH_R | WATCH_I);
+        assert_eq!(matches(&ms, 0x80002ff8, WATCH_R), 2);
+        assert_eq!(matches(&ms, 0x80002000, WATCH_R), 0);
+        assert_eq!(matches(&ms, 0x80003008, WATCH_R), 0);
+
+        // both pairs
+        store_watchlo(&mut ms, WATCHLO[3], 0x80002f08 | WATCH_R);
+        store_watchhi(&mut ms, WATCHHI[3], 1<<WATCHHI_BIT_G);
+        assert_eq!(matches(&ms, 0x80002f0c, WATCH_R), 0b1010);
+
+        store_watchlo(&mut ms, WATCHLO[1], 0);
+        store_watchlo(&mut ms, WATCHLO[3], 0);
+        assert_eq!(ms.reg.watch_en, WATCH_W);
+    }
+
+    #[test]
+    fn asid() {
+        let mut ms = setup();
+        store_watchlo(&mut ms, WATCHLO[2], 0x00400000 | WATCH_I);
+        store_watchhi(&mut ms, WATCHHI[2], 7<<WATCHHI_BIT_ASID);
+        assert_eq!(matches(&ms, 0x00400000, WATCH_I), 0);
+        c0_val!(ms.reg, cp0def::C0_ENTRYHI) = 7;
+        assert_eq!(matches(&ms, 0x00400000, WATCH_I), 4);
+
+        // any ASID with G
+        c0_val!(ms.reg, cp0def::C0_ENTRYHI) = 8;
+        store_watchhi(&mut ms, WATCHHI[2], (1<<WATCHHI_BIT_G) | (7<<WATCHHI_BIT_ASID));
+        assert_eq!(matches(&ms, 0x00400000, WATCH_I), 4);
+
+        // the watch exception before the fetch
+        ms.reg.pc = 0x00400004;
+        assert!(check_fetch(&mut ms));
+        assert_eq!(exccode(&ms), cp0def::EXCEPT_CODE_WATCH);
+        assert_eq!(c0_val!(ms.reg, cp0def::C0_EPC), 0x00400004);
+        assert_eq!(load_watchhi(&ms, WATCHHI[2]) & WATCH_IRW, WATCH_I);
+    }
+
+    #[test]
+    fn status_bits() {
+        let mut ms = setup();
+        store_watchlo(&mut ms, WATCHLO[0], 0x80001000 | WATCH_R | WATCH_W);
+        // the status bits are not set by a write
+        store_watchhi(&mut ms, WATCHHI[0], (1<<WATCHHI_BIT_G) | WATCH_IRW);
+        assert_eq!(load_watchhi(&ms, WATCHHI[0]), (1<<WATCHHI_BIT_M) | (1<<WATCHHI_BIT_G));
+        assert_eq!(load_watchhi(&ms, WATCHHI[3]), 0);
+
+        assert!(check(&mut ms, 0x80001000, WATCH_R));
+        assert!(check(&mut ms, 0x80001004, WATCH_W));
+        assert_eq!(load_watchhi(&ms, WATCHHI[0]) & WATCH_IRW, WATCH_R | WATCH_W);
+
+        // cleared by writing 1
+        store_watchhi(&mut ms, WATCHHI[0], (1<<WATCHHI_BIT_G) | WATCH_W);
+        assert_eq!(load_watchhi(&ms, WATCHHI[0]) & WATCH_IRW, WATCH_R);
+        store_watchhi(&mut ms, WATCHHI[0], (1<<WATCHHI_BIT_G) | WATCH_R);
+        assert_eq!(load_watchhi(&ms, WATCHHI[0]), (1<<WATCHHI_BIT_M) | (1<<WATCHHI_BIT_G));
+    }
+
+    #[test]
+    fn deferred_exception() {
+        let mut ms = setup();
+        store_watchlo(&mut ms, WATCHLO[0], 0x80001000 | WATCH_W);
+        store_watchhi(&mut ms, WATCHHI[0], 1<<WATCHHI_BIT_G);
+
+        // a match in an exception handler sets Cause.WP instead of raising the exception
+        c0_val!(ms.reg, cp0def::C0_STATUS) = EXL | ERL;
+        assert!(!check(&mut ms, 0x80001000, WATCH_W));
+        assert!(0 != (c0_val!(ms.reg, cp0def::C0_CAUSE) & WP));
+        assert_eq!(load_watchhi(&ms, WATCHHI[0]) & WATCH_IRW, WATCH_W);
+
+        ms.reg.pc = 0x80000180;
+        assert!(!check_fetch(&mut ms));
+        c0_val!(ms.reg, cp0def::C0_STATUS) = ERL;
+        assert!(!check_fetch(&mut ms));
+        c0_val!(ms.reg, cp0def::C0_STATUS) = EXL;
+        assert!(!check_fetch(&mut ms));
+        assert_eq!(ms.reg.pc, 0x80000180);
+
+        // raised at the first instruction after both are cleared
+        c0_val!(ms.reg, cp0def::C0_STATUS) = 0;
+        ms.reg.pc = 0x80400000;
+        assert!(check_fetch(&mut ms));
+        assert_eq!(exccode(&ms), cp0def::EXCEPT_CODE_WATCH);
+        assert_eq!(c0_val!(ms.reg, cp0def::C0_EPC), 0x80400000);
+        assert!(0 != (c0_val!(ms.reg, cp0def::C0_STATUS) & EXL));
+    }
+}